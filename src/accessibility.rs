@@ -0,0 +1,175 @@
+use crate::lint::{images, line_of, Issue};
+
+use pulldown_cmark::{Event, Parser, Tag};
+
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
+const STYLE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)\bstyle\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+const RGB_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)^rgba?\(\s*(\d+)\s*,\s*(\d+)\s*,\s*(\d+)\s*(,[^)]*)?\)$").unwrap()
+});
+
+/// WCAG AA's minimum contrast ratio for normal text.
+const MIN_CONTRAST: f64 = 4.5;
+
+/// The background of mdbook's default light theme, which text without a background of its own
+/// is shown on.
+const DEFAULT_BACKGROUND: (u8, u8, u8) = (255, 255, 255);
+
+const NAMED_COLORS: &[(&str, (u8, u8, u8))] = &[
+    ("black", (0, 0, 0)),
+    ("white", (255, 255, 255)),
+    ("gray", (128, 128, 128)),
+    ("grey", (128, 128, 128)),
+    ("silver", (192, 192, 192)),
+    ("lightgray", (211, 211, 211)),
+    ("lightgrey", (211, 211, 211)),
+    ("red", (255, 0, 0)),
+    ("maroon", (128, 0, 0)),
+    ("orange", (255, 165, 0)),
+    ("yellow", (255, 255, 0)),
+    ("olive", (128, 128, 0)),
+    ("lime", (0, 255, 0)),
+    ("green", (0, 128, 0)),
+    ("aqua", (0, 255, 255)),
+    ("cyan", (0, 255, 255)),
+    ("teal", (0, 128, 128)),
+    ("blue", (0, 0, 255)),
+    ("navy", (0, 0, 128)),
+    ("fuchsia", (255, 0, 255)),
+    ("magenta", (255, 0, 255)),
+    ("purple", (128, 0, 128)),
+];
+
+/// A CSS color written as `#rgb`, `#rrggbb`, `rgb(r, g, b)` or one of the basic color names.
+fn parse_color(value: &str) -> Option<(u8, u8, u8)> {
+    let value = value.trim().trim_end_matches("!important").trim();
+    if let Some(hex) = value.strip_prefix('#') {
+        let channel = |i: usize, len: usize| {
+            let digits = hex.get(i * len..(i + 1) * len)?;
+            let value = u8::from_str_radix(digits, 16).ok()?;
+            Some(if len == 1 { value * 17 } else { value })
+        };
+        let len = match hex.len() {
+            3 => 1,
+            6 => 2,
+            _ => return None,
+        };
+        return Some((channel(0, len)?, channel(1, len)?, channel(2, len)?));
+    }
+    if let Some(captures) = RGB_REGEX.captures(value) {
+        let channel = |i: usize| captures[i].parse::<u8>().ok();
+        return Some((channel(1)?, channel(2)?, channel(3)?));
+    }
+    NAMED_COLORS
+        .iter()
+        .find(|(name, _)| value.eq_ignore_ascii_case(name))
+        .map(|(_, color)| *color)
+}
+
+/// The relative luminance of `color`, as defined by WCAG.
+fn luminance((r, g, b): (u8, u8, u8)) -> f64 {
+    let channel = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.03928 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * channel(r) + 0.7152 * channel(g) + 0.0722 * channel(b)
+}
+
+fn contrast(a: (u8, u8, u8), b: (u8, u8, u8)) -> f64 {
+    let (a, b) = (luminance(a), luminance(b));
+    (a.max(b) + 0.05) / (a.min(b) + 0.05)
+}
+
+/// The text and background colors set in the inline `style`, if the text color is set.
+fn style_colors(style: &str) -> Option<(&str, Option<&str>)> {
+    let mut color = None;
+    let mut background = None;
+    for declaration in style.split(';') {
+        let (property, value) = match declaration.find(':') {
+            Some(i) => (declaration[..i].trim(), declaration[i + 1..].trim()),
+            None => continue,
+        };
+        if property.eq_ignore_ascii_case("color") {
+            color = Some(value);
+        } else if property.eq_ignore_ascii_case("background-color")
+            || property.eq_ignore_ascii_case("background")
+        {
+            background = Some(value);
+        }
+    }
+    color.map(|color| (color, background))
+}
+
+/// Issues that make the page at `path` hard to use with a screen reader or to read: images
+/// without alt text, headings that skip a level, and inline styles with too little contrast.
+pub fn audit_page(path: &str, content: &str) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut issue = |line: usize, message: String| {
+        issues.push(Issue {
+            path: path.to_string(),
+            line: Some(line),
+            message,
+            accessibility: true,
+        })
+    };
+
+    for image in images(content) {
+        if image.alt.trim().is_empty() {
+            issue(image.line, format!("Image '{}' has no alt text", image.src));
+        }
+    }
+
+    let mut level = 0;
+    let mut in_code = false;
+    for (event, range) in Parser::new(content).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code = true,
+            Event::End(Tag::CodeBlock(_)) => in_code = false,
+            Event::Start(Tag::Heading(next)) => {
+                if level > 0 && next > level + 1 {
+                    let message = format!("Heading skips from level {} to {}", level, next);
+                    issue(line_of(content, range.start), message);
+                }
+                level = next;
+            }
+            Event::Html(html) if !in_code => {
+                for captures in STYLE_REGEX.captures_iter(&html) {
+                    let style = captures.get(1).or_else(|| captures.get(2)).unwrap();
+                    let (color, background) = match style_colors(style.as_str()) {
+                        Some(colors) => colors,
+                        None => continue,
+                    };
+                    let (text, back) = match background {
+                        Some(background) => (parse_color(color), parse_color(background)),
+                        None => (parse_color(color), Some(DEFAULT_BACKGROUND)),
+                    };
+                    let ratio = match (text, back) {
+                        (Some(text), Some(back)) => contrast(text, back),
+                        _ => continue,
+                    };
+                    if ratio < MIN_CONTRAST {
+                        let offset = captures.get(0).unwrap().start();
+                        let line =
+                            line_of(content, range.start) + html[..offset].matches('\n').count();
+                        let on = background.unwrap_or("the default white background");
+                        let message = format!(
+                            "Text in {} on {} has a contrast ratio of {:.1}:1, less than {}:1",
+                            color, on, ratio, MIN_CONTRAST
+                        );
+                        issue(line, message);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    issues
+}
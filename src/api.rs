@@ -0,0 +1,636 @@
+use crate::bulk::Plan;
+use crate::config::{Config, User};
+use crate::drafts;
+use crate::events::Event;
+use crate::idempotency::{Begin, Idempotent, IdempotencyKey, Storable, StoredResponse};
+use crate::merge;
+use crate::tags::{self, TagChange, TagIndex};
+use crate::utils::{path_is_simple, rand_safe_string};
+use crate::webapp::{page_url, Admin, WebappState};
+use crate::wiki::{WikiRequest, WikiResponse};
+
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_std::fs;
+use async_std::path::{Path, PathBuf};
+
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::status::Custom;
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize)]
+pub struct ApiResponse {
+    ok: bool,
+    message: Option<String>,
+}
+
+pub type ApiResult = Custom<Json<ApiResponse>>;
+
+pub fn respond(status: Status, message: Option<String>) -> ApiResult {
+    Custom(
+        status,
+        Json(ApiResponse {
+            ok: status.code < 400,
+            message,
+        }),
+    )
+}
+
+pub fn from_wiki_response(res: WikiResponse) -> ApiResult {
+    respond(res.status(), res.msg().cloned())
+}
+
+impl Storable for ApiResult {
+    fn to_stored(&self) -> StoredResponse {
+        StoredResponse {
+            status: self.0,
+            body: serde_json::to_string(&(self.1).0).unwrap_or_default(),
+        }
+    }
+}
+
+fn fingerprint(parts: &[&str]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Runs a mutating request at most once per `Idempotency-Key`, replaying the stored response
+/// for retries with the same key.
+async fn idempotent<R, F>(
+    key: IdempotencyKey,
+    username: &str,
+    fingerprint: u64,
+    state: &WebappState,
+    request: F,
+) -> Idempotent<R>
+where
+    R: Storable + From<ApiResult>,
+    F: Future<Output = R>,
+{
+    let key = match key.0 {
+        Some(key) => format!("{}:{}", username, key),
+        None => return Idempotent::Fresh(request.await),
+    };
+    match state.idempotency.begin(&key, fingerprint) {
+        Begin::New => {}
+        Begin::Replay(stored) => return Idempotent::Replayed(stored),
+        Begin::InProgress => {
+            return Idempotent::Fresh(
+                respond(
+                    Status::Conflict,
+                    Some("A request with this idempotency key is in progress".into()),
+                )
+                .into(),
+            )
+        }
+        Begin::Mismatch => {
+            return Idempotent::Fresh(
+                respond(
+                    Status::UnprocessableEntity,
+                    Some("The idempotency key was used for a different request".into()),
+                )
+                .into(),
+            )
+        }
+    }
+
+    let res = request.await;
+    state.idempotency.finish(&key, res.to_stored());
+    Idempotent::Fresh(res)
+}
+
+pub struct ContentLength(pub Option<u64>);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for ContentLength {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(ContentLength(
+            req.headers()
+                .get_one("Content-Length")
+                .and_then(|len| len.parse().ok()),
+        ))
+    }
+}
+
+/// The raw markdown of a page, for clients that edit it with `PUT`.
+#[get("/api/v1/pages/<file..>")]
+pub async fn get_page(
+    file: std::path::PathBuf,
+    user: Option<User>,
+    config: State<'_, Config>,
+) -> Result<String, ApiResult> {
+    if user.is_none() && !config.allow_anonymous {
+        return Err(respond(Status::Unauthorized, Some("Not logged in".into())));
+    }
+    let file = PathBuf::from(file);
+    if !path_is_simple(&file) || file.extension().map_or(true, |ext| ext != "md") {
+        return Err(respond(Status::BadRequest, Some("Not a page".into())));
+    }
+    fs::read_to_string(Path::new(&config.path).join("src").join(&file))
+        .await
+        .map_err(|_| respond(Status::NotFound, Some("No such page".into())))
+}
+
+/// Creates or replaces a page with the raw markdown in the request body. Unlike the form based
+/// routes, the body is streamed to a file the wiki reads the page from when it's its turn, so
+/// waiting requests don't hold pages in memory. Requests announcing a body larger than
+/// `max_page_size` are rejected before anything is read, others once they exceed it.
+#[put("/api/v1/pages/<file..>", data = "<data>")]
+pub async fn put_page(
+    file: std::path::PathBuf,
+    data: Data,
+    content_length: ContentLength,
+    idempotency_key: IdempotencyKey,
+    user: Option<User>,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Idempotent<ApiResult> {
+    let user = match user {
+        Some(user) => user,
+        None => return respond(Status::Unauthorized, Some("Not logged in".into())).into(),
+    };
+
+    let too_large = || {
+        respond(
+            Status::PayloadTooLarge,
+            Some(format!(
+                "Pages can be at most {} bytes",
+                config.max_page_size
+            )),
+        )
+    };
+
+    if content_length
+        .0
+        .map(|len| len > config.max_page_size)
+        .unwrap_or(false)
+    {
+        return too_large().into();
+    }
+
+    // One byte more than allowed, to tell pages that are too large from those that fit, also
+    // when the request doesn't announce its size
+    let upload = config.upload_path().join(rand_safe_string(16));
+    let limit = config.max_page_size + 1;
+    let written = match data.open(limit.bytes()).stream_to_file(&upload).await {
+        Ok(_) => fs::metadata(&upload).await.map(|metadata| metadata.len()),
+        Err(e) => Err(e),
+    };
+    let digest = match written {
+        Ok(written) if written < limit => file_digest(&upload),
+        Ok(_) => {
+            let _ = fs::remove_file(&upload).await;
+            return too_large().into();
+        }
+        Err(e) => Err(e),
+    };
+    let digest = match digest {
+        Ok(digest) => digest,
+        Err(e) => {
+            warn!("could not save page: {}", e);
+            let _ = fs::remove_file(&upload).await;
+            return respond(Status::InternalServerError, None).into();
+        }
+    };
+
+    let fingerprint = fingerprint(&["put_page", &file.to_string_lossy(), &digest]);
+    let username = user.username.clone();
+    let res = idempotent(idempotency_key, &username, fingerprint, &state, async {
+        let res = state
+            .send(|respond| WikiRequest::PutFile {
+                user,
+                file: PathBuf::from(file).into_boxed_path(),
+                upload: upload.clone(),
+                respond,
+            })
+            .await;
+        match res {
+            Ok((true, res)) if res.is_ok() => respond(Status::Created, res.msg().cloned()),
+            Ok((_, res)) | Err(res) => from_wiki_response(res),
+        }
+    })
+    .await;
+    // Requests that were replayed or never reached the wiki leave the upload behind
+    let _ = fs::remove_file(&upload).await;
+    res
+}
+
+/// The md5 digest of the file at `path`, read a chunk at a time.
+fn file_digest(path: &std::path::Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut context = md5::Context::new();
+    let mut chunk = [0; 8192];
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok(format!("{:x}", context.compute())),
+            read => context.consume(&chunk[..read]),
+        }
+    }
+}
+
+#[post("/api/v1/admin/read_only?<enabled>")]
+pub fn set_read_only(enabled: bool, admin: Admin, state: State<'_, WebappState>) -> ApiResult {
+    if !enabled && state.shared.book_read_only() {
+        return respond(
+            Status::Conflict,
+            Some("The book is read-only, read-only mode can't be disabled".into()),
+        );
+    }
+    state.shared.set_read_only(enabled);
+    info!(
+        "'{}' {} read-only mode",
+        admin.0.username,
+        if enabled { "enabled" } else { "disabled" }
+    );
+    respond(
+        Status::Ok,
+        Some(format!(
+            "Read-only mode {}",
+            if enabled { "enabled" } else { "disabled" }
+        )),
+    )
+}
+
+const MAX_EVENTS_WAIT: u64 = 60;
+
+/// Long-polls for wiki events. Returns the events after the id `since` right away if there are
+/// any, otherwise waits up to `wait` seconds for new ones. Clients pass the id of the last event
+/// they received as `since` in the next request.
+#[get("/api/v1/events?<since>&<wait>")]
+pub async fn events(
+    since: Option<u64>,
+    wait: Option<u64>,
+    _user: User,
+    state: State<'_, WebappState>,
+) -> Json<Vec<Event>> {
+    let wait = Duration::from_secs(wait.unwrap_or(30).min(MAX_EVENTS_WAIT));
+    Json(state.shared.events.poll(since.unwrap_or(0), wait).await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveRequest {
+    from: String,
+    to: String,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplaceRequest {
+    search: String,
+    replace: String,
+    #[serde(default)]
+    regex: bool,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagsRequest {
+    #[serde(flatten)]
+    change: TagChange,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Responder)]
+pub enum PlanResponse {
+    Plan(Json<Plan>),
+    Error(ApiResult),
+}
+
+impl From<ApiResult> for PlanResponse {
+    fn from(res: ApiResult) -> Self {
+        PlanResponse::Error(res)
+    }
+}
+
+impl Storable for PlanResponse {
+    fn to_stored(&self) -> StoredResponse {
+        match self {
+            PlanResponse::Plan(plan) => StoredResponse {
+                status: Status::Ok,
+                body: serde_json::to_string(&plan.0).unwrap_or_default(),
+            },
+            PlanResponse::Error(res) => res.to_stored(),
+        }
+    }
+}
+
+impl From<Result<Plan, WikiResponse>> for PlanResponse {
+    fn from(res: Result<Plan, WikiResponse>) -> Self {
+        match res {
+            Ok(plan) => PlanResponse::Plan(Json(plan)),
+            Err(res) => PlanResponse::Error(from_wiki_response(res)),
+        }
+    }
+}
+
+/// Moves a page or a directory, rewriting links to the moved pages. With `dry_run`, either as a
+/// query parameter or in the body, the planned changes are returned without applying them.
+#[post("/api/v1/move?<dry_run>", data = "<body>")]
+pub async fn move_path(
+    dry_run: Option<bool>,
+    body: Json<MoveRequest>,
+    idempotency_key: IdempotencyKey,
+    user: User,
+    state: State<'_, WebappState>,
+) -> Idempotent<PlanResponse> {
+    let body = body.into_inner();
+    let dry_run = dry_run.unwrap_or(body.dry_run);
+    let fingerprint = fingerprint(&["move", &body.from, &body.to, &dry_run.to_string()]);
+    let username = user.username.clone();
+    idempotent(idempotency_key, &username, fingerprint, &state, async {
+        state
+            .send(|respond| WikiRequest::Move {
+                user,
+                from: PathBuf::from(body.from).into_boxed_path(),
+                to: PathBuf::from(body.to).into_boxed_path(),
+                dry_run,
+                respond,
+            })
+            .await
+            .and_then(|res| res)
+            .into()
+    })
+    .await
+}
+
+/// Search and replace across all pages, see `move_path` for `dry_run`.
+#[post("/api/v1/replace?<dry_run>", data = "<body>")]
+pub async fn replace(
+    dry_run: Option<bool>,
+    body: Json<ReplaceRequest>,
+    idempotency_key: IdempotencyKey,
+    admin: Admin,
+    state: State<'_, WebappState>,
+) -> Idempotent<PlanResponse> {
+    let body = body.into_inner();
+    let dry_run = dry_run.unwrap_or(body.dry_run);
+    let fingerprint = fingerprint(&[
+        "replace",
+        &body.search,
+        &body.replace,
+        &body.regex.to_string(),
+        &dry_run.to_string(),
+    ]);
+    let username = admin.0.username.clone();
+    idempotent(idempotency_key, &username, fingerprint, &state, async {
+        state
+            .send(|respond| WikiRequest::Replace {
+                user: admin.0,
+                search: body.search,
+                replace: body.replace,
+                regex: body.regex,
+                dry_run,
+                respond,
+            })
+            .await
+            .and_then(|res| res)
+            .into()
+    })
+    .await
+}
+
+/// The tags in use, with how many pages have each of them, and the pages without tags.
+#[get("/api/v1/tags")]
+pub fn tag_index(_admin: Admin, config: State<'_, Config>) -> Json<TagIndex> {
+    Json(tags::index(&std::path::Path::new(&config.path).join("src")))
+}
+
+/// Renames a tag, or merges it into another, on every page, see `move_path` for `dry_run`.
+#[post("/api/v1/tags?<dry_run>", data = "<body>")]
+pub async fn change_tags(
+    dry_run: Option<bool>,
+    body: Json<TagsRequest>,
+    idempotency_key: IdempotencyKey,
+    admin: Admin,
+    state: State<'_, WebappState>,
+) -> Idempotent<PlanResponse> {
+    let body = body.into_inner();
+    let dry_run = dry_run.unwrap_or(body.dry_run);
+    let (action, from, to) = match &body.change {
+        TagChange::Rename { from, to } => ("rename", from, to),
+        TagChange::Merge { from, into } => ("merge", from, into),
+    };
+    let fingerprint = fingerprint(&["tags", action, from, to, &dry_run.to_string()]);
+    let username = admin.0.username.clone();
+    idempotent(idempotency_key, &username, fingerprint, &state, async {
+        state
+            .send(|respond| WikiRequest::ChangeTags {
+                user: admin.0,
+                change: body.change,
+                dry_run,
+                respond,
+            })
+            .await
+            .and_then(|res| res)
+            .into()
+    })
+    .await
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContextUser {
+    username: String,
+    name: String,
+    admin: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Permissions {
+    edit: bool,
+    /// Whether the user can suggest changes to the page, see `Config::suggestions`.
+    suggest: bool,
+    create: bool,
+    watch: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PageMetadata {
+    path: String,
+    exists: bool,
+    /// Unix time of the last change to the file.
+    modified: Option<u64>,
+    watched: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    kind: &'static str,
+    message: String,
+}
+
+/// Everything the script injected into the book needs to know about the current user and page.
+#[derive(Debug, Serialize)]
+pub struct PageContext {
+    user: Option<ContextUser>,
+    read_only: bool,
+    permissions: Permissions,
+    page: PageMetadata,
+    /// Url paths of the pages, and prefixes of the directories, shown in the sidebar by default.
+    sidebar: Vec<String>,
+    notifications: Vec<Notification>,
+}
+
+/// Conflicting edits by `username` waiting in the review area, see `merge::Conflict`.
+fn conflicts_for_review(config: &Config, username: &str) -> usize {
+    std::fs::read_dir(config.data_path().join("conflicts"))
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| merge::saved_by(&entry.path()).as_deref() == Some(username))
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+/// The context of the page built from the markdown file `path`, for the injected script.
+#[get("/api/v1/context?<path>")]
+pub async fn page_context(
+    path: Option<String>,
+    user: Option<User>,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<Json<PageContext>, Status> {
+    let mut path = path.unwrap_or_else(|| "README.md".into());
+    // mdbook renames the top level README.md to index.md
+    if path == "index.md" || path.ends_with("/index.md") {
+        path = format!("{}README.md", &path[..path.len() - "index.md".len()]);
+    }
+    if !path_is_simple(Path::new(&path)) {
+        return Err(Status::BadRequest);
+    }
+
+    let read_only = state.shared.read_only();
+    // Creating is checked for the directory of the page, which is where the new page form
+    // starts from
+    let dir = Path::new(&path).parent().unwrap_or_else(|| Path::new(""));
+    let may_change = |path: &Path| match &user {
+        Some(user) => !read_only && config.may_change(user, path).is_ok(),
+        None => false,
+    };
+    let (may_edit, may_create) = (may_change(Path::new(&path)), may_change(dir));
+    let can_watch = state.shared.mailer.is_some()
+        && user.as_ref().map_or(false, |user| user.email.is_some());
+
+    let file = Path::new(&config.path).join("src").join(&path);
+    let modified = file
+        .metadata()
+        .await
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs());
+    let watched = user.as_ref().map_or(false, |user| {
+        state
+            .shared
+            .watches
+            .watched_by(&user.username)
+            .contains(&path)
+    });
+
+    let sidebar = user
+        .iter()
+        .flat_map(|user| config.pages_for(user))
+        .flat_map(|pages| pages.sidebar.iter())
+        .map(|path| match path.ends_with(".md") {
+            true => page_url(path),
+            false => format!("{}/", path.trim_matches('/')),
+        })
+        .collect();
+
+    let mut notifications = Vec::new();
+    if let Some(message) = state.shared.read_only_message() {
+        notifications.push(Notification {
+            kind: "read_only",
+            message: message.into(),
+        });
+    }
+    if let Some(user) = &user {
+        if user.is_admin() && state.shared.builds.pinned_dir().is_some() {
+            notifications.push(Notification {
+                kind: "pinned_build",
+                message: "An earlier build is pinned, changes aren't shown until it's unpinned"
+                    .into(),
+            });
+        }
+        let suggestions = state
+            .shared
+            .suggestions
+            .all()
+            .into_iter()
+            .filter(|suggestion| suggestion.username != user.username)
+            .filter(|suggestion| config.may_change(user, Path::new(&suggestion.page)).is_ok())
+            .count();
+        if suggestions > 0 {
+            notifications.push(Notification {
+                kind: "suggestions",
+                message: format!(
+                    "{} suggested changes are waiting for your review on /reviews",
+                    suggestions
+                ),
+            });
+        }
+        let conflicts = conflicts_for_review(&config, &user.username);
+        if conflicts > 0 {
+            notifications.push(Notification {
+                kind: "conflicts",
+                message: format!(
+                    "{} of your edits could not be merged, and were saved for review",
+                    conflicts
+                ),
+            });
+        }
+        let drafts = git2::Repository::open(&config.path)
+            .map_err(|e| e.to_string())
+            .and_then(|repo| drafts::list(&repo, &user.username))
+            .unwrap_or_default();
+        if !drafts.is_empty() {
+            let message = match drafts.iter().any(|draft| draft.page == path) {
+                true => "You have an unpublished draft of this page, see /drafts".to_string(),
+                false => format!("You have {} unpublished drafts on /drafts", drafts.len()),
+            };
+            notifications.push(Notification {
+                kind: "drafts",
+                message,
+            });
+        }
+    }
+
+    Ok(Json(PageContext {
+        user: user.as_ref().map(|user| ContextUser {
+            username: user.username.clone(),
+            name: user.name().to_string(),
+            admin: user.is_admin(),
+        }),
+        read_only,
+        permissions: Permissions {
+            edit: may_edit,
+            suggest: user.is_some() && !read_only && !may_edit && config.suggestions,
+            create: may_create,
+            watch: can_watch,
+        },
+        page: PageMetadata {
+            exists: modified.is_some(),
+            path,
+            modified,
+            watched,
+        },
+        sidebar,
+        notifications,
+    }))
+}
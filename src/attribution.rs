@@ -0,0 +1,116 @@
+//! Who last edited every page, when, and how many people have, so readers know who to ask
+//! about a page. Computed from the history after every build, since the change being built
+//! isn't committed until the build succeeds.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use git2::Repository;
+
+use time::OffsetDateTime;
+
+/// The history of a page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribution {
+    /// The author of the last commit changing the page.
+    pub last_author: String,
+    /// Unix time of the last commit changing the page.
+    pub last_edited: i64,
+    /// Everyone who has changed the page, most recent first.
+    pub contributors: Vec<String>,
+}
+
+impl Attribution {
+    fn html(&self) -> String {
+        let edited = OffsetDateTime::from_unix_timestamp(self.last_edited).format("%Y-%m-%d");
+        let contributors = match self.contributors.len() {
+            1 => "1 contributor".to_string(),
+            n => format!("{} contributors", n),
+        };
+        format!(
+            "<p class=\"mdwiki-attribution\" style=\"margin-top: 3em; font-size: 0.8em; \
+             opacity: 0.7;\">Last edited by {} on {}, {}</p>",
+            escape_html(&self.last_author),
+            edited,
+            contributors
+        )
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// The history of every page in the book at `path`, by their path relative to the book source.
+/// Walks every commit on the current branch, comparing it to its first parent.
+pub fn attributions(path: &str) -> Result<HashMap<String, Attribution>, String> {
+    let err = |e: git2::Error| format!("could not read the history: {}", e);
+    let repo = Repository::open(path).map_err(err)?;
+    let mut revwalk = repo.revwalk().map_err(err)?;
+    revwalk.push_head().map_err(err)?;
+
+    let mut attributions: HashMap<String, Attribution> = HashMap::new();
+    for oid in revwalk {
+        let commit = oid.and_then(|oid| repo.find_commit(oid)).map_err(err)?;
+        let tree = commit.tree().map_err(err)?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree().map_err(err)?),
+            None => None,
+        };
+        let diff = repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+            .map_err(err)?;
+        let author = commit.author().name().unwrap_or("").to_string();
+        for delta in diff.deltas() {
+            let page = delta
+                .new_file()
+                .path()
+                .and_then(|path| path.strip_prefix("src").ok())
+                .filter(|path| path.extension().map_or(false, |ext| ext == "md"))
+                .map(|path| path.to_string_lossy().into_owned());
+            let page = match page {
+                Some(page) => page,
+                None => continue,
+            };
+            // commits are walked newest first
+            let attribution = attributions.entry(page).or_insert_with(|| Attribution {
+                last_author: author.clone(),
+                last_edited: commit.time().seconds(),
+                contributors: Vec::new(),
+            });
+            if !attribution.contributors.contains(&author) {
+                attribution.contributors.push(author.clone());
+            }
+        }
+    }
+    Ok(attributions)
+}
+
+/// The page built from `page`, relative to the build directory. mdbook builds `README.md`s
+/// into `index.html`.
+fn html_path(page: &str) -> String {
+    match page.strip_suffix("README.md") {
+        Some(dir) if dir.is_empty() || dir.ends_with('/') => format!("{}index.html", dir),
+        _ => format!("{}.html", page.trim_end_matches(".md")),
+    }
+}
+
+/// Adds who last edited every page of the book at `path` and when to the end of the pages
+/// built into `dir`.
+pub fn add_attribution(path: &str, dir: &Path) -> Result<(), String> {
+    for (page, attribution) in attributions(path)? {
+        let file = dir.join(html_path(&page));
+        let html = match fs::read_to_string(&file) {
+            Ok(html) => html,
+            // deleted pages and pages missing from the summary
+            Err(_) => continue,
+        };
+        let html = html.replacen("</main>", &format!("{}\n</main>", attribution.html()), 1);
+        fs::write(&file, html)
+            .map_err(|e| format!("could not write '{}': {}", file.display(), e))?;
+    }
+    Ok(())
+}
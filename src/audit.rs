@@ -0,0 +1,145 @@
+use crate::net::ClientIp;
+use crate::wiki::WikiResponse;
+
+use std::io::Write;
+use std::time::Instant;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+const AUDIT_TARGET: &str = "mdwiki::audit";
+const REQUEST_TARGET: &str = "mdwiki::request";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Sets up logging, in the given format. The level is still read from `LOG_LEVEL`.
+pub fn init_logger(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_env("LOG_LEVEL");
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json_line(record, buf.timestamp_millis().to_string());
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// A log record as a JSON object, with the time it was logged at.
+pub fn json_line(record: &log::Record, time: String) -> Value {
+    // Audit and request records are already JSON objects, their fields are inlined
+    let mut line = match record.target() {
+        AUDIT_TARGET | REQUEST_TARGET => {
+            serde_json::from_str(&record.args().to_string()).unwrap_or_default()
+        }
+        _ => {
+            let mut line = Map::new();
+            line.insert("message".into(), record.args().to_string().into());
+            line
+        }
+    };
+    line.insert("time".into(), time.into());
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    Value::Object(line)
+}
+
+/// A change made to the wiki, and how it went.
+#[derive(Debug, Serialize)]
+pub struct Mutation<'a> {
+    pub user: &'a str,
+    pub action: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+}
+
+impl<'a> Mutation<'a> {
+    pub fn new(
+        user: &'a str,
+        action: &'a str,
+        path: Option<String>,
+        outcome: Result<Option<String>, &WikiResponse>,
+    ) -> Mutation<'a> {
+        let (status, message, commit) = match outcome {
+            Ok(commit) => (200, None, commit),
+            Err(res) => (res.status().code, res.msg().cloned(), None),
+        };
+        Mutation {
+            user,
+            action,
+            path,
+            status,
+            message,
+            commit,
+        }
+    }
+    pub fn log(&self) {
+        match serde_json::to_string(self) {
+            Ok(line) => info!(target: AUDIT_TARGET, "{}", line),
+            Err(e) => warn!("failed to serialize audit record: {}", e),
+        }
+    }
+}
+
+/// The user a request was made by, set by the `User` guard.
+pub struct RequestUser(pub Option<String>);
+
+struct RequestStart(Instant);
+
+#[derive(Serialize)]
+struct RequestRecord<'a> {
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+}
+
+/// Logs every request as a JSON line.
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let start = req.local_cache(|| RequestStart(Instant::now()));
+        let client = req.guard::<ClientIp>().await.succeeded();
+        let record = RequestRecord {
+            method: req.method().as_str(),
+            path: req.uri().path(),
+            status: res.status().code,
+            duration_ms: start.0.elapsed().as_millis(),
+            client: client.map(|ip| ip.to_string()),
+            user: req.local_cache(|| RequestUser(None)).0.as_deref(),
+        };
+        match serde_json::to_string(&record) {
+            Ok(line) => info!(target: REQUEST_TARGET, "{}", line),
+            Err(e) => warn!("failed to serialize request record: {}", e),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use crate::config::{OidcConfig, User};
+
+use std::collections::HashMap;
+
+use rocket::tokio::sync::RwLock;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+
+use reqwest::Url;
+
+type Claims = HashMap<String, Value>;
+
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+pub struct OidcProvider {
+    config: OidcConfig,
+    client: reqwest::Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+}
+
+impl OidcProvider {
+    pub fn new(config: OidcConfig) -> Self {
+        OidcProvider {
+            config,
+            client: reqwest::Client::new(),
+            metadata: RwLock::new(None),
+        }
+    }
+    async fn metadata(&self) -> Result<ProviderMetadata, String> {
+        if let Some(metadata) = self.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer_url.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = self
+            .client
+            .get(&discovery_url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| format!("failed to fetch '{}': {}", discovery_url, e))?
+            .json()
+            .await
+            .map_err(|e| format!("invalid provider metadata: {}", e))?;
+
+        *self.metadata.write().await = Some(metadata.clone());
+
+        Ok(metadata)
+    }
+    pub async fn authorize_url(&self, state: &str, nonce: &str) -> Result<String, String> {
+        let metadata = self.metadata().await?;
+
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_url),
+                ("scope", &self.config.scopes.join(" ")),
+                ("state", state),
+                ("nonce", nonce),
+            ],
+        )
+        .map_err(|e| format!("invalid authorization endpoint: {}", e))?;
+
+        Ok(url.to_string())
+    }
+    pub async fn exchange_code(&self, code: &str, nonce: &str) -> Result<Claims, String> {
+        let metadata = self.metadata().await?;
+
+        let token: TokenResponse = self
+            .client
+            .post(&metadata.token_endpoint)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+            ])
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| format!("failed to exchange authorization code: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("invalid token response: {}", e))?;
+
+        let claims = self.validate_id_token(&metadata, &token.id_token).await?;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err("id token nonce does not match".into());
+        }
+
+        Ok(claims)
+    }
+    async fn validate_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+    ) -> Result<Claims, String> {
+        let header = decode_header(id_token).map_err(|e| format!("invalid id token: {}", e))?;
+        match header.alg {
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => {}
+            alg => return Err(format!("unsupported id token algorithm {:?}", alg)),
+        }
+
+        let jwks: Jwks = self
+            .client
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| format!("failed to fetch '{}': {}", metadata.jwks_uri, e))?
+            .json()
+            .await
+            .map_err(|e| format!("invalid jwks: {}", e))?;
+
+        let jwk = jwks
+            .keys
+            .iter()
+            .filter(|key| key.kty == "RSA")
+            .find(|key| header.kid.is_none() || key.kid == header.kid)
+            .ok_or("no matching key for id token")?;
+        let key = DecodingKey::from_rsa_components(
+            jwk.n.as_deref().unwrap_or_default(),
+            jwk.e.as_deref().unwrap_or_default(),
+        );
+
+        let mut validation = Validation::new(header.alg);
+        validation.iss = Some(metadata.issuer.clone());
+        validation.set_audience(&[&self.config.client_id]);
+
+        decode::<Claims>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| format!("id token validation failed: {}", e))
+    }
+    /// Maps validated claims to a user, named by `username_claim`, and the roles mapped from the
+    /// claims. Users without a password, made for single sign-on, are found by name, users with
+    /// one only by the email the identity provider verified, with `link_by_email`. The mapped
+    /// roles are only for the session, in addition to the user's own, so a role taken away at
+    /// the identity provider is gone the next time the user logs in.
+    pub fn user_from_claims(
+        &self,
+        claims: &Claims,
+        users: &[User],
+    ) -> Result<(User, Vec<String>), String> {
+        let username = claim(claims, &self.config.username_claim)
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("id token is missing claim '{}'", self.config.username_claim))?;
+
+        let linked = match verified_email(claims) {
+            Some(email) if self.config.link_by_email => {
+                let mut linked = users.iter().filter(|user| {
+                    let local = user.email.as_deref().map(str::trim);
+                    local.map_or(false, |local| local.eq_ignore_ascii_case(email))
+                });
+                match (linked.next(), linked.next()) {
+                    (Some(user), None) => Some(user),
+                    (Some(_), Some(_)) => {
+                        return Err(format!("more than one user has the email '{}'", email))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let existing = linked.or_else(|| users.iter().find(|user| user.username == username));
+        let mut user = match existing {
+            Some(user) if linked.is_none() && !user.password.is_empty() => {
+                return Err(format!(
+                    "'{}' is a local user, and can only log in with single sign-on by a \
+                     verified email with link_by_email",
+                    username
+                ))
+            }
+            Some(user) => user.clone(),
+            None if self.config.auto_provision => User {
+                username: username.to_string(),
+                password: "".into(),
+                roles: Vec::new(),
+                disabled: false,
+                email: None,
+                email_verified: false,
+                display_name: None,
+                avatar: None,
+            },
+            None => return Err(format!("unknown user '{}'", username)),
+        };
+        if user.disabled {
+            return Err(format!("user '{}' is disabled", user.username));
+        }
+
+        let standard_claim = |name| claim(claims, name).and_then(Value::as_str).map(String::from);
+        match verified_email(claims) {
+            Some(email) => {
+                user.email = Some(email.to_string());
+                user.email_verified = true;
+            }
+            None => user.email = user.email.or_else(|| standard_claim("email")),
+        }
+        user.display_name = user.display_name.or_else(|| standard_claim("name"));
+        user.avatar = user.avatar.or_else(|| standard_claim("picture"));
+
+        Ok((user, self.roles_from_claims(claims)))
+    }
+    fn roles_from_claims(&self, claims: &Claims) -> Vec<String> {
+        let values = match self
+            .config
+            .roles_claim
+            .as_ref()
+            .and_then(|name| claim(claims, name))
+        {
+            Some(Value::String(value)) => vec![value.as_str()],
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        values
+            .into_iter()
+            .filter_map(|value| self.config.role_mapping.get(value).cloned())
+            .collect()
+    }
+}
+
+/// The user's email, if the identity provider has verified that it's theirs. Some providers
+/// send `email_verified` as a string.
+fn verified_email(claims: &Claims) -> Option<&str> {
+    let verified = match claims.get("email_verified") {
+        Some(Value::Bool(verified)) => *verified,
+        Some(Value::String(verified)) => verified == "true",
+        _ => false,
+    };
+    claims
+        .get("email")
+        .and_then(Value::as_str)
+        .filter(|_| verified)
+}
+
+/// Looks up a claim by name, where nested claims (like keycloak's `realm_access.roles`) can be
+/// reached with a dotted path.
+fn claim<'a>(claims: &'a Claims, name: &str) -> Option<&'a Value> {
+    let mut parts = name.split('.');
+    let mut value = claims.get(parts.next()?)?;
+    for part in parts {
+        value = value.get(part)?;
+    }
+    Some(value)
+}
@@ -0,0 +1,305 @@
+use crate::cli::add_dir;
+use crate::config::Config;
+
+use std::fs::{self, File};
+use std::io::{self, Cursor};
+use std::path::{Component, Path, PathBuf};
+
+use git2::Repository;
+
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
+use reqwest::{Client, Method, Url};
+
+use ring::{digest, hmac};
+
+use serde::{Deserialize, Serialize};
+
+use time::OffsetDateTime;
+
+use zip::read::ZipArchive;
+use zip::write::ZipWriter;
+
+const KEY_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"<Key>([^<]*)</Key>").unwrap());
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// S3 compatible object storage that a zip file of the repository is uploaded to every
+/// `interval` seconds, and when an admin POSTs to `/admin/backup`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackupConfig {
+    /// Like `https://s3.eu-west-1.amazonaws.com`, with the bucket in the path of requests.
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "BackupConfig::default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Put in front of the name of every backup, like `mdwiki/`.
+    #[serde(default)]
+    pub prefix: String,
+    /// Seconds between backups, 0 for only taking them on request.
+    #[serde(default = "BackupConfig::default_interval")]
+    pub interval: u64,
+    /// How many backups are kept, the oldest ones are removed after every backup. 0 keeps all.
+    #[serde(default = "BackupConfig::default_keep")]
+    pub keep: usize,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn sign(key: &[u8], data: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` if `path` is set.
+fn uri_encode(text: &str, path: bool) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if path => "/".to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// A zip file of the repository with its history. mdwiki's own state, which holds secrets, and
+/// the built book are left out.
+pub fn archive(config: &Config) -> Result<Vec<u8>, String> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let skip = [".mdwiki", config.book_path.as_str()];
+    add_dir(&mut zip, Path::new(&config.path), "", &skip)?;
+    zip.finish()
+        .map(Cursor::into_inner)
+        .map_err(|e| format!("could not write backup: {}", e))
+}
+
+/// Extracts the backup `archive` into `dir`, and checks that it's a wiki: a git repository
+/// whose latest commit has a `src/SUMMARY.md`.
+fn extract(archive: &Path, dir: &Path) -> Result<(), String> {
+    let file = File::open(archive).map_err(|e| format!("could not open backup: {}", e))?;
+    let mut zip = ZipArchive::new(file).map_err(|e| format!("not a zip file: {}", e))?;
+    for i in 0..zip.len() {
+        let mut entry = zip
+            .by_index(i)
+            .map_err(|e| format!("invalid backup: {}", e))?;
+        let name = PathBuf::from(entry.name());
+        if !name.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("invalid path '{}' in backup", name.display()));
+        }
+        let path = dir.join(&name);
+        let parent = if entry.is_dir() {
+            Some(path.as_path())
+        } else {
+            path.parent()
+        };
+        if let Some(parent) = parent {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("could not create '{}': {}", parent.display(), e))?;
+        }
+        if !entry.is_dir() {
+            File::create(&path)
+                .and_then(|mut file| io::copy(&mut entry, &mut file))
+                .map_err(|e| format!("could not extract '{}': {}", name.display(), e))?;
+        }
+    }
+
+    let repo =
+        Repository::open(dir).map_err(|e| format!("the backup isn't a git repository: {}", e))?;
+    let tree = repo
+        .head()
+        .and_then(|head| head.peel_to_tree())
+        .map_err(|e| format!("the backup has no commits: {}", e))?;
+    if tree.get_path(Path::new("src/SUMMARY.md")).is_err() {
+        return Err("the backup has no src/SUMMARY.md".into());
+    }
+    Ok(())
+}
+
+/// Moves everything in `from` but `skip` to `to`.
+fn move_entries(from: &Path, to: &Path, skip: &[&str]) -> Result<(), String> {
+    let entries =
+        fs::read_dir(from).map_err(|e| format!("could not read '{}': {}", from.display(), e))?;
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name();
+        if skip.iter().any(|skip| name == *skip) {
+            continue;
+        }
+        fs::rename(entry.path(), to.join(&name))
+            .map_err(|e| format!("could not move '{}': {}", entry.path().display(), e))?;
+    }
+    Ok(())
+}
+
+/// Replaces the repository with the backup `archive`, once it's been checked. mdwiki's own
+/// state and the built book are kept, and the replaced files are moved to
+/// `.mdwiki/replaced/<time>`, which is returned.
+pub fn restore(config: &Config, archive: &Path) -> Result<PathBuf, String> {
+    let staging = config.data_path().join("restore");
+    let _ = fs::remove_dir_all(&staging);
+    fs::create_dir_all(&staging)
+        .map_err(|e| format!("could not create '{}': {}", staging.display(), e))?;
+    if let Err(e) = extract(archive, &staging) {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let replaced = config
+        .data_path()
+        .join("replaced")
+        .join(now.format("%Y%m%dT%H%M%SZ"));
+    fs::create_dir_all(&replaced)
+        .map_err(|e| format!("could not create '{}': {}", replaced.display(), e))?;
+    let root = Path::new(&config.path);
+    let skip = [".mdwiki", config.book_path.as_str()];
+    move_entries(root, &replaced, &skip)?;
+    move_entries(&staging, root, &skip)?;
+    let _ = fs::remove_dir_all(&staging);
+    Ok(replaced)
+}
+
+impl BackupConfig {
+    fn default_region() -> String {
+        "us-east-1".to_string()
+    }
+    fn default_interval() -> u64 {
+        24 * 60 * 60
+    }
+    fn default_keep() -> usize {
+        7
+    }
+    /// Whether `key` is the name of a backup, `<prefix>mdwiki-<time>.zip`.
+    fn is_backup(&self, key: &str) -> bool {
+        key.strip_prefix(&self.prefix)
+            .and_then(|name| name.strip_prefix("mdwiki-"))
+            .and_then(|name| name.strip_suffix(".zip"))
+            .map_or(false, |time| time.len() == 16 && time.ends_with('Z'))
+    }
+    /// Sends a request for the object `key` in the bucket, or the bucket itself if `key` is
+    /// empty, signed with AWS Signature Version 4. Returns the body of the response.
+    async fn request(
+        &self,
+        client: &Client,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<String, String> {
+        let endpoint = Url::parse(&self.endpoint)
+            .map_err(|e| format!("invalid backup endpoint '{}': {}", self.endpoint, e))?;
+        let host = match (endpoint.host_str(), endpoint.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(format!("invalid backup endpoint '{}'", self.endpoint)),
+        };
+        let mut path = format!(
+            "{}/{}",
+            endpoint.path().trim_end_matches('/'),
+            uri_encode(&self.bucket, false)
+        );
+        if !key.is_empty() {
+            path = format!("{}/{}", path, uri_encode(key, true));
+        }
+        let mut query = query
+            .iter()
+            .map(|(name, value)| {
+                format!("{}={}", uri_encode(name, false), uri_encode(value, false))
+            })
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+
+        let now = OffsetDateTime::now_utc();
+        let (date, time) = (now.format("%Y%m%d"), now.format("%Y%m%dT%H%M%SZ"));
+        let payload = sha256(&body);
+        let canonical = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, host, payload, time, SIGNED_HEADERS, payload
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            time,
+            scope,
+            sha256(canonical.as_bytes())
+        );
+        let signing_key = [date.as_str(), &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_key).into_bytes(),
+                |key, part| sign(&key, part),
+            );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key,
+            scope,
+            SIGNED_HEADERS,
+            hex(&sign(&signing_key, &string_to_sign))
+        );
+
+        let url = match query.as_str() {
+            "" => format!("{}://{}{}", endpoint.scheme(), host, path),
+            query => format!("{}://{}{}?{}", endpoint.scheme(), host, path, query),
+        };
+        client
+            .request(method.clone(), &url)
+            .header("x-amz-date", time)
+            .header("x-amz-content-sha256", payload)
+            .header("Authorization", authorization)
+            .body(body)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| format!("{} {} failed: {}", method, url, e))?
+            .text()
+            .await
+            .map_err(|e| format!("{} {} failed: {}", method, url, e))
+    }
+    /// Uploads `archive` as a new backup and removes the oldest backups beyond `keep`. Returns
+    /// the name of the new backup.
+    pub async fn upload(&self, client: &Client, archive: Vec<u8>) -> Result<String, String> {
+        let now = OffsetDateTime::now_utc();
+        let name = format!("{}mdwiki-{}.zip", self.prefix, now.format("%Y%m%dT%H%M%SZ"));
+        self.request(client, Method::PUT, &name, &[], archive)
+            .await?;
+        if self.keep == 0 {
+            return Ok(name);
+        }
+
+        // only the first 1000 objects are listed, there shouldn't be more backups than that
+        let query = [("list-type", "2"), ("prefix", self.prefix.as_str())];
+        let listing = self
+            .request(client, Method::GET, "", &query, Vec::new())
+            .await?;
+        let mut backups = KEY_REGEX
+            .captures_iter(&listing)
+            .map(|captures| captures[1].replace("&amp;", "&"))
+            .filter(|key| self.is_backup(key))
+            .collect::<Vec<_>>();
+        // the names sort by time
+        backups.sort();
+        let expired = backups.len().saturating_sub(self.keep);
+        for key in &backups[..expired] {
+            match self
+                .request(client, Method::DELETE, key, &[], Vec::new())
+                .await
+            {
+                Ok(_) => info!("removed old backup '{}'", key),
+                Err(e) => warn!("could not remove old backup '{}': {}", key, e),
+            }
+        }
+        Ok(name)
+    }
+}
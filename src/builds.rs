@@ -0,0 +1,133 @@
+use crate::config::Config;
+use crate::utils::*;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+const PINNED_FILE: &str = "pinned";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Build {
+    /// Unix time of the build in milliseconds.
+    pub id: String,
+    /// Unix time of the build in seconds.
+    pub time: u64,
+    pub pinned: bool,
+}
+
+/// Copies of the last `build_retention` successful builds. Admins can pin one of them to serve it
+/// instead of the latest build, e.g. when a preprocessor or theme change breaks rendering. The
+/// pin survives restarts and new builds until it's removed.
+#[derive(Debug)]
+pub struct Builds {
+    dir: PathBuf,
+    retention: usize,
+    pinned: RwLock<Option<String>>,
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+impl Builds {
+    pub fn new(config: &Config) -> Builds {
+        let dir = config.data_path().join("builds");
+        let pinned = fs::read_to_string(dir.join(PINNED_FILE))
+            .ok()
+            .map(|id| id.trim().to_string())
+            .filter(|id| dir.join(id).is_dir());
+        if let Some(id) = &pinned {
+            warn!("serving pinned build {} instead of the latest build", id);
+        }
+        Builds {
+            dir,
+            retention: config.build_retention,
+            pinned: RwLock::new(pinned),
+        }
+    }
+    /// Keeps a copy of the build in `book_dir`, and removes the oldest copies past the retention.
+    pub fn retain(&self, book_dir: &Path) -> Result<(), String> {
+        if self.retention == 0 {
+            return Ok(());
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let target = self.dir.join(time.to_string());
+        copy_dir(book_dir, &target)
+            .map_err(|e| format!("could not keep a copy of the build: {}", e))?;
+
+        let pinned = self.pinned.read().unwrap().clone();
+        for build in self.list().into_iter().skip(self.retention) {
+            if Some(&build.id) != pinned.as_ref() {
+                let _ = fs::remove_dir_all(self.dir.join(&build.id)).map_err(log_warn);
+            }
+        }
+        Ok(())
+    }
+    /// The kept builds, newest first.
+    pub fn list(&self) -> Vec<Build> {
+        let pinned = self.pinned.read().unwrap().clone();
+        let mut builds = fs::read_dir(&self.dir)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.path().is_dir())
+                    .filter_map(|entry| entry.file_name().to_str()?.parse::<u64>().ok())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        builds.sort_by(|a, b| b.cmp(a));
+        builds
+            .into_iter()
+            .map(|millis| Build {
+                id: millis.to_string(),
+                time: millis / 1000,
+                pinned: pinned == Some(millis.to_string()),
+            })
+            .collect()
+    }
+    /// Serves the build `id` instead of the latest one, or the latest one again if `id` is
+    /// `None`.
+    pub fn pin(&self, id: Option<&str>) -> Result<(), String> {
+        let path = self.dir.join(PINNED_FILE);
+        match id {
+            Some(id) => {
+                if !self.list().iter().any(|build| build.id == id) {
+                    return Err(format!("There is no build '{}'", id));
+                }
+                fs::write(&path, id).map_err(|e| format!("could not pin build: {}", e))?;
+            }
+            None => {
+                if path.exists() {
+                    fs::remove_file(&path).map_err(|e| format!("could not unpin build: {}", e))?;
+                }
+            }
+        }
+        *self.pinned.write().unwrap() = id.map(String::from);
+        Ok(())
+    }
+    /// Where the pinned build is, if one is pinned.
+    pub fn pinned_dir(&self) -> Option<PathBuf> {
+        self.pinned
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|id| self.dir.join(id))
+    }
+}
@@ -0,0 +1,230 @@
+use crate::utils::url_path;
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use git2::Patch;
+
+use once_cell::sync::Lazy;
+
+use regex::{Captures, Regex};
+
+use serde::Serialize;
+
+pub const MARKDOWN_LINK_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"\]\(([^()\s]+)\)"#).unwrap());
+
+/// A change a bulk operation will make, or has made. `path` is relative to the book source.
+#[derive(Debug, Serialize)]
+pub struct PlannedChange {
+    pub action: &'static str,
+    pub path: String,
+    pub to: Option<String>,
+    pub diff: Option<String>,
+    #[serde(skip)]
+    pub content: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Plan {
+    pub dry_run: bool,
+    pub commit: Option<String>,
+    pub changes: Vec<PlannedChange>,
+}
+
+/// Lists all markdown files in the book source, relative to it, except the summary.
+pub fn markdown_files(src: &Path) -> Vec<PathBuf> {
+    fn visit(src: &Path, dir: &Path, files: &mut Vec<PathBuf>) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let relative = path.strip_prefix(src).unwrap().to_path_buf();
+            if path.is_dir() {
+                if !relative.starts_with("images") {
+                    visit(src, &path, files);
+                }
+            } else if path.extension().map(|ext| ext == "md").unwrap_or(false)
+                && relative != Path::new("SUMMARY.md")
+            {
+                files.push(relative);
+            }
+        }
+    }
+    let mut files = Vec::new();
+    visit(src, src, &mut files);
+    files.sort();
+    files
+}
+
+pub fn diff(path: &Path, old: &str, new: &str) -> Option<String> {
+    let patch =
+        Patch::from_buffers(old.as_bytes(), Some(path), new.as_bytes(), Some(path), None).ok()?;
+    let buf = patch.to_buf().ok()?;
+    Some(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(normalized)
+}
+
+/// Relative link from a page in `base_dir` to `target`, both relative to the book source.
+pub fn relative_link(base_dir: &Path, target: &Path) -> String {
+    let base: Vec<_> = base_dir.components().collect();
+    let target_components: Vec<_> = target.components().collect();
+    let common = base
+        .iter()
+        .zip(target_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut parts: Vec<String> = (common..base.len()).map(|_| "..".to_string()).collect();
+    parts.extend(
+        target_components[common..]
+            .iter()
+            .map(|part| part.as_os_str().to_string_lossy().into_owned()),
+    );
+    parts.join("/")
+}
+
+/// Where `path` ends up after moving `from` to `to`, if it's affected by the move. Links to the
+/// rendered `.html` pages are mapped as well.
+pub fn moved_path(path: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    if let Ok(rest) = path.strip_prefix(from) {
+        if rest.as_os_str().is_empty() {
+            return Some(to.to_path_buf());
+        }
+        return Some(to.join(rest));
+    }
+    if path.extension().map(|ext| ext == "html").unwrap_or(false)
+        && path.with_extension("md") == from
+    {
+        return Some(to.with_extension("html"));
+    }
+    None
+}
+
+fn rewrite_links(content: &str, old_dir: &Path, new_dir: &Path, from: &Path, to: &Path) -> String {
+    MARKDOWN_LINK_REGEX
+        .replace_all(content, |caps: &Captures| {
+            let link = &caps[1];
+            if link.contains("://") || link.starts_with('#') || link.starts_with("mailto:") {
+                return caps[0].to_string();
+            }
+            let (target, anchor) = match link.find('#') {
+                Some(i) => (&link[..i], &link[i..]),
+                None => (link, ""),
+            };
+
+            let absolute = target.starts_with('/');
+            let resolved = if absolute {
+                normalize(Path::new(target.trim_start_matches('/')))
+            } else {
+                normalize(&old_dir.join(target))
+            };
+            let resolved = match resolved {
+                Some(resolved) => resolved,
+                None => return caps[0].to_string(),
+            };
+
+            let moved = moved_path(&resolved, from, to);
+            if moved.is_none() && (absolute || old_dir == new_dir) {
+                return caps[0].to_string();
+            }
+            let new_target = moved.unwrap_or(resolved);
+
+            let new_link = if absolute {
+                format!("/{}", relative_link(Path::new(""), &new_target))
+            } else {
+                relative_link(new_dir, &new_target)
+            };
+            format!("]({}{})", new_link, anchor)
+        })
+        .into_owned()
+}
+
+/// Plans moving a page or directory, including rewriting links in every page that points to or
+/// from the moved files.
+pub fn plan_move(src: &Path, from: &Path, to: &Path) -> Result<Plan, String> {
+    let mut plan = Plan::default();
+    plan.changes.push(PlannedChange {
+        action: "move",
+        path: url_path(from),
+        to: Some(url_path(to)),
+        diff: None,
+        content: None,
+    });
+
+    for page in markdown_files(src) {
+        let content = fs::read_to_string(src.join(&page))
+            .map_err(|e| format!("could not read '{}': {}", page.display(), e))?;
+
+        let new_page = moved_path(&page, from, to).unwrap_or(page.clone());
+        let old_dir = page.parent().unwrap_or(Path::new(""));
+        let new_dir = new_page.parent().unwrap_or(Path::new(""));
+
+        let new_content = rewrite_links(&content, old_dir, new_dir, from, to);
+        if new_content != content {
+            plan.changes.push(PlannedChange {
+                action: "edit",
+                path: url_path(&new_page),
+                to: None,
+                diff: diff(&new_page, &content, &new_content),
+                content: Some(new_content),
+            });
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Plans a search and replace across every page. `search` is a regex if `regex` is set, and a
+/// literal string otherwise.
+pub fn plan_replace(src: &Path, search: &str, replace: &str, regex: bool) -> Result<Plan, String> {
+    if search.is_empty() {
+        return Err("the search string can't be empty".into());
+    }
+    let pattern = if regex {
+        search.to_string()
+    } else {
+        regex::escape(search)
+    };
+    let pattern = Regex::new(&pattern).map_err(|e| format!("invalid pattern: {}", e))?;
+    let replace = if regex {
+        replace.to_string()
+    } else {
+        replace.replace('$', "$$")
+    };
+
+    let mut plan = Plan::default();
+    for page in markdown_files(src) {
+        let content = fs::read_to_string(src.join(&page))
+            .map_err(|e| format!("could not read '{}': {}", page.display(), e))?;
+        let new_content = pattern.replace_all(&content, replace.as_str()).into_owned();
+        if new_content != content {
+            plan.changes.push(PlannedChange {
+                action: "edit",
+                path: url_path(&page),
+                to: None,
+                diff: diff(&page, &content, &new_content),
+                content: Some(new_content),
+            });
+        }
+    }
+
+    Ok(plan)
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
+use time::OffsetDateTime;
+
+const IMMUTABLE: &str = "max-age=31536000, immutable";
+const REVALIDATE: &str = "no-cache";
+
+const HASHED_NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"[.-][0-9a-f]{8,}$").unwrap());
+
+/// The conditional request headers used for revalidating cached files.
+pub struct Conditional {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    if_range: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Conditional {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let header = |name| req.headers().get_one(name).map(String::from);
+        request::Outcome::Success(Conditional {
+            if_none_match: header("If-None-Match"),
+            if_modified_since: header("If-Modified-Since"),
+            if_range: header("If-Range"),
+        })
+    }
+}
+
+/// Validators for a file, derived from its size and modification time like most web servers
+/// do, so nothing has to be read or hashed to answer a revalidation.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    etag: String,
+    last_modified: Option<String>,
+    cache_control: String,
+}
+
+impl Validators {
+    /// Private files, i.e. when anonymous access is disabled, are never stored by shared caches.
+    pub fn new(path: &Path, metadata: &Metadata, private: bool) -> Validators {
+        let modified = metadata.modified().ok();
+        let mtime = modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_nanos())
+            .unwrap_or(0);
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", metadata.len(), mtime),
+            last_modified: modified.map(|modified| {
+                OffsetDateTime::from(modified).format("%a, %d %b %Y %H:%M:%S GMT")
+            }),
+            cache_control: format!(
+                "{}, {}",
+                if private { "private" } else { "public" },
+                if is_immutable(path) {
+                    IMMUTABLE
+                } else {
+                    REVALIDATE
+                }
+            ),
+        }
+    }
+    /// Whether the client's cached copy is still current. `If-Modified-Since` is only compared
+    /// exactly, and ignored when `If-None-Match` is present.
+    pub fn not_modified(&self, conditional: &Conditional) -> bool {
+        match (&conditional.if_none_match, &conditional.if_modified_since) {
+            (Some(if_none_match), _) => if_none_match
+                .split(',')
+                .map(|etag| etag.trim().trim_start_matches("W/"))
+                .any(|etag| etag == "*" || etag == self.etag),
+            (None, Some(since)) => self.last_modified.as_ref() == Some(since),
+            (None, None) => false,
+        }
+    }
+    /// Ranges may only be served if `If-Range` is missing or still matches the file.
+    pub fn range_allowed(&self, conditional: &Conditional) -> bool {
+        match &conditional.if_range {
+            Some(if_range) => {
+                if_range == &self.etag || self.last_modified.as_ref() == Some(if_range)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Uploaded images get random names and are never changed, and files with a content hash in
+/// their name change name when their content does, so those can be cached forever. Everything
+/// else, like the pages themselves, is revalidated on every use.
+fn is_immutable(path: &Path) -> bool {
+    path.starts_with("images")
+        || path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map(|stem| HASHED_NAME_REGEX.is_match(stem))
+            .unwrap_or(false)
+}
+
+pub enum Cached<R> {
+    Fresh(R, Validators),
+    NotModified(Validators),
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Cached<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let (mut response, validators) = match self {
+            Cached::Fresh(inner, validators) => (inner.respond_to(req)?, validators),
+            Cached::NotModified(validators) => (
+                Response::build().status(Status::NotModified).finalize(),
+                validators,
+            ),
+        };
+        response.set_raw_header("ETag", validators.etag);
+        if let Some(last_modified) = validators.last_modified {
+            response.set_raw_header("Last-Modified", last_modified);
+        }
+        response.set_raw_header("Cache-Control", validators.cache_control);
+        Ok(response)
+    }
+}
+
+/// A page held in memory, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct CachedBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for CachedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedPage {
+    pub body: CachedBytes,
+    pub validators: Validators,
+}
+
+impl CachedPage {
+    pub fn new(body: Vec<u8>, validators: Validators) -> CachedPage {
+        CachedPage {
+            body: CachedBytes(Arc::new(body)),
+            validators,
+        }
+    }
+    fn size(&self) -> u64 {
+        self.body.0.len() as u64
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    page: CachedPage,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Pages {
+    entries: HashMap<PathBuf, Entry>,
+    size: u64,
+    clock: u64,
+}
+
+/// Rendered pages of the book, kept in memory so hot pages are served without touching the
+/// filesystem. The wiki task clears the cache after every build, and the least recently used
+/// pages are evicted when the cache grows past `capacity` bytes.
+#[derive(Debug)]
+pub struct PageCache {
+    capacity: u64,
+    generation: AtomicU64,
+    pages: Mutex<Pages>,
+}
+
+impl PageCache {
+    /// Pages larger than this are always read from disk.
+    pub const MAX_PAGE_SIZE: u64 = 1024 * 1024;
+
+    pub fn new(capacity: u64) -> PageCache {
+        PageCache {
+            capacity,
+            generation: AtomicU64::new(0),
+            pages: Mutex::new(Pages::default()),
+        }
+    }
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+    /// Pages read from disk are only inserted if the generation hasn't changed since before they
+    /// were read, so a build finishing in between can't leave stale pages in the cache.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+    pub fn get(&self, path: &Path) -> Option<CachedPage> {
+        let mut pages = self.pages.lock().unwrap();
+        pages.clock += 1;
+        let clock = pages.clock;
+        let entry = pages.entries.get_mut(path)?;
+        entry.last_used = clock;
+        Some(entry.page.clone())
+    }
+    pub fn insert(&self, generation: u64, path: PathBuf, page: CachedPage) {
+        let size = page.size();
+        if size > Self::MAX_PAGE_SIZE || size > self.capacity {
+            return;
+        }
+        let mut pages = self.pages.lock().unwrap();
+        if generation != self.generation() {
+            return;
+        }
+
+        while pages.size + size > self.capacity {
+            let oldest = pages
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            match oldest.and_then(|oldest| pages.entries.remove(&oldest)) {
+                Some(evicted) => pages.size -= evicted.page.size(),
+                None => break,
+            }
+        }
+
+        pages.clock += 1;
+        let last_used = pages.clock;
+        pages.size += size;
+        if let Some(replaced) = pages.entries.insert(path, Entry { page, last_used }) {
+            pages.size -= replaced.page.size();
+        }
+    }
+    pub fn clear(&self) {
+        let mut pages = self.pages.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        pages.entries.clear();
+        pages.size = 0;
+    }
+}
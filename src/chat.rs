@@ -0,0 +1,143 @@
+use crate::utils::rand_safe_string;
+use crate::webapp::page_url;
+use crate::webhooks::PageChange;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// A chat channel that gets a short message with links to the page and the changes whenever a
+/// page is changed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ChatChannel {
+    /// A Slack incoming webhook.
+    Slack { webhook_url: String },
+    /// A Discord channel webhook.
+    Discord { webhook_url: String },
+    /// A Matrix room, posted to as the user of `access_token`. `room` is the room id, like
+    /// `!abc123:example.com`.
+    Matrix {
+        homeserver: String,
+        room: String,
+        access_token: String,
+    },
+}
+
+/// The links and wording of a message, shared by every kind of channel.
+struct Message<'a> {
+    user: &'a str,
+    verb: &'static str,
+    path: &'a str,
+    from: Option<&'a str>,
+    page_url: String,
+    diff_url: String,
+}
+
+impl<'a> Message<'a> {
+    fn new(change: &'a PageChange, base_url: &str) -> Message<'a> {
+        Message {
+            user: &change.user_name,
+            verb: match change.action {
+                "create" => "created",
+                "edit" => "edited",
+                "move" => "moved",
+                _ => "changed",
+            },
+            path: &change.path,
+            from: change.from.as_deref(),
+            page_url: format!("{}/{}", base_url, page_url(&change.path)),
+            diff_url: format!("{}/diff/{}", base_url, change.commit),
+        }
+    }
+    /// The message with `bold` and `link` applied to the user and the links.
+    fn format(
+        &self,
+        bold: impl Fn(&str) -> String,
+        link: impl Fn(&str, &str) -> String,
+    ) -> String {
+        let moved_from = match self.from {
+            Some(from) => format!(" {} to", bold(from)),
+            None => String::new(),
+        };
+        format!(
+            "{} {}{} {} ({})",
+            bold(self.user),
+            self.verb,
+            moved_from,
+            link(&self.page_url, self.path),
+            link(&self.diff_url, "changes")
+        )
+    }
+}
+
+fn escape_slack(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_html(text: &str) -> String {
+    escape_slack(text).replace('"', "&quot;")
+}
+
+impl ChatChannel {
+    pub async fn send(
+        &self,
+        client: &reqwest::Client,
+        change: &PageChange,
+        base_url: &str,
+    ) -> Result<(), String> {
+        let message = Message::new(change, base_url);
+        let (request, target) = match self {
+            ChatChannel::Slack { webhook_url } => {
+                let text = message.format(
+                    |text| format!("*{}*", escape_slack(text)),
+                    |url, text| format!("<{}|{}>", url, escape_slack(text)),
+                );
+                (client.post(webhook_url).json(&json!({ "text": text })), "slack")
+            }
+            ChatChannel::Discord { webhook_url } => {
+                let text = message.format(
+                    |text| format!("**{}**", text),
+                    |url, text| format!("[{}]({})", text, url),
+                );
+                (client.post(webhook_url).json(&json!({ "content": text })), "discord")
+            }
+            ChatChannel::Matrix {
+                homeserver,
+                room,
+                access_token,
+            } => {
+                let body = message.format(
+                    |text| text.to_string(),
+                    |url, text| format!("{} {}", text, url),
+                );
+                let formatted_body = message.format(
+                    |text| format!("<strong>{}</strong>", escape_html(text)),
+                    |url, text| {
+                        format!(r#"<a href="{}">{}</a>"#, escape_html(url), escape_html(text))
+                    },
+                );
+                let url = format!(
+                    "{}/_matrix/client/r0/rooms/{}/send/m.room.message/{}",
+                    homeserver.trim_end_matches('/'),
+                    room,
+                    rand_safe_string(16)
+                );
+                let request = client.put(&url).bearer_auth(access_token).json(&json!({
+                    "msgtype": "m.notice",
+                    "body": body,
+                    "format": "org.matrix.custom.html",
+                    "formatted_body": formatted_body,
+                }));
+                (request, "matrix")
+            }
+        };
+        request
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map(|_| ())
+            .map_err(|e| format!("{} notification failed: {}", target, e))
+    }
+}
@@ -0,0 +1,408 @@
+use crate::config::{Config, User, MDWIKI_USER};
+use crate::plugin::Plugins;
+use crate::users::{hash_password, UserStore};
+use crate::validate;
+use crate::wiki::WikiState;
+
+use std::fs::{self, File};
+use std::io::{self, Seek, Write};
+use std::path::Path;
+
+use clap::{crate_version, App, AppSettings, Arg, SubCommand};
+
+use rand::Rng;
+
+use zip::write::{FileOptions, ZipWriter};
+
+const CONFIG_FILE: &str = "mdwiki.toml";
+
+pub enum Command {
+    Serve,
+    Doctor,
+    Check,
+    Init { path: String },
+    Build,
+    AddUser {
+        username: String,
+        roles: Vec<String>,
+        password_stdin: bool,
+    },
+    Export { out: String, built: bool },
+    Restore { archive: String },
+    #[cfg(feature = "stress")]
+    Stress {
+        users: usize,
+        operations: usize,
+        directories: usize,
+        pages: usize,
+    },
+}
+
+pub fn parse() -> Command {
+    let app = App::new("mdwiki")
+        .version(crate_version!())
+        .about("A wiki built on mdbook and git")
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(SubCommand::with_name("serve").about("Serves the wiki (the default)"))
+        .subcommand(
+            SubCommand::with_name("doctor").about("Checks the configuration and environment"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Checks the configuration, exiting with an error if there are problems"),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Creates a new wiki, and an mdwiki.toml pointing to it")
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(SubCommand::with_name("build").about("Builds the book once"))
+        .subcommand(
+            SubCommand::with_name("adduser")
+                .about("Adds a user, or sets the password of an existing one")
+                .arg(Arg::with_name("username").required(true))
+                .arg(
+                    Arg::with_name("role")
+                        .long("role")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Gives the user a role, e.g. admin"),
+                )
+                .arg(
+                    Arg::with_name("password-stdin")
+                        .long("password-stdin")
+                        .help("Reads the password from stdin instead of prompting for it"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Writes the pages, images and theme to a zip file")
+                .arg(Arg::with_name("out").required(true))
+                .arg(
+                    Arg::with_name("built")
+                        .long("built")
+                        .help("Exports the built book instead of its source"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Replaces the wiki with a backup, keeping the replaced files in .mdwiki")
+                .arg(Arg::with_name("archive").required(true)),
+        );
+    #[cfg(feature = "stress")]
+    let app = app.subcommand(
+        SubCommand::with_name("stress")
+            .about("Load tests a new wiki in a temporary directory")
+            .arg(
+                Arg::with_name("users")
+                    .long("users")
+                    .takes_value(true)
+                    .help("Users sending requests at the same time (8 by default)"),
+            )
+            .arg(
+                Arg::with_name("operations")
+                    .long("operations")
+                    .takes_value(true)
+                    .help("Requests every user sends (40 by default)"),
+            )
+            .arg(
+                Arg::with_name("directories")
+                    .long("directories")
+                    .takes_value(true)
+                    .help("Directories in the book the wiki starts with (10 by default)"),
+            )
+            .arg(
+                Arg::with_name("pages")
+                    .long("pages")
+                    .takes_value(true)
+                    .help("Pages in every directory of the book (20 by default)"),
+            ),
+    );
+    let matches = app.get_matches();
+
+    match matches.subcommand() {
+        ("doctor", _) => Command::Doctor,
+        ("check", _) => Command::Check,
+        ("init", Some(args)) => Command::Init {
+            path: args.value_of("path").unwrap().to_string(),
+        },
+        ("build", _) => Command::Build,
+        ("adduser", Some(args)) => Command::AddUser {
+            username: args.value_of("username").unwrap().to_string(),
+            roles: args
+                .values_of("role")
+                .map(|roles| roles.map(String::from).collect())
+                .unwrap_or_default(),
+            password_stdin: args.is_present("password-stdin"),
+        },
+        ("export", Some(args)) => Command::Export {
+            out: args.value_of("out").unwrap().to_string(),
+            built: args.is_present("built"),
+        },
+        ("restore", Some(args)) => Command::Restore {
+            archive: args.value_of("archive").unwrap().to_string(),
+        },
+        #[cfg(feature = "stress")]
+        ("stress", Some(args)) => {
+            let defaults = crate::stress::StressOptions::default();
+            let number = |name: &str, default: usize| {
+                args.value_of(name)
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(default)
+            };
+            Command::Stress {
+                users: number("users", defaults.users),
+                operations: number("operations", defaults.operations),
+                directories: number("directories", defaults.book.directories),
+                pages: number("pages", defaults.book.pages),
+            }
+        }
+        _ => Command::Serve,
+    }
+}
+
+/// Runs every command except `serve` and `doctor`, returning the exit code.
+pub async fn run(command: Command, plugins: Plugins) -> i32 {
+    let res = match command {
+        Command::Check => check(),
+        Command::Init { path } => init(&path).await,
+        Command::Build => build(plugins).await,
+        Command::AddUser {
+            username,
+            roles,
+            password_stdin,
+        } => add_user(&username, roles, password_stdin),
+        Command::Export { out, built } => export(&out, built),
+        Command::Restore { archive } => restore(&archive, plugins).await,
+        #[cfg(feature = "stress")]
+        Command::Stress {
+            users,
+            operations,
+            directories,
+            pages,
+        } => {
+            let options = crate::stress::StressOptions {
+                users,
+                operations,
+                book: crate::fixtures::FixtureOptions {
+                    directories,
+                    pages,
+                    ..Default::default()
+                },
+            };
+            crate::stress::run(options)
+                .await
+                .map(|report| report.to_string())
+        }
+        Command::Serve | Command::Doctor => unreachable!(),
+    };
+    match res {
+        Ok(message) => {
+            println!("{}", message);
+            0
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            1
+        }
+    }
+}
+
+/// Lists the problems with the configuration, see `validate`. Any problem is an error, even
+/// one mdwiki would start with.
+fn check() -> Result<String, String> {
+    let problems = match validate::load() {
+        Ok((_, problems)) => problems,
+        Err(problems) => problems,
+    };
+    if problems.is_empty() {
+        return Ok("The configuration is valid".to_string());
+    }
+    let list = problems
+        .iter()
+        .map(|problem| format!("\n  {}", problem))
+        .collect::<String>();
+    Err(format!(
+        "{} problem{} in the configuration:{}",
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" },
+        list
+    ))
+}
+
+fn load_config() -> Result<Config, String> {
+    Config::figment()
+        .extract()
+        .map_err(|e| format!("configuration is invalid: {}", e))
+}
+
+async fn init(path: &str) -> Result<String, String> {
+    let mut config = load_config()?;
+    config.path = path.to_string();
+    let (wiki_state, _) = WikiState::with_config(config, Plugins::new());
+    wiki_state.setup().await?;
+
+    if Path::new(CONFIG_FILE).exists() {
+        return Ok(format!(
+            "Created a wiki in '{}', set `path` in {} to use it",
+            path, CONFIG_FILE
+        ));
+    }
+    let secret_key: [u8; 32] = rand::thread_rng().gen();
+    let secret_key: String = secret_key.iter().map(|b| format!("{:02x}", b)).collect();
+    let quoted_path = serde_json::to_string(path).map_err(|e| e.to_string())?;
+    fs::write(
+        CONFIG_FILE,
+        format!(
+            "[default]\npath = {}\nsecret_key = \"{}\"\n",
+            quoted_path, secret_key
+        ),
+    )
+    .map_err(|e| format!("could not write {}: {}", CONFIG_FILE, e))?;
+
+    Ok(format!(
+        "Created a wiki in '{}' and {}, add a user with `mdwiki adduser <name> --role admin`",
+        path, CONFIG_FILE
+    ))
+}
+
+async fn build(plugins: Plugins) -> Result<String, String> {
+    let mut config = load_config()?;
+    config.warm_start = false;
+    let book_dir = Path::new(&config.path).join(&config.book_path);
+    let (wiki_state, _) = WikiState::with_config(config, plugins);
+    wiki_state.setup().await?;
+    Ok(format!("Built the book in '{}'", book_dir.display()))
+}
+
+/// Restores a backup made by mdwiki, or any zip file of a wiki's repository, while the wiki
+/// isn't being served.
+async fn restore(archive: &str, plugins: Plugins) -> Result<String, String> {
+    let config = load_config()?;
+    let (wiki_state, _) = WikiState::with_config(config, plugins);
+    let commit = wiki_state
+        .restore(&MDWIKI_USER, Path::new(archive), archive)
+        .await
+        .map_err(|res| {
+            res.msg()
+                .cloned()
+                .unwrap_or_else(|| "could not restore the backup".into())
+        })?;
+    Ok(format!(
+        "Restored '{}', recorded in commit {}",
+        archive, commit
+    ))
+}
+
+/// Prompts for the password twice, or reads it once from stdin, e.g. in an init container.
+fn read_password(stdin: bool) -> Result<String, String> {
+    let password = if stdin {
+        let mut password = String::new();
+        io::stdin()
+            .read_line(&mut password)
+            .map_err(|e| format!("could not read password: {}", e))?;
+        password.trim_end_matches(&['\r', '\n'][..]).to_string()
+    } else {
+        rpassword::read_password_from_tty(Some("Password: "))
+            .map_err(|e| format!("could not read password: {}", e))?
+    };
+    if password.is_empty() {
+        return Err("the password can't be empty".into());
+    }
+    if !stdin {
+        let repeated = rpassword::read_password_from_tty(Some("Repeat password: "))
+            .map_err(|e| format!("could not read password: {}", e))?;
+        if password != repeated {
+            return Err("the passwords don't match".into());
+        }
+    }
+    Ok(password)
+}
+
+fn add_user(username: &str, roles: Vec<String>, password_stdin: bool) -> Result<String, String> {
+    let config = load_config()?;
+    if config.users.iter().any(|user| user.username == username) {
+        return Err(format!("'{}' is configured in {}", username, CONFIG_FILE));
+    }
+
+    let password = read_password(password_stdin)?;
+    let password = hash_password(&password)?;
+
+    let users = UserStore::load(config.data_path().join("users.json"));
+    let updated = users.update(username, |user| {
+        user.password = password.clone();
+        if !roles.is_empty() {
+            user.roles = roles.clone();
+        }
+    })?;
+    if updated {
+        return Ok(format!("Updated '{}'", username));
+    }
+    users.upsert(User {
+        username: username.to_string(),
+        password,
+        roles,
+        disabled: false,
+        email: None,
+        email_verified: false,
+        display_name: None,
+        avatar: None,
+    })?;
+    Ok(format!("Added '{}'", username))
+}
+
+/// Adds the files in `dir` to the zip file under `prefix`, leaving out `skip` at the top level.
+pub(crate) fn add_dir<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    dir: &Path,
+    prefix: &str,
+    skip: &[&str],
+) -> Result<usize, String> {
+    let mut entries = fs::read_dir(dir)
+        .map_err(|e| format!("could not read '{}': {}", dir.display(), e))?
+        .filter_map(Result::ok)
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut count = 0;
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if skip.contains(&name.as_str()) {
+            continue;
+        }
+        let path = entry.path();
+        let name = format!("{}{}", prefix, name);
+        if path.is_dir() {
+            zip.add_directory(name.clone(), FileOptions::default())
+                .map_err(|e| e.to_string())?;
+            count += add_dir(zip, &path, &format!("{}/", name), &[])?;
+        } else {
+            let content = fs::read(&path)
+                .map_err(|e| format!("could not read '{}': {}", path.display(), e))?;
+            zip.start_file(name, FileOptions::default())
+                .map_err(|e| e.to_string())?;
+            zip.write_all(&content).map_err(|e| e.to_string())?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn export(out: &str, built: bool) -> Result<String, String> {
+    let config = load_config()?;
+    let root = Path::new(&config.path);
+
+    let file = File::create(out).map_err(|e| format!("could not create '{}': {}", out, e))?;
+    let mut zip = ZipWriter::new(file);
+    let count = if built {
+        add_dir(&mut zip, &root.join(&config.book_path), "", &[])?
+    } else {
+        // mdwiki's own state holds secrets, and the history stays in git
+        add_dir(&mut zip, root, "", &[".git", ".mdwiki", config.book_path.as_str()])?
+    };
+    zip.finish()
+        .map_err(|e| format!("could not write '{}': {}", out, e))?;
+
+    Ok(format!("Exported {} files to '{}'", count, out))
+}
@@ -0,0 +1,303 @@
+//! A client for the JSON API of an mdwiki instance, for automation written in Rust.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), mdwiki::client::Error> {
+//! let mut wiki = mdwiki::client::Client::new("https://wiki.example.com");
+//! wiki.login("bot", "password").await?;
+//! let content = wiki.page("team/setup.md").await?;
+//! wiki.put_page("team/setup.md", &format!("{}\nUpdated by a bot\n", content))
+//!     .await?;
+//! for result in wiki.search("install").await? {
+//!     println!("{}: {}", result.title, result.url);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+
+use reqwest::header::{CONTENT_TYPE, COOKIE, SET_COOKIE};
+use reqwest::redirect::Policy;
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+use serde::Deserialize;
+
+const AUTH_COOKIE: &str = "mdwiki_auth";
+
+/// A failed request, with the status the wiki answered with if it did.
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub status: Option<StatusCode>,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{}: {}", status, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error {
+            status: e.status(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// The body of most API responses.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+}
+
+/// A change made by a bulk operation, like moving a page, or that it would make in a dry run.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Change {
+    /// `edit`, `move` or `tag`.
+    pub action: String,
+    pub path: String,
+    pub to: Option<String>,
+    /// A unified diff of the change to the page.
+    pub diff: Option<String>,
+}
+
+/// What a bulk operation changed, or would change in a dry run.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Plan {
+    pub dry_run: bool,
+    /// The commit with the changes, unless it was a dry run.
+    pub commit: Option<String>,
+    pub changes: Vec<Change>,
+}
+
+/// A page whose title or text matches a search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    /// The url of the page, relative to the wiki, with the section the match is in.
+    pub url: String,
+    pub title: String,
+    /// Where the page is in the book, like `Team » Setup`.
+    pub breadcrumbs: String,
+}
+
+/// The pages and sections in mdbook's `searchindex.json` that contain every word of `query`,
+/// ignoring case.
+pub fn search_index(index: &serde_json::Value, query: &str) -> Vec<SearchResult> {
+    let words = query
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let urls = index["doc_urls"].as_array();
+    let docs = match index["index"]["documentStore"]["docs"].as_object() {
+        Some(docs) => docs,
+        None => return Vec::new(),
+    };
+    let mut results = docs
+        .iter()
+        .filter_map(|(id, doc)| {
+            let field = |name: &str| doc[name].as_str().unwrap_or("").to_string();
+            let text = format!("{} {}", field("title"), field("body")).to_lowercase();
+            if words.is_empty() || !words.iter().all(|word| text.contains(word)) {
+                return None;
+            }
+            let id = id.parse::<usize>().ok()?;
+            let url = urls?.get(id)?.as_str()?.to_string();
+            Some((
+                id,
+                SearchResult {
+                    url,
+                    title: field("title"),
+                    breadcrumbs: field("breadcrumbs"),
+                },
+            ))
+        })
+        .collect::<Vec<_>>();
+    results.sort_by_key(|(id, _)| *id);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// A session with an mdwiki instance. Requests are made as an anonymous user until `login`.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    cookie: Option<String>,
+}
+
+impl Client {
+    /// A client for the wiki at `base_url`, like `https://wiki.example.com`.
+    pub fn new(base_url: &str) -> Client {
+        Client {
+            // The session cookie comes with a redirect, which isn't followed to get it
+            http: reqwest::Client::builder()
+                .redirect(Policy::none())
+                .build()
+                .unwrap_or_default(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cookie: None,
+        }
+    }
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http
+            .request(method, &format!("{}{}", self.base_url, path));
+        match &self.cookie {
+            Some(cookie) => request.header(COOKIE, cookie),
+            None => request,
+        }
+    }
+    /// The body of `response`, or an error with the message the wiki gave.
+    async fn text(response: Response) -> Result<String, Error> {
+        let status = response.status();
+        let body = response.text().await?;
+        if status.is_success() {
+            return Ok(body);
+        }
+        // error pages are html, which isn't much of a message
+        let html = body.trim_start().starts_with('<');
+        let message = match serde_json::from_str::<ApiResponse>(&body) {
+            Ok(ApiResponse {
+                message: Some(message),
+                ..
+            }) => message,
+            _ if body.trim().is_empty() || html => status.to_string(),
+            _ => body,
+        };
+        Err(Error {
+            status: Some(status),
+            message,
+        })
+    }
+    /// Logs in with a username and password, the session lasts until it expires or is revoked.
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<(), Error> {
+        let response = self
+            .request(reqwest::Method::POST, "/login")
+            .form(&[("username", username), ("password", password)])
+            .send()
+            .await?;
+        let cookie = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| value.split(';').next())
+            .find(|cookie| cookie.starts_with(&format!("{}=", AUTH_COOKIE)))
+            .map(String::from);
+        match cookie {
+            Some(cookie) => {
+                self.cookie = Some(cookie);
+                Ok(())
+            }
+            None if response.status().is_redirection() => Err(Error {
+                status: Some(StatusCode::UNAUTHORIZED),
+                message: "Invalid username/password".to_string(),
+            }),
+            None => {
+                Self::text(response).await?;
+                Err(Error {
+                    status: None,
+                    message: "the wiki didn't start a session".to_string(),
+                })
+            }
+        }
+    }
+    /// The markdown of the page at `path`, relative to the book source.
+    pub async fn page(&self, path: &str) -> Result<String, Error> {
+        let response = self
+            .request(reqwest::Method::GET, &format!("/api/v1/pages/{}", path))
+            .send()
+            .await?;
+        Self::text(response).await
+    }
+    /// Creates the page at `path` or replaces its content, returning whether it was created.
+    pub async fn put_page(&self, path: &str, content: &str) -> Result<bool, Error> {
+        let response = self
+            .request(reqwest::Method::PUT, &format!("/api/v1/pages/{}", path))
+            .body(content.to_string())
+            .send()
+            .await?;
+        let created = response.status() == StatusCode::CREATED;
+        Self::text(response).await.map(|_| created)
+    }
+    /// Moves a page or directory, updating the links to it. With `dry_run` nothing is changed,
+    /// and the plan tells what would be.
+    pub async fn move_page(&self, from: &str, to: &str, dry_run: bool) -> Result<Plan, Error> {
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/api/v1/move?dry_run={}", dry_run),
+            )
+            .json(&serde_json::json!({ "from": from, "to": to }))
+            .send()
+            .await?;
+        let plan = Self::text(response).await?;
+        serde_json::from_str(&plan).map_err(|e| Error {
+            status: None,
+            message: format!("invalid response: {}", e),
+        })
+    }
+    /// The pages and sections that contain every word of `query`, from the book's search index.
+    pub async fn search(&self, query: &str) -> Result<Vec<SearchResult>, Error> {
+        let response = self
+            .request(reqwest::Method::GET, "/searchindex.json")
+            .send()
+            .await?;
+        let index = serde_json::from_str(&Self::text(response).await?).map_err(|e| Error {
+            status: None,
+            message: format!("invalid search index: {}", e),
+        })?;
+        Ok(search_index(&index, query))
+    }
+    /// Uploads an image of `content_type`, like `image/png`, for the page at `page`, returning
+    /// the snippet the wiki is configured to return, the image's url by default. The image is
+    /// kept once a page links to it.
+    pub async fn upload_image(
+        &self,
+        content_type: &str,
+        image: Vec<u8>,
+        page: Option<&str>,
+        alt: Option<&str>,
+    ) -> Result<String, Error> {
+        let mut query = Vec::new();
+        if let Some(page) = page {
+            query.push(("page", page));
+        }
+        if let Some(alt) = alt {
+            query.push(("alt", alt));
+        }
+        let response = self
+            .request(reqwest::Method::POST, "/upload/image")
+            .query(&query)
+            .header(CONTENT_TYPE, content_type)
+            .body(image)
+            .send()
+            .await?;
+        Self::text(response).await
+    }
+    /// Attaches a file named `name` to the page at `page`, returning the url of the file.
+    pub async fn attach_file(
+        &self,
+        page: &str,
+        name: &str,
+        file: Vec<u8>,
+    ) -> Result<String, Error> {
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/upload/attachment/{}", page),
+            )
+            .query(&[("name", name)])
+            .body(file)
+            .send()
+            .await?;
+        Self::text(response).await
+    }
+}
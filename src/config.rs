@@ -1,6 +1,19 @@
+use crate::audit::LogFormat;
+use crate::backup::BackupConfig;
+use crate::chat::ChatChannel;
+use crate::lifecycle::{Lifecycle, PageStatus};
+use crate::remote::RemoteConfig;
+use crate::render::Renderer;
+use crate::robots::Crawlers;
+use crate::security;
+use crate::sinks::EventSink;
+use crate::upload_names::UploadNames;
 use crate::utils::*;
+use crate::webapp::ImageSnippet;
+use crate::webhooks::Webhook;
 use crate::wiki::WikiResponse;
 
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 
 use async_std::fs;
@@ -11,13 +24,22 @@ use serde::{Deserialize, Serialize};
 
 use once_cell::sync::Lazy;
 
-use figment::providers::{Env, Format, Toml};
+use figment::providers::{Env, Format, Serialized, Toml};
 use figment::value::{Dict, Map};
 use figment::{Error, Figment, Metadata, Profile, Provider};
 
+/// Groups nested deeper than this are ignored, which also stops cycles.
+const MAX_GROUP_DEPTH: usize = 8;
+
 pub const MDWIKI_USER: Lazy<User> = Lazy::new(|| User {
     username: String::from("mdwiki"),
     password: "".into(),
+    roles: Vec::new(),
+    disabled: false,
+    email: None,
+    email_verified: false,
+    display_name: None,
+    avatar: None,
 });
 
 #[derive(Debug)]
@@ -39,16 +61,294 @@ impl WikiTree {
 pub struct User {
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub disabled: bool,
+    /// Where password reset links are sent, see `smtp`, and the email their commits are
+    /// authored with if it's trusted, see `Config::commit_email`.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Whether `email` can be trusted, as an admin set it or the identity provider verified it.
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// Url of an image, used when the user hasn't uploaded an avatar.
+    #[serde(default)]
+    pub avatar: Option<String>,
+}
+
+impl User {
+    pub fn is_admin(&self) -> bool {
+        self.roles.iter().any(|role| role == "admin")
+    }
+    /// The name shown to other users, and used as the author of commits.
+    pub fn name(&self) -> &str {
+        self.display_name
+            .as_deref()
+            .filter(|name| !name.trim().is_empty())
+            .unwrap_or(&self.username)
+    }
+    /// Passwords are argon2 hashes, or plain text for users configured in mdwiki.toml.
+    pub fn verify_password(&self, password: &str) -> bool {
+        if self.disabled || self.password.is_empty() {
+            return false;
+        }
+        if self.password.starts_with("$argon2") {
+            return argon2::verify_encoded(&self.password, password.as_bytes()).unwrap_or(false);
+        }
+        self.password == password
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_url: String,
+    #[serde(default = "OidcConfig::default_scopes")]
+    pub scopes: Vec<String>,
+    /// The claim users are named by. `sub` by default, as users can't change it.
+    #[serde(default = "OidcConfig::default_username_claim")]
+    pub username_claim: String,
+    #[serde(default)]
+    pub auto_provision: bool,
+    /// Logs users in to the local user with their email, if the identity provider verified it.
+    #[serde(default)]
+    pub link_by_email: bool,
+    #[serde(default)]
+    pub roles_claim: Option<String>,
+    #[serde(default)]
+    pub role_mapping: HashMap<String, String>,
+}
+
+impl OidcConfig {
+    fn default_scopes() -> Vec<String> {
+        vec!["openid".into(), "profile".into(), "email".into()]
+    }
+    fn default_username_claim() -> String {
+        "sub".into()
+    }
+}
+
+/// Limits who can change the pages under `path`, a page or directory relative to the book
+/// source.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AclRule {
+    pub path: String,
+    /// Usernames, and `@name` for everyone in the group or with the role `name`.
+    pub editors: Vec<String>,
+}
+
+/// Limits for external mdbook preprocessors, which run as subprocesses during builds.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PreprocessorSandbox {
+    /// Seconds a preprocessor may run before it's killed, which fails the build.
+    #[serde(default = "PreprocessorSandbox::default_timeout")]
+    pub timeout: u64,
+    /// Memory limit in MiB.
+    #[serde(default)]
+    pub memory: Option<u64>,
+    /// CPU time limit in seconds.
+    #[serde(default)]
+    pub cpu: Option<u64>,
+    /// Runs preprocessors as this user, with `sudo`.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Runs preprocessors in this docker image, without network access and with the book
+    /// mounted read-only.
+    #[serde(default)]
+    pub container: Option<String>,
+}
+
+impl PreprocessorSandbox {
+    fn default_timeout() -> u64 {
+        60
+    }
+}
+
+/// Where members of a group start, and which parts of the sidebar they see by default.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GroupPages {
+    /// Page relative to the book source that `/` leads to.
+    #[serde(default)]
+    pub home: Option<String>,
+    /// Pages and directories shown in the sidebar, the rest is hidden behind a toggle.
+    #[serde(default)]
+    pub sidebar: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Branding {
+    pub title: String,
+    /// Path or url of an image shown next to the title.
+    #[serde(default)]
+    pub logo: Option<String>,
+    /// Any css color, used for links and buttons.
+    #[serde(default)]
+    pub accent_color: Option<String>,
+}
+
+impl Default for Branding {
+    fn default() -> Branding {
+        Branding {
+            title: "mdwiki".into(),
+            logo: None,
+            accent_color: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Upgrade the connection with STARTTLS, usually on port 587
+    Starttls,
+    /// Connect with TLS, usually on port 465
+    Tls,
+    /// Plain text, only for relays on the same host or network
+    None,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Default for SmtpSecurity {
+    fn default() -> SmtpSecurity {
+        SmtpSecurity::Starttls
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoginLimits {
+    pub max_failures_per_ip: u32,
+    pub max_failures_per_user: u32,
+    pub decay_seconds: u64,
+    pub lockout_seconds: u64,
+}
+
+impl Default for LoginLimits {
+    fn default() -> LoginLimits {
+        LoginLimits {
+            max_failures_per_ip: 20,
+            max_failures_per_user: 5,
+            decay_seconds: 60,
+            lockout_seconds: 15 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub path: String,
     pub book_path: String,
-    pub tmp_upload_path: String,
+    pub branding: Branding,
+    /// Page relative to the book source that `/` leads to, and that's first in the sidebar.
+    /// `README.md` if not set.
+    pub home_page: Option<String>,
+    /// Adds a footer with the mdwiki version, the commit and the build time to every page.
+    pub footer: bool,
+    /// Adds who last edited every page, when, and how many people have to the end of the page.
+    pub attribution: bool,
+    /// The book is managed outside of mdwiki, which only writes and commits pages, and leaves
+    /// SUMMARY.md, book.toml, .gitignore and the theme alone.
+    pub external_book: bool,
+    /// Templates in this directory replace the built-in ones with the same name.
+    pub templates_dir: Option<String>,
+    /// Where uploaded images wait until a page uses them, `.mdwiki/uploads` if not set.
+    pub tmp_upload_path: Option<String>,
+    pub max_page_size: u64,
+    pub max_attachment_size: u64,
+    pub image_snippet: ImageSnippet,
+    /// How uploaded images are named, see `UploadNames`.
+    pub upload_names: UploadNames,
+    /// Pages can't be saved with images without alt text, and uploading an image asks for it.
+    pub require_alt_text: bool,
+    pub page_cache_size: u64,
+    /// How many successful builds are kept for switching back to, 0 keeps none.
+    pub build_retention: usize,
+    /// How many requests can wait for the wiki task, more are answered with a 503.
+    pub queue_capacity: usize,
+    /// Seconds a build may take before it's cancelled, 0 for no limit.
+    pub build_timeout: u64,
+    /// Seconds between repacking the repository and pruning old unreachable objects, 0 never.
+    pub maintenance_interval: u64,
+    /// Serve the previous build on startup, and rebuild once the wiki is up.
+    pub warm_start: bool,
+    /// Pages can't be changed. Books that can't be written to are served read-only anyway.
+    pub read_only: bool,
+    pub sanitize_html: bool,
+    /// Page urls in the wrong case redirect to the page, and pages that differ from existing
+    /// ones only by case can't be created.
+    pub case_insensitive_paths: bool,
+    pub renderer: Renderer,
+    /// mdbook preprocessors by name, with their book.toml settings. They're merged into the
+    /// `[preprocessor]` tables of book.toml on startup, which fails if one isn't installed.
+    pub preprocessors: BTreeMap<String, toml::value::Table>,
+    pub preprocessor_sandbox: Option<PreprocessorSandbox>,
+    pub merge_driver: Option<String>,
+    /// Seconds a page stays locked for others after someone opens it for editing, renewed while
+    /// the editor is open. 0 turns the locks off.
+    pub edit_lock_timeout: u64,
+    /// Let users who may not change a page suggest changes to it, which the page's editors
+    /// accept or reject on `/reviews`.
+    pub suggestions: bool,
+    /// Store uploaded images and attachments with Git LFS.
+    pub lfs: bool,
+    pub backup: Option<BackupConfig>,
+    /// The remote the book is pushed to and pulled from, with its credentials.
+    pub remote: Option<RemoteConfig>,
+    /// Remotes every commit is pushed to.
+    pub mirrors: Vec<RemoteConfig>,
+    /// How many times a push to a mirror that failed for a reason that might pass is retried,
+    /// waiting twice as long before each retry.
+    pub mirror_retries: u32,
 
     pub users: Vec<User>,
+    /// Commits by users without an email are authored as `<username>@<domain>`, or as
+    /// `mdwiki@example.com` if this isn't set.
+    pub commit_email_domain: Option<String>,
+    pub groups: HashMap<String, Vec<String>>,
+    pub acl: Vec<AclRule>,
+    pub lifecycle: Lifecycle,
+    pub group_pages: BTreeMap<String, GroupPages>,
     pub allow_anonymous: bool,
+    pub oidc: Option<OidcConfig>,
+    pub login_limits: LoginLimits,
+    pub session_lifetime: u64,
+    pub remember_me_lifetime: u64,
+    pub smtp: Option<SmtpConfig>,
+    /// The url mdwiki is reached at, used for links in emails.
+    pub public_url: Option<String>,
+    /// Pages and directories left out of `sitemap.xml`, which is generated with every build when
+    /// `public_url` is set and crawlers are allowed.
+    pub sitemap_exclude: Vec<String>,
+    pub crawlers: Crawlers,
+
+    pub trusted_proxies: Vec<String>,
+    pub embed_frame_ancestors: Vec<String>,
+    pub security_headers: HashMap<String, String>,
+
+    pub log_format: LogFormat,
+    pub event_sinks: Vec<EventSink>,
+    /// Get a JSON payload after every change to the pages.
+    pub webhooks: Vec<Webhook>,
+    /// Chat channels that get a message with links after every change to the pages.
+    pub chat_notifications: Vec<ChatChannel>,
+    pub scripts: Vec<String>,
 }
 
 impl Default for Config {
@@ -56,14 +356,64 @@ impl Default for Config {
         Config {
             path: "./mdwiki".to_string(),
             book_path: "book".to_string(),
-            tmp_upload_path: env::temp_dir()
-                .join("mdwiki_tmp_uploads")
-                .to_str()
-                .unwrap()
-                .into(),
+            branding: Branding::default(),
+            home_page: None,
+            footer: false,
+            attribution: false,
+            external_book: false,
+            templates_dir: None,
+            tmp_upload_path: None,
+            max_page_size: 4 * 1024 * 1024,
+            max_attachment_size: 16 * 1024 * 1024,
+            image_snippet: ImageSnippet::default(),
+            upload_names: UploadNames::default(),
+            require_alt_text: false,
+            page_cache_size: 64 * 1024 * 1024,
+            build_retention: 5,
+            queue_capacity: 100,
+            build_timeout: 600,
+            maintenance_interval: 24 * 60 * 60,
+            warm_start: false,
+            read_only: false,
+            sanitize_html: true,
+            case_insensitive_paths: false,
+            renderer: Renderer::Mdbook,
+            preprocessors: BTreeMap::new(),
+            preprocessor_sandbox: None,
+            merge_driver: None,
+            edit_lock_timeout: 10 * 60,
+            suggestions: false,
+            lfs: false,
+            backup: None,
+            remote: None,
+            mirrors: Vec::new(),
+            mirror_retries: 3,
 
             users: Vec::new(),
+            commit_email_domain: None,
+            groups: HashMap::new(),
+            acl: Vec::new(),
+            lifecycle: Lifecycle::default(),
+            group_pages: BTreeMap::new(),
             allow_anonymous: true,
+            oidc: None,
+            login_limits: LoginLimits::default(),
+            session_lifetime: 12 * 60 * 60,
+            remember_me_lifetime: 30 * 24 * 60 * 60,
+            smtp: None,
+            public_url: None,
+            sitemap_exclude: Vec::new(),
+            crawlers: Crawlers::default(),
+
+            trusted_proxies: Vec::new(),
+            embed_frame_ancestors: Vec::new(),
+            security_headers: security::default_headers(),
+
+            log_format: LogFormat::Text,
+            event_sinks: Vec::new(),
+            webhooks: Vec::new(),
+            chat_notifications: Vec::new(),
+            scripts: Vec::new(),
         }
     }
 }
@@ -74,10 +424,165 @@ impl Config {
     #[cfg(not(debug_assertions))]
     pub const DEFAULT_PROFILE: Profile = Profile::const_new("release");
 
+    /// The configuration from, in order, the defaults, `mdwiki.toml` (or the file in
+    /// `MDWIKI_CONFIG`), `MDWIKI_` environment variables, and files named by `MDWIKI_<KEY>_FILE`.
     pub fn figment() -> Figment {
-        Figment::from(Config::default())
-            .merge(Toml::file("mdwiki.toml").nested())
-            .merge(Env::prefixed("MDWIKI_").global())
+        let config_file = env::var("MDWIKI_CONFIG").unwrap_or_else(|_| "mdwiki.toml".into());
+        let figment = Figment::from(Config::default())
+            .merge(Toml::file(config_file).nested())
+            .merge(
+                Env::prefixed("MDWIKI_")
+                    .filter(|key| {
+                        let key = key.as_str().to_ascii_lowercase();
+                        key != "config" && !key.ends_with("_file")
+                    })
+                    .split("__")
+                    .global(),
+            );
+        value_files()
+            .into_iter()
+            .fold(figment, |figment, (key, value)| {
+                figment.merge(Serialized::global(&key, value))
+            })
+    }
+
+    /// Directory for mdwiki's own state, kept inside the book but ignored by git.
+    pub fn data_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(&self.path).join(".mdwiki")
+    }
+
+    /// The email commits by `user` are authored with, so hosts like GitHub attribute them to
+    /// the user's account, see `commit_email_domain`. Only emails users can't set themselves
+    /// are used, the ones in mdwiki.toml, set by an admin or verified by the identity provider.
+    pub fn commit_email(&self, user: &User) -> String {
+        let configured = self
+            .users
+            .iter()
+            .find(|configured| configured.username == user.username)
+            .and_then(|configured| configured.email.as_ref());
+        let verified = user.email.as_ref().filter(|_| user.email_verified);
+        match (configured.or(verified), &self.commit_email_domain) {
+            (Some(email), _) if !email.trim().is_empty() => email.trim().to_string(),
+            (_, Some(domain)) => format!("{}@{}", user.username, domain.trim_start_matches('@')),
+            _ => "mdwiki@example.com".to_string(),
+        }
+    }
+
+    pub fn home_page(&self) -> &str {
+        self.home_page.as_deref().unwrap_or("README.md")
+    }
+
+    /// Whether search engines may index the wiki, which they can't when it requires logging in.
+    pub fn crawlers_allowed(&self) -> bool {
+        self.allow_anonymous && self.crawlers.allow
+    }
+
+    pub fn upload_path(&self) -> std::path::PathBuf {
+        match &self.tmp_upload_path {
+            Some(path) => path.into(),
+            None => self.data_path().join("uploads"),
+        }
+    }
+
+    /// Whether `path`, relative to the book, is kept out of commits whatever `.gitignore` says:
+    /// the build output, mdwiki's own state, uploads that no page uses yet and editor swap and
+    /// backup files.
+    pub fn never_committed(&self, path: &std::path::Path) -> bool {
+        let book = std::path::Path::new(&self.path);
+        let dirs = [
+            book.join(&self.book_path),
+            self.data_path(),
+            self.upload_path(),
+        ];
+        if dirs
+            .iter()
+            .filter_map(|dir| dir.strip_prefix(book).ok())
+            .any(|dir| dir.components().next().is_some() && path.starts_with(dir))
+        {
+            return true;
+        }
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => return false,
+        };
+        name.ends_with(".swp")
+            || name.ends_with(".swo")
+            || name.ends_with('~')
+            || name.starts_with(".#")
+            || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+    }
+
+    /// Whether `principal`, a username or `@name`, includes `user`. `@name` is everyone in the
+    /// group `name` and everyone with the role `name`, and groups can contain other groups.
+    pub fn includes(&self, principal: &str, user: &User) -> bool {
+        fn includes(config: &Config, principal: &str, user: &User, depth: usize) -> bool {
+            let name = match principal.strip_prefix('@') {
+                Some(name) => name,
+                None => return principal == user.username,
+            };
+            user.roles.iter().any(|role| role == name)
+                || (depth < MAX_GROUP_DEPTH
+                    && config.groups.get(name).map_or(false, |members| {
+                        members
+                            .iter()
+                            .any(|member| includes(config, member, user, depth + 1))
+                    }))
+        }
+        includes(self, principal, user, 0)
+    }
+
+    /// The `group_pages` of the groups `user` is in, ordered by group name.
+    pub fn pages_for(&self, user: &User) -> Vec<&GroupPages> {
+        self.group_pages
+            .iter()
+            .filter(|(group, _)| self.includes(&format!("@{}", group), user))
+            .map(|(_, pages)| pages)
+            .collect()
+    }
+
+    /// Whether `user` may change the page at `path`. The most specific rule in `acl` decides,
+    /// pages without a rule can be changed by anyone, and admins can change everything.
+    pub fn may_change(&self, user: &User, path: &Path) -> WikiResponse {
+        if user.is_admin() {
+            return WikiResponse::OK(None);
+        }
+        let rule = self
+            .acl
+            .iter()
+            .filter(|rule| path.starts_with(rule.path.trim_matches('/')))
+            .max_by_key(|rule| Path::new(rule.path.trim_matches('/')).components().count());
+        match rule {
+            Some(rule) if !rule.editors.iter().any(|editor| self.includes(editor, user)) => {
+                WikiResponse::NotAllowed(Some(format!(
+                    "You're not allowed to change '{}'",
+                    path.display()
+                )))
+            }
+            _ => WikiResponse::OK(None),
+        }
+    }
+
+    /// Whether `user` may change the status of a page from `from` to `to`, see
+    /// `Lifecycle::transitions`. Admins can make any change.
+    pub fn may_transition(&self, user: &User, from: PageStatus, to: PageStatus) -> WikiResponse {
+        if from == to || user.is_admin() || self.lifecycle.transitions.is_empty() {
+            return WikiResponse::OK(None);
+        }
+        let allowed = self.lifecycle.transitions.iter().any(|transition| {
+            transition.from == from
+                && transition.to == to
+                && (transition.allow.is_empty()
+                    || transition.allow.iter().any(|allow| self.includes(allow, user)))
+        });
+        if allowed {
+            WikiResponse::OK(None)
+        } else {
+            WikiResponse::NotAllowed(Some(format!(
+                "You're not allowed to change the status from {} to {}",
+                from.name(),
+                to.name()
+            )))
+        }
     }
 
     async fn safe_path(&self, path: &Path) -> WikiResponse {
@@ -107,8 +612,44 @@ impl Config {
         }
         WikiResponse::OK(None)
     }
+    /// With `case_insensitive_paths`, rejects `path` if it, or a directory it's in, differs only
+    /// by case from an existing one, other than `moved_from`.
+    fn case_conflict(&self, path: &Path, moved_from: Option<&Path>) -> WikiResponse {
+        if !self.case_insensitive_paths {
+            return WikiResponse::OK(None);
+        }
+        let src = std::path::Path::new(&self.path).join("src");
+        let path: &std::path::Path = path.as_ref();
+        let moved_from = moved_from.map(<Path as AsRef<std::path::Path>>::as_ref);
+        for ancestor in path.ancestors().filter(|ancestor| ancestor.as_os_str() != "") {
+            let existing = match find_ignoring_case(&src, ancestor) {
+                Some(existing) => existing,
+                None => continue,
+            };
+            if existing != ancestor && Some(existing.as_path()) != moved_from {
+                return WikiResponse::BadRequest(Some(format!(
+                    "'{}' differs only by case from '{}', which already exists",
+                    ancestor.display(),
+                    existing.display()
+                )));
+            }
+        }
+        WikiResponse::OK(None)
+    }
     pub async fn can_create(&self, path: &Path) -> WikiResponse {
+        self.can_create_moved(path, None).await
+    }
+    async fn can_create_moved(&self, path: &Path, moved_from: Option<&Path>) -> WikiResponse {
         try_response!(self.safe_path(path).await);
+        try_response!(self.case_conflict(path, moved_from));
+
+        let mut names = path.iter().map(|name| name.to_string_lossy());
+        if let Some(name) = names.find(|name| !is_portable_name(name)) {
+            return WikiResponse::BadRequest(Some(format!(
+                "'{}' can't be used as a name, it isn't allowed on every platform",
+                name
+            )));
+        }
 
         if path.ancestors().count() > 5 {
             return WikiResponse::BadRequest(Some(format!(
@@ -127,13 +668,56 @@ impl Config {
         }
         WikiResponse::OK(None)
     }
+    pub async fn can_move(&self, from: &Path, to: &Path) -> WikiResponse {
+        let src = Path::new(&self.path).join("src");
+
+        if src.join(from).is_file().await {
+            try_response!(self.can_edit(from).await);
+            if from.ends_with("README.md") {
+                return WikiResponse::BadRequest(Some(
+                    "Directory index pages can't be moved, move the directory instead".into(),
+                ));
+            }
+            return self.can_create_moved(to, Some(from)).await;
+        }
+
+        if !path_is_simple(from)
+            || from.as_os_str().is_empty()
+            || is_reserved_name(from)
+            || !src.join(from).is_dir().await
+        {
+            return WikiResponse::NotFound(Some(format!(
+                "No file or directory named '{}'",
+                from.display()
+            )));
+        }
+        if !path_is_simple(to) || to.as_os_str().is_empty() || is_reserved_name(to) {
+            return WikiResponse::BadRequest(Some(format!(
+                "Path '{}' is not a valid directory name",
+                to.display()
+            )));
+        }
+        if to.starts_with(from) {
+            return WikiResponse::BadRequest(Some(format!(
+                "Can't move '{}' into itself",
+                from.display()
+            )));
+        }
+        if src.join(to).exists().await {
+            return WikiResponse::BadRequest(Some(format!(
+                "'{}' already exists",
+                to.display()
+            )));
+        }
+        self.case_conflict(to, Some(from))
+    }
     pub async fn get_wiki_tree(&self) -> WikiTree {
         use rocket::futures::future::{BoxFuture, FutureExt};
         fn visit(prefix: PathBuf, path: PathBuf) -> BoxFuture<'static, Option<WikiTree>> {
             async move {
                 let relative_path = path.strip_prefix(&prefix).unwrap();
                 if path.is_dir().await {
-                    if relative_path.starts_with("images") {
+                    if relative_path.starts_with("images") || relative_path.starts_with("files") {
                         return None;
                     }
                     let mut children = Vec::new();
@@ -158,6 +742,8 @@ impl Config {
                         return None;
                     } else if is_reserved_name(relative_path) {
                         return None;
+                    } else if relative_path.ends_with(SUMMARY_FRAGMENT) {
+                        return None;
                     }
                     return Some(WikiTree::File(
                         relative_path.to_path_buf().into_boxed_path(),
@@ -176,6 +762,24 @@ impl Config {
     }
 }
 
+/// Values read from the files named by `MDWIKI_<KEY>_FILE` variables, for secrets mounted as
+/// files. `__` separates nested keys, e.g. `MDWIKI_SMTP__PASSWORD_FILE`.
+fn value_files() -> Vec<(String, String)> {
+    env::vars()
+        .filter_map(|(name, path)| {
+            let key = name.strip_prefix("MDWIKI_")?.strip_suffix("_FILE")?;
+            let key = key.to_ascii_lowercase().replace("__", ".");
+            match std::fs::read_to_string(&path) {
+                Ok(value) => Some((key, value.trim_end_matches(&['\r', '\n'][..]).to_string())),
+                Err(e) => {
+                    warn!("could not read {} from '{}': {}", name, path, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
 impl Provider for Config {
     fn metadata(&self) -> Metadata {
         Metadata::named("mdwiki config")
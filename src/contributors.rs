@@ -0,0 +1,126 @@
+//! Who has contributed what to the wiki, from the git history, for `/stats/contributors`.
+//! Contributors are told apart by the author name of their commits, which is the display name
+//! of the user at the time.
+
+use std::collections::HashMap;
+
+use git2::{Delta, Patch, Repository};
+
+use serde::Serialize;
+
+/// What a contributor did to a page.
+#[derive(Debug, Clone, Serialize)]
+pub struct PageContribution {
+    pub page: String,
+    pub commits: usize,
+    /// Whether they created the page.
+    pub created: bool,
+    pub lines_added: usize,
+    pub lines_removed: usize,
+}
+
+/// Everything one person changed in the pages.
+#[derive(Debug, Clone, Serialize)]
+pub struct Contributor {
+    pub name: String,
+    /// Commits changing at least one page.
+    pub commits: usize,
+    pub pages_created: usize,
+    pub lines_added: usize,
+    pub lines_removed: usize,
+    /// Unix time of their last commit.
+    pub last_commit: i64,
+    /// The pages they changed, the ones they changed most often first.
+    pub pages: Vec<PageContribution>,
+}
+
+/// Every contributor to the pages of the book at `path`, the ones with the most commits first.
+/// Commits are compared to their first parent, and only changes to pages count.
+pub fn contributors(path: &str) -> Result<Vec<Contributor>, String> {
+    let err = |e: git2::Error| format!("could not read the history: {}", e);
+    let repo = Repository::open(path).map_err(err)?;
+    let mut revwalk = repo.revwalk().map_err(err)?;
+    revwalk.push_head().map_err(err)?;
+
+    let mut contributors: HashMap<String, Contributor> = HashMap::new();
+    for oid in revwalk {
+        let commit = oid.and_then(|oid| repo.find_commit(oid)).map_err(err)?;
+        let tree = commit.tree().map_err(err)?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree().map_err(err)?),
+            None => None,
+        };
+        let diff = repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+            .map_err(err)?;
+        let name = commit.author().name().unwrap_or("").to_string();
+        let mut changed_pages = false;
+        for (i, delta) in diff.deltas().enumerate() {
+            let page = delta
+                .new_file()
+                .path()
+                .and_then(|path| path.strip_prefix("src").ok())
+                .filter(|path| path.extension().map_or(false, |ext| ext == "md"))
+                .filter(|path| !path.ends_with("SUMMARY.md"))
+                .map(|path| path.to_string_lossy().into_owned());
+            let page = match page {
+                Some(page) => page,
+                None => continue,
+            };
+            let (_, added, removed) = match Patch::from_diff(&diff, i).map_err(err)? {
+                Some(patch) => patch.line_stats().map_err(err)?,
+                None => (0, 0, 0),
+            };
+            let contributor = contributors
+                .entry(name.clone())
+                .or_insert_with(|| Contributor {
+                    name: name.clone(),
+                    commits: 0,
+                    pages_created: 0,
+                    lines_added: 0,
+                    lines_removed: 0,
+                    // commits are walked newest first
+                    last_commit: commit.time().seconds(),
+                    pages: Vec::new(),
+                });
+            let i = match contributor.pages.iter().position(|p| p.page == page) {
+                Some(i) => i,
+                None => {
+                    contributor.pages.push(PageContribution {
+                        page,
+                        commits: 0,
+                        created: false,
+                        lines_added: 0,
+                        lines_removed: 0,
+                    });
+                    contributor.pages.len() - 1
+                }
+            };
+            let contribution = &mut contributor.pages[i];
+            contribution.commits += 1;
+            contribution.lines_added += added;
+            contribution.lines_removed += removed;
+            if delta.status() == Delta::Added {
+                contribution.created = true;
+                contributor.pages_created += 1;
+            }
+            contributor.lines_added += added;
+            contributor.lines_removed += removed;
+            changed_pages = true;
+        }
+        if changed_pages {
+            if let Some(contributor) = contributors.get_mut(&name) {
+                contributor.commits += 1;
+            }
+        }
+    }
+
+    let mut contributors = contributors.into_iter().map(|(_, c)| c).collect::<Vec<_>>();
+    for contributor in &mut contributors {
+        contributor
+            .pages
+            .sort_by(|a, b| b.commits.cmp(&a.commits).then_with(|| a.page.cmp(&b.page)));
+    }
+    contributors.sort_by(|a, b| b.commits.cmp(&a.commits).then_with(|| a.name.cmp(&b.name)));
+    Ok(contributors)
+}
@@ -0,0 +1,382 @@
+use crate::config::Config;
+use crate::remote;
+use crate::render::{Renderer, COMRAK_EXTENSIONS};
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+use rocket::figment::Figment;
+
+use mdbook::MDBook;
+
+use git2::{Repository, StatusOptions};
+
+pub const BUILTIN_PREPROCESSORS: &[&str] = &["links", "index"];
+
+#[derive(Debug, PartialEq, PartialOrd)]
+enum Level {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Default)]
+struct Report {
+    findings: Vec<Level>,
+}
+
+impl Report {
+    fn ok(&mut self, message: impl Into<String>) {
+        self.push(Level::Ok, message.into(), None);
+    }
+    fn warn(&mut self, message: impl Into<String>, hint: impl Into<String>) {
+        self.push(Level::Warn, message.into(), Some(hint.into()));
+    }
+    fn fail(&mut self, message: impl Into<String>, hint: impl Into<String>) {
+        self.push(Level::Fail, message.into(), Some(hint.into()));
+    }
+    fn push(&mut self, level: Level, message: String, hint: Option<String>) {
+        let tag = match level {
+            Level::Ok => "[ OK ]",
+            Level::Warn => "[WARN]",
+            Level::Fail => "[FAIL]",
+        };
+        println!("{} {}", tag, message);
+        if let Some(hint) = &hint {
+            println!("       -> {}", hint);
+        }
+        self.findings.push(level);
+    }
+    fn failed(&self) -> bool {
+        self.findings.contains(&Level::Fail)
+    }
+}
+
+/// The program an external preprocessor runs, from its `command` or `mdbook-<name>`.
+pub fn preprocessor_executable(name: &str, table: &toml::value::Table) -> String {
+    table
+        .get("command")
+        .and_then(|command| command.as_str())
+        .and_then(|command| command.split_whitespace().next())
+        .map(String::from)
+        .unwrap_or(format!("mdbook-{}", name))
+}
+
+pub fn find_in_path(command: &str) -> Option<PathBuf> {
+    let command = Path::new(command);
+    if command.components().count() > 1 {
+        return Some(command.to_path_buf()).filter(|path| path.is_file());
+    }
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(command))
+            .find(|path| path.is_file())
+    })
+}
+
+pub fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(".mdwiki-doctor-probe");
+    let writable = fs::write(&probe, b"").is_ok();
+    let _ = fs::remove_file(&probe);
+    writable
+}
+
+fn check_config(report: &mut Report, config: &Config) {
+    let mut seen = HashSet::new();
+    for user in &config.users {
+        if !seen.insert(&user.username) {
+            report.warn(
+                format!("user '{}' is configured more than once", user.username),
+                "only the first entry is used, remove the duplicates",
+            );
+        }
+    }
+
+    if !config.allow_anonymous && config.users.is_empty() && config.oidc.is_none() {
+        report.fail(
+            "anonymous access is disabled, but no users are configured",
+            "add a [[release.users]] entry, configure oidc, or set allow_anonymous = true",
+        );
+    } else {
+        report.ok(format!(
+            "{} configured users, anonymous access {}",
+            config.users.len(),
+            if config.allow_anonymous {
+                "allowed"
+            } else {
+                "disabled"
+            }
+        ));
+    }
+
+    if !config.users.iter().any(|user| user.is_admin()) {
+        report.warn(
+            "no configured user has the 'admin' role",
+            "add roles = [\"admin\"] to a user to enable the /admin pages",
+        );
+    }
+}
+
+fn check_book(report: &mut Report, config: &Config) {
+    let path = Path::new(&config.path);
+    if !path.is_dir() {
+        let parent = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        if is_writable(parent) {
+            report.warn(
+                format!("book path '{}' does not exist", config.path),
+                "a new wiki will be created there on startup",
+            );
+        } else {
+            report.fail(
+                format!("book path '{}' does not exist and can't be created", config.path),
+                format!("make '{}' writable, or set MDWIKI_PATH", parent.display()),
+            );
+        }
+        return;
+    }
+    if is_writable(path) {
+        report.ok(format!("book path '{}' is writable", config.path));
+    } else {
+        report.fail(
+            format!("book path '{}' is not writable", config.path),
+            "fix the permissions for the user running mdwiki",
+        );
+    }
+
+    match Repository::open(path) {
+        Ok(repo) => {
+            if repo.head().is_err() {
+                report.warn(
+                    "git repository has no commits",
+                    "an initial commit is created on startup",
+                );
+            }
+            let mut options = StatusOptions::new();
+            options.include_untracked(true);
+            match repo.statuses(Some(&mut options)) {
+                Ok(statuses) => {
+                    let changes = statuses
+                        .iter()
+                        .filter(|entry| !entry.status().is_ignored())
+                        .count();
+                    if changes > 0 {
+                        report.warn(
+                            format!("git repository has {} uncommitted changes", changes),
+                            "they will be committed as recovered changes on startup",
+                        );
+                    } else {
+                        report.ok("git repository is clean");
+                    }
+                }
+                Err(e) => report.fail(
+                    format!("git repository is broken: {}", e),
+                    "run `git fsck` in the book path",
+                ),
+            }
+        }
+        Err(_) => report.warn(
+            "book path is not a git repository",
+            "a new repository is initialized on startup",
+        ),
+    }
+
+    match MDBook::load(path) {
+        Ok(book) => {
+            report.ok("mdbook configuration is valid");
+            let mut preprocessors = book
+                .config
+                .get("preprocessor")
+                .and_then(|value| value.as_table())
+                .cloned()
+                .unwrap_or_default();
+            for (name, settings) in &config.preprocessors {
+                let table = preprocessors
+                    .entry(name.clone())
+                    .or_insert_with(|| toml::Value::Table(Default::default()));
+                if let Some(table) = table.as_table_mut() {
+                    table.extend(settings.clone());
+                }
+            }
+            for (name, table) in preprocessors {
+                if BUILTIN_PREPROCESSORS.contains(&name.as_str()) {
+                    continue;
+                }
+                let executable =
+                    preprocessor_executable(&name, table.as_table().unwrap_or(&Default::default()));
+                if config
+                    .preprocessor_sandbox
+                    .as_ref()
+                    .map_or(false, |sandbox| sandbox.container.is_some())
+                {
+                    report.ok(format!(
+                        "preprocessor '{}' runs in the sandbox container, which needs '{}'",
+                        name, executable
+                    ));
+                } else if find_in_path(&executable).is_some() {
+                    report.ok(format!("preprocessor '{}' found", name));
+                } else {
+                    report.fail(
+                        format!(
+                            "preprocessor '{}' needs '{}', which is not installed",
+                            name, executable
+                        ),
+                        format!("install it, e.g. `cargo install mdbook-{}`", name),
+                    );
+                }
+            }
+        }
+        Err(e) if config.external_book => report.fail(
+            format!("could not load mdbook: {}", e),
+            "external_book is set, so the book has to exist",
+        ),
+        Err(e) => report.warn(
+            format!("could not load mdbook: {}", e),
+            "a new book is created on startup if book.toml is missing",
+        ),
+    }
+}
+
+fn check_sandbox(report: &mut Report, config: &Config) {
+    let sandbox = match &config.preprocessor_sandbox {
+        Some(sandbox) => sandbox,
+        None => return,
+    };
+    let mut tools = vec!["timeout"];
+    if sandbox.container.is_some() {
+        tools.push("docker");
+    } else {
+        if sandbox.user.is_some() {
+            tools.push("sudo");
+        }
+        if sandbox.memory.is_some() || sandbox.cpu.is_some() {
+            tools.push("prlimit");
+        }
+    }
+    for tool in tools {
+        if find_in_path(tool).is_some() {
+            report.ok(format!("preprocessor sandbox tool '{}' found", tool));
+        } else {
+            report.fail(
+                format!("preprocessor_sandbox needs '{}', which is not installed", tool),
+                "install it, or change preprocessor_sandbox",
+            );
+        }
+    }
+}
+
+fn check_renderer(report: &mut Report, config: &Config) {
+    if let Renderer::Comrak { extensions } = &config.renderer {
+        for extension in extensions {
+            if !COMRAK_EXTENSIONS.contains(&extension.as_str()) {
+                report.warn(
+                    format!("unknown comrak extension '{}'", extension),
+                    format!("use some of {}", COMRAK_EXTENSIONS.join(", ")),
+                );
+            }
+        }
+    }
+}
+
+fn check_uploads(report: &mut Report, config: &Config) {
+    let path = config.upload_path();
+    if path.is_dir() && !is_writable(&path) {
+        report.fail(
+            format!("upload directory '{}' is not writable", path.display()),
+            "fix the permissions, or set MDWIKI_TMP_UPLOAD_PATH",
+        );
+    } else {
+        report.ok(format!("upload directory '{}' is usable", path.display()));
+    }
+}
+
+fn check_remote(report: &mut Report, config: &Config) {
+    let remote = match &config.remote {
+        Some(remote) => remote,
+        None => return,
+    };
+    // Without a repository only the configuration can be checked
+    let res = match Repository::open(&config.path) {
+        Ok(_) => remote::check(&config.path, remote),
+        Err(_) => remote::validate(remote),
+    };
+    match res {
+        Ok(_) => report.ok(format!("remote '{}' is usable", remote.url)),
+        Err(e) => report.fail(
+            e,
+            "fix the url or the credentials in the remote section of mdwiki.toml",
+        ),
+    }
+}
+
+fn check_server(report: &mut Report, figment: &Figment) {
+    let rocket_config = rocket::Config::from(figment);
+
+    if cfg!(not(debug_assertions)) && Config::figment().find_value("secret_key").is_err() {
+        report.fail(
+            "no secret_key configured for the release profile",
+            "generate one with `openssl rand -base64 32` and set it in mdwiki.toml",
+        );
+    }
+
+    match TcpListener::bind((rocket_config.address, rocket_config.port)) {
+        Ok(_) => report.ok(format!(
+            "{}:{} is available",
+            rocket_config.address, rocket_config.port
+        )),
+        Err(e) => report.fail(
+            format!(
+                "can't listen on {}:{}: {}",
+                rocket_config.address, rocket_config.port, e
+            ),
+            "stop whatever is using the port, or set ROCKET_PORT",
+        ),
+    }
+}
+
+/// Checks the environment mdwiki runs in, printing the findings. Returns the exit code.
+pub fn run() -> i32 {
+    let mut report = Report::default();
+
+    let figment = Figment::from(rocket::Config::default()).merge(Config::figment());
+    let config: Config = match Config::figment().extract() {
+        Ok(config) => {
+            report.ok("configuration loaded");
+            config
+        }
+        Err(e) => {
+            report.fail(
+                format!("configuration is invalid: {}", e),
+                "check mdwiki.toml and MDWIKI_ environment variables",
+            );
+            return 1;
+        }
+    };
+
+    check_config(&mut report, &config);
+    check_book(&mut report, &config);
+    check_sandbox(&mut report, &config);
+    check_renderer(&mut report, &config);
+    check_uploads(&mut report, &config);
+    check_remote(&mut report, &config);
+    check_server(&mut report, &figment);
+
+    let problems = report
+        .findings
+        .iter()
+        .filter(|level| **level > Level::Ok)
+        .count();
+    println!();
+    println!("{} checks, {} problems found", report.findings.len(), problems);
+
+    if report.failed() {
+        1
+    } else {
+        0
+    }
+}
@@ -0,0 +1,168 @@
+//! Drafts of pages, committed to a branch per user, `drafts/<username>`, instead of the branch
+//! the book is built from. Every save is a commit changing one page, its message naming the
+//! page and the revision the draft started from. The branch's tree is the book as it was when
+//! the first draft was saved with the drafts on top, only the pages with drafts are published.
+
+use crate::config::User;
+
+use std::path::Path;
+
+use git2::build::TreeUpdateBuilder;
+use git2::{BranchType, Commit, FileMode, Reference, Repository, Signature, Sort};
+
+const DRAFT_PREFIX: &str = "Draft ";
+const DISCARD_PREFIX: &str = "Discard draft of ";
+const BASE_TRAILER: &str = "Base: ";
+
+/// The draft of a page by a user.
+#[derive(Debug, Clone)]
+pub struct Draft {
+    pub page: String,
+    /// The revision of the page the draft started from, see `merge::revision`.
+    pub base: String,
+    pub content: String,
+    /// Unix time of the last save.
+    pub saved: u64,
+}
+
+/// The branch with the drafts of `username`.
+pub fn branch_name(username: &str) -> String {
+    format!("drafts/{}", username)
+}
+
+fn tip<'r>(repo: &'r Repository, username: &str) -> Option<Commit<'r>> {
+    repo.find_branch(&branch_name(username), BranchType::Local)
+        .ok()?
+        .get()
+        .peel_to_commit()
+        .ok()
+}
+
+/// The drafts of `username` that aren't published or discarded, most recently saved first.
+pub fn list(repo: &Repository, username: &str) -> Result<Vec<Draft>, String> {
+    let err = |e: git2::Error| format!("could not read the drafts of '{}': {}", username, e);
+    let tip = match tip(repo, username) {
+        Some(tip) => tip,
+        None => return Ok(Vec::new()),
+    };
+    let tree = tip.tree().map_err(err)?;
+    let mut walk = repo.revwalk().map_err(err)?;
+    walk.set_sorting(Sort::TOPOLOGICAL).map_err(err)?;
+    walk.push(tip.id()).map_err(err)?;
+    // what the book's branch already has isn't a draft
+    if let Ok(head) = repo.head().and_then(|head| head.peel_to_commit()) {
+        walk.hide(head.id()).map_err(err)?;
+    }
+
+    let mut seen = Vec::new();
+    let mut drafts = Vec::new();
+    for oid in walk {
+        let commit = repo.find_commit(oid.map_err(err)?).map_err(err)?;
+        let message = commit.message().unwrap_or_default();
+        let mut lines = message.lines();
+        let subject = lines.next().unwrap_or_default();
+        let (page, discarded) = if subject.starts_with(DISCARD_PREFIX) {
+            (&subject[DISCARD_PREFIX.len()..], true)
+        } else if subject.starts_with(DRAFT_PREFIX) {
+            (&subject[DRAFT_PREFIX.len()..], false)
+        } else {
+            continue;
+        };
+        // only the last save of a page counts
+        if seen.iter().any(|seen| seen == page) {
+            continue;
+        }
+        seen.push(page.to_string());
+        if discarded {
+            continue;
+        }
+        let base = lines
+            .find(|line| line.starts_with(BASE_TRAILER))
+            .map_or("", |line| &line[BASE_TRAILER.len()..]);
+        let content = match tree
+            .get_path(&Path::new("src").join(page))
+            .and_then(|entry| entry.to_object(repo))
+            .and_then(|object| object.peel_to_blob())
+        {
+            Ok(blob) => String::from_utf8_lossy(blob.content()).into_owned(),
+            Err(_) => continue,
+        };
+        drafts.push(Draft {
+            page: page.to_string(),
+            base: base.to_string(),
+            content,
+            saved: commit.time().seconds().max(0) as u64,
+        });
+    }
+    Ok(drafts)
+}
+
+/// The draft of `page` by `username`, if they have one.
+pub fn get(repo: &Repository, username: &str, page: &str) -> Option<Draft> {
+    list(repo, username)
+        .ok()?
+        .into_iter()
+        .find(|draft| draft.page == page)
+}
+
+fn commit(
+    repo: &Repository,
+    user: &User,
+    email: &str,
+    message: &str,
+    update: impl FnOnce(&mut TreeUpdateBuilder) -> Result<(), git2::Error>,
+) -> Result<String, git2::Error> {
+    let refname = format!("refs/heads/{}", branch_name(&user.username));
+    if !Reference::is_valid_name(&refname) {
+        return Err(git2::Error::from_str(
+            "the username can't be used in a branch name",
+        ));
+    }
+    // the first draft starts from the book as it is
+    let parent = match tip(repo, &user.username) {
+        Some(tip) => tip,
+        None => repo.head()?.peel_to_commit()?,
+    };
+    let mut builder = TreeUpdateBuilder::new();
+    update(&mut builder)?;
+    let tree = repo.find_tree(builder.create_updated(repo, &parent.tree()?)?)?;
+    let sig = Signature::now(user.name(), email)?;
+    let oid = repo.commit(Some(&refname), &sig, &sig, message, &tree, &[&parent])?;
+    Ok(oid.to_string())
+}
+
+/// Saves `content` as the draft of `page` by `user`, that started from the revision `base` of
+/// the page, committed with `email`, see `Config::commit_email`. Returns the commit.
+pub fn save(
+    repo: &Repository,
+    user: &User,
+    email: &str,
+    page: &str,
+    base: &str,
+    content: &str,
+) -> Result<String, String> {
+    let message = format!("{}{}\n\n{}{}\n", DRAFT_PREFIX, page, BASE_TRAILER, base);
+    commit(repo, user, email, &message, |builder| {
+        let blob = repo.blob(content.as_bytes())?;
+        builder.upsert(format!("src/{}", page), blob, FileMode::Blob);
+        Ok(())
+    })
+    .map_err(|e| format!("could not save the draft of '{}': {}", page, e))
+}
+
+/// Discards the draft of `page` by `user`, keeping it in the history of their branch.
+pub fn discard(repo: &Repository, user: &User, email: &str, page: &str) -> Result<String, String> {
+    let message = format!("{}{}\n", DISCARD_PREFIX, page);
+    commit(repo, user, email, &message, |_| Ok(()))
+        .map_err(|e| format!("could not discard the draft of '{}': {}", page, e))
+}
+
+/// Deletes the branch with the drafts of `username`, once they're published.
+pub fn remove(repo: &Repository, username: &str) -> Result<(), String> {
+    match repo.find_branch(&branch_name(username), BranchType::Local) {
+        Ok(mut branch) => branch
+            .delete()
+            .map_err(|e| format!("could not delete the drafts of '{}': {}", username, e)),
+        Err(_) => Ok(()),
+    }
+}
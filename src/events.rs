@@ -0,0 +1,125 @@
+use crate::session::now;
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rocket::tokio::sync::broadcast;
+use rocket::tokio::time::timeout;
+
+use serde::Serialize;
+
+const MAX_RECENT_EVENTS: usize = 1000;
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    PageCreated { path: String, user: String },
+    PageEdited { path: String, user: String },
+    PageMoved { from: String, to: String, user: String },
+    PagesReplaced { paths: Vec<String>, user: String },
+    /// A tag was renamed or merged into another on `paths`.
+    PagesTagged { paths: Vec<String>, user: String },
+    /// Pages were added, moved or removed in the sidebar. `sections` are the outermost
+    /// directories whose entries changed, `""` being the top level.
+    SectionsReorganized { sections: Vec<String>, user: String },
+    BuildFinished { success: bool },
+    UserLoggedIn { user: String },
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::PageCreated { .. } => "page_created",
+            EventKind::PageEdited { .. } => "page_edited",
+            EventKind::PageMoved { .. } => "page_moved",
+            EventKind::PagesReplaced { .. } => "pages_replaced",
+            EventKind::PagesTagged { .. } => "pages_tagged",
+            EventKind::SectionsReorganized { .. } => "sections_reorganized",
+            EventKind::BuildFinished { .. } => "build_finished",
+            EventKind::UserLoggedIn { .. } => "user_logged_in",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: u64,
+    pub time: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug)]
+struct Recent {
+    next_id: u64,
+    events: VecDeque<Event>,
+}
+
+/// Keeps the most recent wiki events in memory, so clients can poll for everything that
+/// happened after the last event they saw.
+#[derive(Debug)]
+pub struct EventLog {
+    recent: Mutex<Recent>,
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventLog {
+    fn default() -> EventLog {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        EventLog {
+            recent: Mutex::new(Recent {
+                next_id: 1,
+                events: VecDeque::new(),
+            }),
+            tx,
+        }
+    }
+}
+
+impl EventLog {
+    pub fn publish(&self, kind: EventKind) {
+        let event = {
+            let mut recent = self.recent.lock().unwrap();
+            let event = Event {
+                id: recent.next_id,
+                time: now(),
+                kind,
+            };
+            recent.next_id += 1;
+            if recent.events.len() >= MAX_RECENT_EVENTS {
+                recent.events.pop_front();
+            }
+            recent.events.push_back(event.clone());
+            event
+        };
+        // No receivers just means nobody is listening right now
+        let _ = self.tx.send(event);
+    }
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+    /// Events with an id greater than `since`, oldest first.
+    pub fn since(&self, since: u64) -> Vec<Event> {
+        self.recent
+            .lock()
+            .unwrap()
+            .events
+            .iter()
+            .filter(|event| event.id > since)
+            .cloned()
+            .collect()
+    }
+    /// Like `since`, but waits up to `wait` for new events if there are none yet.
+    pub async fn poll(&self, since: u64, wait: Duration) -> Vec<Event> {
+        // Subscribe before checking, so events published in between aren't missed
+        let mut rx = self.tx.subscribe();
+        let events = self.since(since);
+        if !events.is_empty() {
+            return events;
+        }
+        let _ = timeout(wait, rx.recv()).await;
+        self.since(since)
+    }
+}
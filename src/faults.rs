@@ -0,0 +1,68 @@
+//! Faults injected into the steps of the wiki worker, so tests can check how it copes with a
+//! full disk, a broken repository or a build that hangs. Outside of tests nothing is injected,
+//! and checking for a fault costs nothing.
+
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+
+/// The steps of handling a request that can be made to fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    /// Writing a page to the book source.
+    Write,
+    /// Committing the changes to the repository.
+    Commit,
+    /// Building the book, inside `build_timeout`.
+    Build,
+    /// Pushing to a mirror, see `mirrors`.
+    Push,
+    /// Reading the history of the book, see `WikiRequest::is_read`.
+    Read,
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// Fails the step with the message.
+    Fail(String),
+    /// Holds up the step before it runs.
+    Delay(Duration),
+}
+
+#[derive(Debug, Default)]
+pub struct Faults {
+    #[cfg(test)]
+    planned: Mutex<Vec<(Step, Fault)>>,
+}
+
+impl Faults {
+    /// Makes the next time `step` runs fail or wait. Faults for the same step happen in the
+    /// order they were injected, each of them once.
+    #[cfg(test)]
+    pub fn inject(&self, step: Step, fault: Fault) {
+        self.planned.lock().unwrap().push((step, fault));
+    }
+    /// Called before `step` runs, fails with the injected fault if there is one. Delays block
+    /// the thread, so it's called from a blocking task where waiting matters.
+    #[cfg_attr(not(test), allow(unused_variables))]
+    pub fn check(&self, step: Step) -> Result<(), String> {
+        #[cfg(test)]
+        {
+            let fault = {
+                let mut planned = self.planned.lock().unwrap();
+                let next = planned.iter().position(|(planned, _)| *planned == step);
+                next.map(|i| planned.remove(i).1)
+            };
+            match fault {
+                Some(Fault::Fail(message)) => {
+                    return Err(format!("injected fault in {:?}: {}", step, message))
+                }
+                Some(Fault::Delay(delay)) => std::thread::sleep(delay),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
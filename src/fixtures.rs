@@ -0,0 +1,168 @@
+//! Synthetic books of any size for tests and the load test, so they don't only see a wiki with
+//! a handful of pages. The same options always give the same book, down to the byte.
+
+use std::fs;
+use std::path::Path;
+
+const WORDS: &[&str] = &[
+    "wiki", "page", "deploy", "server", "backup", "release", "team", "meeting", "notes", "guide",
+    "setup", "config", "install", "network", "database", "service", "build", "review", "access",
+    "token", "search", "index", "cache", "storage", "monitor", "alert", "schedule", "policy",
+    "budget", "roadmap", "design", "testing",
+];
+
+/// A valid 1x1 transparent png.
+const PIXEL_PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00, 0x01, 0x00, 0x00,
+    0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae,
+    0x42, 0x60, 0x82,
+];
+
+/// The shape of a generated book.
+#[derive(Debug, Clone)]
+pub struct FixtureOptions {
+    /// Directories in the book, each with an index page linking to its pages.
+    pub directories: usize,
+    /// Pages in every directory, besides the index.
+    pub pages: usize,
+    /// Images shared by the pages, every page shows one of them when there are any.
+    pub images: usize,
+    /// Links from every page to other pages, some of them to a heading.
+    pub links: usize,
+    /// Picks the words and links, another seed gives another book of the same shape.
+    pub seed: u64,
+}
+
+impl Default for FixtureOptions {
+    fn default() -> FixtureOptions {
+        FixtureOptions {
+            directories: 10,
+            pages: 20,
+            images: 5,
+            links: 3,
+            seed: 1,
+        }
+    }
+}
+
+/// What was generated, with the paths relative to the book source.
+#[derive(Debug, Clone)]
+pub struct Fixture {
+    /// Every page, index pages included, in the order they were written.
+    pub pages: Vec<String>,
+    pub images: Vec<String>,
+    /// Links between pages, not counting the index pages' links to their pages.
+    pub links: usize,
+}
+
+/// splitmix64, which unlike `rand`'s generators is guaranteed to give the same numbers in
+/// every version.
+struct Sequence(u64);
+
+impl Sequence {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    /// A number below `n`, which must not be 0.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+    fn sentence(&mut self, words: usize) -> String {
+        let mut sentence = (0..words)
+            .map(|_| WORDS[self.below(WORDS.len())])
+            .collect::<Vec<_>>()
+            .join(" ");
+        sentence[..1].make_ascii_uppercase();
+        sentence.push('.');
+        sentence
+    }
+}
+
+fn directory_name(directory: usize) -> String {
+    format!("section-{}", directory)
+}
+
+/// The path of page `page` of directory `directory`, relative to the book source.
+pub fn page_path(directory: usize, page: usize) -> String {
+    format!("{}/page-{}.md", directory_name(directory), page)
+}
+
+/// The path of the image `image`, relative to the book source.
+pub fn image_path(image: usize) -> String {
+    format!("images/fixture-{}.png", image)
+}
+
+fn write(src: &Path, path: &str, content: &[u8]) -> Result<(), String> {
+    let path = src.join(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("could not create directory '{}': {}", parent.display(), e))?;
+    }
+    fs::write(&path, content).map_err(|e| format!("could not write '{}': {}", path.display(), e))
+}
+
+/// Writes a book of `options.directories` directories of `options.pages` pages, linking to each
+/// other and showing images, into the book source `src`. Existing files with the same names are
+/// replaced, others are left alone.
+pub fn generate(src: &Path, options: &FixtureOptions) -> Result<Fixture, String> {
+    let mut sequence = Sequence(options.seed);
+    let mut fixture = Fixture {
+        pages: Vec::new(),
+        images: Vec::new(),
+        links: 0,
+    };
+
+    for image in 0..options.images {
+        let path = image_path(image);
+        write(src, &path, PIXEL_PNG)?;
+        fixture.images.push(path);
+    }
+
+    for directory in 0..options.directories {
+        let mut index = format!("# Section {}\n\n", directory);
+        for page in 0..options.pages {
+            index.push_str(&format!("- [Page {0}](page-{0}.md)\n", page));
+        }
+        let path = format!("{}/README.md", directory_name(directory));
+        write(src, &path, index.as_bytes())?;
+        fixture.pages.push(path);
+
+        for page in 0..options.pages {
+            let mut content = format!("# Page {} of section {}\n\n", page, directory);
+            let words = 12 + sequence.below(20);
+            content.push_str(&format!("{}\n\n## Details\n\n", sequence.sentence(words)));
+            if options.images > 0 {
+                let image = sequence.below(options.images);
+                content.push_str(&format!("![Figure {}](/{})\n\n", image, image_path(image)));
+            }
+            for _ in 0..options.links {
+                let to_directory = sequence.below(options.directories);
+                let to_page = sequence.below(options.pages);
+                let target = if to_directory == directory {
+                    format!("page-{}.md", to_page)
+                } else {
+                    format!("../{}", page_path(to_directory, to_page))
+                };
+                let anchor = match sequence.below(2) {
+                    0 => "",
+                    _ => "#details",
+                };
+                content.push_str(&format!(
+                    "- See [page {} of section {}]({}{})\n",
+                    to_page, to_directory, target, anchor
+                ));
+                fixture.links += 1;
+            }
+            let path = page_path(directory, page);
+            write(src, &path, content.as_bytes())?;
+            fixture.pages.push(path);
+        }
+    }
+    Ok(fixture)
+}
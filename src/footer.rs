@@ -0,0 +1,60 @@
+use mdbook::book::Book;
+use mdbook::errors::Error;
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::BookItem;
+
+use git2::Repository;
+
+use time::OffsetDateTime;
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// The short id of the commit the book at `path` is built from.
+fn head_commit(path: &str) -> Option<String> {
+    let repo = Repository::open(path).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    let id = commit.as_object().short_id().ok()?;
+    id.as_str().map(String::from)
+}
+
+/// Adds a footer to every page, with the wiki's title, the mdwiki version, the commit the book
+/// was built from and when, so a stale copy of the wiki can be told apart from the live one.
+pub(crate) struct FooterPreprocessor {
+    html: String,
+}
+
+impl FooterPreprocessor {
+    pub fn new(title: &str, path: &str) -> FooterPreprocessor {
+        let commit = head_commit(path).unwrap_or_else(|| "no commit".to_string());
+        let built = OffsetDateTime::now_utc().format("%Y-%m-%d %H:%M UTC");
+        FooterPreprocessor {
+            html: format!(
+                "<footer class=\"mdwiki-footer\" style=\"margin-top: 3em; font-size: 0.8em; \
+                 opacity: 0.7;\">{} &middot; mdwiki {} &middot; {} &middot; built {}</footer>",
+                escape_html(title),
+                env!("CARGO_PKG_VERSION"),
+                commit,
+                built
+            ),
+        }
+    }
+}
+
+impl Preprocessor for FooterPreprocessor {
+    fn name(&self) -> &str {
+        "mdwiki-footer"
+    }
+
+    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                chapter.content = format!("{}\n\n{}\n", chapter.content.trim_end(), self.html);
+            }
+        });
+        Ok(book)
+    }
+}
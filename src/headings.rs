@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::lifecycle::front_matter;
+
+use mdbook::utils::normalize_id;
+
+use pulldown_cmark::{Event, Parser, Tag};
+
+use once_cell::sync::Lazy;
+
+use regex::{Captures, Regex};
+
+const HEADING_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)<h([1-6])((?:\s[^>]*)?)>(.*?)</h[1-6]>").unwrap());
+
+const ID_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\sid\s*=\s*"([^"]*)""#).unwrap());
+
+const TAG_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]*>").unwrap());
+
+/// A heading in a page's markdown.
+#[derive(Debug, Clone)]
+pub struct Section {
+    /// The line the heading is on, starting at 1.
+    pub line: usize,
+    /// 1 for `#`, up to 6.
+    pub level: u32,
+    pub title: String,
+    pub id: String,
+}
+
+/// The headings in the markdown `content`, with the ids mdbook gives them.
+pub fn sections(content: &str) -> Vec<Section> {
+    // the closing `---` of front matter would make a heading of the line before it
+    let body = front_matter(content).map_or(content, |(_, body)| body);
+    let offset = content[..content.len() - body.len()].matches('\n').count();
+    let mut sections = Vec::new();
+    let mut counts = HashMap::new();
+    let mut heading: Option<(usize, u32, String)> = None;
+    for (event, range) in Parser::new(body).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading(level)) => {
+                let line = offset + body[..range.start].matches('\n').count() + 1;
+                heading = Some((line, level, String::new()));
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, _, title)) = &mut heading {
+                    title.push_str(&text);
+                }
+            }
+            Event::End(Tag::Heading(_)) => {
+                if let Some((line, level, title)) = heading.take() {
+                    let id = normalize_id(title.trim());
+                    // mdbook numbers repeated ids
+                    let count = counts.entry(id.clone()).or_insert(0);
+                    let id = match *count {
+                        0 => id,
+                        n => format!("{}-{}", id, n),
+                    };
+                    *count += 1;
+                    sections.push(Section {
+                        line,
+                        level,
+                        title: title.trim().to_string(),
+                        id,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    sections
+}
+
+/// Where the section under the heading with the id `id` is in `content`, from the start of the
+/// heading to the next heading of the same or a higher level.
+pub fn section_range(content: &str, id: &str) -> Option<Range<usize>> {
+    let sections = sections(content);
+    let i = sections.iter().position(|section| section.id == id)?;
+    let level = sections[i].level;
+    let end_line = sections[i + 1..]
+        .iter()
+        .find(|section| section.level <= level)
+        .map(|section| section.line);
+    let offset_of = |line: usize| match line {
+        1 => 0,
+        line => content
+            .match_indices('\n')
+            .nth(line - 2)
+            .map_or(content.len(), |(i, _)| i + 1),
+    };
+    let start = offset_of(sections[i].line);
+    let end = end_line.map_or(content.len(), offset_of);
+    Some(start..end)
+}
+
+/// `content` with the section under the heading with the id `id` replaced with `section`. The
+/// blank lines after the section are kept, so it stays apart from the next heading.
+pub fn replace_section(content: &str, id: &str, section: &str) -> Option<String> {
+    let range = section_range(content, id)?;
+    let old = &content[range.clone()];
+    Some(format!(
+        "{}{}{}{}",
+        &content[..range.start],
+        section.trim_end(),
+        &old[old.trim_end().len()..],
+        &content[range.end..]
+    ))
+}
+
+/// The id mdbook gives a heading with the html `content`.
+fn heading_id(content: &str) -> String {
+    let text = TAG_REGEX
+        .replace_all(content, "")
+        .replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'");
+    normalize_id(text.trim())
+}
+
+/// Gives every heading in `html` without an id one, the way mdbook does, so headings from
+/// plugins and raw html can be linked to as well.
+fn add_ids(html: &str) -> Option<String> {
+    let mut ids: HashSet<String> = ID_REGEX
+        .captures_iter(html)
+        .map(|captures| captures[1].to_string())
+        .collect();
+    let mut changed = false;
+    let html = HEADING_REGEX.replace_all(html, |captures: &Captures| {
+        // mdbook puts the id on the heading, or on the link inside it
+        if ID_REGEX.is_match(&captures[2]) || ID_REGEX.is_match(&captures[3]) {
+            return captures[0].to_string();
+        }
+        let base = match heading_id(&captures[3]) {
+            id if id.is_empty() => "section".to_string(),
+            id => id,
+        };
+        let mut id = base.clone();
+        let mut count = 0;
+        while ids.contains(&id) {
+            count += 1;
+            id = format!("{}-{}", base, count);
+        }
+        ids.insert(id.clone());
+        changed = true;
+        format!(
+            "<h{0}{1} id=\"{2}\">{3}</h{0}>",
+            &captures[1], &captures[2], id, &captures[3]
+        )
+    });
+    if changed {
+        Some(html.to_string())
+    } else {
+        None
+    }
+}
+
+/// Makes sure every heading of every page in the build in `dir` has an id. Attachments and
+/// images are left alone.
+pub fn add_heading_ids(dir: &Path) -> Result<(), String> {
+    fn visit(root: &Path, dir: &Path) -> Result<(), String> {
+        let entries = fs::read_dir(dir)
+            .map_err(|e| format!("could not read directory '{}': {}", dir.display(), e))?;
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            if path.is_dir() {
+                if !relative.starts_with("images") && !relative.starts_with("files") {
+                    visit(root, &path)?;
+                }
+            } else if path.extension().map_or(false, |ext| ext == "html") {
+                let html = fs::read_to_string(&path)
+                    .map_err(|e| format!("could not read '{}': {}", path.display(), e))?;
+                if let Some(html) = add_ids(&html) {
+                    fs::write(&path, html)
+                        .map_err(|e| format!("could not write '{}': {}", path.display(), e))?;
+                }
+            }
+        }
+        Ok(())
+    }
+    visit(dir, dir)
+}
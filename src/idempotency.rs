@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+
+const MAX_KEY_LENGTH: usize = 255;
+const MAX_TRACKED_KEYS: usize = 10_000;
+const KEY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The optional `Idempotency-Key` header sent with a mutating API request.
+pub struct IdempotencyKey(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match req.headers().get_one("Idempotency-Key") {
+            Some(key) if key.is_empty() || key.len() > MAX_KEY_LENGTH => {
+                request::Outcome::Failure((Status::BadRequest, ()))
+            }
+            key => request::Outcome::Success(IdempotencyKey(key.map(String::from))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    pub status: Status,
+    pub body: String,
+}
+
+#[derive(Debug)]
+struct Entry {
+    fingerprint: u64,
+    created: Instant,
+    response: Option<StoredResponse>,
+}
+
+pub enum Begin {
+    /// First time the key is seen, the request should be processed.
+    New,
+    /// The request was already processed, this is its response.
+    Replay(StoredResponse),
+    /// A request with the same key is still being processed.
+    InProgress,
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+/// In-memory record of the responses to requests sent with an `Idempotency-Key`, so retried
+/// requests get the original response instead of being applied twice. Keys are scoped to the
+/// user sending them, and forgotten after a day.
+#[derive(Debug, Default)]
+pub struct IdempotencyStore {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl IdempotencyStore {
+    pub fn begin(&self, key: &str, fingerprint: u64) -> Begin {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        if entries.len() > MAX_TRACKED_KEYS {
+            entries.retain(|_, entry| now.duration_since(entry.created) < KEY_LIFETIME);
+        }
+
+        match entries.get(key) {
+            Some(entry) if now.duration_since(entry.created) < KEY_LIFETIME => {
+                return if entry.fingerprint != fingerprint {
+                    Begin::Mismatch
+                } else if let Some(response) = &entry.response {
+                    Begin::Replay(response.clone())
+                } else {
+                    Begin::InProgress
+                };
+            }
+            _ => {}
+        }
+
+        entries.insert(
+            key.to_string(),
+            Entry {
+                fingerprint,
+                created: now,
+                response: None,
+            },
+        );
+        Begin::New
+    }
+    /// Stores the response for a key. Server errors are not stored, so the request can be retried.
+    pub fn finish(&self, key: &str, response: StoredResponse) {
+        let mut entries = self.entries.lock().unwrap();
+        if response.status.code >= 500 {
+            entries.remove(key);
+        } else if let Some(entry) = entries.get_mut(key) {
+            entry.response = Some(response);
+        }
+    }
+}
+
+/// Responses that can be stored for replaying.
+pub trait Storable {
+    fn to_stored(&self) -> StoredResponse;
+}
+
+pub enum Idempotent<R> {
+    Fresh(R),
+    Replayed(StoredResponse),
+}
+
+impl<R> From<R> for Idempotent<R> {
+    fn from(response: R) -> Self {
+        Idempotent::Fresh(response)
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Idempotent<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        match self {
+            Idempotent::Fresh(inner) => inner.respond_to(req),
+            Idempotent::Replayed(stored) => Response::build()
+                .status(stored.status)
+                .header(ContentType::JSON)
+                .raw_header("Idempotent-Replayed", "true")
+                .sized_body(stored.body.len(), Cursor::new(stored.body))
+                .ok(),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs::{self, Metadata};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use git2::{Index, IndexEntry, IndexTime, Oid, Repository, Status, StatusOptions};
+
+use ring::digest;
+
+/// Where uploaded images and attachments are stored, relative to the repository.
+pub const LFS_PATHS: &[&str] = &["src/images/", "src/files/"];
+
+const POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+
+/// Whether the file at `path`, relative to the repository, is stored with Git LFS.
+pub fn is_tracked(path: &Path) -> bool {
+    LFS_PATHS.iter().any(|dir| path.starts_with(dir))
+}
+
+/// The `.gitattributes` lines that make git-lfs handle `LFS_PATHS`.
+pub fn gitattributes() -> Vec<String> {
+    LFS_PATHS
+        .iter()
+        .map(|dir| format!("{}** filter=lfs diff=lfs merge=lfs -text", dir))
+        .collect()
+}
+
+/// The index entry for a file with `metadata`, with the stat data git-lfs' clean filter would
+/// leave, so the file isn't seen as changed when its pointer is in the index.
+fn index_entry(path: &str, metadata: &Metadata, id: Oid) -> IndexEntry {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| IndexTime::new(mtime.as_secs() as i32, mtime.subsec_nanos()))
+        .unwrap_or_else(|| IndexTime::new(0, 0));
+    #[cfg(unix)]
+    let (ctime, dev, ino, uid, gid) = {
+        use std::os::unix::fs::MetadataExt;
+        (
+            IndexTime::new(metadata.ctime() as i32, metadata.ctime_nsec() as u32),
+            metadata.dev() as u32,
+            metadata.ino() as u32,
+            metadata.uid(),
+            metadata.gid(),
+        )
+    };
+    #[cfg(not(unix))]
+    let (ctime, dev, ino, uid, gid) = (mtime, 0, 0, 0, 0);
+    IndexEntry {
+        ctime,
+        mtime,
+        dev,
+        ino,
+        mode: 0o100644,
+        uid,
+        gid,
+        file_size: metadata.len() as u32,
+        id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    }
+}
+
+/// Adds the new and changed files in `LFS_PATHS` under `paths`, or anywhere without them, to
+/// `index` as LFS pointers. Their content is stored in `.git/lfs/objects`, where `git lfs push`
+/// finds it when pushing to a remote.
+pub fn add_pointers(
+    repo: &Repository,
+    index: &mut Index,
+    paths: Option<&[String]>,
+) -> Result<(), String> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| "the repository has no working directory".to_string())?;
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true);
+    if let Some(paths) = paths {
+        // Names of files and directories, which mustn't be matched as patterns
+        options.disable_pathspec_match(true);
+        for path in paths {
+            options.pathspec(path);
+        }
+    }
+    let statuses = repo
+        .statuses(Some(&mut options))
+        .map_err(|e| format!("failed to get repository status: {}", e))?;
+    let changed = statuses
+        .iter()
+        .filter(|entry| {
+            entry
+                .status()
+                .intersects(Status::WT_NEW | Status::WT_MODIFIED)
+        })
+        .filter_map(|entry| entry.path().map(String::from))
+        .filter(|path| is_tracked(Path::new(path)))
+        .collect::<Vec<_>>();
+
+    for path in changed {
+        let file = workdir.join(&path);
+        let content =
+            fs::read(&file).map_err(|e| format!("could not read '{}': {}", file.display(), e))?;
+        let metadata = fs::metadata(&file)
+            .map_err(|e| format!("could not read '{}': {}", file.display(), e))?;
+        let oid: String = digest::digest(&digest::SHA256, &content)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let object = repo
+            .path()
+            .join("lfs/objects")
+            .join(&oid[..2])
+            .join(&oid[2..4])
+            .join(&oid);
+        if !object.is_file() {
+            if let Some(parent) = object.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("could not create lfs object directory: {}", e))?;
+            }
+            fs::write(&object, &content)
+                .map_err(|e| format!("could not write lfs object for '{}': {}", path, e))?;
+        }
+
+        let pointer = format!(
+            "{}\noid sha256:{}\nsize {}\n",
+            POINTER_VERSION,
+            oid,
+            content.len()
+        );
+        let blob = repo
+            .blob(pointer.as_bytes())
+            .map_err(|e| format!("could not write lfs pointer for '{}': {}", path, e))?;
+        index
+            .add(&index_entry(&path, &metadata, blob))
+            .map_err(|e| format!("could not add '{}': {}", path, e))?;
+    }
+    Ok(())
+}
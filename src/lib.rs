@@ -0,0 +1,5398 @@
+#![feature(proc_macro_hygiene, decl_macro, async_closure)]
+
+#[macro_use]
+mod utils;
+mod accessibility;
+mod api;
+mod attribution;
+mod audit;
+mod auth;
+mod backup;
+mod builds;
+mod bulk;
+mod cache;
+mod chat;
+mod cli;
+pub mod client;
+mod config;
+mod contributors;
+mod doctor;
+mod drafts;
+mod events;
+mod faults;
+#[cfg(any(test, feature = "stress"))]
+pub mod fixtures;
+mod footer;
+mod headings;
+mod idempotency;
+mod lfs;
+mod lifecycle;
+mod lint;
+mod locks;
+mod mail;
+mod maintenance;
+mod merge;
+mod migrations;
+mod mirrors;
+mod net;
+pub mod plugin;
+mod queue;
+mod range;
+mod ratelimit;
+mod remote;
+mod render;
+mod reset;
+mod robots;
+mod sandbox;
+mod sanitize;
+#[cfg(feature = "lua")]
+mod scripting;
+mod security;
+mod session;
+mod shared;
+mod sinks;
+mod sitemap;
+#[cfg(feature = "stress")]
+pub mod stress;
+mod suggestions;
+mod tags;
+mod templates;
+mod todos;
+mod upload_names;
+mod users;
+mod validate;
+mod views;
+mod watches;
+mod webapp;
+mod webhooks;
+mod wiki;
+
+#[macro_use]
+extern crate rocket;
+
+#[macro_use]
+extern crate log;
+
+pub use plugin::{Plugin, Plugins};
+pub use webhooks::{verify_signature, SIGNATURE_HEADER, SIGNATURE_TOLERANCE};
+
+use audit::{LogFormat, RequestLogger};
+use config::Config;
+use security::SecurityHeaders;
+use webapp::WebappState;
+use wiki::WikiState;
+
+use std::collections::HashMap;
+
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::futures::join;
+use rocket::tokio::task;
+use rocket_contrib::templates::Template;
+
+fn rocket(state: WebappState) -> rocket::Rocket {
+    use webapp::*;
+
+    let figment = Figment::from(rocket::Config::default()).merge(Config::figment());
+
+    // url encoding can triple the size of a page, so the form limit follows max_page_size
+    let max_page_size: u64 = figment.extract_inner("max_page_size").unwrap();
+    let figment = figment.merge(("limits.forms", max_page_size * 3));
+
+    let log_format: LogFormat = figment.extract_inner("log_format").unwrap();
+    let mut security_headers: HashMap<String, String> =
+        figment.extract_inner("security_headers").unwrap();
+    let branding = figment.extract_inner("branding").unwrap();
+
+    let config: Config = figment.extract().unwrap();
+    if !config.crawlers_allowed() {
+        security_headers
+            .entry("X-Robots-Tag".to_string())
+            .or_insert_with(|| robots::NOINDEX.to_string());
+    }
+    let template_dir = templates::materialize(&config).unwrap();
+    let figment = figment.merge(("template_dir", template_dir));
+
+    let plugin_routes = state.shared.plugins.routes();
+
+    let rocket = rocket::custom(figment).mount("/", plugin_routes);
+    let rocket = if log_format == LogFormat::Json {
+        rocket.attach(RequestLogger)
+    } else {
+        rocket
+    };
+
+    rocket
+        .attach(AdHoc::config::<Config>())
+        .attach(Template::custom(template_functions(branding)))
+        .attach(SecurityHeaders(security_headers))
+        .manage(state)
+        .mount(
+            "/",
+            routes![
+                index,
+                book_files,
+                embed_page,
+                new_page,
+                new_page_post,
+                edit_page,
+                edit_page_post,
+                renew_edit_lock,
+                reviews,
+                accept_suggestion,
+                reject_suggestion,
+                list_drafts,
+                publish_drafts,
+                discard_draft,
+                list_deleted,
+                restore_deleted,
+                upload_image,
+                upload_attachment,
+                mdwiki_script,
+                login,
+                login_post,
+                forgot_password,
+                forgot_password_post,
+                reset_password,
+                reset_password_post,
+                login_oidc,
+                login_oidc_callback,
+                logout,
+                logout_all,
+                profile,
+                profile_post,
+                profile_avatar,
+                avatar,
+                admin_revoke_sessions,
+                admin_users,
+                admin_users_create,
+                admin_users_password,
+                admin_users_disable,
+                admin_users_delete,
+                admin_builds,
+                watch_page,
+                healthz,
+                readyz,
+                metrics,
+                diff,
+                blame_page,
+                dashboard,
+                todo_list,
+                contributor_stats,
+                contributor_details,
+                robots_txt,
+                admin_builds_pin,
+                admin_builds_unpin,
+                admin_mirrors,
+                admin_mirrors_push,
+                admin_lint,
+                admin_backup,
+                admin_restore,
+                api::get_page,
+                api::put_page,
+                api::set_read_only,
+                api::events,
+                api::move_path,
+                api::replace,
+                api::tag_index,
+                api::change_tags,
+                api::page_context,
+            ],
+        )
+}
+
+/// Runs mdwiki with the given plugins, handling the command line arguments like the `mdwiki`
+/// binary does.
+pub async fn run(plugins: Plugins) {
+    audit::init_logger(
+        Config::figment()
+            .extract_inner("log_format")
+            .unwrap_or(LogFormat::Text),
+    );
+
+    match cli::parse() {
+        cli::Command::Serve => serve(plugins).await,
+        cli::Command::Doctor => std::process::exit(doctor::run()),
+        command => std::process::exit(cli::run(command, plugins).await),
+    }
+}
+
+async fn serve(plugins: Plugins) {
+    let config = match validate::load() {
+        Ok((config, problems)) => {
+            for problem in problems {
+                warn!("{}", problem);
+            }
+            config
+        }
+        Err(problems) => {
+            for problem in problems {
+                error!("{}", problem);
+            }
+            error!("mdwiki can't start with this configuration, see `mdwiki check`");
+            std::process::exit(1);
+        }
+    };
+    let (wiki_state, webapp_state) = WikiState::with_config(config, plugins);
+
+    // Rocket launches while the wiki sets up, so probes can reach /healthz and /readyz during a
+    // slow initial build
+    let rocket = rocket(webapp_state);
+    let wiki = task::spawn(async move {
+        if let Err(e) = wiki_state.setup().await {
+            error!("failed to set up the wiki: {}", e);
+            std::process::exit(1);
+        }
+        wiki_state.serve().await
+    });
+
+    let shutdown = rocket.shutdown();
+    task::spawn(async move {
+        terminate_signal().await;
+        info!("received SIGTERM, shutting down");
+        shutdown.shutdown();
+    });
+
+    // The wiki task finishes the queued requests once rocket has stopped and dropped its state
+    let (_, launched) = join!(wiki, rocket.launch());
+    launched.unwrap();
+}
+
+/// Resolves on SIGTERM, which is how containers are stopped. Ctrl-C is handled by rocket.
+#[cfg(unix)]
+async fn terminate_signal() {
+    use rocket::tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut signal) => {
+            signal.recv().await;
+        }
+        Err(e) => {
+            warn!("failed to listen for SIGTERM: {}", e);
+            std::future::pending().await
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate_signal() {
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::future::Future;
+
+    use rocket::futures::executor::block_on;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+
+    use figment::Jail;
+
+    const TEST_CONFIG: &str = r#"
+[debug]
+secret_key = "DEBUGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGG"
+
+[[debug.users]]
+username = "user"
+password = "password"
+"#;
+
+    fn run_test<Fut>(setup_jail: Option<fn(&mut Jail)>, test: impl FnOnce(Client) -> Fut)
+    where
+        Fut: Future<Output = Result<(), figment::Error>>,
+    {
+        Jail::expect_with(|jail| {
+            block_on(async {
+                let book_path = jail.directory().join("mdwiki-test-dir");
+                jail.create_file("mdwiki.toml", TEST_CONFIG).unwrap();
+                jail.set_env("MDWIKI_PATH", book_path.to_str().unwrap());
+
+                if let Some(setup_jail) = setup_jail {
+                    setup_jail(jail);
+                }
+
+                let (wiki_state, webapp_state) = WikiState::new();
+
+                wiki_state.setup().await.unwrap();
+
+                let rocket = rocket(webapp_state);
+
+                let wiki = task::spawn(async { wiki_state.serve().await });
+
+                let client = Client::tracked(rocket)
+                    .await
+                    .expect("valid rocket instance");
+
+                join!(wiki, test(client)).1
+            })
+        });
+    }
+
+    #[rocket::async_test]
+    async fn bootstrap_wiki() {
+        run_test(None, async move |client: Client| {
+            assert_eq!(
+                client.get("/").dispatch().await.status(),
+                Status::PermanentRedirect
+            );
+            assert_eq!(
+                client.get("/SUMMARY.html").dispatch().await.status(),
+                Status::Ok
+            );
+
+            let response = client.get("/index.html").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            assert!(response
+                .into_string()
+                .await
+                .unwrap()
+                .contains(r#"// mdwiki theme override script to add "edit" and "new" buttons"#));
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn page_context() {
+        run_test(None, async move |client: Client| {
+            let response = client
+                .get("/api/v1/context?path=index.md")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            let context = response.into_string().await.unwrap();
+            assert!(context.contains(r#""user":null"#));
+            assert!(context.contains(r#""edit":false"#));
+            assert!(context.contains(r#""path":"README.md","exists":true"#));
+
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let response = client
+                .get("/api/v1/context?path=missing.md")
+                .dispatch()
+                .await;
+            let context = response.into_string().await.unwrap();
+            assert!(context.contains(r#""username":"user""#));
+            assert!(context.contains(r#""edit":true,"create":true"#));
+            assert!(context.contains(r#""exists":false"#));
+
+            let response = client
+                .get("/api/v1/context?path=../mdwiki.toml")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn canonical_urls() {
+        run_test(None, async move |client: Client| {
+            let redirects = [
+                ("/index", "/index.html"),
+                ("/index?search=x", "/index.html?search=x"),
+                ("/README.md", "/index.html"),
+                ("/index.html/", "/index.html"),
+                ("/css", "/css/index.html"),
+                ("/css/", "/css/index.html"),
+            ];
+            for (from, to) in redirects.iter() {
+                let response = client.get(*from).dispatch().await;
+                assert_eq!(response.status(), Status::PermanentRedirect, "{}", from);
+                assert_eq!(response.headers().get_one("location"), Some(*to), "{}", from);
+            }
+            let response = client.get("/missing").dispatch().await;
+            assert_eq!(response.status(), Status::NotFound);
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn home_page() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_HOME_PAGE", "team/start.md");
+            }),
+            async move |client: Client| {
+                let response = client.get("/").dispatch().await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(response.headers().get_one("location"), Some("/team/start.html"));
+                assert_eq!(
+                    client.get("/team/start.html").dispatch().await.status(),
+                    Status::Ok
+                );
+
+                let summary = std::fs::read_to_string(
+                    std::env::current_dir()
+                        .unwrap()
+                        .join("mdwiki-test-dir/src/SUMMARY.md"),
+                )
+                .unwrap();
+                assert!(summary.contains("[Home](team/start.md)"));
+                assert!(!summary.contains("- [start]"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn sitemap() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_PUBLIC_URL", "https://wiki.example.com/");
+                jail.set_env("MDWIKI_SITEMAP_EXCLUDE", r#"["drafts"]"#);
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                for file in &["runbooks/deploy.md", "drafts/plan.md"] {
+                    client
+                        .post("/new")
+                        .header(ContentType::Form)
+                        .body(format!("file={}&content=content", file))
+                        .dispatch()
+                        .await;
+                }
+
+                let response = client.get("/sitemap.xml").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+                let sitemap = response.into_string().await.unwrap();
+                for page in &["index.html", "runbooks/index.html", "runbooks/deploy.html"] {
+                    let loc = format!("<loc>https://wiki.example.com/{}</loc>", page);
+                    assert!(sitemap.contains(&loc), "{}", page);
+                }
+                assert!(!sitemap.contains("drafts"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn footer() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_FOOTER", "true");
+            }),
+            async move |client: Client| {
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let repo = git2::Repository::open(&book).unwrap();
+                let head = repo.head().unwrap().peel_to_commit().unwrap();
+                let head = head.as_object().short_id().unwrap();
+
+                let page = client
+                    .get("/index.html")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(page.contains(r#"<footer class="mdwiki-footer""#));
+                assert!(page.contains(&format!("mdwiki {}", env!("CARGO_PKG_VERSION"))));
+                assert!(page.contains(head.as_str().unwrap()));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn attribution() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_ATTRIBUTION", "true");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/edit/README.md")
+                    .header(ContentType::Form)
+                    .body("content=%23%20Home")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=attributed.md&content=%23%20Attributed")
+                    .dispatch()
+                    .await;
+
+                // the setup commit is by mdwiki
+                let home = client
+                    .get("/index.html")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(home.contains(r#"<p class="mdwiki-attribution""#));
+                assert!(home.contains("Last edited by user on "));
+                assert!(home.contains("2 contributors"));
+                let page = client
+                    .get("/attributed.html")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(page.contains("Last edited by user on "));
+                assert!(page.contains("1 contributor<"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn contributor_stats() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=stats.md&content=%23%20Stats%0A%0AFirst")
+                .dispatch()
+                .await;
+            client
+                .post("/edit/stats.md")
+                .header(ContentType::Form)
+                .body("content=%23%20Stats%0A%0AFirst%0ASecond")
+                .dispatch()
+                .await;
+
+            let stats = client
+                .get("/stats/contributors")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(stats.contains(r#"<a href="/stats/contributors/user">user</a>"#));
+            // the setup commits
+            assert!(stats.contains(r#"<a href="/stats/contributors/mdwiki">mdwiki</a>"#));
+
+            let user = client
+                .get("/stats/contributors/user")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(user.contains("2 commits"));
+            assert!(user.contains("1 page created"));
+            assert!(user.contains(r#"<a href="/stats.html">stats.md</a>"#));
+            let response = client.get("/stats/contributors/nobody").dispatch().await;
+            assert_eq!(response.status(), Status::NotFound);
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn blame() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=blamed.md&content=%23%20Blamed%0AFirst")
+                .dispatch()
+                .await;
+            client
+                .post("/edit/blamed.md")
+                .header(ContentType::Form)
+                .body("content=%23%20Blamed%0AFirst%0ASecond")
+                .dispatch()
+                .await;
+
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            let repo = git2::Repository::open(&book).unwrap();
+            let edit = repo.head().unwrap().peel_to_commit().unwrap();
+            let create = edit.parent(0).unwrap();
+            let blame = client
+                .get("/blame/blamed.md")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(blame.contains(&format!(r#"href="/diff/{}""#, create.id())));
+            assert!(blame.contains(&format!(r#"href="/diff/{}""#, edit.id())));
+            assert!(blame.contains("<pre>Second</pre>"));
+            let response = client.get("/blame/missing.md").dispatch().await;
+            assert_eq!(response.status(), Status::NotFound);
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn curated_summary() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            for page in &["a", "b", "c"] {
+                let response = client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body(format!("file=guides/{0}.md&content=%23%20{0}", page))
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::SeeOther);
+            }
+            let response = client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=guides/_summary.md&content=-%20[Start%20here](b.md)%0A")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::SeeOther);
+            client
+                .post("/edit/guides/_summary.md")
+                .header(ContentType::Form)
+                .body("content=-%20[Start%20here](b.md)%0A-%20[Then](a.md)%0A")
+                .dispatch()
+                .await;
+
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            let summary = std::fs::read_to_string(book.join("src/SUMMARY.md")).unwrap();
+            // pages the fragment doesn't list come after the ones it does
+            let guides = "- [guides](guides/README.md)\n  \
+                          - [Start here](guides/b.md)\n  \
+                          - [Then](guides/a.md)\n  \
+                          - [c](guides/c.md)\n";
+            assert!(summary.contains(guides), "{}", summary);
+            assert!(!summary.contains("_summary"));
+            let response = client.get("/guides/c.html").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn restore_deleted_page() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=gone.md&content=%23%20Gone%0A%0AStill%20needed")
+                .dispatch()
+                .await;
+
+            // deleted by a push, without mdwiki
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            std::fs::remove_file(book.join("src/gone.md")).unwrap();
+            let summary = std::fs::read_to_string(book.join("src/SUMMARY.md")).unwrap();
+            let summary = summary.replace("- [gone](gone.md)\n", "");
+            std::fs::write(book.join("src/SUMMARY.md"), summary).unwrap();
+            let repo = git2::Repository::open(&book).unwrap();
+            let mut index = repo.index().unwrap();
+            index.update_all(["*"].iter(), None).unwrap();
+            index.write().unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let parent = repo.head().unwrap().peel_to_commit().unwrap();
+            let sig = git2::Signature::now("pusher", "pusher@example.com").unwrap();
+            let deleted = repo
+                .commit(Some("HEAD"), &sig, &sig, "Clean up", &tree, &[&parent])
+                .unwrap();
+
+            let list = client
+                .get("/deleted")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            let restore = format!("/deleted/restore/{}/gone.md", deleted);
+            assert!(list.contains(&restore));
+            assert!(list.contains("by pusher"));
+
+            let response = client.post(&restore).dispatch().await;
+            assert_eq!(response.status(), Status::SeeOther);
+            assert_eq!(response.headers().get_one("location"), Some("/gone.html"));
+            let page = std::fs::read_to_string(book.join("src/gone.md")).unwrap();
+            assert_eq!(page, "# Gone\n\nStill needed");
+            let response = client.get("/gone.html").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            let list = client
+                .get("/deleted")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(!list.contains("gone.md"));
+            // it can't be restored twice
+            let response = client.post(&restore).dispatch().await;
+            assert_eq!(response.headers().get_one("location"), Some("/deleted"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn robots() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_PUBLIC_URL", "https://wiki.example.com");
+                jail.set_env("MDWIKI_CRAWLERS", r#"{disallow=["/drafts/"]}"#);
+            }),
+            async move |client: Client| {
+                let robots = client
+                    .get("/robots.txt")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(robots.contains("Disallow: /edit/\n"));
+                assert!(robots.contains("Disallow: /drafts/\n"));
+                assert!(robots.contains("Sitemap: https://wiki.example.com/sitemap.xml"));
+
+                let response = client.get("/index.html").dispatch().await;
+                assert!(response.headers().get_one("X-Robots-Tag").is_none());
+                assert!(!response.into_string().await.unwrap().contains("noindex"));
+
+                Ok(())
+            },
+        );
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_CRAWLERS", "{allow=false}");
+            }),
+            async move |client: Client| {
+                let robots = client.get("/robots.txt").dispatch().await;
+                assert_eq!(robots.into_string().await.unwrap(), "User-agent: *\nDisallow: /\n");
+
+                let response = client.get("/index.html").dispatch().await;
+                assert_eq!(response.headers().get_one("X-Robots-Tag"), Some("noindex, nofollow"));
+                assert!(response
+                    .into_string()
+                    .await
+                    .unwrap()
+                    .contains(r#"<meta name="robots" content="noindex, nofollow">"#));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn range_requests() {
+        run_test(None, async move |client: Client| {
+            let response = client
+                .get("/index.html")
+                .header(Header::new("Range", "bytes=0-9"))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::PartialContent);
+            assert_eq!(response.headers().get_one("Accept-Ranges"), Some("bytes"));
+            assert_eq!(response.into_bytes().await.unwrap().len(), 10);
+
+            let response = client
+                .get("/index.html")
+                .header(Header::new("Range", "bytes=100000000-"))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::RangeNotSatisfiable);
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn cache_headers() {
+        run_test(None, async move |client: Client| {
+            let response = client.get("/index.html").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(
+                response.headers().get_one("Cache-Control"),
+                Some("public, no-cache")
+            );
+            let etag = response.headers().get_one("ETag").unwrap().to_string();
+
+            let response = client
+                .get("/index.html")
+                .header(Header::new("If-None-Match", etag))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::NotModified);
+
+            let response = client
+                .get("/index.html")
+                .header(Header::new("If-None-Match", "\"outdated\""))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn page_cache() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let page = || async {
+                client
+                    .get("/cached.html")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap()
+            };
+            let shared = &client.rocket().state::<WebappState>().unwrap().shared;
+            let cached = std::path::Path::new("cached.html");
+
+            client
+                .put("/api/v1/pages/cached.md")
+                .body("FIRST VERSION")
+                .dispatch()
+                .await;
+            assert!(shared.page_cache.get(cached).is_none());
+            assert!(page().await.contains("FIRST VERSION"));
+            assert!(shared.page_cache.get(cached).is_some());
+
+            // Served from memory, without reading the built page again
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            std::fs::write(book.join("book/cached.html"), "CHANGED ON DISK").unwrap();
+            assert!(page().await.contains("FIRST VERSION"));
+
+            client
+                .put("/api/v1/pages/cached.md")
+                .body("SECOND VERSION")
+                .dispatch()
+                .await;
+            assert!(shared.page_cache.get(cached).is_none());
+            assert!(page().await.contains("SECOND VERSION"));
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn security_headers() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let headers = r#"
+[debug.security_headers]
+X-Frame-Options = "DENY"
+Referrer-Policy = ""
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, headers))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                let response = client.get("/index.html").dispatch().await;
+                let headers = response.headers();
+                assert_eq!(headers.get_one("X-Frame-Options"), Some("DENY"));
+                assert_eq!(headers.get_one("X-Content-Type-Options"), Some("nosniff"));
+                assert!(headers.get_one("Referrer-Policy").is_none());
+                assert!(headers
+                    .get_one("Content-Security-Policy")
+                    .unwrap()
+                    .contains("frame-ancestors 'self'"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn branding() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_BRANDING", "{title=\"Team wiki\",accent_color=\"#336699\"}");
+            }),
+            async move |client: Client| {
+                let login = client
+                    .get("/login")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(login.contains("<title>Login - Team wiki</title>"));
+                assert!(login.contains("--links: #336699;"));
+
+                let book_toml = std::fs::read_to_string(
+                    std::env::current_dir()
+                        .unwrap()
+                        .join("mdwiki-test-dir/book.toml"),
+                )
+                .unwrap();
+                assert!(book_toml.contains(r#"title = "Team wiki""#));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn custom_templates() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                std::fs::create_dir(jail.directory().join("templates")).unwrap();
+                jail.create_file("templates/login.html.tera", "custom login page")
+                    .unwrap();
+                jail.set_env("MDWIKI_TEMPLATES_DIR", "templates");
+            }),
+            async move |client: Client| {
+                let login = client.get("/login").dispatch().await;
+                assert_eq!(login.into_string().await.unwrap(), "custom login page");
+
+                // Templates that aren't overridden fall back to the built-in ones
+                let response = client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::SeeOther);
+                let new_page = client.get("/new").dispatch().await;
+                assert_eq!(new_page.status(), Status::Ok);
+                assert!(new_page.into_string().await.unwrap().contains("<form"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn login() {
+        run_test(None, async move |client: Client| {
+            let response = client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+
+            assert_eq!(response.status(), Status::SeeOther);
+            assert_eq!(response.headers().get_one("location"), Some("/"));
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn login_lockout() {
+        run_test(None, async move |client: Client| {
+            for _ in 0..5 {
+                let response = client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=wrong")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::SeeOther);
+            }
+
+            let response = client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::TooManyRequests);
+            assert!(response.headers().get_one("Retry-After").is_some());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn json_log_format() {
+        use serde_json::{json, Value};
+
+        Jail::expect_with(|jail| {
+            jail.set_env("MDWIKI_LOG_FORMAT", "json");
+            let format: LogFormat = Config::figment().extract_inner("log_format").unwrap();
+            assert_eq!(format, LogFormat::Json);
+            Ok(())
+        });
+
+        let line = |target: &str, level: log::Level, message: &str| {
+            let line = audit::json_line(
+                &log::Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(level)
+                    .target(target)
+                    .build(),
+                "1600000000000".into(),
+            );
+            // each line has to be valid JSON on its own
+            serde_json::from_str::<Value>(&line.to_string()).unwrap()
+        };
+
+        let message = "could not \"build\"\nthe book";
+        assert_eq!(
+            line("mdwiki::wiki", log::Level::Warn, message),
+            json!({
+                "message": message,
+                "time": "1600000000000",
+                "level": "WARN",
+                "target": "mdwiki::wiki",
+            })
+        );
+
+        // audit records are inlined, rather than nested in the message
+        let mutation = audit::Mutation::new(
+            "user",
+            "edit",
+            Some("page.md".into()),
+            Ok(Some("abc123".into())),
+        );
+        let audit = serde_json::to_string(&mutation).unwrap();
+        assert_eq!(
+            line("mdwiki::audit", log::Level::Info, &audit),
+            json!({
+                "user": "user",
+                "action": "edit",
+                "path": "page.md",
+                "status": 200,
+                "commit": "abc123",
+                "time": "1600000000000",
+                "level": "INFO",
+                "target": "mdwiki::audit",
+            })
+        );
+    }
+
+    #[test]
+    fn login_limiter_bounded() {
+        let limiter = ratelimit::LoginLimiter::default();
+        let limits = config::LoginLimits::default();
+        for _ in 0..limits.max_failures_per_user {
+            limiter.record_failure(None, "admin", &limits);
+        }
+        assert!(limiter.check(None, "admin").is_some());
+
+        for i in 0..12_000 {
+            let ip = format!("2001:db8::{:x}", i);
+            limiter.record_failure(Some(&ip), &format!("user{}", i), &limits);
+        }
+        assert!(limiter.tracked() <= 10_002);
+        assert!(limiter.check(None, "admin").is_some());
+    }
+
+    #[rocket::async_test]
+    async fn password_reset() {
+        run_test(None, async move |client: Client| {
+            let response = client
+                .post("/login/forgot")
+                .header(ContentType::Form)
+                .body("username=user")
+                .dispatch()
+                .await;
+            assert_eq!(response.headers().get_one("location"), Some("/login/forgot"));
+
+            let response = client
+                .post("/login/reset")
+                .header(ContentType::Form)
+                .body("token=invalid&password=new&confirm=new")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::SeeOther);
+            assert_eq!(response.headers().get_one("location"), Some("/login/forgot"));
+
+            let tokens = reset::ResetTokens::load(
+                std::env::current_dir().unwrap().join(".mdwiki/reset_key"),
+            );
+            let mut user = config::User {
+                username: "reset".into(),
+                password: "old".into(),
+                roles: Vec::new(),
+                disabled: false,
+                email: Some("reset@example.com".into()),
+                email_verified: false,
+                display_name: None,
+                avatar: None,
+            };
+            let token = tokens.create(&user).unwrap();
+            assert!(tokens.create(&user).is_none());
+            assert!(tokens.verify(&token, |_| Some(user.clone())).is_some());
+
+            user.password = "new".into();
+            assert!(tokens.verify(&token, |_| Some(user.clone())).is_none());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn oidc_users() {
+        use serde_json::{json, Value};
+
+        let provider = |link_by_email| {
+            let config = json!({
+                "issuer_url": "https://idp.example.com",
+                "client_id": "mdwiki",
+                "client_secret": "secret",
+                "redirect_url": "http://localhost:8000/login/oidc/callback",
+                "auto_provision": true,
+                "link_by_email": link_by_email,
+            });
+            auth::OidcProvider::new(serde_json::from_value(config).unwrap())
+        };
+        let claims = |claims: Value| serde_json::from_value(claims).unwrap();
+        let user = |username: &str, password: &str, email: &str| config::User {
+            username: username.into(),
+            password: password.into(),
+            roles: Vec::new(),
+            disabled: false,
+            email: Some(email.into()),
+            email_verified: false,
+            display_name: None,
+            avatar: None,
+        };
+        let users = [
+            config::User {
+                roles: vec!["admin".into()],
+                ..user("admin", "password", "admin@example.com")
+            },
+            user("a1b2", "", "sso@example.com"),
+        ];
+
+        // users can change their preferred_username, and mustn't get a local user by it
+        let spoofed = claims(json!({"sub": "c3d4", "preferred_username": "admin"}));
+        let spoofed = provider(false)
+            .user_from_claims(&spoofed, &users)
+            .unwrap()
+            .0;
+        assert_eq!(spoofed.username, "c3d4");
+        assert!(!spoofed.is_admin());
+        let local = claims(json!({"sub": "admin"}));
+        assert!(provider(false).user_from_claims(&local, &users).is_err());
+
+        let sso = claims(json!({"sub": "a1b2"}));
+        let sso = provider(false).user_from_claims(&sso, &users).unwrap().0;
+        assert_eq!(sso.email.as_deref(), Some("sso@example.com"));
+
+        let email = |verified| {
+            claims(json!({
+                "sub": "e5f6",
+                "email": "admin@example.com",
+                "email_verified": verified,
+            }))
+        };
+        let linked = provider(true)
+            .user_from_claims(&email(true), &users)
+            .unwrap()
+            .0;
+        assert!(linked.is_admin());
+        for (link_by_email, verified) in &[(true, false), (false, true)] {
+            let unlinked = provider(*link_by_email)
+                .user_from_claims(&email(*verified), &users)
+                .unwrap()
+                .0;
+            assert_eq!(unlinked.username, "e5f6");
+            assert!(!unlinked.is_admin());
+        }
+    }
+
+    #[test]
+    fn oidc_roles() {
+        use serde_json::{json, Value};
+
+        let config = json!({
+            "issuer_url": "https://idp.example.com",
+            "client_id": "mdwiki",
+            "client_secret": "secret",
+            "redirect_url": "http://localhost:8000/login/oidc/callback",
+            "auto_provision": true,
+            "roles_claim": "realm_access.roles",
+            "role_mapping": {"wiki-admins": "admin", "wiki-editors": "editor"},
+        });
+        let provider = auth::OidcProvider::new(serde_json::from_value(config).unwrap());
+        let claims = |roles: Value| {
+            let claims = json!({"sub": "a1b2", "realm_access": {"roles": roles}});
+            serde_json::from_value(claims).unwrap()
+        };
+
+        let (user, roles) = provider
+            .user_from_claims(&claims(json!(["wiki-admins", "other"])), &[])
+            .unwrap();
+        assert_eq!(roles, ["admin"]);
+        let (_, roles) = provider
+            .user_from_claims(&claims(json!("wiki-editors")), &[])
+            .unwrap();
+        assert_eq!(roles, ["editor"]);
+
+        // the user is saved without the roles from the claims, so they're gone once the
+        // identity provider takes them away
+        assert!(user.roles.is_empty());
+        let users = [config::User {
+            roles: vec!["reviewer".into()],
+            ..user
+        }];
+        let (user, roles) = provider
+            .user_from_claims(&claims(json!([])), &users)
+            .unwrap();
+        assert!(roles.is_empty());
+        assert_eq!(user.roles, ["reviewer"]);
+
+        Jail::expect_with(|jail| {
+            let path = jail.directory().join("sessions.json");
+            let sessions = session::SessionStore::load(path.clone());
+            let token = sessions.create("a1b2", 60, false, vec!["admin".into()]);
+            assert_eq!(sessions.get(&token).unwrap().roles, ["admin"]);
+            let reloaded = session::SessionStore::load(path);
+            assert!(reloaded.get(&token).unwrap().roles.is_empty());
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn avatars() {
+        run_test(None, async move |client: Client| {
+            let response = client.get("/avatars/user").dispatch().await;
+            assert_eq!(response.status(), Status::SeeOther);
+            assert!(response
+                .headers()
+                .get_one("location")
+                .unwrap()
+                .starts_with("https://www.gravatar.com/avatar/"));
+
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let response = client
+                .post("/profile/avatar")
+                .header(ContentType::SVG)
+                .body("<svg></svg>")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::UnsupportedMediaType);
+            let response = client
+                .post("/profile/avatar")
+                .header(ContentType::PNG)
+                .body(&b"\x89PNG\r\n\x1a\n"[..])
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::NoContent);
+
+            let response = client.get("/avatars/user").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.content_type(), Some(ContentType::PNG));
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn logout_all_sessions() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password&remember=on")
+                .dispatch()
+                .await;
+            assert_eq!(client.get("/new").dispatch().await.status(), Status::Ok);
+
+            let response = client.post("/logout/all").dispatch().await;
+            assert_eq!(response.status(), Status::SeeOther);
+
+            assert_eq!(
+                client.get("/new").dispatch().await.status(),
+                Status::NotFound
+            );
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn new_page() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+
+            let response = client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=newfile.md&content=NEWPAGE")
+                .dispatch()
+                .await;
+
+            assert_eq!(response.status(), Status::SeeOther);
+            assert_eq!(
+                response.headers().get_one("location"),
+                Some("/newfile.html")
+            );
+
+            let response = client.get("/newfile.html").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            assert!(response.into_string().await.unwrap().contains("NEWPAGE"));
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn new_page_with_dirs() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+
+            let response = client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=newdir/newfile.md&content=NEWPAGE")
+                .dispatch()
+                .await;
+
+            assert_eq!(response.status(), Status::SeeOther);
+            assert_eq!(
+                response.headers().get_one("location"),
+                Some("/newdir/newfile.html")
+            );
+
+            assert_eq!(
+                client.get("/newdir/").dispatch().await.status(),
+                Status::PermanentRedirect
+            );
+            assert_eq!(
+                client.get("/newdir/index.html").dispatch().await.status(),
+                Status::Ok
+            );
+            assert_eq!(
+                client.get("/newdir/newfile.html").dispatch().await.status(),
+                Status::Ok
+            );
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn api_put_page() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_MAX_PAGE_SIZE", "16");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+
+                let response = client
+                    .put("/api/v1/pages/apifile.md")
+                    .body("APIPAGE")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Created);
+
+                let response = client
+                    .put("/api/v1/pages/apifile.md")
+                    .body("APIPAGE, BUT WAY TOO LONG")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::PayloadTooLarge);
+
+                let response = client.get("/apifile.html").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+                assert!(response.into_string().await.unwrap().contains("APIPAGE"));
+
+                let response = client
+                    .put("/api/v1/pages/retried.md")
+                    .header(Header::new("Idempotency-Key", "abc"))
+                    .body("RETRIED")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Created);
+
+                let response = client
+                    .put("/api/v1/pages/retried.md")
+                    .header(Header::new("Idempotency-Key", "abc"))
+                    .body("RETRIED")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Created);
+                assert_eq!(
+                    response.headers().get_one("Idempotent-Replayed"),
+                    Some("true")
+                );
+
+                let response = client
+                    .put("/api/v1/pages/retried.md")
+                    .header(Header::new("Idempotency-Key", "abc"))
+                    .body("OTHER")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::UnprocessableEntity);
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn move_page_dry_run() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=target.md&content=TARGET")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=linking.md&content=[link](target.md)")
+                .dispatch()
+                .await;
+
+            let response = client
+                .post("/api/v1/move?dry_run=true")
+                .header(ContentType::JSON)
+                .body(r#"{"from": "target.md", "to": "moved/target.md"}"#)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            let plan = response.into_string().await.unwrap();
+            assert!(plan.contains("+[link](moved/target.md)"));
+
+            assert_eq!(
+                client.get("/target.html").dispatch().await.status(),
+                Status::Ok
+            );
+
+            let response = client
+                .post("/api/v1/move")
+                .header(ContentType::JSON)
+                .body(r#"{"from": "target.md", "to": "moved/target.md"}"#)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(
+                client.get("/moved/target.html").dispatch().await.status(),
+                Status::Ok
+            );
+
+            let events = client
+                .get("/api/v1/events?since=0&wait=0")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(events.contains(r#""type":"user_logged_in""#));
+            assert!(events.contains(r#""type":"page_created""#));
+            assert!(events.contains(r#""type":"page_moved""#));
+            assert!(events.contains(r#""type":"sections_reorganized","sections":[""]"#));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn embed_page() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_EMBED_FRAME_ANCESTORS", "[https://portal.example.com]");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=dir/embedded.md&content=%23+Embedded%0A[other](other.md)")
+                    .dispatch()
+                    .await;
+
+                let response = client.get("/embed/dir/embedded.html").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+                assert!(response
+                    .headers()
+                    .get_one("Content-Security-Policy")
+                    .unwrap()
+                    .ends_with("; frame-ancestors 'self' https://portal.example.com"));
+                assert!(response.headers().get_one("X-Frame-Options").is_none());
+                let body = response.into_string().await.unwrap();
+                assert!(body.contains("<title>Embedded</title>"));
+                assert!(body.contains(r#"href="other.html""#));
+
+                assert_eq!(
+                    client.get("/embed/missing.md").dispatch().await.status(),
+                    Status::NotFound
+                );
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn edit_page() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+
+            let response = client
+                .post("/edit/README.md")
+                .header(ContentType::Form)
+                .body("content=EDITEDCONTENT")
+                .dispatch()
+                .await;
+
+            assert_eq!(response.status(), Status::SeeOther);
+            assert_eq!(response.headers().get_one("location"), Some("/"));
+
+            let response = client.get("/index.html").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            assert!(response
+                .into_string()
+                .await
+                .unwrap()
+                .contains("EDITEDCONTENT"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn acl_with_groups() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let acl = r#"
+[debug.groups]
+team = ["user"]
+leads = ["someone-else"]
+
+[[debug.acl]]
+path = "team"
+editors = ["@team"]
+
+[[debug.acl]]
+path = "team/decisions"
+editors = ["@leads"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, acl))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+
+                let response = client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=team/notes.md&content=notes")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::SeeOther);
+
+                let response = client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=team/decisions/one.md&content=decided")
+                    .dispatch()
+                    .await;
+                assert!(response
+                    .into_string()
+                    .await
+                    .unwrap()
+                    .contains("not allowed to change"));
+
+                let context = |path: &'static str| {
+                    client
+                        .get(format!("/api/v1/context?path={}", path))
+                        .dispatch()
+                };
+                let allowed = context("team/notes.md").await.into_string().await.unwrap();
+                assert!(allowed.contains(r#""edit":true,"create":true"#));
+                let denied = context("team/decisions/one.md").await;
+                let denied = denied.into_string().await.unwrap();
+                assert!(denied.contains(r#""edit":false,"create":false"#));
+
+                let form = client.get("/new?dir=team").dispatch().await;
+                assert!(form.into_string().await.unwrap().contains(r#"value="team&#x2F;""#));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn group_pages() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let pages = r#"
+[debug.groups]
+team = ["user"]
+
+[debug.group_pages.team]
+home = "team/README.md"
+sidebar = ["team", "onboarding.md"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, pages))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                let response = client.get("/").dispatch().await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(response.headers().get_one("location"), Some("/index.html"));
+
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+
+                let response = client.get("/").dispatch().await;
+                assert_eq!(
+                    response.headers().get_one("location"),
+                    Some("/team/index.html")
+                );
+
+                let response = client.get("/api/v1/context?path=index.md").dispatch().await;
+                assert!(response
+                    .into_string()
+                    .await
+                    .unwrap()
+                    .contains(r#""sidebar":["team/","onboarding.html"]"#));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn watch_pages() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let watcher = r#"
+[debug.smtp]
+host = "localhost"
+port = 2525
+security = "none"
+from = "wiki@example.com"
+
+[[debug.users]]
+username = "watcher"
+password = "password"
+email = "watcher@example.com"
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, watcher))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=watcher&password=password")
+                    .dispatch()
+                    .await;
+                let response = client
+                    .put("/api/v1/pages/watched.md")
+                    .body("WATCHED PAGE")
+                    .dispatch()
+                    .await;
+                assert!(response.status().class().is_success());
+
+                let response = client.post("/watch/watched.md").dispatch().await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(response.headers().get_one("location"), Some("/watched.html"));
+                let context = client
+                    .get("/api/v1/context?path=watched.md")
+                    .dispatch()
+                    .await;
+                let context = context.into_string().await.unwrap();
+                assert!(context.contains(r#""watch":true"#));
+                assert!(context.contains(r#""watched":true"#));
+
+                let repo = git2::Repository::open(
+                    std::env::current_dir().unwrap().join("mdwiki-test-dir"),
+                )
+                .unwrap();
+                let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+                let diff = client.get(format!("/diff/{}", head)).dispatch().await;
+                assert!(diff.into_string().await.unwrap().contains("+WATCHED PAGE"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn event_sinks() {
+        use events::{Event, EventKind};
+        use rocket::tokio::io::{AsyncBufReadExt, BufReader};
+        use rocket::tokio::net::TcpListener;
+        use rocket::tokio::sync::broadcast;
+        use serde_json::json;
+        use sinks::EventSink;
+
+        let event = Event {
+            id: 7,
+            time: 1600000000,
+            kind: EventKind::PageMoved {
+                from: "old.md".into(),
+                to: "new.md".into(),
+                user: "user".into(),
+            },
+        };
+        let expected = json!({
+            "id": 7,
+            "time": 1600000000,
+            "type": "page_moved",
+            "from": "old.md",
+            "to": "new.md",
+            "user": "user",
+        });
+        assert_eq!(serde_json::to_value(&event).unwrap(), expected);
+
+        Jail::expect_with(|jail| {
+            let sinks = r#"
+[[default.event_sinks]]
+kind = "nats"
+url = "nats://localhost:4222"
+"#;
+            jail.create_file("mdwiki.toml", sinks)?;
+            let config: Config = Config::figment().extract()?;
+            let nats = EventSink::Nats {
+                url: "nats://localhost:4222".into(),
+                subject: "mdwiki".into(),
+            };
+            assert_eq!(config.event_sinks, [nats]);
+
+            // page changes are sent to http endpoints by `webhooks`
+            let webhook = r#"
+[[default.event_sinks]]
+kind = "webhook"
+url = "https://example.com"
+"#;
+            jail.create_file("mdwiki.toml", webhook)?;
+            assert!(Config::figment().extract::<Config>().is_err());
+            Ok(())
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink = EventSink::Nats {
+            url: format!("nats://{}", listener.local_addr().unwrap()),
+            subject: "wiki".into(),
+        };
+        let (tx, rx) = broadcast::channel(16);
+        rocket::tokio::spawn(sinks::forward(vec![sink], rx));
+        tx.send(event.clone()).unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let connect = lines.next_line().await.unwrap().unwrap();
+        assert!(connect.starts_with("CONNECT {"));
+        let payload = serde_json::to_string(&event).unwrap();
+        let publish = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(publish, format!("PUB wiki.page_moved {}", payload.len()));
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), payload);
+    }
+
+    #[rocket::async_test]
+    async fn webhooks() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let port = std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port();
+                let hook = format!(
+                    "[[debug.webhooks]]\nurl = \"http://127.0.0.1:{}/hook\"\nsecret = \"s3cret\"\n",
+                    port
+                );
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, hook))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+                use rocket::tokio::net::TcpListener;
+
+                let config: Config = Config::figment().extract()?;
+                let address = config.webhooks[0].url["http://".len()..].trim_end_matches("/hook");
+                let listener = TcpListener::bind(address).await.unwrap();
+
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=hooked.md&content=one%0Atwo%0A")
+                    .dispatch()
+                    .await;
+
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                let received = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let request = String::from_utf8_lossy(&request).to_string();
+                    if let Some(end) = request.find("\r\n\r\n") {
+                        let body = &request[end + 4..];
+                        if n == 0 || body.ends_with('}') {
+                            break (request[..end].to_lowercase(), body.to_string());
+                        }
+                    }
+                };
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+
+                let (headers, body) = received;
+                assert!(body.contains(r#""action":"create","path":"hooked.md""#));
+                assert!(body.contains(r#""user":"user""#));
+                assert!(body.contains(r#""diff":{"files_changed":1,"#));
+                let signature = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("x-mdwiki-signature: "))
+                    .unwrap();
+                let body = body.as_bytes();
+                assert!(verify_signature("s3cret", signature, body, SIGNATURE_TOLERANCE).is_ok());
+                assert!(verify_signature("wrong", signature, body, SIGNATURE_TOLERANCE).is_err());
+                assert!(verify_signature("s3cret", signature, b"{}", SIGNATURE_TOLERANCE).is_err());
+
+                // Deliveries signed too long ago are rejected as replays
+                let old = session::now() - SIGNATURE_TOLERANCE - 60;
+                let replayed = webhooks::signature("s3cret", old, body);
+                let replayed = format!("t={},sha256={}", old, replayed);
+                assert!(verify_signature("s3cret", &replayed, body, SIGNATURE_TOLERANCE).is_err());
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn chat_notifications() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let port = std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port();
+                let chats = format!(
+                    r#"
+[[debug.chat_notifications]]
+kind = "slack"
+webhook_url = "http://127.0.0.1:{0}/slack"
+
+[[debug.chat_notifications]]
+kind = "discord"
+webhook_url = "http://127.0.0.1:{0}/discord"
+"#,
+                    port
+                );
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, chats))
+                    .unwrap();
+                jail.set_env("MDWIKI_PUBLIC_URL", "https://wiki.example.com/");
+            }),
+            async move |client: Client| {
+                use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+                use rocket::tokio::net::TcpListener;
+                use serde_json::{json, Value};
+
+                // Without channels or webhooks, page changes aren't sent anywhere
+                assert!(webhooks::Webhooks::new(&Config::default()).is_empty());
+                let unknown = json!({ "kind": "teams", "webhook_url": "http://127.0.0.1" });
+                assert!(serde_json::from_value::<chat::ChatChannel>(unknown).is_err());
+
+                let config: Config = Config::figment().extract()?;
+                assert!(!webhooks::Webhooks::new(&config).is_empty());
+                let url = match &config.chat_notifications[0] {
+                    chat::ChatChannel::Slack { webhook_url } => webhook_url.clone(),
+                    channel => panic!("unexpected channel {:?}", channel),
+                };
+                let address = url["http://".len()..].trim_end_matches("/slack");
+                let listener = TcpListener::bind(address).await.unwrap();
+
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=chat.md&content=chatty")
+                    .dispatch()
+                    .await;
+
+                let mut received = Vec::new();
+                while received.len() < 2 {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut request = Vec::new();
+                    let mut buf = [0; 4096];
+                    let (head, body) = loop {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                        let end = match request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            Some(end) => end,
+                            None => continue,
+                        };
+                        let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .map_or(0, |length| length.trim().parse().unwrap());
+                        if n == 0 || request.len() >= end + 4 + length {
+                            break (head, request[end + 4..].to_vec());
+                        }
+                    };
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await
+                        .unwrap();
+                    let body: Value = serde_json::from_slice(&body).unwrap();
+                    received.push((head.lines().next().unwrap().to_string(), body));
+                }
+                received.sort_by(|a, b| a.0.cmp(&b.0));
+
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let repo = git2::Repository::open(&book).unwrap();
+                let commit = repo.head().unwrap().target().unwrap();
+                let page_url = "https://wiki.example.com/chat.html";
+                let diff_url = format!("https://wiki.example.com/diff/{}", commit);
+                assert_eq!(
+                    received,
+                    vec![
+                        (
+                            "post /discord http/1.1".to_string(),
+                            json!({
+                                "content": format!(
+                                    "**user** created [chat.md]({}) ([changes]({}))",
+                                    page_url, diff_url
+                                )
+                            })
+                        ),
+                        (
+                            "post /slack http/1.1".to_string(),
+                            json!({
+                                "text": format!(
+                                    "*user* created <{}|chat.md> (<{}|changes>)",
+                                    page_url, diff_url
+                                )
+                            })
+                        ),
+                    ]
+                );
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn admin_users() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let admin = r#"
+[[debug.users]]
+username = "admin"
+password = "password"
+roles = ["admin"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, admin))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                let login = |username: &str, password: &str| {
+                    client
+                        .post("/login")
+                        .header(ContentType::Form)
+                        .body(format!("username={}&password={}", username, password))
+                        .dispatch()
+                };
+                let form = |url: &str, body: &str| {
+                    client
+                        .post(url.to_string())
+                        .header(ContentType::Form)
+                        .body(body.to_string())
+                        .dispatch()
+                };
+                let users = || async {
+                    client
+                        .get("/admin/users")
+                        .dispatch()
+                        .await
+                        .into_string()
+                        .await
+                        .unwrap()
+                };
+
+                login("user", "password").await;
+                let response = client.get("/admin/users").dispatch().await;
+                assert_eq!(response.status(), Status::Forbidden);
+                let response = form("/admin/users", "username=x&password=x&email=&roles=").await;
+                assert_eq!(response.status(), Status::Forbidden);
+                let response = form("/admin/users/user/delete", "").await;
+                assert_eq!(response.status(), Status::Forbidden);
+
+                login("admin", "password").await;
+                let listing = users().await;
+                assert!(listing.contains("configured in mdwiki.toml"));
+                assert!(!listing.contains("writer"));
+
+                let response = form(
+                    "/admin/users",
+                    "username=writer&password=secret&email=writer%40example.com\
+                     &roles=editor, reviewer",
+                )
+                .await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(response.headers().get_one("location"), Some("/admin/users"));
+                let listing = users().await;
+                assert!(listing.contains("Created user &#x27;writer&#x27;"));
+                assert!(listing.contains("writer@example.com"));
+                assert!(listing.contains("editor, reviewer"));
+                assert!(listing.contains("/admin/users/writer/password"));
+
+                form("/admin/users", "username=writer&password=x&email=&roles=").await;
+                let listing = users().await;
+                assert!(listing.contains("User &#x27;writer&#x27; already exists"));
+                form("/admin/users", "username=a/b&password=x&email=&roles=").await;
+                let listing = users().await;
+                assert!(listing.contains("Invalid username"));
+                form("/admin/users/user/password", "password=other").await;
+                let listing = users().await;
+                assert!(listing.contains("not a managed user"));
+
+                form("/admin/users/writer/password", "password=changed").await;
+                let listing = users().await;
+                assert!(listing.contains("Reset password for &#x27;writer&#x27;"));
+                let response = login("writer", "secret").await;
+                assert_eq!(response.headers().get_one("location"), Some("/login"));
+                let response = login("writer", "changed").await;
+                assert_eq!(response.headers().get_one("location"), Some("/"));
+
+                login("admin", "password").await;
+                form("/admin/users/writer/disable", "").await;
+                let listing = users().await;
+                assert!(listing.contains("Disabled &#x27;writer&#x27;"));
+                assert!(listing.contains("<s>writer</s>"));
+                let response = login("writer", "changed").await;
+                assert_eq!(response.headers().get_one("location"), Some("/login"));
+
+                login("admin", "password").await;
+                form("/admin/users/writer/disable", "").await;
+                let listing = users().await;
+                assert!(listing.contains("Enabled &#x27;writer&#x27;"));
+                form("/admin/users/admin/disable", "").await;
+                let listing = users().await;
+                assert!(listing.contains("You can&#x27;t disable yourself"));
+
+                form("/admin/users/writer/delete", "").await;
+                let listing = users().await;
+                assert!(listing.contains("Deleted &#x27;writer&#x27;"));
+                assert!(!listing.contains("writer@example.com"));
+                let response = login("writer", "changed").await;
+                assert_eq!(response.headers().get_one("location"), Some("/login"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn lint_report() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let admin = r#"
+[[debug.users]]
+username = "admin"
+password = "password"
+roles = ["admin"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, admin))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=admin&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .put("/api/v1/pages/other.md")
+                    .body("# Other\n\n## Usage\n")
+                    .dispatch()
+                    .await;
+                client
+                    .put("/api/v1/pages/messy.md")
+                    .body(
+                        "Intro\n\n![](diagram.png)\n\nTODO: write this\n\n\
+                         [usage](other.md#usage) [setup](other.md#setup) [gone](gone.md)\n",
+                    )
+                    .dispatch()
+                    .await;
+
+                let report = client
+                    .get("/admin/lint")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(report.contains("No title"));
+                assert!(report.contains("Image &#x27;diagram.png&#x27; has no alt text"));
+                assert!(report.contains("TODO marker"));
+                assert!(report.contains("missing heading &#x27;other.md#setup&#x27;"));
+                assert!(report.contains("missing page &#x27;gone.md&#x27;"));
+                assert!(!report.contains("other.md#usage"));
+                assert!(!report.contains(">other.md<"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn accessibility_audit() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let admin = r#"
+[[debug.users]]
+username = "admin"
+password = "password"
+roles = ["admin"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, admin))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=admin&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .put("/api/v1/pages/styled.md")
+                    .body(
+                        "# Styled\n\n### Details\n\n<span style=\"color: #aaa\">faint</span>\n\n\
+                         <p style='color: white; background-color: yellow'>bright</p>\n\n\
+                         <span style=\"color: black\">plain</span> <img src=\"logo.png\">\n",
+                    )
+                    .dispatch()
+                    .await;
+
+                let report = client
+                    .get("/admin/lint")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(report.contains("Heading skips from level 1 to 3"));
+                assert!(report.contains("Text in #aaa on the default white background"));
+                assert!(report.contains("contrast ratio of 2.3:1"));
+                assert!(report.contains("Text in white on yellow has a contrast ratio of 1.1:1"));
+                assert!(report.contains("Image &#x27;logo.png&#x27; has no alt text"));
+                assert!(!report.contains("Text in black"));
+                assert!(report.contains("4 of them"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn pin_build() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let admin = r#"
+[[debug.users]]
+username = "admin"
+password = "password"
+roles = ["admin"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, admin))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=admin&password=password")
+                    .dispatch()
+                    .await;
+
+                let response = client
+                    .put("/api/v1/pages/pinned.md")
+                    .body("FIRST VERSION")
+                    .dispatch()
+                    .await;
+                assert!(response.status().class().is_success());
+                let builds_dir = std::env::current_dir()
+                    .unwrap()
+                    .join("mdwiki-test-dir/.mdwiki/builds");
+                let first = std::fs::read_dir(&builds_dir)
+                    .unwrap()
+                    .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+                    .filter_map(|name| name.parse::<u64>().ok())
+                    .max()
+                    .unwrap();
+                // Finished builds are moved into place
+                assert!(!builds_dir.with_file_name("staging").exists());
+
+                let response = client
+                    .put("/api/v1/pages/pinned.md")
+                    .body("SECOND VERSION")
+                    .dispatch()
+                    .await;
+                assert!(response.status().class().is_success());
+
+                let response = client
+                    .post(format!("/admin/builds/{}/pin", first))
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::SeeOther);
+                let page = client.get("/pinned.html").dispatch().await;
+                assert!(page.into_string().await.unwrap().contains("FIRST VERSION"));
+
+                let response = client.post("/admin/builds/unpin").dispatch().await;
+                assert_eq!(response.status(), Status::SeeOther);
+                let page = client.get("/pinned.html").dispatch().await;
+                assert!(page.into_string().await.unwrap().contains("SECOND VERSION"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn probes_and_value_files() {
+        run_test(
+            Some(|jail| {
+                jail.create_file("title", "Team wiki\n").unwrap();
+                jail.set_env("MDWIKI_BRANDING__TITLE_FILE", "title");
+            }),
+            async move |client: Client| {
+                let config: Config = Config::figment().extract()?;
+                assert_eq!(config.branding.title, "Team wiki");
+                assert_eq!(config.upload_path(), config.data_path().join("uploads"));
+
+                let response = client.get("/healthz").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+                let response = client.get("/readyz").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+
+                Ok(())
+            },
+        )
+    }
+
+    #[test]
+    fn preprocessor_sandbox() {
+        let sandbox = config::PreprocessorSandbox {
+            timeout: 10,
+            memory: Some(256),
+            cpu: None,
+            user: Some("plugins".into()),
+            container: None,
+        };
+        assert_eq!(
+            sandbox::wrapper(&sandbox, "/wiki"),
+            vec![
+                "timeout",
+                "--kill-after=5",
+                "10",
+                "sudo",
+                "-n",
+                "-u",
+                "plugins",
+                "--",
+                "prlimit",
+                "--as=268435456",
+                "--",
+            ]
+        );
+
+        let sandbox = config::PreprocessorSandbox {
+            container: Some("mdbook-plugins".into()),
+            ..sandbox
+        };
+        let wrapper = sandbox::wrapper(&sandbox, "/wiki");
+        assert!(wrapper.contains(&"--network=none".to_string()));
+        assert!(wrapper.contains(&"--volume=/wiki:/wiki:ro".to_string()));
+        assert_eq!(wrapper.last().unwrap(), "mdbook-plugins");
+    }
+
+    #[rocket::async_test]
+    async fn cli_init_and_export() {
+        Jail::expect_with(|jail| {
+            jail.create_file("mdwiki.toml", TEST_CONFIG).unwrap();
+            let init = cli::Command::Init {
+                path: "mdwiki-test-dir".into(),
+            };
+            assert_eq!(block_on(cli::run(init, Plugins::new())), 0);
+
+            jail.set_env("MDWIKI_PATH", "mdwiki-test-dir");
+            let export = cli::Command::Export {
+                out: "export.zip".into(),
+                built: false,
+            };
+            assert_eq!(block_on(cli::run(export, Plugins::new())), 0);
+
+            let zip = std::fs::read(jail.directory().join("export.zip")).unwrap();
+            let zip = String::from_utf8_lossy(&zip);
+            assert!(zip.contains("src/SUMMARY.md"));
+            assert!(!zip.contains(".mdwiki/builds/"));
+
+            Ok(())
+        });
+    }
+
+    #[cfg(feature = "lua")]
+    #[test]
+    fn lua_scripts() {
+        use events::{Event, EventKind};
+        use std::path::Path;
+
+        Jail::expect_with(|jail| {
+            let hooks = r#"
+events = {}
+function validate(path, content, username)
+    if content == "sandbox" then
+        return type(dofile) .. type(loadfile) .. type(load) .. type(io) .. type(os)
+    end
+    if content == "forever" then
+        while true do end
+    end
+    if content == "memory" then
+        local chunks = {}
+        for i = 1, 100 do chunks[i] = string.rep("x", 1024 * 1024) .. i end
+    end
+    if username ~= "mdwiki" then
+        return "only mdwiki may edit " .. path
+    end
+end
+function transform(path, content)
+    return content .. "\n" .. #events .. " events"
+end
+function render(path, markdown)
+    if path == "raw.md" then return "<p>" .. markdown .. "</p>" end
+end
+function upload_name(name)
+    return name and string.upper(name)
+end
+function on_event(event)
+    events[#events + 1] = event.type .. ":" .. event.path
+end
+"#;
+            jail.create_file("hooks.lua", hooks)?;
+            let script = scripting::LuaScript::load("hooks.lua").unwrap();
+            let user = config::MDWIKI_USER.clone();
+            let page = Path::new("page.md");
+
+            assert_eq!(script.on_save(&user, page, "content"), Ok(()));
+            let other = config::User {
+                username: "other".into(),
+                ..user.clone()
+            };
+            let rejected = script.on_save(&other, page, "content");
+            assert_eq!(rejected, Err("only mdwiki may edit page.md".to_string()));
+
+            script.on_event(&Event {
+                id: 1,
+                time: 0,
+                kind: EventKind::PageEdited {
+                    path: "page.md".into(),
+                    user: "user".into(),
+                },
+            });
+            let transformed = script.transform_markdown(page, "# Page".into());
+            assert_eq!(transformed, "# Page\n1 events");
+            let rendered = script.render_markdown(Path::new("raw.md"), "text");
+            assert_eq!(rendered.as_deref(), Some("<p>text</p>"));
+            assert_eq!(script.render_markdown(page, "text"), None);
+            let name = script.upload_name(Some("photo.png"), b"");
+            assert_eq!(name.as_deref(), Some("PHOTO.PNG"));
+            assert_eq!(script.upload_name(None, b""), None);
+
+            // nothing that reads files or loads code is left, and calls that run for too long
+            // or use too much memory are stopped
+            let sandbox = script.on_save(&user, page, "sandbox");
+            assert_eq!(sandbox, Err("nilnilnilnilnil".to_string()));
+            for content in &["forever", "memory"] {
+                let stopped = script.on_save(&user, page, content);
+                assert_eq!(stopped, Err("Page validation failed".to_string()));
+            }
+            assert_eq!(script.on_save(&user, page, "content"), Ok(()));
+
+            jail.create_file("broken.lua", "dofile('hooks.lua')")?;
+            assert!(scripting::LuaScript::load("broken.lua").is_err());
+
+            Ok(())
+        });
+    }
+
+    #[cfg(feature = "stress")]
+    #[rocket::async_test]
+    async fn stress_report() {
+        Jail::expect_with(|jail| {
+            // set through the jail so they are cleared afterwards
+            for key in &["MDWIKI_CONFIG", "MDWIKI_PATH", "MDWIKI_SECRET_KEY"] {
+                jail.set_env(key, "");
+            }
+            let options = stress::StressOptions {
+                users: 2,
+                operations: 8,
+                book: fixtures::FixtureOptions {
+                    directories: 2,
+                    pages: 3,
+                    ..Default::default()
+                },
+            };
+            let report = block_on(stress::run(options)).unwrap();
+            assert_eq!(report.total(), 16);
+            assert_eq!(report.errors(), 0);
+            assert!(report.to_string().contains("p99"));
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn fixture_book() {
+        fn options() -> fixtures::FixtureOptions {
+            fixtures::FixtureOptions {
+                directories: 4,
+                pages: 8,
+                images: 2,
+                ..Default::default()
+            }
+        }
+        run_test(
+            Some(|jail: &mut Jail| {
+                let src = jail.directory().join("mdwiki-test-dir").join("src");
+                let fixture = fixtures::generate(&src, &options()).unwrap();
+                assert_eq!(fixture.pages.len(), 4 * 9);
+                assert_eq!(fixture.links, 4 * 8 * 3);
+            }),
+            async move |client: Client| {
+                let dir = std::env::current_dir().unwrap();
+                let book = dir.join("mdwiki-test-dir");
+
+                let response = client.get("/section-3/page-7.html").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+                let body = response.into_string().await.unwrap();
+                assert!(body.contains("Page 7 of section 3"));
+                let response = client.get("/images/fixture-1.png").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+
+                // the generated pages are committed with the new wiki, and have nothing to lint
+                let repo = git2::Repository::open(&book).unwrap();
+                assert_eq!(repo.statuses(None).unwrap().len(), 0);
+                let issues = lint::lint(book.to_str().unwrap())
+                    .into_iter()
+                    .filter(|issue| issue.path.starts_with("section-"))
+                    .map(|issue| format!("{}: {}", issue.path, issue.message))
+                    .collect::<Vec<_>>();
+                assert!(issues.is_empty(), "{:?}", issues);
+
+                // the same options give the same book
+                let again = dir.join("again");
+                let fixture = fixtures::generate(&again, &options()).unwrap();
+                for path in fixture.pages.iter().chain(&fixture.images) {
+                    let read = |src: &std::path::Path| std::fs::read(src.join(path)).unwrap();
+                    assert_eq!(read(&book.join("src")), read(&again));
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn configured_preprocessors() {
+        Jail::expect_with(|jail| {
+            let preprocessors = r#"
+[debug.preprocessors.toc]
+command = "cat"
+marker = "<!-- toc -->"
+"#;
+            jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, preprocessors))
+                .unwrap();
+            let book = jail.directory().join("mdwiki-test-dir");
+            jail.set_env("MDWIKI_PATH", book.to_str().unwrap());
+
+            let (wiki_state, _) = WikiState::new();
+            block_on(wiki_state.setup()).unwrap();
+            let book_toml = std::fs::read_to_string(book.join("book.toml")).unwrap();
+            assert!(book_toml.contains("[preprocessor.toc]"));
+            assert!(book_toml.contains(r#"marker = "<!-- toc -->""#));
+            let repo = git2::Repository::open(&book).unwrap();
+            let head = repo.head().unwrap().target();
+            assert_eq!(repo.statuses(None).unwrap().len(), 0);
+
+            let (wiki_state, _) = WikiState::new();
+            block_on(wiki_state.setup()).unwrap();
+            assert_eq!(repo.head().unwrap().target(), head);
+
+            jail.set_env("MDWIKI_PREPROCESSORS", "{admonish={}}");
+            let (wiki_state, _) = WikiState::new();
+            let error = block_on(wiki_state.setup()).unwrap_err();
+            assert!(error.contains("'mdbook-admonish', which is not installed"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn doctor_checks() {
+        Jail::expect_with(|jail| {
+            jail.create_file("mdwiki.toml", TEST_CONFIG).unwrap();
+            let book = jail.directory().join("mdwiki-test-dir");
+            jail.set_env("MDWIKI_PATH", book.to_str().unwrap());
+            // any free port, so the check doesn't depend on what else is running
+            jail.set_env("MDWIKI_PORT", "0");
+
+            let (wiki_state, _) = WikiState::new();
+            block_on(wiki_state.setup()).unwrap();
+            assert_eq!(doctor::run(), 0);
+
+            // uncommitted changes are recovered on startup, so they're only a warning
+            std::fs::write(book.join("src/stray.md"), "stray").unwrap();
+            assert_eq!(doctor::run(), 0);
+
+            jail.set_env("MDWIKI_PREPROCESSORS", "{admonish={}}");
+            assert_eq!(doctor::run(), 1);
+            std::env::remove_var("MDWIKI_PREPROCESSORS");
+
+            jail.set_env("MDWIKI_ALLOW_ANONYMOUS", "false");
+            jail.create_file("mdwiki.toml", "[debug]\n").unwrap();
+            assert_eq!(doctor::run(), 1);
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn concurrent_edits() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=merge.md&content=one%0A%0Atwo%0A%0Athree%0A")
+                .dispatch()
+                .await;
+
+            let base = merge::revision("one\n\ntwo\n\nthree\n");
+            let edit = |content: &str| {
+                client
+                    .post("/edit/merge.md")
+                    .header(ContentType::Form)
+                    .body(format!("base={}&content={}", base, content))
+                    .dispatch()
+            };
+
+            let response = edit("ONE%0A%0Atwo%0A%0Athree%0A").await;
+            assert_eq!(response.status(), Status::SeeOther);
+
+            // Started from the same version, but changes a different part of the page
+            let response = edit("one%0A%0Atwo%0A%0ATHREE%0A").await;
+            assert_eq!(response.status(), Status::SeeOther);
+            let page = client
+                .get("/merge.html")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(page.contains("ONE") && page.contains("THREE"));
+
+            // Only the part that conflicts is marked, the rest is merged
+            let response = edit("uno%0A%0Atwo%0A%0Athree%0A").await;
+            assert_eq!(response.status(), Status::Ok);
+            let body = response.into_string().await.unwrap();
+            assert!(body.contains("saved for review"));
+            assert!(body.contains("current version"));
+            assert!(body.contains("ONE\n=======\nuno"));
+            assert!(body.contains("THREE"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn conflicts_for_review() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            let review_dir = book.join(".mdwiki/conflicts");
+            let save = |username: &str| {
+                merge::Conflict {
+                    file: std::path::Path::new("src/page.md"),
+                    username,
+                    base: "base",
+                    current: "current",
+                    edit: "edit",
+                }
+                .save_for_review(&review_dir)
+                .unwrap()
+            };
+            let notifications = || async {
+                client
+                    .get("/api/v1/context?path=index.md")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap()
+            };
+
+            // A user whose name starts with another's doesn't show up for them
+            let saved = save("user-smith");
+            assert_eq!(merge::saved_by(&saved).as_deref(), Some("user-smith"));
+            assert!(!notifications().await.contains(r#""kind":"conflicts""#));
+
+            save("user");
+            assert!(notifications()
+                .await
+                .contains("1 of your edits could not be merged"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn section_editing() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let content = "# Runbook\n\nintro\n\n## Setup\n\nold setup\n\n### Details\n\n\
+                           details\n\n## Teardown\n\nteardown\n";
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body(format!(
+                    "file=runbook.md&content={}",
+                    content.replace('\n', "%0A").replace('#', "%23")
+                ))
+                .dispatch()
+                .await;
+
+            // The section goes on until the next heading of the same level
+            let response = client
+                .get("/edit/runbook.md?section=setup")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            let body = response.into_string().await.unwrap();
+            assert!(body.contains("old setup") && body.contains("details"));
+            assert!(!body.contains("intro") && !body.contains("teardown"));
+
+            let response = client
+                .post("/edit/runbook.md?section=setup")
+                .header(ContentType::Form)
+                .body(format!(
+                    "base={}&content=%23%23 Setup%0A%0Anew setup%0A",
+                    merge::revision(content)
+                ))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::SeeOther);
+            assert_eq!(
+                response.headers().get_one("Location"),
+                Some("/runbook.html#setup")
+            );
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            let page = std::fs::read_to_string(book.join("src/runbook.md")).unwrap();
+            assert_eq!(
+                page,
+                "# Runbook\n\nintro\n\n## Setup\n\nnew setup\n\n## Teardown\n\nteardown\n"
+            );
+
+            let response = client
+                .get("/edit/runbook.md?section=missing")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::NotFound);
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn suggested_changes() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let config = r#"
+[[debug.users]]
+username = "admin"
+password = "password"
+roles = ["admin"]
+
+[[debug.acl]]
+path = "policies"
+editors = ["@admin"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, config))
+                    .unwrap();
+                jail.set_env("MDWIKI_SUGGESTIONS", "true");
+            }),
+            async move |client: Client| {
+                let client = &client;
+                let login = move |username: &str| {
+                    client
+                        .post("/login")
+                        .header(ContentType::Form)
+                        .body(format!("username={}&password=password", username))
+                        .dispatch()
+                };
+                let suggest = move |content: &str| {
+                    client
+                        .post("/edit/policies/rules.md")
+                        .header(ContentType::Form)
+                        .body(format!(
+                            "base={}&content={}",
+                            merge::revision("one\n"),
+                            content
+                        ))
+                        .dispatch()
+                };
+                let reviews = move || async move {
+                    let response = client.get("/reviews").dispatch().await;
+                    response.into_string().await.unwrap()
+                };
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let page = move || std::fs::read_to_string(book.join("src/policies/rules.md"));
+
+                login("admin").await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=policies/rules.md&content=one%0A")
+                    .dispatch()
+                    .await;
+
+                login("user").await;
+                let response = client.get("/edit/policies/rules.md").dispatch().await;
+                let body = response.into_string().await.unwrap();
+                assert!(body.contains("Suggest change"));
+                let response = suggest("two%0A").await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(page().unwrap(), "one\n");
+                let body = reviews().await;
+                assert!(body.contains("Waiting for review") && body.contains("+two"));
+
+                login("admin").await;
+                let body = reviews().await;
+                let start = body.find("/reviews/").unwrap() + "/reviews/".len();
+                let id = &body[start..start + body[start..].find("/accept").unwrap()];
+                let response = client
+                    .post(format!("/reviews/{}/accept", id))
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(page().unwrap(), "two\n");
+                assert!(reviews().await.contains("No suggested changes"));
+
+                login("user").await;
+                suggest("three%0A").await;
+                login("admin").await;
+                let body = reviews().await;
+                let start = body.find("/reviews/").unwrap() + "/reviews/".len();
+                let id = &body[start..start + body[start..].find("/reject").unwrap()];
+                client
+                    .post(format!("/reviews/{}/reject", id))
+                    .dispatch()
+                    .await;
+                assert_eq!(page().unwrap(), "two\n");
+                assert!(reviews().await.contains("No suggested changes"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn draft_pages() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let other = r#"
+[[debug.users]]
+username = "other"
+password = "password"
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, other))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                let client = &client;
+                let login = move |username: &str| {
+                    client
+                        .post("/login")
+                        .header(ContentType::Form)
+                        .body(format!("username={}&password=password", username))
+                        .dispatch()
+                };
+                let save = move |base: &str, content: &str, draft: bool| {
+                    client
+                        .post("/edit/plan.md")
+                        .header(ContentType::Form)
+                        .body(format!(
+                            "base={}&content={}&draft={}",
+                            merge::revision(base),
+                            content.replace('\n', "%0A"),
+                            draft
+                        ))
+                        .dispatch()
+                };
+                let drafts = move || async move {
+                    let response = client.get("/drafts").dispatch().await;
+                    response.into_string().await.unwrap()
+                };
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let repo = git2::Repository::open(&book).unwrap();
+                let branch = |repo: &git2::Repository| {
+                    repo.find_branch("drafts/user", git2::BranchType::Local)
+                        .is_ok()
+                };
+                let page = move || std::fs::read_to_string(book.join("src/plan.md")).unwrap();
+                let original = "# Plan\n\none\n\nmiddle\n\nend\n";
+
+                login("user").await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body(format!(
+                        "file=plan.md&content={}",
+                        original.replace('\n', "%0A")
+                    ))
+                    .dispatch()
+                    .await;
+                let response = save(original, "# Plan\n\ntwo\n\nmiddle\n\nend\n", true).await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(page(), original);
+                assert!(branch(&repo));
+                let body = drafts().await;
+                assert!(body.contains("plan.md") && body.contains("+two"));
+                let response = client.get("/edit/plan.md").dispatch().await;
+                let body = response.into_string().await.unwrap();
+                assert!(body.contains("your draft of this page") && body.contains("two"));
+
+                // changes made meanwhile are merged with the draft when it's published
+                login("other").await;
+                let response = save(original, "# Plan\n\none\n\nmiddle\n\nthree\n", false).await;
+                assert_eq!(response.status(), Status::SeeOther);
+                login("user").await;
+                let response = client.post("/drafts/publish").dispatch().await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(page(), "# Plan\n\ntwo\n\nmiddle\n\nthree\n");
+                assert!(!branch(&repo));
+                let head = repo.head().unwrap().peel_to_commit().unwrap();
+                assert_eq!(head.message(), Some("Publish drafts of plan.md"));
+                assert!(drafts().await.contains("no unpublished drafts"));
+
+                let published = page();
+                save(&published, "# Plan\n\nfour\n", true).await;
+                let response = client.post("/drafts/discard/plan.md").dispatch().await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(page(), published);
+                assert!(drafts().await.contains("no unpublished drafts"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn migrate_old_book() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                // a book from before layouts were versioned
+                let book = jail.directory().join("mdwiki-test-dir");
+                std::fs::create_dir_all(book.join("src")).unwrap();
+                std::fs::write(book.join("book.toml"), "[book]\nsrc = \"src\"\n").unwrap();
+                std::fs::write(book.join(".gitignore"), "book").unwrap();
+                std::fs::write(book.join("src/README.md"), "# Old book\n").unwrap();
+                let repo = git2::Repository::init(&book).unwrap();
+                let mut index = repo.index().unwrap();
+                index
+                    .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+                    .unwrap();
+                let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+                let sig = git2::Signature::now("old", "old@example.com").unwrap();
+                repo.commit(Some("HEAD"), &sig, &sig, "Old book", &tree, &[])
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let response = client.get("/index.html").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+                assert!(response.into_string().await.unwrap().contains("Old book"));
+
+                let layout = std::fs::read_to_string(book.join(".mdwiki-layout")).unwrap();
+                assert_eq!(layout, format!("{}\n", migrations::latest()));
+                let gitignore = std::fs::read_to_string(book.join(".gitignore")).unwrap();
+                assert_eq!(gitignore, "book\n.mdwiki\n");
+                let head = std::fs::read_to_string(book.join("theme/head.hbs")).unwrap();
+                assert!(head.starts_with(wiki::THEME_SCRIPT_START));
+
+                let repo = git2::Repository::open(&book).unwrap();
+                assert_eq!(repo.statuses(None).unwrap().len(), 0);
+                let mut revwalk = repo.revwalk().unwrap();
+                revwalk.push_head().unwrap();
+                let commits = revwalk
+                    .map(|oid| repo.find_commit(oid.unwrap()).unwrap())
+                    .filter(|commit| commit.summary().unwrap().starts_with("Migrate to layout"))
+                    .count();
+                assert_eq!(commits, migrations::MIGRATIONS.len());
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn theme_script_block() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let theme = jail.directory().join("mdwiki-test-dir/theme");
+                std::fs::create_dir_all(&theme).unwrap();
+                let head = format!(
+                    "<link rel=\"stylesheet\" href=\"/custom.css\">\n{}\nold script\n{}\n\
+                     <meta name=\"custom\">\n",
+                    wiki::THEME_SCRIPT_START,
+                    wiki::THEME_SCRIPT_END
+                );
+                std::fs::write(theme.join("head.hbs"), head).unwrap();
+            }),
+            async move |_client: Client| {
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let head = std::fs::read_to_string(book.join("theme/head.hbs")).unwrap();
+                assert!(head.starts_with("<link rel=\"stylesheet\" href=\"/custom.css\">\n"));
+                let custom_end = format!("{}\n<meta name=\"custom\">\n", wiki::THEME_SCRIPT_END);
+                assert!(head.ends_with(&custom_end));
+                assert!(!head.contains("old script"));
+                assert_eq!(head.matches("/mdwiki_script.js").count(), 1);
+
+                // the theme is the book's, so it's committed
+                let repo = git2::Repository::open(&book).unwrap();
+                assert_eq!(repo.statuses(None).unwrap().len(), 0);
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn external_book() {
+        const SUMMARY: &str = "# Summary\n\n[Introduction](README.md)\n\n\
+                               - [Setup](guides/setup.md)\n";
+        const BOOK_TOML: &str = "# kept as it is\n[book]\ntitle = \"Handbook\"\n";
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_EXTERNAL_BOOK", "true");
+                let book = jail.directory().join("mdwiki-test-dir");
+                std::fs::create_dir_all(book.join("src/guides")).unwrap();
+                std::fs::write(book.join("book.toml"), BOOK_TOML).unwrap();
+                std::fs::write(book.join("src/SUMMARY.md"), SUMMARY).unwrap();
+                std::fs::write(book.join("src/README.md"), "# Handbook\n").unwrap();
+                std::fs::write(book.join("src/guides/setup.md"), "# Setup\n").unwrap();
+                let repo = git2::Repository::init(&book).unwrap();
+                let mut index = repo.index().unwrap();
+                index
+                    .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+                    .unwrap();
+                let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+                let sig = git2::Signature::now("docs", "docs@example.com").unwrap();
+                repo.commit(Some("HEAD"), &sig, &sig, "Handbook", &tree, &[])
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                let response = client
+                    .post("/edit/guides/setup.md")
+                    .header(ContentType::Form)
+                    .body("content=%23%20Setup%0A%0AEdited")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::SeeOther);
+                let response = client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=unlisted.md&content=%23%20Unlisted")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::SeeOther);
+
+                let page = client.get("/guides/setup.html").dispatch().await;
+                assert!(page.into_string().await.unwrap().contains("Edited"));
+                let read = |path: &str| std::fs::read_to_string(book.join(path)).unwrap();
+                assert_eq!(read("src/SUMMARY.md"), SUMMARY);
+                assert_eq!(read("book.toml"), BOOK_TOML);
+                assert!(read("src/unlisted.md").starts_with("# Unlisted"));
+                for untouched in &[".gitignore", ".mdwiki-layout", "theme/head.hbs"] {
+                    assert!(!book.join(untouched).exists(), "{}", untouched);
+                }
+                // only the pages are committed, not mdwiki's data or the build
+                let repo = git2::Repository::open(&book).unwrap();
+                assert_eq!(repo.statuses(None).unwrap().len(), 0);
+                let head = repo.head().unwrap().peel_to_commit().unwrap();
+                assert_eq!(head.summary(), Some("Create unlisted.md"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn injected_faults() {
+        use faults::{Fault, Step};
+        use std::time::Duration;
+
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_BUILD_TIMEOUT", "2");
+            }),
+            async move |client: Client| {
+                let client = &client;
+                let state = client.rocket().state::<WebappState>().unwrap();
+                let faults = &state.shared.faults;
+                let edit = move |content: &str| {
+                    let content = content.replace('#', "%23").replace(' ', "%20");
+                    async move {
+                        let response = client
+                            .post("/edit/faults.md")
+                            .header(ContentType::Form)
+                            .body(format!("content={}", content))
+                            .dispatch()
+                            .await;
+                        let status = response.status();
+                        (status, response.into_string().await.unwrap_or_default())
+                    }
+                };
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let repo = git2::Repository::open(&book).unwrap();
+                let page = move || std::fs::read_to_string(book.join("src/faults.md")).unwrap();
+
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=faults.md&content=%23%20Faults")
+                    .dispatch()
+                    .await;
+
+                faults.inject(Step::Build, Fault::Fail("out of memory".into()));
+                let (_, body) = edit("# Unbuilt").await;
+                assert!(body.contains("could not be built with it"));
+                assert!(body.contains("out of memory"));
+                assert_eq!(page(), "# Faults");
+
+                faults.inject(Step::Write, Fault::Fail("disk full".into()));
+                assert!(edit("# Unwritten").await.1.contains("Something went wrong"));
+                assert_eq!(page(), "# Faults");
+
+                // the rejected change isn't committed with the next one
+                faults.inject(Step::Commit, Fault::Fail("repository locked".into()));
+                let (_, body) = edit("# Uncommitted").await;
+                assert!(body.contains("Something went wrong"));
+                assert_eq!(page(), "# Faults");
+                assert_eq!(repo.statuses(None).unwrap().len(), 0);
+
+                faults.inject(Step::Build, Fault::Delay(Duration::from_secs(3)));
+                let (_, body) = edit("# Slow").await;
+                assert!(body.contains("build cancelled after 2 seconds"));
+                assert_eq!(page(), "# Faults");
+
+                let (status, _) = edit("# Recovered").await;
+                assert_eq!(status, Status::SeeOther);
+                assert_eq!(page(), "# Recovered");
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn comrak_renderer() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let renderer = r#"
+[debug.renderer]
+kind = "comrak"
+extensions = ["superscript"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, renderer))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body(concat!(
+                        "file=comrak.md&content=",
+                        "x^2^%0A%0A",
+                        "```%0Aa%0A%0A++++b%0A```%0A%0A",
+                        "[other](other.md#top)%0A",
+                    ))
+                    .dispatch()
+                    .await;
+
+                let page = client
+                    .get("/comrak.html")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(page.contains("x<sup>2</sup>"));
+                assert!(page.contains("a&#10;&#10;    b"));
+                assert!(page.contains(r#"href="other.html#top""#));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn dashboard() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=popular.md&content=popular")
+                .dispatch()
+                .await;
+            for _ in 0..3 {
+                client.get("/popular.html").dispatch().await;
+            }
+            client.get("/index.html").dispatch().await;
+
+            let dashboard = client
+                .get("/dashboard")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            let (recent, popular) = dashboard.split_at(dashboard.find("Most viewed").unwrap());
+            assert!(recent.contains("popular.md</a>"));
+            assert!(recent.find("popular.md").unwrap() < recent.find("README.md").unwrap());
+            assert!(popular.contains("3 views"));
+            assert!(popular.find("popular.md").unwrap() < popular.find("README.md").unwrap());
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn case_insensitive_paths() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_CASE_INSENSITIVE_PATHS", "true");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=team/Setup.md&content=content")
+                    .dispatch()
+                    .await;
+
+                let redirects = [
+                    ("/Team/setup", "/team/Setup.html"),
+                    ("/team/SETUP.HTML", "/team/Setup.html"),
+                    ("/TEAM/", "/team/index.html"),
+                ];
+                for (from, to) in redirects.iter() {
+                    let response = client.get(*from).dispatch().await;
+                    assert_eq!(response.status(), Status::PermanentRedirect, "{}", from);
+                    assert_eq!(response.headers().get_one("location"), Some(*to), "{}", from);
+                }
+
+                for file in &["team/setup.md", "Team/other.md"] {
+                    let response = client
+                        .post("/new")
+                        .header(ContentType::Form)
+                        .body(format!("file={}&content=content", file))
+                        .dispatch()
+                        .await;
+                    assert!(response
+                        .into_string()
+                        .await
+                        .unwrap()
+                        .contains("differs only by case from"));
+                }
+
+                let response = client
+                    .post("/api/v1/move")
+                    .header(ContentType::JSON)
+                    .body(r#"{"from": "team/Setup.md", "to": "team/setup.md"}"#)
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+                assert_eq!(
+                    client.get("/team/setup.html").dispatch().await.status(),
+                    Status::Ok
+                );
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn reject_changes_that_break_the_build() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            let repo = git2::Repository::open(&book).unwrap();
+            let head = repo.head().unwrap().target();
+            // Succeeds when mdbook asks if it supports the renderer, fails when it runs
+            let mut book_toml = std::fs::read_to_string(book.join("book.toml")).unwrap();
+            book_toml.push_str(
+                r#"
+[preprocessor.broken]
+command = "sh -c 'test \"$1\" = supports' sh"
+"#,
+            );
+            std::fs::write(book.join("book.toml"), book_toml).unwrap();
+
+            let response = client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=broken/page.md&content=content")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            assert!(response
+                .into_string()
+                .await
+                .unwrap()
+                .contains("the book could not be built with it"));
+            assert_eq!(repo.head().unwrap().target(), head);
+            assert!(!book.join("src/broken").exists());
+            let summary = std::fs::read_to_string(book.join("src/SUMMARY.md")).unwrap();
+            assert!(!summary.contains("broken"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn sanitize_html() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body(concat!(
+                    "file=xss.md&content=",
+                    "%3Cscript%3Ealert(1)%3C/script%3E%0A%0A",
+                    "%3Cb+onclick=%22alert(2)%22%3Ebold%3C/b%3E%0A%0A",
+                    "[link](javascript:alert(3))%0A",
+                ))
+                .dispatch()
+                .await;
+
+            let page = client
+                .get("/xss.html")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(!page.contains("alert(1)"));
+            assert!(!page.contains("alert(2)"));
+            assert!(!page.contains("javascript:alert(3)"));
+            assert!(page.contains("<b>bold</b>"));
+
+            let embedded = client
+                .get("/embed/xss.html")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(!embedded.contains("alert("));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn read_only_mode() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_READ_ONLY", "true");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+
+                let response = client
+                    .put("/api/v1/pages/readonly.md")
+                    .body("READONLY")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Forbidden);
+
+                let response = client
+                    .get("/api/v1/context?path=index.md")
+                    .dispatch()
+                    .await;
+                let context = response.into_string().await.unwrap();
+                assert!(context.contains(r#""edit":false,"create":false"#));
+                assert!(context.contains(r#""kind":"read_only""#));
+
+                Ok(())
+            },
+        )
+    }
+
+    // Windows has no permission bits to make the book read-only with
+    #[cfg(unix)]
+    #[rocket::async_test]
+    async fn read_only_book() {
+        use std::os::unix::fs::PermissionsExt;
+
+        run_test(
+            Some(|jail: &mut Jail| {
+                // a built book on a read-only volume
+                let book = jail.directory().join("mdwiki-test-dir");
+                std::fs::create_dir_all(book.join("src")).unwrap();
+                std::fs::create_dir_all(book.join("book")).unwrap();
+                std::fs::write(book.join("book.toml"), "[book]\nsrc = \"src\"\n").unwrap();
+                std::fs::write(book.join("src/README.md"), "# Archive\n").unwrap();
+                std::fs::write(book.join("book/index.html"), "<h1>Archive</h1>").unwrap();
+                std::fs::set_permissions(&book, std::fs::Permissions::from_mode(0o555)).unwrap();
+            }),
+            async move |client: Client| {
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let response = client.get("/index.html").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+                assert!(response.into_string().await.unwrap().contains("Archive"));
+                assert!(!book.join(".git").exists());
+
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                let response = client.get("/edit/README.md").dispatch().await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert_eq!(response.headers().get_one("location"), Some("/index.html"));
+                let response = client.get("/new").dispatch().await;
+                assert_eq!(response.status(), Status::SeeOther);
+
+                let response = client.get("/api/v1/context?path=index.md").dispatch().await;
+                let context = response.into_string().await.unwrap();
+                assert!(context.contains(r#""edit":false,"create":false"#));
+                assert!(context.contains("The wiki's files are read-only"));
+
+                std::fs::set_permissions(&book, std::fs::Permissions::from_mode(0o755)).unwrap();
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn remote_credentials() {
+        use remote::RemoteConfig;
+
+        run_test(
+            Some(|jail: &mut Jail| {
+                let remote = jail.directory().join("remote.git");
+                git2::Repository::init_bare(&remote).unwrap();
+                jail.set_env("MDWIKI_REMOTE__URL", remote.to_str().unwrap());
+            }),
+            async move |_client: Client| {
+                let dir = std::env::current_dir().unwrap();
+                let book = dir.join("mdwiki-test-dir");
+                let config = |url: &str, ssh_key: Option<&str>, token: Option<&str>| RemoteConfig {
+                    url: url.to_string(),
+                    ssh_key: ssh_key.map(String::from),
+                    ssh_key_passphrase: None,
+                    token: token.map(String::from),
+                    username: None,
+                };
+
+                let missing = config(dir.join("missing.git").to_str().unwrap(), None, None);
+                let err = remote::check(book.to_str().unwrap(), &missing).unwrap_err();
+                assert!(err.starts_with("could not connect to the remote"));
+
+                let ssh = "git@github.com:org/wiki.git";
+                let err = remote::validate(&config(ssh, None, Some("token"))).unwrap_err();
+                assert!(err.starts_with("remote.token only works with https remotes"));
+                let err = remote::validate(&config(ssh, Some("/missing/id"), None)).unwrap_err();
+                assert!(err.starts_with("could not read remote.ssh_key"));
+                let https = "https://github.com/org/wiki.git";
+                let err = remote::validate(&config(https, Some("/missing/id"), None)).unwrap_err();
+                assert!(err.starts_with("remote.ssh_key only works with ssh remotes"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn push_to_mirrors() {
+        use faults::{Fault, Step};
+        use std::time::Duration;
+
+        // Waits until every mirror has been brought up to `commit`
+        async fn mirrored(shared: &shared::SharedState, commit: git2::Oid) -> bool {
+            for _ in 0..100 {
+                let status = shared.mirrors.status();
+                let commit = Some(commit.to_string());
+                if status.iter().all(|mirror| mirror.commit == commit) {
+                    return true;
+                }
+                rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            false
+        }
+
+        run_test(
+            Some(|jail: &mut Jail| {
+                let mut config = format!(
+                    "{}\n[[debug.users]]\nusername = \"admin\"\npassword = \"password\"\n\
+                     roles = [\"admin\"]\n",
+                    TEST_CONFIG
+                );
+                for mirror in &["mirror-a.git", "mirror-b.git"] {
+                    let path = jail.directory().join(mirror);
+                    git2::Repository::init_bare(&path).unwrap();
+                    config.push_str(&format!("\n[[debug.mirrors]]\nurl = {:?}\n", path));
+                }
+                jail.create_file("mdwiki.toml", &config).unwrap();
+            }),
+            async move |client: Client| {
+                let dir = std::env::current_dir().unwrap();
+                let book = git2::Repository::open(dir.join("mdwiki-test-dir")).unwrap();
+                let head = || book.head().unwrap().peel_to_commit().unwrap().id();
+                let shared = &client.rocket().state::<WebappState>().unwrap().shared;
+                assert!(mirrored(shared, head()).await);
+
+                // the first push after the edit fails, and is retried
+                let fault = Fault::Fail("connection reset".into());
+                shared.faults.inject(Step::Push, fault);
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=admin&password=password")
+                    .dispatch()
+                    .await;
+                let response = client
+                    .put("/api/v1/pages/mirrored.md")
+                    .body("# Mirrored")
+                    .dispatch()
+                    .await;
+                assert!(response.status().class().is_success());
+                assert!(mirrored(shared, head()).await);
+                let branch = book.head().unwrap().name().unwrap().to_string();
+                for mirror in &["mirror-a.git", "mirror-b.git"] {
+                    let mirror = git2::Repository::open(dir.join(mirror)).unwrap();
+                    assert_eq!(mirror.refname_to_id(&branch).unwrap(), head());
+                }
+
+                let response = client.get("/admin/mirrors").dispatch().await;
+                let page = response.into_string().await.unwrap();
+                assert!(page.contains("mirror-a.git") && page.contains("mirror-b.git"));
+                assert_eq!(page.matches("Up to date").count(), 2);
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn anonymous_users_not_allowed() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_ALLOW_ANONYMOUS", "false");
+            }),
+            async move |client: Client| {
+                let response = client.get("/index.html").dispatch().await;
+                assert_eq!(response.status(), Status::SeeOther);
+                assert!(response
+                    .headers()
+                    .get_one("location")
+                    .unwrap()
+                    .starts_with("/login?next="));
+
+                let response = client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password&next=//evil.example.com")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.headers().get_one("location"), Some("/"));
+
+                let response = client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password&next=/index.html")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.headers().get_one("location"), Some("/index.html"));
+
+                assert_eq!(
+                    client.get("/index.html").dispatch().await.status(),
+                    Status::Ok
+                );
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn attachments() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_MAX_ATTACHMENT_SIZE", "16");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=team/setup.md&content=content")
+                    .dispatch()
+                    .await;
+
+                let response = client
+                    .post("/upload/attachment/team/setup.md?name=notes.txt")
+                    .body("some notes")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+                let url = response.into_string().await.unwrap();
+                assert_eq!(url, "/files/team/setup/notes.txt");
+
+                let response = client.get(url).dispatch().await;
+                assert_eq!(response.into_string().await.unwrap(), "some notes");
+                let edit = client
+                    .get("/edit/team/setup.md")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(edit.contains(">notes.txt</a>"));
+
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let repo = git2::Repository::open(&book).unwrap();
+                let head = repo.head().unwrap().peel_to_commit().unwrap();
+                assert_eq!(head.message(), Some("Attach notes.txt to team/setup.md"));
+
+                let response = client
+                    .post("/upload/attachment/team/setup.md?name=big.txt")
+                    .body("more than sixteen bytes")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::PayloadTooLarge);
+                let response = client
+                    .post("/upload/attachment/team/setup.md?name=.hidden")
+                    .body("notes")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::BadRequest);
+                let response = client
+                    .post("/upload/attachment/team/missing.md?name=notes.txt")
+                    .body("notes")
+                    .dispatch()
+                    .await;
+                assert_ne!(response.status(), Status::Ok);
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn todos() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body(
+                    "file=plans.md&content=TODO(@user): write the plan\n\
+                     TODO(@someone): review it\n\n```\nTODO(@user) in code\n```\n",
+                )
+                .dispatch()
+                .await;
+
+            let todos = client
+                .get("/todos")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(todos.contains("write the plan"));
+            assert!(todos.contains("review it"));
+            assert!(!todos.contains("in code"));
+
+            let todos = client
+                .get("/todos?assignee=@someone")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(!todos.contains("write the plan"));
+            assert!(todos.contains("review it"));
+
+            let dashboard = client
+                .get("/dashboard")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(dashboard.contains("Assigned to you"));
+            assert!(dashboard.contains("write the plan"));
+            assert!(!dashboard.contains("review it"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn page_lifecycle() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let lifecycle = r#"
+[debug.lifecycle]
+default = "draft"
+
+[[debug.lifecycle.transitions]]
+from = "draft"
+to = "in-review"
+
+[[debug.lifecycle.transitions]]
+from = "in-review"
+to = "published"
+allow = ["@reviewers"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, lifecycle))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                let put = |content: &'static str| {
+                    client.put("/api/v1/pages/guide.md").body(content).dispatch()
+                };
+
+                let response = put("---\nstatus: published\n---\n# Guide\n").await;
+                assert_eq!(response.status(), Status::Forbidden);
+                let response = put("---\nstatus: finished\n---\n# Guide\n").await;
+                assert_eq!(response.status(), Status::BadRequest);
+                let response = put("---\nstatus: in-review\n---\n# Guide\n").await;
+                assert_eq!(response.status(), Status::Created);
+                let response = put("---\nstatus: published\n---\n# Guide\n").await;
+                assert_eq!(response.status(), Status::Forbidden);
+
+                let page = client
+                    .get("/guide.html")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(page.contains("mdwiki-status-in-review"));
+                assert!(!page.contains("status: in-review"));
+
+                let dashboard = client
+                    .get("/dashboard?status=in-review")
+                    .dispatch()
+                    .await
+                    .into_string()
+                    .await
+                    .unwrap();
+                assert!(dashboard.contains("All pages with this status"));
+                assert!(dashboard.contains(">guide.md</a>"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn deprecation_banner() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .put("/api/v1/pages/runbooks/new.md")
+                .body("# New runbook\n")
+                .dispatch()
+                .await;
+
+            let response = client
+                .put("/api/v1/pages/runbooks/old.md")
+                .body("---\nstatus: deprecated\nsuperseded_by: runbooks/gone.md\n---\n# Old\n")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client
+                .put("/api/v1/pages/runbooks/old.md")
+                .body(
+                    "---\nstatus: deprecated\nsuperseded_by: runbooks/new.md\n\
+                     redirect_after: 10\n---\n# Old\n",
+                )
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Created);
+
+            let page = client
+                .get("/runbooks/old.html")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(page.contains("This page is deprecated."));
+            assert!(page.contains(r#"superseded by <a href="/runbooks/new.html">"#));
+            assert!(page.contains(r#"<span id="mdwiki-redirect-seconds">10</span>"#));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn upload_image_form() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_UPLOAD_NAMES", "original_name");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+
+                let form = "--BOUNDARY\r\n\
+                            Content-Disposition: form-data; name=\"image\"; \
+                            filename=\"My Screenshot (2).png\"\r\n\
+                            Content-Type: image/png\r\n\r\n\
+                            PNGDATA\r\n\
+                            --BOUNDARY--\r\n";
+                let response = client
+                    .post("/upload/image")
+                    .header(Header::new(
+                        "Content-Type",
+                        "multipart/form-data; boundary=BOUNDARY",
+                    ))
+                    .body(form)
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+                let url = response.into_string().await.unwrap();
+                assert!(url.starts_with("/images/my_screenshot_2_"), "{}", url);
+                assert!(url.ends_with(".png"), "{}", url);
+
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body(format!("file=shots.md&content=![shot]({})", url))
+                    .dispatch()
+                    .await;
+                let response = client.get(url).dispatch().await;
+                assert_eq!(response.into_string().await.unwrap(), "PNGDATA");
+
+                let form = "--BOUNDARY\r\n\
+                            Content-Disposition: form-data; name=\"other\"\r\n\r\n\
+                            text\r\n\
+                            --BOUNDARY--\r\n";
+                let response = client
+                    .post("/upload/image")
+                    .header(Header::new(
+                        "Content-Type",
+                        "multipart/form-data; boundary=BOUNDARY",
+                    ))
+                    .body(form)
+                    .dispatch()
+                    .await;
+                assert_ne!(response.status(), Status::Ok);
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn heading_ids() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .put("/api/v1/pages/headings.md")
+                .body(
+                    "# Setup\n\n<h2 class=\"note\">Raw <em>heading</em></h2>\n\n\
+                     <h2 class=\"note\">Setup</h2>\n",
+                )
+                .dispatch()
+                .await;
+
+            let page = client
+                .get("/headings.html")
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(page.contains(r#"id="setup""#));
+            assert!(page.contains(r#"<h2 class="note" id="raw-heading">"#));
+            assert!(page.contains(r#"<h2 class="note" id="setup-1">"#));
+
+            let script = client.get("/mdwiki_script.js").dispatch().await;
+            assert!(script.into_string().await.unwrap().contains("mdwiki-anchor"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn lfs() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_LFS", "true");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=setup.md&content=content")
+                    .dispatch()
+                    .await;
+                let response = client
+                    .post("/upload/attachment/setup.md?name=notes.txt")
+                    .body("some notes")
+                    .dispatch()
+                    .await;
+                let url = response.into_string().await.unwrap();
+                let response = client.get(url).dispatch().await;
+                assert_eq!(response.into_string().await.unwrap(), "some notes");
+
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let attributes = std::fs::read_to_string(book.join(".gitattributes")).unwrap();
+                assert!(attributes.contains("src/files/** filter=lfs diff=lfs merge=lfs -text"));
+
+                let repo = git2::Repository::open(&book).unwrap();
+                let tree = repo.head().unwrap().peel_to_tree().unwrap();
+                let entry = tree
+                    .get_path(std::path::Path::new("src/files/setup/notes.txt"))
+                    .unwrap();
+                let blob = repo.find_blob(entry.id()).unwrap();
+                let pointer = String::from_utf8_lossy(blob.content()).to_string();
+                assert!(pointer.starts_with("version https://git-lfs.github.com/spec/v1\n"));
+                assert!(pointer.ends_with("size 10\n"));
+                let oid = &pointer[pointer.find("sha256:").unwrap() + 7..][..64];
+                let object = book
+                    .join(".git/lfs/objects")
+                    .join(&oid[..2])
+                    .join(&oid[2..4])
+                    .join(oid);
+                assert_eq!(std::fs::read_to_string(object).unwrap(), "some notes");
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn repository_maintenance() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=setup.md&content=content")
+                .dispatch()
+                .await;
+
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            let (before, after) = maintenance::gc(book.to_str().unwrap()).unwrap();
+            assert!(before.loose_objects > 0);
+            assert_eq!(after.packs, 1);
+            let (_, after) = maintenance::gc(book.to_str().unwrap()).unwrap();
+            assert_eq!(after.packs, 1);
+
+            let repo = git2::Repository::open(&book).unwrap();
+            let tree = repo.head().unwrap().peel_to_tree().unwrap();
+            let entry = tree.get_path(std::path::Path::new("src/setup.md")).unwrap();
+            let blob = repo.find_blob(entry.id()).unwrap();
+            assert_eq!(blob.content(), b"content");
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn maintenance_under_load() {
+        use std::time::{Duration, Instant};
+
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_MAINTENANCE_INTERVAL", "1");
+            }),
+            async move |client: Client| {
+                let client = &client;
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                // two writers, so there's always a change waiting for the wiki
+                let until = Instant::now() + Duration::from_secs(3);
+                let write = move |page: &'static str| async move {
+                    let mut i = 0;
+                    while Instant::now() < until {
+                        client
+                            .put(format!("/api/v1/pages/{}", page))
+                            .body(format!("edit {}", i))
+                            .dispatch()
+                            .await;
+                        i += 1;
+                    }
+                };
+                join!(write("first.md"), write("second.md"));
+
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let packs = std::fs::read_dir(book.join(".git/objects/pack"))
+                    .unwrap()
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "pack"))
+                    .count();
+                assert!(packs > 0);
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn diff_sections() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            for step in &["one", "two"] {
+                let response = client
+                    .put("/api/v1/pages/setup.md")
+                    .body(format!("# Setup\n\nIntro\n\n## Install\n\nStep {}\n", step))
+                    .dispatch()
+                    .await;
+                assert!(response.status().class().is_success());
+            }
+
+            let repo =
+                git2::Repository::open(std::env::current_dir().unwrap().join("mdwiki-test-dir"))
+                    .unwrap();
+            let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+            let diff = client.get(format!("/diff/{}", head)).dispatch().await;
+            let diff = diff.into_string().await.unwrap();
+            assert!(diff.contains("+Step two"));
+            assert!(diff.contains("setup.html#install\""));
+            assert!(diff.contains("Install\n"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn backup() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let port = std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port();
+                let config = format!(
+                    r#"
+[[debug.users]]
+username = "admin"
+password = "password"
+roles = ["admin"]
+
+[debug.backup]
+endpoint = "http://127.0.0.1:{}"
+bucket = "wiki"
+access_key = "key"
+secret_key = "secret"
+prefix = "backups/"
+keep = 0
+"#,
+                    port
+                );
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, config))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+                use rocket::tokio::net::TcpListener;
+
+                let config: Config = Config::figment().extract()?;
+                let address = &config.backup.unwrap().endpoint["http://".len()..];
+                let listener = TcpListener::bind(address).await.unwrap();
+                let server = rocket::tokio::spawn(async move {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut request = Vec::new();
+                    let mut buf = [0; 4096];
+                    let (head, body) = loop {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                        let end = match request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            Some(end) => end,
+                            None => continue,
+                        };
+                        let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .map_or(0, |length| length.trim().parse().unwrap());
+                        if n == 0 || request.len() >= end + 4 + length {
+                            break (head, request[end + 4..].to_vec());
+                        }
+                    };
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await
+                        .unwrap();
+                    (head, body)
+                });
+
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                let response = client.post("/admin/backup").dispatch().await;
+                assert_eq!(response.status(), Status::Forbidden);
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=admin&password=password")
+                    .dispatch()
+                    .await;
+                let response = client.post("/admin/backup").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+                let name = response.into_string().await.unwrap();
+                assert!(name.starts_with("backups/mdwiki-"));
+                assert!(name.ends_with("Z.zip"));
+
+                let (head, body) = server.await.unwrap();
+                assert!(head.starts_with(&format!("put /wiki/{} http/1.1", name)));
+                assert!(head.contains("authorization: aws4-hmac-sha256 credential=key/"));
+                assert!(head.contains("x-amz-content-sha256: "));
+                assert!(body.starts_with(b"PK"));
+                let mut archive = zip::ZipArchive::new(std::io::Cursor::new(body)).unwrap();
+                let names = (0..archive.len())
+                    .map(|i| archive.by_index(i).unwrap().name().to_string())
+                    .collect::<Vec<_>>();
+                assert!(names.iter().any(|name| name == "src/SUMMARY.md"));
+                assert!(names.iter().any(|name| name == ".git/HEAD"));
+                assert!(!names.iter().any(|name| name.starts_with(".mdwiki")));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn restore() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let config = r#"
+[[debug.users]]
+username = "admin"
+password = "password"
+roles = ["admin"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, config))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=admin&password=password")
+                    .dispatch()
+                    .await;
+                for content in &["before", "after"] {
+                    if *content == "after" {
+                        let config: Config = Config::figment().extract()?;
+                        std::fs::write("backup.zip", backup::archive(&config).unwrap()).unwrap();
+                    }
+                    let response = client
+                        .put("/api/v1/pages/setup.md")
+                        .body(*content)
+                        .dispatch()
+                        .await;
+                    assert!(response.status().class().is_success());
+                }
+
+                let response = client
+                    .post("/admin/restore")
+                    .body("not a backup")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::BadRequest);
+                let response = client
+                    .post("/admin/restore")
+                    .body(std::fs::read("backup.zip").unwrap())
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let content = std::fs::read_to_string(book.join("src/setup.md")).unwrap();
+                assert_eq!(content.trim(), "before");
+                let repo = git2::Repository::open(&book).unwrap();
+                let head = repo.head().unwrap().peel_to_commit().unwrap();
+                assert_eq!(head.message(), Some("Restore from backup (an upload)"));
+                let replaced = std::fs::read_dir(book.join(".mdwiki/replaced"))
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .unwrap()
+                    .path();
+                let content = std::fs::read_to_string(replaced.join("src/setup.md")).unwrap();
+                assert_eq!(content.trim(), "after");
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn bulk_tags() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let admin = r#"
+[[debug.users]]
+username = "admin"
+password = "password"
+roles = ["admin"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, admin))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=admin&password=password")
+                    .dispatch()
+                    .await;
+                let pages = [
+                    ("deploy.md", "---\ntags: [deployment, ops]\n---\n# Deploy\n"),
+                    ("restart.md", "---\ntags: deploy, ops\n---\n# Restart\n"),
+                    ("notes.md", "# Notes\n"),
+                ];
+                for (page, content) in &pages {
+                    client
+                        .put(format!("/api/v1/pages/{}", page))
+                        .body(*content)
+                        .dispatch()
+                        .await;
+                }
+
+                let index = client.get("/api/v1/tags").dispatch().await;
+                let index = index.into_string().await.unwrap();
+                assert!(index.contains(r#""deploy":1,"deployment":1,"ops":2"#));
+                assert!(index.contains(r#""notes.md""#));
+
+                let response = client
+                    .post("/api/v1/tags")
+                    .header(ContentType::JSON)
+                    .body(r#"{"action": "rename", "from": "deploy", "to": "ops"}"#)
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::BadRequest);
+
+                let response = client
+                    .post("/api/v1/tags?dry_run=true")
+                    .header(ContentType::JSON)
+                    .body(r#"{"action": "merge", "from": "deployment", "into": "deploy"}"#)
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+                let plan = response.into_string().await.unwrap();
+                assert!(plan.contains("+tags: [deploy, ops]"));
+
+                let src = std::env::current_dir()
+                    .unwrap()
+                    .join("mdwiki-test-dir")
+                    .join("src");
+                let deploy = std::fs::read_to_string(src.join("deploy.md")).unwrap();
+                assert!(deploy.contains("tags: [deployment, ops]"));
+
+                let response = client
+                    .post("/api/v1/tags")
+                    .header(ContentType::JSON)
+                    .body(r#"{"action": "merge", "from": "deployment", "into": "deploy"}"#)
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+                let deploy = std::fs::read_to_string(src.join("deploy.md")).unwrap();
+                assert!(deploy.starts_with("---\ntags: [deploy, ops]\n---\n"));
+
+                let response = client
+                    .post("/api/v1/tags")
+                    .header(ContentType::JSON)
+                    .body(r#"{"action": "rename", "from": "ops", "to": "operations"}"#)
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+                let restart = std::fs::read_to_string(src.join("restart.md")).unwrap();
+                assert!(restart.contains("tags: deploy, operations\n"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn image_snippet() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let snippet = "[debug.image_snippet]\nformat = \"figure\"\nrelative = true\n";
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, snippet))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+
+                let response = client
+                    .post("/upload/image?page=team/setup.md")
+                    .header(ContentType::PNG)
+                    .body("PNGDATA")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+                let snippet = response.into_string().await.unwrap();
+                assert!(snippet.starts_with("<figure>\n<img src=\"../images/"), "{}", snippet);
+                assert!(snippet.contains("<figcaption>insert caption</figcaption>"));
+
+                let response = client
+                    .put("/api/v1/pages/team/setup.md")
+                    .body(format!("# Setup\n\n{}", snippet))
+                    .dispatch()
+                    .await;
+                assert!(response.status().class().is_success());
+                let url = &snippet[snippet.find("images/").unwrap()..];
+                let url = format!("/{}", &url[..url.find('"').unwrap()]);
+                let response = client.get(url).dispatch().await;
+                assert_eq!(response.into_string().await.unwrap(), "PNGDATA");
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn require_alt_text() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_REQUIRE_ALT_TEXT", "true");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+
+                let response = client
+                    .post("/upload/image")
+                    .header(ContentType::PNG)
+                    .body("PNGDATA")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::BadRequest);
+                let response = client
+                    .post("/upload/image?alt=A%20diagram")
+                    .header(ContentType::PNG)
+                    .body("PNGDATA")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+
+                for (content, saved) in &[
+                    ("# Setup\n\n![](/images/diagram.png)\n", false),
+                    ("# Setup\n\n![insert image description](/images/diagram.png)\n", false),
+                    ("# Setup\n\n<img src=\"/images/diagram.png\">\n", false),
+                    ("# Setup\n\n![A diagram](/images/diagram.png)\n", true),
+                ] {
+                    let response = client
+                        .put("/api/v1/pages/setup.md")
+                        .body(*content)
+                        .dispatch()
+                        .await;
+                    assert_eq!(response.status().class().is_success(), *saved);
+                    if !saved {
+                        let message = response.into_string().await.unwrap();
+                        assert!(message.contains("on line 3 needs alt text"), "{}", message);
+                    }
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn edit_locks() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                let other = r#"
+[[debug.users]]
+username = "other"
+password = "password"
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, other))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                let client = &client;
+                let login = move |username: &str| {
+                    client
+                        .post("/login")
+                        .header(ContentType::Form)
+                        .body(format!("username={}&password=password", username))
+                        .dispatch()
+                };
+                login("user").await;
+                client
+                    .put("/api/v1/pages/setup.md")
+                    .body("# Setup\n")
+                    .dispatch()
+                    .await;
+                let editor = client.get("/edit/setup.md").dispatch().await;
+                let editor = editor.into_string().await.unwrap();
+                assert!(!editor.contains("is currently editing"));
+
+                login("other").await;
+                let editor = client.get("/edit/setup.md").dispatch().await;
+                let editor = editor.into_string().await.unwrap();
+                assert!(editor.contains("user is currently editing this page"));
+                let response = client.post("/lock/setup.md").dispatch().await;
+                assert_eq!(response.status(), Status::Conflict);
+                let editor = client.get("/edit/setup.md?take_over=true").dispatch().await;
+                let editor = editor.into_string().await.unwrap();
+                assert!(!editor.contains("is currently editing"));
+
+                login("user").await;
+                let response = client.post("/lock/setup.md").dispatch().await;
+                assert_eq!(response.status(), Status::Conflict);
+                assert_eq!(
+                    response.into_string().await.unwrap(),
+                    "other took over editing the page"
+                );
+
+                login("other").await;
+                let response = client
+                    .post("/edit/setup.md")
+                    .header(ContentType::Form)
+                    .body("content=%23%20Setup%0A%0AEdited%0A")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::SeeOther);
+                login("user").await;
+                let editor = client.get("/edit/setup.md").dispatch().await;
+                let editor = editor.into_string().await.unwrap();
+                assert!(!editor.contains("is currently editing"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn page_api() {
+        run_test(None, async move |client: Client| {
+            let response = client.get("/api/v1/pages/setup.md").dispatch().await;
+            assert_eq!(response.status(), Status::Unauthorized);
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let content = "# Setup\n\n## Install\n\nRun the installer\n";
+            client
+                .put("/api/v1/pages/setup.md")
+                .body(content)
+                .dispatch()
+                .await;
+
+            let response = client.get("/api/v1/pages/setup.md").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.into_string().await.unwrap(), content);
+            let response = client.get("/api/v1/pages/missing.md").dispatch().await;
+            assert_eq!(response.status(), Status::NotFound);
+            let response = client.get("/api/v1/pages/SUMMARY").dispatch().await;
+            assert_eq!(response.status(), Status::BadRequest);
+
+            let index = client.get("/searchindex.json").dispatch().await;
+            let index = serde_json::from_str(&index.into_string().await.unwrap()).unwrap();
+            let results = client::search_index(&index, "INSTALLER run");
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].url, "setup.html#install");
+            assert_eq!(results[0].title, "Install");
+            assert!(client::search_index(&index, "installer missing").is_empty());
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn portable_paths() {
+        assert!(utils::is_portable_name("notes.md"));
+        for name in &["con.md", "Com1.md", "aux", "a:b.md", "what?.md"] {
+            assert!(!utils::is_portable_name(name), "{}", name);
+        }
+        assert!(!utils::is_portable_name("notes."));
+        assert!(!utils::is_portable_name("notes "));
+        let path = async_std::path::Path::new("guides\\setup.md");
+        assert!(!utils::path_is_simple(path));
+        let path = std::path::PathBuf::from("guides").join("setup.md");
+        assert_eq!(utils::url_path(&path), "guides/setup.md");
+        assert_eq!(webapp::page_url("guides\\README.md"), "guides/index.html");
+
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let put = |path: &'static str| client.put(path).body("# Page\n").dispatch();
+
+            let response = put("/api/v1/pages/con.md").await;
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = put("/api/v1/pages/aux/page.md").await;
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = put("/api/v1/pages/guides/setup.md").await;
+            assert_eq!(response.status(), Status::Created);
+
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            let summary = std::fs::read_to_string(book.join("src/SUMMARY.md")).unwrap();
+            assert!(summary.contains("(guides/setup.md)"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn busy_queue() {
+        use faults::{Fault, Step};
+        use rocket::tokio::time::sleep;
+        use std::time::Duration;
+
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_QUEUE_CAPACITY", "1");
+            }),
+            async move |client: Client| {
+                let client = &client;
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                let state = client.rocket().state::<WebappState>().unwrap();
+                state
+                    .shared
+                    .faults
+                    .inject(Step::Build, Fault::Delay(Duration::from_secs(2)));
+                let put = move |path: &'static str, wait: u64| async move {
+                    sleep(Duration::from_millis(wait)).await;
+                    let response = client.put(path).body("# Page\n").dispatch().await;
+                    response.status()
+                };
+                let metrics = || async move {
+                    let response = client.get("/metrics").dispatch().await;
+                    response.into_string().await.unwrap()
+                };
+
+                // the first page is being built while the second waits, which fills the queue
+                let (first, second, (third, busy)) = join!(
+                    put("/api/v1/pages/first.md", 0),
+                    put("/api/v1/pages/second.md", 500),
+                    async {
+                        let third = put("/api/v1/pages/third.md", 1000).await;
+                        (third, metrics().await)
+                    }
+                );
+                assert_eq!(first, Status::Created);
+                assert_eq!(second, Status::Created);
+                assert_eq!(third, Status::ServiceUnavailable);
+                assert!(busy.contains("mdwiki_queue_depth 1\n"));
+                assert!(busy.contains("mdwiki_queue_capacity 1\n"));
+                assert!(busy.contains("mdwiki_queue_rejected_total 1\n"));
+
+                assert!(metrics().await.contains("mdwiki_queue_depth 0\n"));
+                let response = put("/api/v1/pages/third.md", 0).await;
+                assert_eq!(response, Status::Created);
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn upload_names() {
+        use upload_names::UploadNames;
+
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_UPLOAD_NAMES", "uuid");
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                let response = client
+                    .post("/upload/image")
+                    .header(ContentType::PNG)
+                    .body("PNGDATA")
+                    .dispatch()
+                    .await;
+                let url = response.into_string().await.unwrap();
+                let name = url.trim_start_matches("/images/").trim_end_matches(".png");
+                let parts = name.split('-').map(str::len).collect::<Vec<_>>();
+                assert_eq!(parts, [8, 4, 4, 4, 12], "{}", url);
+                assert!(name[14..].starts_with('7'), "{}", url);
+
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body(format!("file=shots.md&content=![shot]({})", url))
+                    .dispatch()
+                    .await;
+                let response = client.get(&url).dispatch().await;
+                assert_eq!(response.into_string().await.unwrap(), "PNGDATA");
+
+                let upload = std::env::current_dir().unwrap().join("upload");
+                std::fs::write(&upload, "PNGDATA").unwrap();
+                let upload = async_std::path::Path::new(&upload);
+                let plugins = Plugins::new();
+                let hash = UploadNames::ContentHash.name(None, upload, &plugins).await;
+                assert_eq!(
+                    hash.unwrap(),
+                    "2d4566582844690f8634a8b2534ea5221560038c6c0650c99140759bad603ae2"
+                );
+                let date = UploadNames::Date.name(None, upload, &plugins).await;
+                assert_eq!(date.unwrap().as_bytes()[10], b'_');
+                let name = Some("Team photo.JPG");
+                let original = UploadNames::OriginalName.name(name, upload, &plugins).await;
+                assert!(original.unwrap().starts_with("team_photo_"));
+                let random = UploadNames::Plugins.name(name, upload, &plugins).await;
+                assert!(upload_names::is_upload_name(&random.unwrap()));
+                // the names of the uploader's files are only kept when asked for
+                let default = UploadNames::default().name(name, upload, &plugins).await;
+                assert!(!default.unwrap().contains("team"));
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn commit_files() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            std::fs::write(book.join("src/stray.md"), "# Stray").unwrap();
+
+            let response = client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=a/b/c.md&content=content")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::SeeOther);
+
+            let repo = git2::Repository::open(&book).unwrap();
+            let head = repo.head().unwrap().peel_to_commit().unwrap();
+            assert_eq!(head.summary(), Some("Create a/b/c.md"));
+            let files = head.message().unwrap().lines().skip(2).collect::<Vec<_>>();
+            assert_eq!(
+                files,
+                [
+                    "M src/SUMMARY.md",
+                    "A src/a/README.md",
+                    "A src/a/b/README.md",
+                    "A src/a/b/c.md"
+                ]
+            );
+            let tree = head.tree().unwrap();
+            assert!(tree.get_path(std::path::Path::new("src/stray.md")).is_err());
+            let status = repo.status_file(std::path::Path::new("src/stray.md"));
+            assert_eq!(status.unwrap(), git2::Status::WT_NEW);
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn failed_changes_uncommitted() {
+        use faults::{Fault, Step};
+
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=page.md&content=%23%20Page")
+                .dispatch()
+                .await;
+            let state = client.rocket().state::<WebappState>().unwrap();
+            state
+                .shared
+                .faults
+                .inject(Step::Write, Fault::Fail("disk full".into()));
+            // the index page of the directory is written before the page fails to be
+            let response = client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=failed/page.md&content=content")
+                .dispatch()
+                .await;
+            assert_ne!(response.status(), Status::SeeOther);
+
+            let response = client
+                .post("/edit/page.md")
+                .header(ContentType::Form)
+                .body("content=%23%20Edited")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::SeeOther);
+
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            let repo = git2::Repository::open(&book).unwrap();
+            let head = repo.head().unwrap().peel_to_commit().unwrap();
+            let parent = head.parent(0).unwrap().tree().unwrap();
+            let tree = head.tree().unwrap();
+            let diff = repo
+                .diff_tree_to_tree(Some(&parent), Some(&tree), None)
+                .unwrap();
+            let files = diff
+                .deltas()
+                .map(|delta| delta.new_file().path().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(files, [std::path::Path::new("src/page.md")]);
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn bulk_changes_checked() {
+        use faults::{Fault, Step};
+
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            // file names are committed as they are, not matched as patterns
+            std::fs::write(book.join("src/notes1.md"), "by hand").unwrap();
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=notes%5B1%5D.md&content=old%20text")
+                .dispatch()
+                .await;
+            let repo = git2::Repository::open(&book).unwrap();
+            let head = repo.head().unwrap().peel_to_commit().unwrap();
+            let tree = head.tree().unwrap();
+            let committed = |path: &str| tree.get_path(std::path::Path::new(path)).is_ok();
+            assert!(committed("src/notes[1].md"));
+            assert!(!committed("src/notes1.md"));
+
+            let state = client.rocket().state::<WebappState>().unwrap();
+            state
+                .shared
+                .faults
+                .inject(Step::Build, Fault::Fail("broken".into()));
+            let response = client
+                .post("/api/v1/replace")
+                .header(ContentType::JSON)
+                .body(r#"{"search": "old", "replace": "new"}"#)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
+            let page = std::fs::read_to_string(book.join("src/notes[1].md")).unwrap();
+            assert_eq!(page, "old text");
+            let unchanged = repo.head().unwrap().peel_to_commit().unwrap();
+            assert_eq!(unchanged.id(), head.id());
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn parallel_reads() {
+        use faults::{Fault, Step};
+        use rocket::tokio::sync::oneshot;
+        use rocket::tokio::time::sleep;
+        use std::time::{Duration, Instant};
+        use wiki::WikiRequest;
+
+        let diff = WikiRequest::Diff {
+            commit: "HEAD".into(),
+            respond: oneshot::channel().0,
+        };
+        assert!(diff.is_read());
+        let change = |dry_run| WikiRequest::Replace {
+            user: config::MDWIKI_USER.clone(),
+            search: "a".into(),
+            replace: "b".into(),
+            regex: false,
+            dry_run,
+            respond: oneshot::channel().0,
+        };
+        assert!(change(true).is_read());
+        assert!(!change(false).is_read());
+
+        run_test(None, async move |client: Client| {
+            let client = &client;
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=page.md&content=content")
+                .dispatch()
+                .await;
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            let repo = git2::Repository::open(&book).unwrap();
+            let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+            let state = client.rocket().state::<WebappState>().unwrap();
+            state
+                .shared
+                .faults
+                .inject(Step::Read, Fault::Delay(Duration::from_secs(2)));
+
+            let read = move |url: String, wait: u64| async move {
+                sleep(Duration::from_millis(wait)).await;
+                let response = client.get(url).dispatch().await;
+                (response.status(), Instant::now())
+            };
+            // the history of the page is read while the diff is held up
+            let (blocked, other) = join!(
+                read(format!("/diff/{}", head), 0),
+                read("/blame/page.md".to_string(), 300)
+            );
+            assert_eq!(blocked.0, Status::Ok);
+            assert_eq!(other.0, Status::Ok);
+            assert!(other.1 < blocked.1);
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn parallel_previews() {
+        use faults::{Fault, Step};
+        use rocket::tokio::sync::oneshot;
+        use rocket::tokio::time::sleep;
+        use std::time::Duration;
+        use wiki::WikiRequest;
+
+        let backup = WikiRequest::Backup {
+            respond: oneshot::channel().0,
+        };
+        assert!(backup.is_read());
+
+        run_test(None, async move |client: Client| {
+            let client = &client;
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=target.md&content=[link](linking.md)")
+                .dispatch()
+                .await;
+            client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=linking.md&content=[link](target.md)")
+                .dispatch()
+                .await;
+            let state = client.rocket().state::<WebappState>().unwrap();
+            state
+                .shared
+                .faults
+                .inject(Step::Build, Fault::Delay(Duration::from_secs(1)));
+
+            let preview = move |from: &'static str, wait: u64| async move {
+                sleep(Duration::from_millis(wait)).await;
+                let response = client
+                    .post("/api/v1/move?dry_run=true")
+                    .header(ContentType::JSON)
+                    .body(format!(r#"{{"from": "{}", "to": "moved/{}"}}"#, from, from))
+                    .dispatch()
+                    .await;
+                (response.status(), response.into_string().await.unwrap())
+            };
+            // previews wait for the page being created, and then run side by side
+            let (created, first, second, third) = join!(
+                async {
+                    let response = client
+                        .put("/api/v1/pages/new.md")
+                        .body("[link](target.md)")
+                        .dispatch()
+                        .await;
+                    response.status()
+                },
+                preview("target.md", 300),
+                preview("target.md", 300),
+                preview("linking.md", 300)
+            );
+            assert_eq!(created, Status::Created);
+            for (status, _) in &[&first, &second, &third] {
+                assert_eq!(*status, Status::Ok);
+            }
+            assert!(first.1.contains("new.md"));
+            assert_eq!(first.1, second.1);
+            assert!(third.1.contains("+[link](moved/linking.md)"));
+
+            Ok(())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn failed_reads() {
+        use faults::{Fault, Step};
+        use rocket::tokio::time::sleep;
+        use std::time::Duration;
+
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_QUEUE_CAPACITY", "1");
+            }),
+            async move |client: Client| {
+                let client = &client;
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                let state = client.rocket().state::<WebappState>().unwrap();
+                let missing = "0123456789012345678901234567890123456789";
+                let response = client.get(format!("/diff/{}", missing)).dispatch().await;
+                assert_eq!(response.status(), Status::NotFound);
+
+                state
+                    .shared
+                    .faults
+                    .inject(Step::Read, Fault::Fail("broken repository".into()));
+                let response = client.get("/deleted").dispatch().await;
+                assert_eq!(response.status(), Status::InternalServerError);
+
+                // the first page is being built while the second waits, which fills the queue
+                state
+                    .shared
+                    .faults
+                    .inject(Step::Build, Fault::Delay(Duration::from_secs(2)));
+                let put = move |path: &'static str, wait: u64| async move {
+                    sleep(Duration::from_millis(wait)).await;
+                    let response = client.put(path).body("# Page\n").dispatch().await;
+                    response.status()
+                };
+                let (_, _, busy) = join!(
+                    put("/api/v1/pages/first.md", 0),
+                    put("/api/v1/pages/second.md", 500),
+                    async {
+                        sleep(Duration::from_millis(1000)).await;
+                        let response = client.get("/dashboard").dispatch().await;
+                        response.status()
+                    }
+                );
+                assert_eq!(busy, Status::ServiceUnavailable);
+                let response = client.get("/dashboard").dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+
+                Ok(())
+            },
+        )
+    }
+
+    #[rocket::async_test]
+    async fn never_committed() {
+        run_test(None, async move |client: Client| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body("username=user&password=password")
+                .dispatch()
+                .await;
+            let config = client.rocket().state::<Config>().unwrap();
+            for path in &[
+                "book/index.html",
+                ".mdwiki/uploads/image.png",
+                "src/.page.md.swp",
+                "src/page.md~",
+                "src/.#page.md",
+                "src/#page.md#",
+            ] {
+                let path = std::path::Path::new(path);
+                assert!(config.never_committed(path), "{}", path.display());
+            }
+            assert!(!config.never_committed(std::path::Path::new("src/page.md")));
+            assert!(!config.never_committed(std::path::Path::new("src/bookmarks.md")));
+
+            // build output added by hand while .gitignore was emptied
+            let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+            std::fs::write(book.join(".gitignore"), "").unwrap();
+            std::fs::write(book.join("src/.page.md.swp"), "swap").unwrap();
+            let repo = git2::Repository::open(&book).unwrap();
+            let mut index = repo.index().unwrap();
+            index
+                .add_path(std::path::Path::new("book/index.html"))
+                .unwrap();
+            index.write().unwrap();
+
+            let response = client
+                .post("/new")
+                .header(ContentType::Form)
+                .body("file=page.md&content=content")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::SeeOther);
+            let head = repo.head().unwrap().peel_to_commit().unwrap();
+            let tree = head.tree().unwrap();
+            assert!(tree.get_path(std::path::Path::new("src/page.md")).is_ok());
+            for path in &["book", ".mdwiki", "src/.page.md.swp"] {
+                let entry = tree.get_path(std::path::Path::new(path));
+                assert!(entry.is_err(), "{}", path);
+            }
+            assert!(book.join("book/index.html").is_file());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        use net::{resolve_client_ip, IpNet};
+        use rocket::http::HeaderMap;
+        use std::net::IpAddr;
+
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let net = |net: &str| IpNet::parse(net).unwrap();
+
+        assert!(net(" 10.0.0.0/8 ").contains(&ip("10.255.0.1")));
+        assert!(!net("10.0.0.0/8").contains(&ip("11.0.0.1")));
+        assert!(net("0.0.0.0/0").contains(&ip("203.0.113.7")));
+        assert!(!net("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(net("::/0").contains(&ip("2001:db8::1")));
+        assert!(!net("::/0").contains(&ip("203.0.113.7")));
+        assert!(net("192.0.2.1/32").contains(&ip("192.0.2.1")));
+        assert!(!net("192.0.2.1/32").contains(&ip("192.0.2.2")));
+        assert!(net("192.0.2.1").contains(&ip("192.0.2.1")));
+        assert!(!net("192.0.2.1").contains(&ip("192.0.2.0")));
+        assert!(net("2001:db8::1/128").contains(&ip("2001:db8::1")));
+        assert!(!net("2001:db8::1/128").contains(&ip("2001:db8::2")));
+        assert!(net("2001:db8::/32").contains(&ip("2001:db8:ffff::1")));
+        assert!(!net("2001:db8::/32").contains(&ip("2001:db9::1")));
+        for invalid in &[
+            "",
+            "proxy",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+        ] {
+            assert!(IpNet::parse(invalid).is_none(), "{}", invalid);
+        }
+
+        let trusted = [net("10.0.0.0/8"), net("2001:db8::/48")];
+        let headers = |name: &'static str, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.add_raw(name, value);
+            headers
+        };
+        let resolve =
+            |peer: &str, headers: &HeaderMap<'_>| resolve_client_ip(ip(peer), headers, &trusted);
+
+        // headers from clients that aren't proxies are ignored
+        let forwarded = headers("X-Forwarded-For", "192.0.2.1");
+        assert_eq!(resolve("203.0.113.7", &forwarded), ip("203.0.113.7"));
+        assert_eq!(resolve("10.0.0.1", &HeaderMap::new()), ip("10.0.0.1"));
+        assert_eq!(resolve("10.0.0.1", &forwarded), ip("192.0.2.1"));
+
+        // a client can put anything in front of the address the proxy adds
+        let spoofed = headers("X-Forwarded-For", "10.0.0.2, 192.0.2.1, 203.0.113.7");
+        assert_eq!(resolve("10.0.0.1", &spoofed), ip("203.0.113.7"));
+        let spoofed = headers("Forwarded", "for=192.0.2.1, for=203.0.113.7");
+        assert_eq!(resolve("10.0.0.1", &spoofed), ip("203.0.113.7"));
+
+        // the first hop that isn't trusted is the client, whatever it says it forwards
+        let hops = headers("X-Forwarded-For", "192.0.2.1, 198.51.100.2, 10.0.0.2");
+        assert_eq!(resolve("10.0.0.1", &hops), ip("198.51.100.2"));
+        let hops = headers("X-Forwarded-For", "10.0.0.3, 10.0.0.2");
+        assert_eq!(resolve("10.0.0.1", &hops), ip("10.0.0.3"));
+
+        let forwarded = headers(
+            "Forwarded",
+            r#"for="[2001:db8:cafe::17]:4711";proto=https, for="10.0.0.2:443""#,
+        );
+        assert_eq!(resolve("2001:db8::1", &forwarded), ip("2001:db8:cafe::17"));
+        let forwarded = headers("Forwarded", "proto=https;for=192.0.2.60;by=10.0.0.2");
+        assert_eq!(resolve("10.0.0.1", &forwarded), ip("192.0.2.60"));
+
+        // malformed entries are skipped, and without any address the proxy is the client
+        let malformed = headers("X-Forwarded-For", "unknown, 192.0.2.1:80, ,203.0.113.7");
+        assert_eq!(resolve("10.0.0.1", &malformed), ip("203.0.113.7"));
+        let malformed = headers("Forwarded", r#"for=unknown, for="_hidden", for"#);
+        assert_eq!(resolve("10.0.0.1", &malformed), ip("10.0.0.1"));
+        let malformed = headers("X-Forwarded-For", "not an address");
+        assert_eq!(resolve("10.0.0.1", &malformed), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn config_validation() {
+        Jail::expect_with(|jail| {
+            let typos = "\n[default]\nmax_page_szie = 10\n\n\
+                         [default.branding]\ntitel = \"Wiki\"\n\n\
+                         [defualt]\npath = \"wiki\"\n";
+            jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, typos))?;
+            jail.set_env("MDWIKI_PATH", "mdwiki-test-dir");
+            jail.set_env("MDWIKI_QUEUE_CAPCITY", "10");
+            let (_, problems) = validate::load().unwrap();
+            let problems = problems.iter().map(ToString::to_string).collect::<Vec<_>>();
+            assert_eq!(
+                problems,
+                [
+                    "unknown key 'branding.titel' in [default] (did you mean 'title'?)",
+                    "unknown key 'max_page_szie' in [default] (did you mean 'max_page_size'?)",
+                    "unknown profile [defualt], its keys are ignored \
+                     (move them to [default], [debug] or [release])",
+                    "unknown key 'queue_capcity' in [global] (did you mean 'queue_capacity'?)",
+                ]
+            );
+
+            jail.create_file("mdwiki.toml", "[default]\nallow_anonymous = false\n")?;
+            let problems = validate::load().unwrap_err();
+            assert_eq!(problems.len(), 2);
+            assert!(!problems[0].fatal);
+            assert!(problems[1].fatal);
+            assert!(problems[1].message.contains("no users"));
+            assert_eq!(block_on(cli::run(cli::Command::Check, Plugins::new())), 1);
+
+            jail.set_env("MDWIKI_ALLOW_ANONYMOUS", "maybe");
+            let problems = validate::load().unwrap_err();
+            assert!(problems
+                .iter()
+                .any(|problem| problem.fatal && problem.message.contains("allow_anonymous")));
+
+            Ok(())
+        });
+    }
+
+    #[rocket::async_test]
+    async fn commit_email() {
+        run_test(
+            Some(|jail: &mut Jail| {
+                jail.set_env("MDWIKI_COMMIT_EMAIL_DOMAIN", "example.org");
+                let admin = r#"
+[[debug.users]]
+username = "admin"
+password = "password"
+roles = ["admin"]
+"#;
+                jail.create_file("mdwiki.toml", &format!("{}{}", TEST_CONFIG, admin))
+                    .unwrap();
+            }),
+            async move |client: Client| {
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=user&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=signed.md&content=content")
+                    .dispatch()
+                    .await;
+
+                let book = std::env::current_dir().unwrap().join("mdwiki-test-dir");
+                let repo = git2::Repository::open(&book).unwrap();
+                let head = repo.head().unwrap().peel_to_commit().unwrap();
+                assert_eq!(head.author().email(), Some("user@example.org"));
+
+                let config = client.rocket().state::<Config>().unwrap();
+                let mut user = config::User {
+                    email: Some("user@users.noreply.github.com".into()),
+                    ..config::MDWIKI_USER.clone()
+                };
+                // unless the identity provider verified it, anyone could have set it
+                assert_eq!(config.commit_email(&user), "mdwiki@example.org");
+                user.email_verified = true;
+                assert_eq!(config.commit_email(&user), "user@users.noreply.github.com");
+                let mdwiki = config.commit_email(&config::MDWIKI_USER);
+                assert_eq!(mdwiki, "mdwiki@example.org");
+
+                // emails set by an admin are trusted
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=admin&password=password")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/admin/users")
+                    .header(ContentType::Form)
+                    .body("username=writer&password=secret&email=writer%40example.com&roles=")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/login")
+                    .header(ContentType::Form)
+                    .body("username=writer&password=secret")
+                    .dispatch()
+                    .await;
+                client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body("file=written.md&content=content")
+                    .dispatch()
+                    .await;
+                let head = repo.head().unwrap().peel_to_commit().unwrap();
+                assert_eq!(head.author().email(), Some("writer@example.com"));
+
+                Ok(())
+            },
+        )
+    }
+}
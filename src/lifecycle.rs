@@ -0,0 +1,232 @@
+use crate::webapp::page_url;
+
+use mdbook::book::Book;
+use mdbook::errors::Error;
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::BookItem;
+
+use serde::{Deserialize, Serialize};
+
+/// Where a page is in its life, set with `status: <status>` in the page's front matter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PageStatus {
+    Draft,
+    InReview,
+    Published,
+    Deprecated,
+}
+
+impl PageStatus {
+    pub const ALL: &'static [PageStatus] = &[
+        PageStatus::Draft,
+        PageStatus::InReview,
+        PageStatus::Published,
+        PageStatus::Deprecated,
+    ];
+    pub fn parse(name: &str) -> Option<PageStatus> {
+        PageStatus::ALL
+            .iter()
+            .copied()
+            .find(|status| status.name() == name)
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            PageStatus::Draft => "draft",
+            PageStatus::InReview => "in-review",
+            PageStatus::Published => "published",
+            PageStatus::Deprecated => "deprecated",
+        }
+    }
+    pub fn label(&self) -> &'static str {
+        match self {
+            PageStatus::Draft => "Draft",
+            PageStatus::InReview => "In review",
+            PageStatus::Published => "Published",
+            PageStatus::Deprecated => "Deprecated",
+        }
+    }
+    fn color(&self) -> &'static str {
+        match self {
+            PageStatus::Draft => "#6c757d",
+            PageStatus::InReview => "#b8860b",
+            PageStatus::Published => "#2e7d32",
+            PageStatus::Deprecated => "#c62828",
+        }
+    }
+}
+
+/// A change of status `allow` may make, usernames and `@name` for groups and roles. Anyone
+/// may make it if `allow` is empty.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Transition {
+    pub from: PageStatus,
+    pub to: PageStatus,
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// The page statuses, and who may change them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Lifecycle {
+    /// The status of pages without one, and what new pages are created from.
+    pub default: PageStatus,
+    /// Without any transitions, any status can be changed to any other.
+    #[serde(default)]
+    pub transitions: Vec<Transition>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Lifecycle {
+        Lifecycle {
+            default: PageStatus::Published,
+            transitions: Vec::new(),
+        }
+    }
+}
+
+/// The `key: value` lines of the front matter at the start of `content`, between two `---`
+/// lines, and the content following it.
+pub fn front_matter(content: &str) -> Option<(Vec<(&str, &str)>, &str)> {
+    let rest = content
+        .strip_prefix("---\n")
+        .or_else(|| content.strip_prefix("---\r\n"))?;
+    let mut fields = Vec::new();
+    let mut offset = 0;
+    while offset < rest.len() {
+        let end = rest[offset..]
+            .find('\n')
+            .map_or(rest.len(), |i| offset + i + 1);
+        let line = rest[offset..end].trim_end();
+        offset = end;
+        if line == "---" {
+            return Some((fields, &rest[offset..]));
+        }
+        if let Some(i) = line.find(':') {
+            fields.push((line[..i].trim(), line[i + 1..].trim()));
+        }
+    }
+    None
+}
+
+/// The value of the front matter field `name` in `content`, without quotes.
+fn field<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    front_matter(content)?
+        .0
+        .into_iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches(|c| c == '"' || c == '\''))
+        .filter(|value| !value.is_empty())
+}
+
+/// The page a deprecated page points readers to, relative to the book source.
+pub fn superseded_by(content: &str) -> Option<&str> {
+    field(content, "superseded_by").map(|page| page.trim_start_matches('/'))
+}
+
+/// The status set in the front matter of `content`, if any.
+pub fn page_status(content: &str) -> Result<Option<PageStatus>, String> {
+    match field(content, "status") {
+        Some(name) => PageStatus::parse(name).map(Some).ok_or_else(|| {
+            let names: Vec<_> = PageStatus::ALL.iter().map(PageStatus::name).collect();
+            format!(
+                "Unknown status '{}', it should be one of {}",
+                name,
+                names.join(", ")
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn badge(status: PageStatus) -> String {
+    format!(
+        "<span class=\"mdwiki-status mdwiki-status-{}\" style=\"background: {}; color: white; \
+         border-radius: 4px; padding: 2px 6px; font-size: 0.8em;\">{}</span>\n\n",
+        status.name(),
+        status.color(),
+        status.label()
+    )
+}
+
+/// The banner of a deprecated page, linking to the page in its `superseded_by` field, if any,
+/// and going there after `redirect_after` seconds, if set.
+fn deprecation_banner(content: &str) -> String {
+    let mut banner = "<div class=\"mdwiki-deprecated\" style=\"border: 2px solid #c62828; \
+                      border-radius: 4px; padding: 0.5em 1em; margin: 1em 0;\">\
+                      <strong>This page is deprecated.</strong>"
+        .to_string();
+    let successor = match superseded_by(content) {
+        Some(successor) => successor,
+        None => return banner + "</div>\n\n",
+    };
+    let url = format!("/{}", page_url(successor));
+    banner.push_str(&format!(
+        " It has been superseded by <a href=\"{}\">{}</a>.",
+        escape_html(&url),
+        escape_html(successor)
+    ));
+    let seconds = match field(content, "redirect_after").map(str::parse::<u64>) {
+        Some(Ok(seconds)) => seconds,
+        _ => return banner + "</div>\n\n",
+    };
+    banner.push_str(&format!(
+        " <span id=\"mdwiki-redirect\">Going there in <span id=\"mdwiki-redirect-seconds\">{}\
+         </span> seconds, <a href=\"#\" id=\"mdwiki-redirect-cancel\">stay here</a>.</span>\
+         </div>\n\n\
+         <script type=\"text/javascript\">(function () {{\
+         let seconds = {};\
+         const timer = setInterval(() => {{\
+         seconds -= 1;\
+         document.getElementById(\"mdwiki-redirect-seconds\").textContent = seconds;\
+         if (seconds <= 0) {{ clearInterval(timer); window.location.href = {}; }}\
+         }}, 1000);\
+         document.getElementById(\"mdwiki-redirect-cancel\").onclick = (e) => {{\
+         e.preventDefault();\
+         clearInterval(timer);\
+         document.getElementById(\"mdwiki-redirect\").remove();\
+         }};\
+         }})();</script>\n\n",
+        seconds,
+        seconds,
+        serde_json::to_string(&url).unwrap_or_default().replace('<', "\\u003c")
+    ));
+    banner
+}
+
+/// Replaces the front matter of every page with a badge showing the page's status, and a
+/// banner on deprecated pages.
+pub(crate) struct LifecyclePreprocessor;
+
+impl Preprocessor for LifecyclePreprocessor {
+    fn name(&self) -> &str {
+        "mdwiki-lifecycle"
+    }
+
+    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                let body = match front_matter(&chapter.content) {
+                    Some((_, body)) => body,
+                    None => return,
+                };
+                let header = match page_status(&chapter.content).ok().flatten() {
+                    Some(PageStatus::Deprecated) => {
+                        badge(PageStatus::Deprecated) + &deprecation_banner(&chapter.content)
+                    }
+                    Some(status) => badge(status),
+                    None => String::new(),
+                };
+                chapter.content = header + body;
+            }
+        });
+        Ok(book)
+    }
+}
@@ -0,0 +1,267 @@
+use crate::accessibility;
+use crate::utils::{url_path, SUMMARY_FRAGMENT};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use mdbook::utils::normalize_id;
+
+use pulldown_cmark::{Event, Parser, Tag};
+
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
+use serde::Serialize;
+
+const TODO_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b(TODO|FIXME)\b").unwrap());
+const IMG_TAG_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)<img\b[^>]*>").unwrap());
+const ATTRIBUTE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)\b(src|alt)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap()
+});
+
+const MAX_LINE_LENGTH: usize = 300;
+const MAX_PAGE_LINES: usize = 1000;
+
+#[derive(Debug, Serialize)]
+pub struct Issue {
+    pub path: String,
+    pub line: Option<usize>,
+    pub message: String,
+    /// Found by the accessibility audit, see `accessibility::audit_page`.
+    pub accessibility: bool,
+}
+
+/// A page's markdown, with what other pages need to know about it.
+struct Page {
+    path: String,
+    content: String,
+    anchors: HashSet<String>,
+}
+
+impl Page {
+    fn new(path: String, content: String) -> Page {
+        let mut anchors = HashSet::new();
+        let mut counts = HashMap::new();
+        let mut heading: Option<String> = None;
+        for event in Parser::new(&content) {
+            match event {
+                Event::Start(Tag::Heading(_)) => heading = Some(String::new()),
+                Event::Text(text) | Event::Code(text) => {
+                    if let Some(heading) = &mut heading {
+                        heading.push_str(&text);
+                    }
+                }
+                Event::End(Tag::Heading(_)) => {
+                    let id = normalize_id(heading.take().unwrap_or_default().trim());
+                    // mdbook numbers repeated ids
+                    let count = counts.entry(id.clone()).or_insert(0);
+                    if *count > 0 {
+                        anchors.insert(format!("{}-{}", id, count));
+                    }
+                    *count += 1;
+                    anchors.insert(id);
+                }
+                _ => {}
+            }
+        }
+        Page {
+            path,
+            content,
+            anchors,
+        }
+    }
+}
+
+/// `link` from the page at `page`, relative to the book source, or `None` for links that
+/// leave the book.
+fn resolve(page: &str, link: &str) -> Option<String> {
+    let mut resolved = PathBuf::new();
+    for component in Path::new(page).parent()?.join(link).components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(url_path(resolved))
+}
+
+pub fn line_of(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+/// An image in a page, written in markdown or with an `<img>` tag.
+#[derive(Debug)]
+pub struct Image {
+    pub line: usize,
+    pub src: String,
+    pub alt: String,
+}
+
+/// The images in `content`, leaving out code.
+pub fn images(content: &str) -> Vec<Image> {
+    let mut images = Vec::new();
+    let mut image: Option<(usize, String)> = None;
+    let mut in_code = false;
+    for (event, range) in Parser::new(content).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code = true,
+            Event::End(Tag::CodeBlock(_)) => in_code = false,
+            Event::Start(Tag::Image(..)) => image = Some((range.start, String::new())),
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, alt)) = &mut image {
+                    alt.push_str(&text);
+                }
+            }
+            Event::End(Tag::Image(_, dest, _)) => {
+                if let Some((start, alt)) = image.take() {
+                    images.push(Image {
+                        line: line_of(content, start),
+                        src: dest.to_string(),
+                        alt,
+                    });
+                }
+            }
+            Event::Html(html) if !in_code => {
+                for tag in IMG_TAG_REGEX.find_iter(&html) {
+                    let mut img = Image {
+                        line: line_of(content, range.start)
+                            + html[..tag.start()].matches('\n').count(),
+                        src: String::new(),
+                        alt: String::new(),
+                    };
+                    for attribute in ATTRIBUTE_REGEX.captures_iter(tag.as_str()) {
+                        let value = (2..=4)
+                            .find_map(|i| attribute.get(i))
+                            .map_or("", |value| value.as_str())
+                            .to_string();
+                        if attribute[1].eq_ignore_ascii_case("src") {
+                            img.src = value;
+                        } else {
+                            img.alt = value;
+                        }
+                    }
+                    images.push(img);
+                }
+            }
+            _ => {}
+        }
+    }
+    images
+}
+
+fn lint_page(page: &Page, pages: &HashMap<String, Page>, issues: &mut Vec<Issue>) {
+    let mut issue = |line: Option<usize>, message: String| {
+        issues.push(Issue {
+            path: page.path.clone(),
+            line,
+            message,
+            accessibility: false,
+        })
+    };
+    let content = &page.content;
+
+    let mut title = false;
+    let mut in_code = false;
+    for (event, range) in Parser::new(content).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading(1)) => title = true,
+            Event::Start(Tag::CodeBlock(_)) => in_code = true,
+            Event::End(Tag::CodeBlock(_)) => in_code = false,
+            Event::Start(Tag::Link(_, dest, _)) if !in_code => {
+                if dest.contains(':') || dest.starts_with('/') {
+                    continue;
+                }
+                let (target, anchor) = match dest.find('#') {
+                    Some(i) => (&dest[..i], Some(&dest[i + 1..])),
+                    None => (&*dest, None),
+                };
+                let target = match target {
+                    "" => Some(page),
+                    target if target.ends_with(".md") => {
+                        resolve(&page.path, target).and_then(|target| pages.get(&target))
+                    }
+                    _ => continue,
+                };
+                let line = Some(line_of(content, range.start));
+                match (target, anchor) {
+                    (None, _) => issue(line, format!("Link to missing page '{}'", dest)),
+                    (Some(target), Some(anchor)) if !target.anchors.contains(anchor) => {
+                        issue(line, format!("Link to missing heading '{}'", dest))
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    if !title {
+        issue(None, "No title, the page should start with a `# ` heading".to_string());
+    }
+
+    let lines = content.lines().count();
+    if lines > MAX_PAGE_LINES {
+        issue(
+            None,
+            format!("{} lines long, consider splitting it into several pages", lines),
+        );
+    }
+    for (i, line) in content.lines().enumerate() {
+        let length = line.chars().count();
+        if length > MAX_LINE_LENGTH {
+            issue(Some(i + 1), format!("Line is {} characters long", length));
+        }
+        if let Some(todo) = TODO_REGEX.find(line) {
+            issue(Some(i + 1), format!("{} marker", todo.as_str()));
+        }
+    }
+}
+
+fn read_pages(src: &Path, dir: &Path, pages: &mut HashMap<String, Page>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("could not read directory '{}': {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let relative = path.strip_prefix(src).unwrap_or(&path).to_path_buf();
+        if path.is_dir() {
+            if !relative.starts_with("images") && !relative.starts_with("files") {
+                read_pages(src, &path, pages);
+            }
+        } else if path.extension().map_or(false, |ext| ext == "md")
+            && !relative.ends_with("SUMMARY.md")
+            && !relative.ends_with(SUMMARY_FRAGMENT)
+        {
+            if let Ok(content) = fs::read_to_string(&path) {
+                let relative = url_path(relative);
+                pages.insert(relative.clone(), Page::new(relative, content));
+            }
+        }
+    }
+}
+
+/// The issues with the pages of the book at `path`, ordered by page and line.
+pub fn lint(path: &str) -> Vec<Issue> {
+    let src = Path::new(path).join("src");
+    let mut pages = HashMap::new();
+    read_pages(&src, &src, &mut pages);
+
+    let mut issues = Vec::new();
+    for page in pages.values() {
+        lint_page(page, &pages, &mut issues);
+        issues.extend(accessibility::audit_page(&page.path, &page.content));
+    }
+    issues.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    issues
+}
@@ -0,0 +1,58 @@
+use crate::config::User;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Who is editing a page.
+#[derive(Debug, Clone)]
+pub struct EditLock {
+    pub username: String,
+    /// The display name of the user, for telling others who is editing.
+    pub name: String,
+    expires: Instant,
+}
+
+/// Advisory locks on the pages being edited, so others know to wait. They aren't enforced when
+/// saving, and expire unless the editor renews them.
+#[derive(Debug, Default)]
+pub struct EditLocks {
+    locks: Mutex<HashMap<String, EditLock>>,
+}
+
+impl EditLocks {
+    /// Locks `page` for `user` for `duration`, or renews their lock. If someone else holds the
+    /// lock, it's taken from them with `take_over`, and returned otherwise.
+    pub fn acquire(
+        &self,
+        page: &str,
+        user: &User,
+        duration: Duration,
+        take_over: bool,
+    ) -> Result<(), EditLock> {
+        let mut locks = self.locks.lock().unwrap();
+        let now = Instant::now();
+        locks.retain(|_, lock| lock.expires > now);
+        if let Some(lock) = locks.get(page) {
+            if lock.username != user.username && !take_over {
+                return Err(lock.clone());
+            }
+        }
+        locks.insert(
+            page.to_string(),
+            EditLock {
+                username: user.username.clone(),
+                name: user.name().to_string(),
+                expires: now + duration,
+            },
+        );
+        Ok(())
+    }
+    /// Releases the lock on `page`, if `username` holds it.
+    pub fn release(&self, page: &str, username: &str) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.get(page).map_or(false, |lock| lock.username == username) {
+            locks.remove(page);
+        }
+    }
+}
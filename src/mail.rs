@@ -0,0 +1,70 @@
+use crate::config::{SmtpConfig, SmtpSecurity};
+
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+
+use rocket::tokio::task;
+
+/// Sends plain text emails through the configured SMTP server.
+#[derive(Clone)]
+pub struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl std::fmt::Debug for Mailer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mailer").field("from", &self.from).finish()
+    }
+}
+
+impl Mailer {
+    pub fn new(config: &SmtpConfig) -> Result<Mailer, String> {
+        let from = config
+            .from
+            .parse()
+            .map_err(|e| format!("invalid smtp from address '{}': {}", config.from, e))?;
+
+        let builder = match config.security {
+            SmtpSecurity::Starttls => SmtpTransport::starttls_relay(&config.host),
+            SmtpSecurity::Tls => SmtpTransport::relay(&config.host),
+            SmtpSecurity::None => Ok(SmtpTransport::builder_dangerous(&config.host)),
+        }
+        .map_err(|e| format!("invalid smtp server '{}': {}", config.host, e))?;
+        let builder = match config.port {
+            Some(port) => builder.port(port),
+            None => builder,
+        };
+        let builder = match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                builder.credentials(Credentials::new(username.clone(), password.clone()))
+            }
+            _ => builder,
+        };
+
+        Ok(Mailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), String> {
+        let to: Mailbox = to
+            .parse()
+            .map_err(|e| format!("invalid email address '{}': {}", to, e))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body)
+            .map_err(|e| format!("could not build email: {}", e))?;
+
+        // The smtp transport blocks
+        let transport = self.transport.clone();
+        task::spawn_blocking(move || transport.send(&message))
+            .await
+            .map_err(|e| format!("email task failed: {}", e))?
+            .map(|_| ())
+            .map_err(|e| format!("could not send email: {}", e))
+    }
+}
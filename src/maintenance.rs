@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use git2::{Buf, ObjectType, Repository};
+
+/// Unreachable loose objects younger than this are kept, a commit might be about to use them.
+const PRUNE_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// How much space the objects of a repository take.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RepoStats {
+    pub loose_objects: usize,
+    pub loose_size: u64,
+    pub packs: usize,
+    pub pack_size: u64,
+}
+
+impl fmt::Display for RepoStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} loose objects ({} KiB), {} packs ({} KiB)",
+            self.loose_objects,
+            self.loose_size / 1024,
+            self.packs,
+            self.pack_size / 1024
+        )
+    }
+}
+
+fn is_loose_dir(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| {
+            name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit())
+        })
+}
+
+/// The loose object files in `objects`.
+fn loose_objects(objects: &Path) -> Vec<PathBuf> {
+    let dirs = match fs::read_dir(objects) {
+        Ok(dirs) => dirs,
+        Err(_) => return Vec::new(),
+    };
+    dirs.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && is_loose_dir(path))
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|files| {
+            files
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+        })
+        .collect()
+}
+
+/// The pack files in `objects`, with their indexes.
+fn pack_files(objects: &Path) -> HashSet<PathBuf> {
+    match fs::read_dir(objects.join("pack")) {
+        Ok(files) => files
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |ext| ext == "pack" || ext == "idx")
+            })
+            .collect(),
+        Err(_) => HashSet::new(),
+    }
+}
+
+fn size(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+fn stats(repo: &Repository) -> RepoStats {
+    let objects = repo.path().join("objects");
+    let loose = loose_objects(&objects);
+    let packs = pack_files(&objects);
+    RepoStats {
+        loose_objects: loose.len(),
+        loose_size: loose.iter().map(|path| size(path)).sum(),
+        packs: packs
+            .iter()
+            .filter(|path| path.extension().map_or(false, |ext| ext == "pack"))
+            .count(),
+        pack_size: packs.iter().map(|path| size(path)).sum(),
+    }
+}
+
+/// Writes every object reachable from a reference to a single new pack, and returns whether a
+/// new pack was written. It isn't if there's nothing to pack, or the same pack already exists.
+fn repack(repo: &Repository, objects: &Path) -> Result<bool, String> {
+    let err = |e: git2::Error| format!("failed to repack repository: {}", e);
+    let mut builder = repo.packbuilder().map_err(err)?;
+    let mut walk = repo.revwalk().map_err(err)?;
+    walk.push_glob("*").map_err(err)?;
+    if repo.head().is_ok() {
+        walk.push_head().map_err(err)?;
+    }
+    builder.insert_walk(&mut walk).map_err(err)?;
+    // the walk only has the commits tags point to, not annotated tags themselves
+    for reference in repo.references().map_err(err)?.filter_map(|r| r.ok()) {
+        if let Some(id) = reference.target() {
+            let object = repo.find_object(id, None).ok();
+            if object.and_then(|object| object.kind()) == Some(ObjectType::Tag) {
+                builder.insert_object(id, None).map_err(err)?;
+            }
+        }
+    }
+    if builder.object_count() == 0 {
+        return Ok(false);
+    }
+
+    let mut buf = Buf::new();
+    builder.write_buf(&mut buf).map_err(err)?;
+    let before = pack_files(objects);
+    let odb = repo.odb().map_err(err)?;
+    let mut writer = odb.packwriter().map_err(err)?;
+    writer
+        .write_all(&buf)
+        .map_err(|e| format!("failed to write pack: {}", e))?;
+    writer.commit().map_err(err)?;
+    Ok(pack_files(objects).difference(&before).next().is_some())
+}
+
+/// Packs the objects of the repository at `path` into a single pack, removing the packs it
+/// replaces and loose objects older than `PRUNE_GRACE`, and returns the repository's stats
+/// from before and after.
+pub fn gc(path: &str) -> Result<(RepoStats, RepoStats), String> {
+    let repo = Repository::open(path).map_err(|e| format!("failed to open repository: {}", e))?;
+    let objects = repo.path().join("objects");
+    let before = stats(&repo);
+    let old_packs = pack_files(&objects);
+    let old_loose = loose_objects(&objects);
+
+    if !repack(&repo, &objects)? {
+        return Ok((before, before));
+    }
+    for pack in old_packs {
+        let _ = fs::remove_file(pack);
+    }
+    let now = SystemTime::now();
+    for object in old_loose {
+        let age = fs::metadata(&object)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok());
+        if age.map_or(false, |age| age >= PRUNE_GRACE) {
+            let _ = fs::remove_file(&object);
+            if let Some(dir) = object.parent() {
+                // fails unless the directory is empty
+                let _ = fs::remove_dir(dir);
+            }
+        }
+    }
+    Ok((before, stats(&repo)))
+}
@@ -0,0 +1,180 @@
+use crate::session::now;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use git2::build::CheckoutBuilder;
+use git2::{Index, ObjectType, Oid, Repository, Tree};
+
+const MERGE_FILENAME: &str = "page.md";
+const USER_FILENAME: &str = "user";
+
+/// Identifies the version of a page an edit started from, the id git gives the content as a blob.
+pub fn revision(content: &str) -> String {
+    Oid::hash_object(ObjectType::Blob, content.as_bytes())
+        .map(|oid| oid.to_string())
+        .unwrap_or_default()
+}
+
+fn tree_with(repo: &Repository, content: &[u8]) -> Result<Tree<'_>, git2::Error> {
+    let blob = repo.blob(content)?;
+    let mut builder = repo.treebuilder(None)?;
+    builder.insert(MERGE_FILENAME, blob, 0o100644)?;
+    repo.find_tree(builder.write()?)
+}
+
+/// The content of a page at `revision`, if it has ever been committed.
+pub fn content_at(repo: &Repository, revision: &str) -> Option<String> {
+    let blob = repo.find_blob(Oid::from_str(revision).ok()?).ok()?;
+    Some(String::from_utf8_lossy(blob.content()).into_owned())
+}
+
+fn merge_index(
+    repo: &Repository,
+    base: &str,
+    current: &str,
+    edit: &str,
+) -> Result<Index, git2::Error> {
+    repo.merge_trees(
+        &tree_with(repo, base.as_bytes())?,
+        &tree_with(repo, current.as_bytes())?,
+        &tree_with(repo, edit.as_bytes())?,
+        None,
+    )
+}
+
+/// Three-way merges the changes from `base` to `current` and from `base` to `edit`. Returns
+/// `None` if the changes conflict.
+pub fn merge(
+    repo: &Repository,
+    base: &str,
+    current: &str,
+    edit: &str,
+) -> Result<Option<String>, git2::Error> {
+    let index = merge_index(repo, base, current, edit)?;
+    if index.has_conflicts() {
+        return Ok(None);
+    }
+    let merged = match index.get_path(Path::new(MERGE_FILENAME), 0) {
+        Some(entry) => repo.find_blob(entry.id)?,
+        None => return Ok(None),
+    };
+    Ok(Some(String::from_utf8_lossy(merged.content()).into_owned()))
+}
+
+/// Merges like `merge`, but keeps both sides of the hunks that conflict between conflict
+/// markers, labelled "current version" and "your edit", for the editor to resolve. The merge is
+/// written in `work_dir`, which is removed afterwards.
+pub fn mark_conflicts(
+    repo: &Repository,
+    base: &str,
+    current: &str,
+    edit: &str,
+    work_dir: &Path,
+) -> Result<String, String> {
+    let err = |e: git2::Error| format!("could not merge: {}", e);
+    let mut index = merge_index(repo, base, current, edit).map_err(err)?;
+    fs::create_dir_all(work_dir)
+        .map_err(|e| format!("could not create '{}': {}", work_dir.display(), e))?;
+    let mut checkout = CheckoutBuilder::new();
+    checkout
+        .target_dir(work_dir)
+        .force()
+        .allow_conflicts(true)
+        .conflict_style_merge(true)
+        .our_label("current version")
+        .their_label("your edit");
+    let merged = repo
+        .checkout_index(Some(&mut index), Some(&mut checkout))
+        .map_err(err)
+        .and_then(|_| {
+            fs::read_to_string(work_dir.join(MERGE_FILENAME))
+                .map_err(|e| format!("could not read merge: {}", e))
+        });
+    let _ = fs::remove_dir_all(work_dir);
+    merged
+}
+
+/// The versions of a page involved in a conflicting edit.
+pub struct Conflict<'a> {
+    pub file: &'a Path,
+    pub username: &'a str,
+    pub base: &'a str,
+    pub current: &'a str,
+    pub edit: &'a str,
+}
+
+impl<'a> Conflict<'a> {
+    fn write_versions(&self, dir: &Path) -> Result<(PathBuf, PathBuf, PathBuf), String> {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("could not create '{}': {}", dir.display(), e))?;
+        let paths = (
+            dir.join("base.md"),
+            dir.join("current.md"),
+            dir.join("edit.md"),
+        );
+        for (path, content) in &[
+            (&paths.0, self.base),
+            (&paths.1, self.current),
+            (&paths.2, self.edit),
+        ] {
+            fs::write(path, content)
+                .map_err(|e| format!("could not write '{}': {}", path.display(), e))?;
+        }
+        Ok(paths)
+    }
+    /// Runs an external merge driver, following git's convention: `%O`, `%A` and `%B` in the
+    /// command are replaced with files containing the base, current and edited versions, `%P`
+    /// with the path of the page, and on success the driver leaves the merged result in `%A`.
+    pub fn run_driver(&self, driver: &str, work_dir: &Path) -> Result<Option<String>, String> {
+        let (base, current, edit) = self.write_versions(work_dir)?;
+        let file = self.file.to_string_lossy();
+
+        let mut args = driver.split_whitespace().map(|arg| {
+            arg.replace("%O", &base.to_string_lossy())
+                .replace("%A", &current.to_string_lossy())
+                .replace("%B", &edit.to_string_lossy())
+                .replace("%P", &file)
+        });
+        let program = args.next().ok_or("merge driver is empty")?;
+        let status = Command::new(&program)
+            .args(args)
+            .status()
+            .map_err(|e| format!("could not run merge driver '{}': {}", program, e));
+
+        let merged = match status {
+            Ok(status) if status.success() => fs::read_to_string(&current).ok(),
+            Ok(status) => {
+                info!("merge driver failed to merge {}: {}", file, status);
+                None
+            }
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        };
+        let _ = fs::remove_dir_all(work_dir);
+        Ok(merged)
+    }
+    /// Keeps every version of the page in the review area, returning the directory they're in.
+    /// The editor's username is kept in a `user` file next to the versions, see `saved_by`.
+    pub fn save_for_review(&self, review_dir: &Path) -> Result<PathBuf, String> {
+        let dir = review_dir.join(format!(
+            "{}-{}-{}",
+            now(),
+            self.username,
+            self.file.to_string_lossy().replace('/', "_")
+        ));
+        self.write_versions(&dir)?;
+        let user = dir.join(USER_FILENAME);
+        fs::write(&user, self.username)
+            .map_err(|e| format!("could not write '{}': {}", user.display(), e))?;
+        Ok(dir)
+    }
+}
+
+/// The username of whoever made the conflicting edit saved in `dir` by `save_for_review`.
+pub fn saved_by(dir: &Path) -> Option<String> {
+    fs::read_to_string(dir.join(USER_FILENAME)).ok()
+}
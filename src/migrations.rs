@@ -0,0 +1,120 @@
+//! Upgrades books made by older versions of mdwiki to the layout this version expects. Every
+//! book records the version of its layout in `LAYOUT_FILE`, and on startup the migrations for
+//! newer versions are run in order, each committed on its own. Books without the file are from
+//! before layouts were versioned, version 0.
+
+use crate::wiki::MDWIKI_GITIGNORE;
+
+use std::fs;
+use std::path::Path;
+
+/// Where the version of the layout is recorded, relative to the book.
+pub const LAYOUT_FILE: &str = ".mdwiki-layout";
+
+/// A change to the files mdwiki manages in the book.
+pub struct Migration {
+    /// The version of the layout after the migration.
+    pub version: u32,
+    /// What the migration does, for the commit message.
+    pub description: &'static str,
+    /// Changes the book at the path, leaving the changes uncommitted.
+    pub run: fn(&Path) -> Result<(), String>,
+}
+
+/// Every migration, oldest first. New ones go at the end, with the next version.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Keep mdwiki's files out of the repository",
+        run: update_gitignore,
+    },
+    Migration {
+        version: 2,
+        description: "Keep the theme in the repository",
+        run: track_theme,
+    },
+];
+
+/// The version of the layout books are created with.
+pub fn latest() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// The version of the layout of the book at `path`.
+pub fn layout(path: &Path) -> Result<u32, String> {
+    let file = path.join(LAYOUT_FILE);
+    match fs::read_to_string(&file) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid layout version in '{}': {}", file.display(), e)),
+        Err(_) => Ok(0),
+    }
+}
+
+/// Records that the book at `path` has the layout `version`.
+pub fn set_layout(path: &Path, version: u32) -> Result<(), String> {
+    let file = path.join(LAYOUT_FILE);
+    fs::write(&file, format!("{}\n", version))
+        .map_err(|e| format!("could not write '{}': {}", file.display(), e))
+}
+
+/// The migrations the book at `path` still needs, oldest first. Fails for books upgraded by a
+/// newer version of mdwiki, which this one might break.
+pub fn pending(path: &Path) -> Result<Vec<&'static Migration>, String> {
+    let version = layout(path)?;
+    if version > latest() {
+        return Err(format!(
+            "the book has layout version {}, but this version of mdwiki only knows up to {}, \
+             upgrade mdwiki to use it",
+            version,
+            latest()
+        ));
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > version)
+        .collect())
+}
+
+/// Adds the lines of the default `.gitignore` the book's is missing: the build output and the
+/// data directory.
+fn update_gitignore(path: &Path) -> Result<(), String> {
+    let file = path.join(".gitignore");
+    let mut gitignore = fs::read_to_string(&file).unwrap_or_default();
+    let missing = MDWIKI_GITIGNORE
+        .lines()
+        .filter(|line| !gitignore.lines().any(|existing| existing.trim() == *line))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if !gitignore.is_empty() && !gitignore.ends_with('\n') {
+        gitignore.push('\n');
+    }
+    for line in missing {
+        gitignore.push_str(line);
+        gitignore.push('\n');
+    }
+    fs::write(&file, gitignore).map_err(|e| format!("could not write .gitignore: {}", e))
+}
+
+/// Stops ignoring `theme/head.hbs`, which mdwiki used to generate, now that mdwiki's script is
+/// kept in a block of it and the rest is the book's own.
+fn track_theme(path: &Path) -> Result<(), String> {
+    let file = path.join(".gitignore");
+    let gitignore = match fs::read_to_string(&file) {
+        Ok(gitignore) => gitignore,
+        Err(_) => return Ok(()),
+    };
+    let is_theme = |line: &str| line.trim() == "theme/head.hbs";
+    if !gitignore.lines().any(is_theme) {
+        return Ok(());
+    }
+    let kept = gitignore
+        .lines()
+        .filter(|line| !is_theme(line))
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+    fs::write(&file, kept).map_err(|e| format!("could not write .gitignore: {}", e))
+}
@@ -0,0 +1,203 @@
+//! Mirrors of the repository, remotes every commit is pushed to, like GitHub and an internal
+//! backup server. Pushes happen in the background after commits, so a slow or unreachable
+//! mirror doesn't hold up the wiki, and failures that may pass, like a dropped connection, are
+//! retried.
+
+use crate::config::Config;
+use crate::faults::Step;
+use crate::remote::{self, RemoteConfig};
+use crate::session::now;
+use crate::shared::SharedState;
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use rocket::tokio::sync::Notify;
+use rocket::tokio::task;
+use rocket::tokio::time::sleep;
+
+use git2::{ErrorClass, ErrorCode, PushOptions, Repository};
+
+use serde::Serialize;
+
+/// How a mirror is doing, for `/admin/mirrors`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MirrorStatus {
+    pub url: String,
+    /// The commit the mirror was last brought up to.
+    pub commit: Option<String>,
+    /// Unix time of the last successful push.
+    pub pushed: Option<u64>,
+    /// Why the last push failed, if it did.
+    pub error: Option<String>,
+    /// Failed attempts since the last successful push.
+    pub failures: u32,
+}
+
+#[derive(Debug)]
+pub struct Mirrors {
+    remotes: Vec<RemoteConfig>,
+    retries: u32,
+    status: RwLock<Vec<MirrorStatus>>,
+    pending: Notify,
+}
+
+impl Mirrors {
+    pub fn new(config: &Config) -> Mirrors {
+        let status = config
+            .mirrors
+            .iter()
+            .map(|remote| MirrorStatus {
+                url: remote.url.clone(),
+                commit: None,
+                pushed: None,
+                error: None,
+                failures: 0,
+            })
+            .collect();
+        Mirrors {
+            remotes: config.mirrors.clone(),
+            retries: config.mirror_retries,
+            status: RwLock::new(status),
+            pending: Notify::new(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.remotes.is_empty()
+    }
+    /// How every mirror is doing, in the order they're configured.
+    pub fn status(&self) -> Vec<MirrorStatus> {
+        self.status.read().unwrap().clone()
+    }
+    /// Has the mirrors brought up to date. Commits made while they're pushed to are pushed
+    /// right after.
+    pub fn changed(&self) {
+        self.pending.notify_one();
+    }
+    fn update(&self, i: usize, update: impl FnOnce(&mut MirrorStatus)) {
+        if let Some(status) = self.status.write().unwrap().get_mut(i) {
+            update(status);
+        }
+    }
+}
+
+struct PushError {
+    message: String,
+    /// Whether pushing again might work, which it won't with wrong credentials or a mirror
+    /// that refuses the branch.
+    transient: bool,
+}
+
+impl From<git2::Error> for PushError {
+    fn from(e: git2::Error) -> PushError {
+        let network = matches!(
+            e.class(),
+            ErrorClass::Net | ErrorClass::Os | ErrorClass::Http | ErrorClass::Ssh
+        );
+        PushError {
+            message: e.message().to_string(),
+            transient: network && e.code() != ErrorCode::Auth,
+        }
+    }
+}
+
+/// Pushes the current branch of the repository at `path` to `remote`, replacing what the
+/// mirror has. Returns the commit pushed.
+fn push(path: &str, remote: &RemoteConfig) -> Result<String, PushError> {
+    let repo = Repository::open(path)?;
+    let head = repo.head()?;
+    let branch = head.name().unwrap_or("HEAD").to_string();
+    let commit = head.peel_to_commit()?.id().to_string();
+
+    let mut rejected = None;
+    {
+        let mut callbacks = remote::callbacks(remote);
+        callbacks.push_update_reference(|_, status| {
+            if let Some(status) = status {
+                rejected = Some(status.to_string());
+            }
+            Ok(())
+        });
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+        let mut mirror = repo.remote_anonymous(&remote.url)?;
+        mirror.push(&[format!("+{}:{}", branch, branch)], Some(&mut options))?;
+    }
+    match rejected {
+        Some(status) => Err(PushError {
+            message: format!("the mirror rejected {}: {}", branch, status),
+            transient: false,
+        }),
+        None => Ok(commit),
+    }
+}
+
+/// Brings the `i`th mirror up to date, retrying up to `retries` times while the push fails in
+/// a way that might pass, waiting twice as long before each retry.
+async fn sync(path: &str, shared: &SharedState, i: usize) {
+    let mirrors = &shared.mirrors;
+    let remote = &mirrors.remotes[i];
+    let mut delay = Duration::from_secs(1);
+    for attempt in 0..=mirrors.retries {
+        if attempt > 0 {
+            sleep(delay).await;
+            delay *= 2;
+        }
+        let res = match shared.faults.check(Step::Push) {
+            Ok(_) => {
+                let (path, remote) = (path.to_string(), remote.clone());
+                task::spawn_blocking(move || push(&path, &remote))
+                    .await
+                    .unwrap_or_else(|e| {
+                        Err(PushError {
+                            message: e.to_string(),
+                            transient: false,
+                        })
+                    })
+            }
+            Err(message) => Err(PushError {
+                message,
+                transient: true,
+            }),
+        };
+        match res {
+            Ok(commit) => {
+                debug!("pushed {} to {}", commit, remote.url);
+                mirrors.update(i, |status| {
+                    status.commit = Some(commit);
+                    status.pushed = Some(now());
+                    status.error = None;
+                    status.failures = 0;
+                });
+                return;
+            }
+            Err(PushError { message, transient }) => {
+                warn!(
+                    "could not push to '{}': {} (attempt {} of {})",
+                    remote.url,
+                    message,
+                    attempt + 1,
+                    mirrors.retries + 1
+                );
+                mirrors.update(i, |status| {
+                    status.error = Some(message);
+                    status.failures += 1;
+                });
+                if !transient {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Pushes to every mirror on startup and after every commit, as long as the wiki runs.
+pub async fn run(path: String, shared: Arc<SharedState>) {
+    shared.mirrors.changed();
+    loop {
+        shared.mirrors.pending.notified().await;
+        for i in 0..shared.mirrors.remotes.len() {
+            sync(&path, &shared, i).await;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::config::Config;
+
+use std::net::IpAddr;
+
+use rocket::http::HeaderMap;
+use rocket::request::{self, FromRequest, Request};
+use rocket::State;
+
+/// The address of the client making the request. Forwarding headers are only trusted when the
+/// direct peer (and every hop after the client) is one of the configured `trusted_proxies`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let config = try_outcome!(req.guard::<State<'r, Config>>().await);
+
+        let peer = match req.remote() {
+            Some(addr) => addr.ip(),
+            None => return request::Outcome::Forward(()),
+        };
+
+        let trusted: Vec<_> = config
+            .trusted_proxies
+            .iter()
+            .filter_map(|proxy| IpNet::parse(proxy))
+            .collect();
+
+        request::Outcome::Success(ClientIp(resolve_client_ip(
+            peer,
+            req.headers(),
+            &trusted,
+        )))
+    }
+}
+
+impl std::fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Parses either a single address or a network in CIDR notation.
+    pub fn parse(s: &str) -> Option<IpNet> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts.next()?.parse().ok()?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max_prefix)?,
+            None => max_prefix,
+        };
+        Some(IpNet { addr, prefix })
+    }
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn forwarded_chain(headers: &HeaderMap<'_>) -> Vec<IpAddr> {
+    let forwarded: Vec<_> = headers
+        .get("Forwarded")
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let mut pair = pair.trim().splitn(2, '=');
+                match (pair.next(), pair.next()) {
+                    (Some(key), Some(value)) if key.eq_ignore_ascii_case("for") => {
+                        parse_forwarded_node(value)
+                    }
+                    _ => None,
+                }
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get("X-Forwarded-For")
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect()
+}
+
+/// Parses a node from the `Forwarded` header, like `192.0.2.60`, `"192.0.2.60:443"` or
+/// `"[2001:db8::17]:4711"`.
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.splitn(2, ':').next()?.parse().ok())
+}
+
+pub fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap<'_>, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    // Walk the chain from the closest hop, the first untrusted address is the client
+    let chain = forwarded_chain(headers);
+    chain
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or_else(|| chain.first())
+        .copied()
+        .unwrap_or(peer)
+}
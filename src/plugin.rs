@@ -0,0 +1,163 @@
+pub use crate::config::{Config, User};
+pub use crate::events::{Event, EventKind};
+
+use std::path::Path;
+use std::sync::Arc;
+
+use mdbook::book::Book;
+use mdbook::errors::Error;
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::BookItem;
+
+use rocket::Route;
+
+/// Extension point for features that don't belong in mdwiki itself. Every hook has a default
+/// that does nothing, so plugins only implement the hooks they need.
+///
+/// Plugins are registered at compile time, by depending on mdwiki as a library and starting it
+/// with `mdwiki::run(Plugins::new().with(MyPlugin))`.
+pub trait Plugin: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Called before a page is created or changed, an error rejects the change with the error as
+    /// the message shown to the user.
+    fn on_save(&self, _user: &User, _path: &Path, _content: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Transforms the markdown of a page while the book is built. The source files are not
+    /// changed.
+    fn transform_markdown(&self, _path: &Path, content: String) -> String {
+        content
+    }
+
+    /// Renders the markdown of a page to html instead of mdbook, when `renderer` is set to
+    /// `plugins`. `None` leaves the page to the next plugin, and to mdbook if no plugin renders
+    /// it.
+    fn render_markdown(&self, _path: &Path, _markdown: &str) -> Option<String> {
+        None
+    }
+
+    /// Names an uploaded image, when `upload_names` is set to `plugins`. `original` is the name
+    /// the file was uploaded with, if it had one. Names may only have letters, digits, `_` and
+    /// `-`, mdwiki adds the extension, and an image with the same name is replaced. `None` leaves
+    /// the image to the next plugin, and to a random name if no plugin names it.
+    fn upload_name(&self, _original: Option<&str>, _content: &[u8]) -> Option<String> {
+        None
+    }
+
+    /// Called after every build of the book.
+    fn on_build(&self, _success: bool) {}
+
+    /// Called for every wiki event, see `EventKind`.
+    fn on_event(&self, _event: &Event) {}
+
+    /// Routes mounted at `/` next to mdwiki's own.
+    fn routes(&self) -> Vec<Route> {
+        Vec::new()
+    }
+}
+
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+impl Plugins {
+    pub fn new() -> Plugins {
+        Plugins::default()
+    }
+    pub fn with(mut self, plugin: impl Plugin + 'static) -> Plugins {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+    /// Adds the configured Lua scripts as plugins. Scripts that can't be loaded are fatal, as
+    /// running without a validation script could let unwanted changes through.
+    pub(crate) fn with_scripts(self, scripts: &[String]) -> Plugins {
+        #[cfg(feature = "lua")]
+        {
+            scripts.iter().fold(self, |plugins, script| {
+                plugins.with(crate::scripting::LuaScript::load(script).unwrap())
+            })
+        }
+        #[cfg(not(feature = "lua"))]
+        {
+            for script in scripts {
+                error!("can't load '{}', mdwiki was built without lua support", script);
+            }
+            self
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+    pub(crate) fn on_save(&self, user: &User, path: &Path, content: &str) -> Result<(), String> {
+        for plugin in &self.plugins {
+            plugin.on_save(user, path, content)?;
+        }
+        Ok(())
+    }
+    pub(crate) fn transform_markdown(&self, path: &Path, content: String) -> String {
+        self.plugins
+            .iter()
+            .fold(content, |content, plugin| plugin.transform_markdown(path, content))
+    }
+    pub(crate) fn render_markdown(&self, path: &Path, markdown: &str) -> Option<String> {
+        self.plugins
+            .iter()
+            .find_map(|plugin| plugin.render_markdown(path, markdown))
+    }
+    pub(crate) fn upload_name(&self, original: Option<&str>, content: &[u8]) -> Option<String> {
+        self.plugins
+            .iter()
+            .find_map(|plugin| plugin.upload_name(original, content))
+    }
+    pub(crate) fn on_build(&self, success: bool) {
+        for plugin in &self.plugins {
+            plugin.on_build(success);
+        }
+    }
+    pub(crate) fn on_event(&self, event: &Event) {
+        for plugin in &self.plugins {
+            plugin.on_event(event);
+        }
+    }
+    pub(crate) fn routes(&self) -> Vec<Route> {
+        self.plugins
+            .iter()
+            .flat_map(|plugin| {
+                info!("mounting routes for plugin '{}'", plugin.name());
+                plugin.routes()
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.plugins.iter().map(|plugin| plugin.name()))
+            .finish()
+    }
+}
+
+/// Runs the plugins' markdown transforms as part of the mdbook build.
+pub(crate) struct PluginPreprocessor(pub Arc<Plugins>);
+
+impl Preprocessor for PluginPreprocessor {
+    fn name(&self) -> &str {
+        "mdwiki-plugins"
+    }
+
+    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                if let Some(path) = &chapter.path {
+                    let content = std::mem::take(&mut chapter.content);
+                    chapter.content = self.0.transform_markdown(path, content);
+                }
+            }
+        });
+        Ok(book)
+    }
+}
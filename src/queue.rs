@@ -0,0 +1,49 @@
+//! The queue of requests waiting for the wiki task. It holds at most `queue_capacity` requests,
+//! and requests that don't fit are turned away with a 503 right away, instead of piling up in
+//! the webapp while the wiki task works through a slow build.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// How full the queue is, for `/metrics`.
+#[derive(Debug)]
+pub struct QueueStats {
+    capacity: usize,
+    depth: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+impl QueueStats {
+    pub fn new(capacity: usize) -> QueueStats {
+        QueueStats {
+            capacity,
+            depth: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Requests sent and not yet picked up by the wiki task.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+    /// Requests turned away since mdwiki started, because the queue was full.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+    /// Called before a request is sent, since the wiki task may pick it up right away.
+    pub fn sending(&self) {
+        self.depth.fetch_add(1, Ordering::SeqCst);
+    }
+    /// Called when a request couldn't be sent, with whether that's because the queue was full.
+    pub fn not_sent(&self, full: bool) {
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+        if full {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    /// Called by the wiki task for every request it picks up.
+    pub fn received(&self) {
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::{self, SeekFrom};
+
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteRange {
+    From(u64),
+    FromTo(u64, u64),
+    Last(u64),
+}
+
+impl ByteRange {
+    /// Parses a `Range` header. Multiple ranges are not supported, and are treated like a
+    /// missing header, which is allowed by the spec.
+    pub fn parse(header: &str) -> Option<ByteRange> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let mut parts = spec.splitn(2, '-');
+        let (start, end) = (parts.next()?.trim(), parts.next()?.trim());
+        match (start.is_empty(), end.is_empty()) {
+            (true, false) => end.parse().ok().map(ByteRange::Last),
+            (false, true) => start.parse().ok().map(ByteRange::From),
+            (false, false) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                if start <= end {
+                    Some(ByteRange::FromTo(start, end))
+                } else {
+                    None
+                }
+            }
+            (true, true) => None,
+        }
+    }
+    /// Resolves the range against the size of the body, returning the inclusive start and end
+    /// offsets, or `None` if the range can't be satisfied.
+    pub fn resolve(self, total: u64) -> Option<(u64, u64)> {
+        if total == 0 {
+            return None;
+        }
+        match self {
+            ByteRange::From(start) if start < total => Some((start, total - 1)),
+            ByteRange::FromTo(start, end) if start < total => Some((start, end.min(total - 1))),
+            ByteRange::Last(len) if len > 0 => Some((total.saturating_sub(len), total - 1)),
+            _ => None,
+        }
+    }
+}
+
+pub struct RangeHeader(pub Option<ByteRange>);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for RangeHeader {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(RangeHeader(
+            req.headers().get_one("Range").and_then(ByteRange::parse),
+        ))
+    }
+}
+
+/// Responder for any seekable body, e.g. a file on disk or a proxied object, that answers range
+/// requests with `206 Partial Content` and advertises range support on full responses.
+pub struct RangedBody<R> {
+    body: R,
+    content_type: Option<ContentType>,
+    total: u64,
+    range: Option<Option<(u64, u64)>>,
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin + Send> RangedBody<R> {
+    pub async fn new(
+        mut body: R,
+        total: u64,
+        content_type: Option<ContentType>,
+        range: Option<ByteRange>,
+    ) -> io::Result<Self> {
+        let range = range.map(|range| range.resolve(total));
+        if let Some(Some((start, _))) = range {
+            body.seek(SeekFrom::Start(start)).await?;
+        }
+        Ok(RangedBody {
+            body,
+            content_type,
+            total,
+            range,
+        })
+    }
+}
+
+impl<'r, 'o: 'r, R: AsyncRead + AsyncSeek + Unpin + Send + 'o> Responder<'r, 'o>
+    for RangedBody<R>
+{
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = Response::build();
+        response.raw_header("Accept-Ranges", "bytes");
+        if let Some(content_type) = self.content_type {
+            response.header(content_type);
+        }
+
+        match self.range {
+            None => {
+                response
+                    .raw_header("Content-Length", self.total.to_string())
+                    .streamed_body(self.body.take(self.total));
+            }
+            Some(Some((start, end))) => {
+                let len = end - start + 1;
+                response
+                    .status(Status::PartialContent)
+                    .raw_header(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, end, self.total),
+                    )
+                    .raw_header("Content-Length", len.to_string())
+                    .streamed_body(self.body.take(len));
+            }
+            Some(None) => {
+                response
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", self.total));
+            }
+        }
+
+        response.ok()
+    }
+}
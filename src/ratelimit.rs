@@ -0,0 +1,115 @@
+use crate::config::LoginLimits;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MAX_TRACKED_KEYS: usize = 10_000;
+/// How many keys are left after forgetting some, so it isn't done on every failure.
+const TRACKED_KEYS_AFTER_EVICTION: usize = MAX_TRACKED_KEYS * 9 / 10;
+
+#[derive(Debug)]
+struct Attempts {
+    failures: f64,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    /// Failures decay linearly, one failure is forgiven every `decay_seconds`
+    fn decay(&mut self, now: Instant, limits: &LoginLimits) {
+        let elapsed = now.duration_since(self.last_failure).as_secs_f64();
+        self.failures = (self.failures - elapsed / limits.decay_seconds.max(1) as f64).max(0.0);
+        self.last_failure = now;
+        if self.locked_until.map(|until| until <= now).unwrap_or(false) {
+            self.locked_until = None;
+        }
+    }
+}
+
+/// In-memory throttling of failed logins, both per client address and per username.
+#[derive(Debug, Default)]
+pub struct LoginLimiter {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+impl LoginLimiter {
+    fn keys(ip: Option<&str>, username: &str) -> Vec<(String, bool)> {
+        let mut keys = vec![(format!("user:{}", username), true)];
+        if let Some(ip) = ip {
+            keys.push((format!("ip:{}", ip), false));
+        }
+        keys
+    }
+    /// Returns how long the client has to wait if either the address or the username is locked.
+    pub fn check(&self, ip: Option<&str>, username: &str) -> Option<Duration> {
+        let attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+        Self::keys(ip, username)
+            .iter()
+            .filter_map(|(key, _)| attempts.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max()
+    }
+    pub fn record_failure(&self, ip: Option<&str>, username: &str, limits: &LoginLimits) {
+        let mut attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+
+        if attempts.len() > MAX_TRACKED_KEYS {
+            attempts.retain(|_, entry| {
+                entry.decay(now, limits);
+                entry.failures > 0.0 || entry.locked_until.is_some()
+            });
+        }
+        // Rotating usernames or addresses keeps them all failing, so the ones least likely to
+        // be locked out soon are forgotten, and lockouts last
+        if attempts.len() > MAX_TRACKED_KEYS {
+            let mut keys: Vec<_> = attempts
+                .iter()
+                .map(|(key, entry)| (entry.locked_until, entry.failures, key.clone()))
+                .collect();
+            keys.sort_by(|a, b| {
+                (a.0, a.1)
+                    .partial_cmp(&(b.0, b.1))
+                    .unwrap_or(Ordering::Equal)
+            });
+            let excess = attempts.len() - TRACKED_KEYS_AFTER_EVICTION;
+            for (_, _, key) in keys.into_iter().take(excess) {
+                attempts.remove(&key);
+            }
+        }
+
+        for (key, is_username) in Self::keys(ip, username) {
+            let max_failures = if is_username {
+                limits.max_failures_per_user
+            } else {
+                limits.max_failures_per_ip
+            };
+            let entry = attempts.entry(key.clone()).or_insert(Attempts {
+                failures: 0.0,
+                last_failure: now,
+                locked_until: None,
+            });
+            entry.decay(now, limits);
+            entry.failures += 1.0;
+            if max_failures > 0 && entry.failures >= max_failures as f64 {
+                warn!("locking out {} after repeated failed logins", key);
+                entry.locked_until = Some(now + Duration::from_secs(limits.lockout_seconds));
+                entry.failures = 0.0;
+            }
+        }
+    }
+    /// How many addresses and usernames have failures or are locked out.
+    #[cfg(test)]
+    pub fn tracked(&self) -> usize {
+        self.attempts.lock().unwrap().len()
+    }
+    pub fn record_success(&self, username: &str) {
+        self.attempts
+            .lock()
+            .unwrap()
+            .remove(&format!("user:{}", username));
+    }
+}
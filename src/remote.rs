@@ -0,0 +1,127 @@
+//! Credentials for the remote the book is pushed to and pulled from, an SSH deploy key or a
+//! personal access token over https. They're checked on startup by connecting to the remote, so
+//! wrong credentials stop mdwiki right away instead of failing the first push.
+
+use std::path::Path;
+
+use git2::{Cred, CredentialType, Direction, ErrorCode, RemoteCallbacks, Repository};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RemoteConfig {
+    /// Like `git@github.com:org/wiki.git` or `https://github.com/org/wiki.git`.
+    pub url: String,
+    /// The private key for ssh remotes, like a deploy key.
+    #[serde(default)]
+    pub ssh_key: Option<String>,
+    #[serde(default)]
+    pub ssh_key_passphrase: Option<String>,
+    /// A personal access token for https remotes.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Who to authenticate as, by default the user in the url, or `git`.
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+fn is_ssh(url: &str) -> bool {
+    // scp-like urls, `git@github.com:org/wiki.git`, have no scheme
+    url.starts_with("ssh://") || (!url.contains("://") && url.contains('@') && url.contains(':'))
+}
+
+fn is_https(url: &str) -> bool {
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+/// Checks that the credentials fit the remote, without connecting to it.
+pub fn validate(config: &RemoteConfig) -> Result<(), String> {
+    let url = &config.url;
+    if config.ssh_key.is_some() && config.token.is_some() {
+        return Err("set either remote.ssh_key or remote.token, not both".into());
+    }
+    if is_ssh(url) {
+        if config.token.is_some() {
+            return Err(format!(
+                "remote.token only works with https remotes, use remote.ssh_key for '{}'",
+                url
+            ));
+        }
+        let key = config
+            .ssh_key
+            .as_ref()
+            .ok_or_else(|| format!("the ssh remote '{}' needs remote.ssh_key", url))?;
+        std::fs::metadata(key)
+            .map_err(|e| format!("could not read remote.ssh_key '{}': {}", key, e))?;
+    } else if config.ssh_key.is_some() {
+        return Err(format!(
+            "remote.ssh_key only works with ssh remotes, use remote.token for '{}'",
+            url
+        ));
+    }
+    if (is_ssh(url) || is_https(url)) && cfg!(not(feature = "remote")) {
+        return Err(format!(
+            "mdwiki was built without the remote feature, which '{}' needs",
+            url
+        ));
+    }
+    Ok(())
+}
+
+/// Callbacks answering the remote's requests for credentials from `config`. Credentials are
+/// only offered once, since libgit2 keeps asking for as long as they're rejected.
+pub fn callbacks(config: &RemoteConfig) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
+    let mut offered = false;
+    callbacks.credentials(move |_, username_from_url, allowed| {
+        if offered {
+            return Err(git2::Error::from_str("the remote rejected the credentials"));
+        }
+        let username = config
+            .username
+            .as_deref()
+            .or(username_from_url)
+            .unwrap_or("git");
+        // ssh asks for the user first when the url has none
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username);
+        }
+        offered = true;
+        match (&config.ssh_key, &config.token) {
+            (Some(key), _) if allowed.contains(CredentialType::SSH_KEY) => {
+                let passphrase = config.ssh_key_passphrase.as_deref();
+                Cred::ssh_key(username, None, Path::new(key), passphrase)
+            }
+            (_, Some(token)) if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) => {
+                Cred::userpass_plaintext(username, token)
+            }
+            _ => Err(git2::Error::from_str(
+                "the remote needs credentials, set remote.ssh_key or remote.token",
+            )),
+        }
+    });
+    callbacks
+}
+
+/// Connects to the remote from the repository at `path` with the credentials in `config`.
+pub fn check(path: &str, config: &RemoteConfig) -> Result<(), String> {
+    validate(config)?;
+    let err = |e: git2::Error| match e.code() {
+        ErrorCode::Auth => format!(
+            "the remote '{}' rejected the credentials, check remote.ssh_key or remote.token: {}",
+            config.url,
+            e.message()
+        ),
+        _ => format!(
+            "could not connect to the remote '{}': {}",
+            config.url,
+            e.message()
+        ),
+    };
+    let repo = Repository::open(path).map_err(err)?;
+    let mut remote = repo.remote_anonymous(&config.url).map_err(err)?;
+    remote
+        .connect_auth(Direction::Fetch, Some(callbacks(config)), None)
+        .map_err(err)?;
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use crate::plugin::Plugins;
+use crate::sanitize::sanitize_rendered;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use comrak::{markdown_to_html, ComrakOptions};
+
+use mdbook::book::Book;
+use mdbook::errors::Error;
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::BookItem;
+
+use once_cell::sync::Lazy;
+
+use regex::{Captures, Regex};
+
+use serde::{Deserialize, Serialize};
+
+const PRE_BLOCK_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<pre[ >].*?</pre>").unwrap());
+
+const MD_LINK_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"href="([^":?#]*)\.md(#[^"]*)?""#).unwrap());
+
+const BLANK_LINES_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n\s*\n").unwrap());
+
+pub const COMRAK_EXTENSIONS: &[&str] = &[
+    "strikethrough",
+    "tagfilter",
+    "table",
+    "autolink",
+    "tasklist",
+    "superscript",
+    "footnotes",
+    "description_lists",
+    "smart",
+    "hardbreaks",
+];
+
+/// What turns the markdown of a page into html.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Renderer {
+    /// mdbook's own renderer.
+    Mdbook,
+    /// comrak, with any of `COMRAK_EXTENSIONS`.
+    Comrak {
+        #[serde(default)]
+        extensions: Vec<String>,
+    },
+    /// The first plugin that renders the page, see `Plugin::render_markdown`. Pages no plugin
+    /// renders are left to mdbook.
+    Plugins,
+}
+
+impl Default for Renderer {
+    fn default() -> Renderer {
+        Renderer::Mdbook
+    }
+}
+
+fn comrak_options(extensions: &[String]) -> ComrakOptions {
+    let mut options = ComrakOptions::default();
+    // The rendered html is sanitized, unless `sanitize_html` is turned off
+    options.render.unsafe_ = true;
+    for extension in extensions {
+        match extension.as_str() {
+            "strikethrough" => options.extension.strikethrough = true,
+            "tagfilter" => options.extension.tagfilter = true,
+            "table" => options.extension.table = true,
+            "autolink" => options.extension.autolink = true,
+            "tasklist" => options.extension.tasklist = true,
+            "superscript" => options.extension.superscript = true,
+            "footnotes" => options.extension.footnotes = true,
+            "description_lists" => options.extension.description_lists = true,
+            "smart" => options.parse.smart = true,
+            "hardbreaks" => options.render.hardbreaks = true,
+            other => warn!("unknown comrak extension '{}'", other),
+        }
+    }
+    options
+}
+
+/// Makes rendered html pass through mdbook's markdown renderer unchanged. Html blocks end at
+/// blank lines, after which mdbook would treat indented lines as code, so the newlines in `pre`
+/// blocks are escaped and the other blank lines removed. Links to pages get the same `.md` to
+/// `.html` rewrite mdbook does for markdown links.
+fn protect_html(html: &str) -> String {
+    let html = PRE_BLOCK_REGEX.replace_all(html, |caps: &Captures| caps[0].replace('\n', "&#10;"));
+    let html = BLANK_LINES_REGEX.replace_all(&html, "\n");
+    MD_LINK_REGEX
+        .replace_all(&html, |caps: &Captures| {
+            let anchor = caps.get(2).map(|anchor| anchor.as_str()).unwrap_or("");
+            format!(r#"href="{}.html{}""#, &caps[1], anchor)
+        })
+        .into_owned()
+}
+
+/// Renders the pages with the configured renderer during the mdbook build, handing mdbook html
+/// instead of markdown. Runs after the other preprocessors, so it gets sanitized markdown, and
+/// sanitizes the html too with `sanitize`.
+pub(crate) struct RenderPreprocessor {
+    pub renderer: Renderer,
+    pub plugins: Arc<Plugins>,
+    pub sanitize: bool,
+}
+
+impl RenderPreprocessor {
+    fn render(&self, path: &Path, markdown: &str) -> Option<String> {
+        match &self.renderer {
+            Renderer::Mdbook => None,
+            Renderer::Comrak { extensions } => {
+                Some(markdown_to_html(markdown, &comrak_options(extensions)))
+            }
+            Renderer::Plugins => self.plugins.render_markdown(path, markdown),
+        }
+    }
+}
+
+impl Preprocessor for RenderPreprocessor {
+    fn name(&self) -> &str {
+        "mdwiki-render"
+    }
+
+    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                if let Some(path) = &chapter.path {
+                    if let Some(mut html) = self.render(path, &chapter.content) {
+                        if self.sanitize {
+                            html = sanitize_rendered(&html);
+                        }
+                        chapter.content = protect_html(&html);
+                    }
+                }
+            }
+        });
+        Ok(book)
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config::User;
+use crate::session::now;
+use crate::utils::*;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+
+use rand::Rng;
+
+use serde::{Deserialize, Serialize};
+
+/// How long a reset link is valid.
+pub const RESET_LIFETIME: u64 = 60 * 60;
+/// Minimum time between reset emails to the same user.
+const RESEND_INTERVAL: u64 = 5 * 60;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+    /// Fingerprint of the password the token was created for, so a token stops working once it
+    /// has been used.
+    pwd: String,
+}
+
+fn password_fingerprint(user: &User) -> String {
+    let mut hasher = DefaultHasher::new();
+    user.password.hash(&mut hasher);
+    format!("{:x}", hasher.finish())
+}
+
+/// Signed tokens for password reset links. Nothing is stored per token, the signing key is kept
+/// in the data directory so links survive restarts.
+pub struct ResetTokens {
+    key: Vec<u8>,
+    sent: Mutex<HashMap<String, u64>>,
+}
+
+impl ResetTokens {
+    pub fn load(path: PathBuf) -> ResetTokens {
+        let key = match fs::read(&path) {
+            Ok(key) if key.len() >= 32 => key,
+            _ => {
+                let key: [u8; 32] = rand::thread_rng().gen();
+                if let Some(parent) = path.parent() {
+                    let _ = fs::create_dir_all(parent).map_err(log_warn);
+                }
+                let _ = fs::write(&path, &key).map_err(log_warn);
+                key.to_vec()
+            }
+        };
+        ResetTokens {
+            key,
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Returns a token for resetting the password of `user`, or `None` if a reset email was
+    /// sent to them recently.
+    pub fn create(&self, user: &User) -> Option<String> {
+        let now = now();
+        {
+            let mut sent = self.sent.lock().unwrap();
+            sent.retain(|_, time| *time + RESEND_INTERVAL > now);
+            if sent.contains_key(&user.username) {
+                return None;
+            }
+            sent.insert(user.username.clone(), now);
+        }
+
+        let claims = Claims {
+            sub: user.username.clone(),
+            exp: now + RESET_LIFETIME,
+            pwd: password_fingerprint(user),
+        };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(&self.key))
+            .map_err(log_warn)
+            .ok()
+    }
+    /// Returns the user a token was created for, if it's valid and hasn't been used.
+    pub fn verify(
+        &self,
+        token: &str,
+        find_user: impl FnOnce(&str) -> Option<User>,
+    ) -> Option<User> {
+        let claims = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(&self.key),
+            &Validation::default(),
+        )
+        .map_err(|e| info!("invalid password reset token: {}", e))
+        .ok()?
+        .claims;
+        find_user(&claims.sub)
+            .filter(|user| !user.disabled && password_fingerprint(user) == claims.pwd)
+    }
+}
@@ -0,0 +1,74 @@
+use crate::config::Config;
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+pub const NOINDEX: &str = "noindex, nofollow";
+
+/// Paths of mdwiki's own pages, which aren't worth crawling.
+const MDWIKI_PATHS: &[&str] = &[
+    "/new", "/edit/", "/embed/", "/login", "/logout", "/profile", "/diff/", "/admin/", "/api/",
+    "/reviews",
+];
+
+/// What search engines may crawl and index.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Crawlers {
+    /// Wikis that require logging in are never indexed.
+    pub allow: bool,
+    /// Paths crawlers are asked to stay away from, e.g. `/drafts/`.
+    #[serde(default)]
+    pub disallow: Vec<String>,
+}
+
+impl Default for Crawlers {
+    fn default() -> Crawlers {
+        Crawlers {
+            allow: true,
+            disallow: Vec::new(),
+        }
+    }
+}
+
+/// The `robots.txt` for the wiki, pointing to the sitemap when there is one.
+pub fn robots_txt(config: &Config) -> String {
+    if !config.crawlers_allowed() {
+        return "User-agent: *\nDisallow: /\n".to_string();
+    }
+    let mut robots = "User-agent: *\n".to_string();
+    let disallow = MDWIKI_PATHS
+        .iter()
+        .copied()
+        .chain(config.crawlers.disallow.iter().map(String::as_str));
+    for path in disallow {
+        robots.push_str(&format!("Disallow: {}\n", path));
+    }
+    if let Some(public_url) = &config.public_url {
+        robots.push_str(&format!(
+            "\nSitemap: {}/sitemap.xml\n",
+            public_url.trim_end_matches('/')
+        ));
+    }
+    robots
+}
+
+/// Adds a `noindex` meta tag to every html file under `dir`.
+pub fn add_noindex(dir: &Path) -> Result<(), String> {
+    let meta = format!("<head>\n        <meta name=\"robots\" content=\"{}\">", NOINDEX);
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("could not read directory '{}': {}", dir.display(), e))?;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            add_noindex(&path)?;
+        } else if path.extension().map_or(false, |ext| ext == "html") {
+            let html = fs::read_to_string(&path)
+                .map_err(|e| format!("could not read '{}': {}", path.display(), e))?;
+            fs::write(&path, html.replacen("<head>", &meta, 1))
+                .map_err(|e| format!("could not write '{}': {}", path.display(), e))?;
+        }
+    }
+    Ok(())
+}
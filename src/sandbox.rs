@@ -0,0 +1,88 @@
+use crate::config::PreprocessorSandbox;
+use crate::doctor::BUILTIN_PREPROCESSORS;
+
+use mdbook::MDBook;
+
+/// Quotes `arg` so mdbook splits it back into the same argument.
+fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r#"'"'"'"#))
+    }
+}
+
+/// The commands `sandbox` puts in front of a preprocessor's own command.
+pub fn wrapper(sandbox: &PreprocessorSandbox, book_root: &str) -> Vec<String> {
+    let mut wrapper = vec![
+        "timeout".to_string(),
+        "--kill-after=5".to_string(),
+        sandbox.timeout.to_string(),
+    ];
+    if let Some(image) = &sandbox.container {
+        wrapper.extend(
+            ["docker", "run", "--rm", "-i", "--network=none", "--read-only"]
+                .iter()
+                .map(|arg| arg.to_string()),
+        );
+        wrapper.push(format!("--volume={0}:{0}:ro", book_root));
+        wrapper.push(format!("--workdir={}", book_root));
+        if let Some(memory) = sandbox.memory {
+            wrapper.push(format!("--memory={}m", memory));
+        }
+        if let Some(cpu) = sandbox.cpu {
+            wrapper.push(format!("--ulimit=cpu={}", cpu));
+        }
+        if let Some(user) = &sandbox.user {
+            wrapper.push(format!("--user={}", user));
+        }
+        wrapper.push(image.clone());
+        return wrapper;
+    }
+    if let Some(user) = &sandbox.user {
+        let sudo = ["sudo", "-n", "-u", user.as_str(), "--"];
+        wrapper.extend(sudo.iter().map(|arg| arg.to_string()));
+    }
+    if sandbox.memory.is_some() || sandbox.cpu.is_some() {
+        wrapper.push("prlimit".to_string());
+        if let Some(memory) = sandbox.memory {
+            wrapper.push(format!("--as={}", memory * 1024 * 1024));
+        }
+        if let Some(cpu) = sandbox.cpu {
+            wrapper.push(format!("--cpu={}", cpu));
+        }
+        wrapper.push("--".to_string());
+    }
+    wrapper
+}
+
+/// Changes the commands of the external preprocessors in `book` to run them in `sandbox`.
+pub fn sandbox_preprocessors(book: &mut MDBook, sandbox: &PreprocessorSandbox) {
+    let preprocessors = book
+        .config
+        .get("preprocessor")
+        .and_then(|value| value.as_table())
+        .cloned()
+        .unwrap_or_default();
+    let wrapper = wrapper(sandbox, &book.root.to_string_lossy())
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    for (name, table) in preprocessors {
+        if BUILTIN_PREPROCESSORS.contains(&name.as_str()) {
+            continue;
+        }
+        let command = table
+            .get("command")
+            .and_then(|command| command.as_str())
+            .map(String::from)
+            .unwrap_or(format!("mdbook-{}", name));
+        let key = format!("preprocessor.{}.command", name);
+        if let Err(e) = book.config.set(&key, format!("{} {}", wrapper, command)) {
+            warn!("could not sandbox preprocessor '{}': {}", name, e);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use ammonia::Builder;
+
+use mdbook::book::Book;
+use mdbook::errors::Error;
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::BookItem;
+
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
+const TAG_NAME_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^<(/?)([a-zA-Z][a-zA-Z0-9-]*)").unwrap());
+
+const SAFE_URL_SCHEMES: &[&str] = &["http", "https", "mailto", "ftp", "tel"];
+
+/// Sanitizing can turn escaped text into markup, e.g. by removing the tag around it, so the
+/// result is sanitized again until nothing changes.
+const MAX_PASSES: usize = 4;
+
+fn builder() -> Builder<'static> {
+    let mut builder = Builder::default();
+    // Code blocks get their language as a class, which the highlighting depends on
+    builder.add_generic_attributes(&["class"]);
+    builder
+}
+
+fn is_safe_url(url: &str) -> bool {
+    // Browsers ignore whitespace and control characters, e.g. in "java\tscript:"
+    let url: String = url
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control())
+        .collect();
+    match url.find(|c| c == ':' || c == '/' || c == '?' || c == '#') {
+        Some(i) if url[i..].starts_with(':') => {
+            SAFE_URL_SCHEMES.contains(&url[..i].to_ascii_lowercase().as_str())
+        }
+        _ => true,
+    }
+}
+
+/// Cleans a single inline tag, leaving it open so it still wraps the text following it.
+fn clean_tag(tag: &str) -> String {
+    let (closing, name) = match TAG_NAME_REGEX.captures(tag) {
+        Some(caps) => (!caps[1].is_empty(), caps[2].to_ascii_lowercase()),
+        // Comments, processing instructions and the like
+        None => return String::new(),
+    };
+    let allowed = !builder()
+        .clean(&format!("<{0}></{0}>", name))
+        .to_string()
+        .is_empty();
+    if closing {
+        return if allowed {
+            format!("</{}>", name)
+        } else {
+            String::new()
+        };
+    }
+    let cleaned = builder().clean(tag).to_string();
+    match cleaned.strip_suffix(&format!("</{}>", name)) {
+        Some(open) => open.to_string(),
+        None => cleaned,
+    }
+}
+
+fn sanitize_pass(content: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let mut sanitized = String::with_capacity(content.len());
+    let mut last = 0;
+    let mut events = Parser::new_ext(content, options)
+        .into_offset_iter()
+        .peekable();
+    while let Some((event, range)) = events.next() {
+        let (start, mut end) = (range.start, range.end);
+        let replacement = match &event {
+            // Html blocks come one line at a time, inline html one tag at a time
+            Event::Html(html) if html.ends_with('\n') => {
+                while let Some((Event::Html(next), next_range)) = events.peek() {
+                    if next_range.start != end || !next.ends_with('\n') {
+                        break;
+                    }
+                    end = next_range.end;
+                    events.next();
+                }
+                let mut cleaned = builder().clean(&content[start..end]).to_string();
+                if !cleaned.ends_with('\n') {
+                    cleaned.push('\n');
+                }
+                cleaned
+            }
+            Event::Html(html) => clean_tag(html),
+            Event::Start(Tag::Link(_, url, _)) | Event::Start(Tag::Image(_, url, _))
+                if !is_safe_url(url) =>
+            {
+                let mut link = vec![event.clone()];
+                let mut depth = 1;
+                while depth > 0 {
+                    match events.next() {
+                        Some((event, _)) => {
+                            match event {
+                                Event::Start(_) => depth += 1,
+                                Event::End(_) => depth -= 1,
+                                _ => {}
+                            }
+                            link.push(event);
+                        }
+                        None => break,
+                    }
+                }
+                let mut rendered = String::new();
+                html::push_html(&mut rendered, link.into_iter());
+                builder().clean(&rendered).to_string()
+            }
+            _ => continue,
+        };
+        sanitized.push_str(&content[last..start]);
+        sanitized.push_str(&replacement);
+        last = end;
+    }
+    sanitized.push_str(&content[last..]);
+    sanitized
+}
+
+/// Removes scripts, event handlers and other unsafe html from a page, along with links to
+/// `javascript:` and similar urls. Markdown is left as it is.
+pub fn sanitize_markdown(content: &str) -> String {
+    let mut content = content.to_string();
+    // Html blocks are recognized by their trailing newline, including the last one
+    if !content.ends_with('\n') {
+        content.push('\n');
+    }
+    for _ in 0..MAX_PASSES {
+        let sanitized = sanitize_pass(&content);
+        if sanitized == content {
+            return content;
+        }
+        content = sanitized;
+    }
+    warn!("page could not be sanitized, escaping all html");
+    content.replace('<', "&lt;")
+}
+
+/// Removes unsafe html from a page rendered by a renderer other than mdbook, which can turn
+/// markdown into html the sanitized markdown didn't have. Footnotes keep their ids, and task
+/// lists their checkboxes.
+pub fn sanitize_rendered(html: &str) -> String {
+    let mut builder = builder();
+    builder
+        .add_generic_attributes(&["id"])
+        .add_tags(&["input"])
+        .add_tag_attributes("input", &["type", "checked", "disabled"]);
+    builder.clean(html).to_string()
+}
+
+/// Sanitizes every page as part of the mdbook build, the source files are not changed.
+pub(crate) struct SanitizePreprocessor;
+
+impl Preprocessor for SanitizePreprocessor {
+    fn name(&self) -> &str {
+        "mdwiki-sanitize"
+    }
+
+    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                chapter.content = sanitize_markdown(&chapter.content);
+            }
+        });
+        Ok(book)
+    }
+}
@@ -0,0 +1,168 @@
+use crate::plugin::{Event, Plugin, User};
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mlua::{Function, HookTriggers, Lua, LuaSerdeExt, StdLib, Value};
+
+/// Instructions are counted in steps of this many.
+const INSTRUCTION_STEP: u32 = 1000;
+/// A single hook call may run at most this many steps, i.e. about ten million instructions.
+const MAX_STEPS: u32 = 10_000;
+const MEMORY_LIMIT: usize = 32 * 1024 * 1024;
+/// Functions of the base library, which is always loaded, that read files or load code.
+const REMOVED_GLOBALS: &[&str] = &["dofile", "loadfile", "load"];
+
+/// A Lua script implementing any of the hooks:
+///
+/// - `validate(path, content, username)`, return a message to reject a change, or nil
+/// - `transform(path, content)`, return the markdown to build instead of `content`
+/// - `render(path, content)`, return the page as html, or nil to leave it to mdbook (only used
+///   with `renderer = { kind = "plugins" }`)
+/// - `upload_name(name)`, return the name for an uploaded image, or nil for a random one (only
+///   used with `upload_names = "plugins"`), `name` is the name it was uploaded with, or nil
+/// - `on_event(event)`, called with every wiki event as a table
+///
+/// Scripts only get the `string`, `table`, `math` and `utf8` libraries, and the base library
+/// without `REMOVED_GLOBALS`, so they can't touch the filesystem or the network, and every call
+/// is limited in time and memory.
+pub struct LuaScript {
+    path: String,
+    lua: Mutex<Lua>,
+    steps: Arc<AtomicU32>,
+}
+
+impl LuaScript {
+    pub fn load(path: &str) -> Result<LuaScript, String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read script '{}': {}", path, e))?;
+
+        let lua = Lua::new_with(StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8)
+            .map_err(|e| format!("could not create lua state: {}", e))?;
+        lua.set_memory_limit(MEMORY_LIMIT)
+            .map_err(|e| format!("could not limit lua memory: {}", e))?;
+        for name in REMOVED_GLOBALS {
+            lua.globals()
+                .set(*name, Value::Nil)
+                .map_err(|e| format!("could not remove '{}': {}", name, e))?;
+        }
+
+        let steps = Arc::new(AtomicU32::new(0));
+        let hook_steps = steps.clone();
+        lua.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(INSTRUCTION_STEP),
+                ..Default::default()
+            },
+            move |_, _| {
+                if hook_steps.fetch_add(1, Ordering::SeqCst) >= MAX_STEPS {
+                    return Err(mlua::Error::RuntimeError(
+                        "script ran for too long".into(),
+                    ));
+                }
+                Ok(())
+            },
+        )
+        .map_err(|e| format!("could not limit lua instructions: {}", e))?;
+
+        lua.load(&source)
+            .set_name(path)
+            .and_then(|chunk| chunk.exec())
+            .map_err(|e| format!("could not load script '{}': {}", path, e))?;
+
+        info!("loaded script '{}'", path);
+
+        Ok(LuaScript {
+            path: path.to_string(),
+            lua: Mutex::new(lua),
+            steps,
+        })
+    }
+    /// Calls the global function `name` if the script defines it.
+    fn call<R>(
+        &self,
+        name: &str,
+        call: impl FnOnce(&Lua, Function) -> mlua::Result<R>,
+    ) -> Option<Result<R, String>> {
+        let lua = self.lua.lock().unwrap();
+        let function: Function = lua.globals().get(name).ok()?;
+        self.steps.store(0, Ordering::SeqCst);
+        Some(
+            call(&lua, function)
+                .map_err(|e| format!("script '{}' failed in '{}': {}", self.path, name, e)),
+        )
+    }
+}
+
+impl Plugin for LuaScript {
+    fn name(&self) -> &'static str {
+        "lua"
+    }
+
+    fn on_save(&self, user: &User, path: &Path, content: &str) -> Result<(), String> {
+        let path = path.to_string_lossy();
+        let res = self.call("validate", |_, validate| {
+            validate.call::<_, Option<String>>((path.as_ref(), content, user.username.as_str()))
+        });
+        match res {
+            Some(Ok(Some(message))) => Err(message),
+            Some(Err(e)) => {
+                warn!("{}", e);
+                Err("Page validation failed".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn transform_markdown(&self, path: &Path, content: String) -> String {
+        let path = path.to_string_lossy();
+        match self.call("transform", |_, transform| {
+            transform.call::<_, String>((path.as_ref(), content.as_str()))
+        }) {
+            Some(Ok(transformed)) => transformed,
+            Some(Err(e)) => {
+                warn!("{}", e);
+                content
+            }
+            None => content,
+        }
+    }
+
+    fn render_markdown(&self, path: &Path, markdown: &str) -> Option<String> {
+        let path = path.to_string_lossy();
+        match self.call("render", |_, render| {
+            render.call::<_, Option<String>>((path.as_ref(), markdown))
+        }) {
+            Some(Ok(html)) => html,
+            Some(Err(e)) => {
+                warn!("{}", e);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn upload_name(&self, original: Option<&str>, _content: &[u8]) -> Option<String> {
+        match self.call("upload_name", |_, upload_name| {
+            upload_name.call::<_, Option<String>>(original)
+        }) {
+            Some(Ok(name)) => name,
+            Some(Err(e)) => {
+                warn!("{}", e);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn on_event(&self, event: &Event) {
+        let res = self.call("on_event", |lua, on_event| {
+            let event: Value = lua.to_value(event)?;
+            on_event.call::<_, ()>(event)
+        });
+        if let Some(Err(e)) = res {
+            warn!("{}", e);
+        }
+    }
+}
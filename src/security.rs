@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+
+pub const CONTENT_SECURITY_POLICY: &str = "Content-Security-Policy";
+
+/// The book's theme and mdwiki's script are served by mdwiki itself, but the theme relies on
+/// inline scripts and styles. Images may come from anywhere.
+pub const DEFAULT_CSP: &str = "default-src 'self'; script-src 'self' 'unsafe-inline'; \
+    style-src 'self' 'unsafe-inline'; img-src 'self' data: https:; font-src 'self' data:; \
+    object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors 'self'";
+
+/// Uploaded files are never trusted to run scripts, e.g. svg images.
+const UPLOADS_CSP: &str = "default-src 'none'; img-src 'self'; style-src 'unsafe-inline'; sandbox";
+
+pub fn default_headers() -> HashMap<String, String> {
+    vec![
+        (CONTENT_SECURITY_POLICY, DEFAULT_CSP),
+        ("X-Content-Type-Options", "nosniff"),
+        ("X-Frame-Options", "SAMEORIGIN"),
+        ("Referrer-Policy", "strict-origin-when-cross-origin"),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect()
+}
+
+/// `csp` with its `frame-ancestors` directive replaced, allowing `ancestors` to embed the page.
+pub fn with_frame_ancestors(csp: Option<&str>, ancestors: &[String]) -> String {
+    let frame_ancestors = std::iter::once("frame-ancestors 'self'")
+        .chain(ancestors.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ");
+    csp.unwrap_or("")
+        .split(';')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty() && !directive.starts_with("frame-ancestors"))
+        .chain(std::iter::once(frame_ancestors.as_str()))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Adds the configured `security_headers` to every response that doesn't set them itself.
+/// Headers configured as empty strings are left out.
+pub struct SecurityHeaders(pub HashMap<String, String>);
+
+#[rocket::async_trait]
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        // Pages that set their own policy, like embedded pages, decide who may frame them
+        let own_policy = res.headers().contains(CONTENT_SECURITY_POLICY);
+        let upload = req.uri().path().starts_with("/images/");
+
+        for (name, value) in &self.0 {
+            if value.is_empty() || res.headers().contains(name.as_str()) {
+                continue;
+            }
+            if own_policy && name.eq_ignore_ascii_case("X-Frame-Options") {
+                continue;
+            }
+            if upload && name.eq_ignore_ascii_case(CONTENT_SECURITY_POLICY) {
+                continue;
+            }
+            res.set_header(Header::new(name.clone(), value.clone()));
+        }
+        if upload && !own_policy {
+            res.set_header(Header::new(CONTENT_SECURITY_POLICY, UPLOADS_CSP));
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::utils::*;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Session {
+    pub username: String,
+    pub created: u64,
+    pub expires: u64,
+    pub remember: bool,
+    /// Roles mapped from the identity provider's claims when logging in, see
+    /// `OidcProvider::user_from_claims`. They aren't saved, so after a restart they're back once
+    /// the user logs in again.
+    #[serde(skip)]
+    pub roles: Vec<String>,
+}
+
+/// Server side session records, persisted to the data directory so sessions survive restarts
+/// and can be revoked.
+#[derive(Debug)]
+pub struct SessionStore {
+    path: PathBuf,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl SessionStore {
+    pub fn load(path: PathBuf) -> SessionStore {
+        let mut sessions: HashMap<String, Session> = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).map_err(log_warn).ok())
+            .unwrap_or_default();
+        let now = now();
+        sessions.retain(|_, session| session.expires > now);
+
+        SessionStore {
+            path,
+            sessions: Mutex::new(sessions),
+        }
+    }
+    fn persist(&self, sessions: &HashMap<String, Session>) {
+        if let Some(parent) = self.path.parent() {
+            let _ = fs::create_dir_all(parent).map_err(log_warn);
+        }
+        match serde_json::to_string(sessions) {
+            Ok(content) => {
+                let _ = fs::write(&self.path, content).map_err(log_warn);
+            }
+            Err(e) => warn!("failed to serialize sessions: {}", e),
+        }
+    }
+    pub fn create(
+        &self,
+        username: &str,
+        lifetime: u64,
+        remember: bool,
+        roles: Vec<String>,
+    ) -> String {
+        let token = rand_safe_string(32);
+        let now = now();
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(
+            token.clone(),
+            Session {
+                username: username.to_string(),
+                created: now,
+                expires: now + lifetime,
+                remember,
+                roles,
+            },
+        );
+        self.persist(&sessions);
+
+        token
+    }
+    pub fn get(&self, token: &str) -> Option<Session> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(token)
+            .filter(|session| session.expires > now())
+            .cloned()
+    }
+    pub fn revoke(&self, token: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.remove(token).is_some() {
+            self.persist(&sessions);
+        }
+    }
+    /// Revokes every session belonging to the user, returning how many were active.
+    pub fn revoke_user(&self, username: &str) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.username != username);
+        let revoked = before - sessions.len();
+        if revoked > 0 {
+            self.persist(&sessions);
+        }
+        revoked
+    }
+    pub fn count_for_user(&self, username: &str) -> usize {
+        let now = now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.username == username && session.expires > now)
+            .count()
+    }
+}
@@ -0,0 +1,98 @@
+use crate::builds::Builds;
+use crate::cache::PageCache;
+use crate::config::Config;
+use crate::events::EventLog;
+use crate::faults::Faults;
+use crate::mail::Mailer;
+use crate::mirrors::Mirrors;
+use crate::plugin::Plugins;
+use crate::queue::QueueStats;
+use crate::suggestions::SuggestionStore;
+use crate::todos::TodoList;
+use crate::utils::*;
+use crate::views::PageViews;
+use crate::watches::WatchStore;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// State shared between the wiki task and the webapp.
+#[derive(Debug)]
+pub struct SharedState {
+    read_only: AtomicBool,
+    /// Set when the book can't be written to, read-only mode can't be turned off then.
+    book_read_only: AtomicBool,
+    ready: AtomicBool,
+    pub events: EventLog,
+    pub plugins: Arc<Plugins>,
+    pub page_cache: PageCache,
+    pub builds: Builds,
+    /// Held for writing while a new build is swapped into place.
+    pub book_swap: RwLock<()>,
+    pub watches: WatchStore,
+    pub suggestions: SuggestionStore,
+    pub views: PageViews,
+    pub todos: TodoList,
+    pub mailer: Option<Mailer>,
+    pub mirrors: Mirrors,
+    pub queue: QueueStats,
+    /// Injected by tests, see `faults`.
+    pub faults: Faults,
+}
+
+impl SharedState {
+    pub fn new(config: &Config, plugins: Plugins) -> SharedState {
+        SharedState {
+            read_only: AtomicBool::new(config.read_only),
+            book_read_only: AtomicBool::new(false),
+            ready: AtomicBool::new(false),
+            events: EventLog::default(),
+            plugins: Arc::new(plugins),
+            page_cache: PageCache::new(config.page_cache_size),
+            builds: Builds::new(config),
+            book_swap: RwLock::new(()),
+            watches: WatchStore::load(config.data_path().join("watches.json")),
+            suggestions: SuggestionStore::load(config.data_path().join("suggestions.json")),
+            views: PageViews::load(config.data_path().join("views.json")),
+            todos: TodoList::default(),
+            mailer: config
+                .smtp
+                .as_ref()
+                .and_then(|smtp| Mailer::new(smtp).map_err(log_warn).ok()),
+            mirrors: Mirrors::new(config),
+            queue: QueueStats::new(config.queue_capacity),
+            faults: Faults::default(),
+        }
+    }
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+    pub fn book_read_only(&self) -> bool {
+        self.book_read_only.load(Ordering::SeqCst)
+    }
+    /// Puts the wiki in read-only mode for good, since the book can't be written to.
+    pub fn set_book_read_only(&self) {
+        self.book_read_only.store(true, Ordering::SeqCst);
+        self.set_read_only(true);
+    }
+    /// Why pages can't be changed, if they can't.
+    pub fn read_only_message(&self) -> Option<&'static str> {
+        if self.book_read_only() {
+            Some("The wiki's files are read-only, pages can't be changed")
+        } else if self.read_only() {
+            Some("The wiki is in read-only mode, pages can't be changed right now")
+        } else {
+            None
+        }
+    }
+    /// Whether there is a build to serve, which is once setup is done.
+    pub fn ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::events::Event;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use rocket::tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use rocket::tokio::net::tcp::OwnedWriteHalf;
+use rocket::tokio::net::TcpStream;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::tokio::sync::Mutex;
+use rocket::tokio::task;
+
+use serde::{Deserialize, Serialize};
+
+/// A message bus wiki events are published to, in addition to the event API. Page changes are
+/// POSTed to plain HTTP endpoints by `webhooks` instead.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum EventSink {
+    /// Events are published to a NATS server at `url` (`nats://host:port`), on the subject
+    /// `<subject>.<event type>`, e.g. `mdwiki.page_created`.
+    Nats {
+        url: String,
+        #[serde(default = "EventSink::default_subject")]
+        subject: String,
+    },
+}
+
+impl EventSink {
+    fn default_subject() -> String {
+        "mdwiki".into()
+    }
+}
+
+/// A connection to a NATS server. The server pings its clients, so a task answers the pings
+/// and notices when the connection is lost.
+struct NatsConnection {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl NatsConnection {
+    async fn connect(url: &str) -> Result<NatsConnection, String> {
+        let address = url.trim_start_matches("nats://").trim_end_matches('/');
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| format!("failed to connect to '{}': {}", url, e))?;
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"mdwiki\"}\r\n")
+            .await
+            .map_err(|e| format!("failed to connect to '{}': {}", url, e))?;
+
+        let writer = Arc::new(Mutex::new(writer));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let (ping_writer, ping_closed) = (writer.clone(), closed.clone());
+        let url = url.to_string();
+        task::spawn(async move {
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.starts_with("PING") {
+                    if ping_writer.lock().await.write_all(b"PONG\r\n").await.is_err() {
+                        break;
+                    }
+                } else if line.starts_with("-ERR") {
+                    warn!("nats server '{}' returned an error: {}", url, line);
+                }
+            }
+            ping_closed.store(true, Ordering::SeqCst);
+        });
+
+        Ok(NatsConnection { writer, closed })
+    }
+    async fn publish(&self, subject: &str, payload: &str) -> Result<(), String> {
+        let message = format!("PUB {} {}\r\n{}\r\n", subject, payload.len(), payload);
+        self.writer
+            .lock()
+            .await
+            .write_all(message.as_bytes())
+            .await
+            .map_err(|e| format!("failed to publish to nats: {}", e))
+    }
+}
+
+struct Publisher {
+    sink: EventSink,
+    nats: Option<NatsConnection>,
+}
+
+impl Publisher {
+    async fn publish(&mut self, event: &Event, payload: &str) -> Result<(), String> {
+        match &self.sink {
+            EventSink::Nats { url, subject } => {
+                let subject = format!("{}.{}", subject, event.kind.name());
+                if let Some(connection) = &self.nats {
+                    if !connection.closed.load(Ordering::SeqCst)
+                        && connection.publish(&subject, payload).await.is_ok()
+                    {
+                        return Ok(());
+                    }
+                }
+                // Reconnect once, the server might have restarted since the last event
+                self.nats = None;
+                let connection = NatsConnection::connect(url).await?;
+                connection.publish(&subject, payload).await?;
+                self.nats = Some(connection);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Publishes every event from `events` to the configured sinks, until the event log is dropped.
+/// Events are delivered at most once, failures are logged and not retried.
+pub async fn forward(sinks: Vec<EventSink>, mut events: broadcast::Receiver<Event>) {
+    if sinks.is_empty() {
+        return;
+    }
+    let mut publishers: Vec<_> = sinks
+        .into_iter()
+        .map(|sink| Publisher { sink, nats: None })
+        .collect();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("event sinks are falling behind, {} events were dropped", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let payload = match serde_json::to_string(&event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("failed to serialize event: {}", e);
+                continue;
+            }
+        };
+        for publisher in publishers.iter_mut() {
+            if let Err(e) = publisher.publish(&event, &payload).await {
+                warn!("{}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::config::WikiTree;
+use crate::webapp::page_url;
+
+use std::fs;
+use std::path::Path;
+
+use time::OffsetDateTime;
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn add_urls(urls: &mut String, tree: &WikiTree, base_url: &str, exclude: &[String], src: &Path) {
+    if exclude
+        .iter()
+        .any(|excluded| tree.path().starts_with(excluded.trim_matches('/')))
+    {
+        return;
+    }
+    let (page, children) = match tree {
+        WikiTree::File(path) => (path.to_path_buf(), &[][..]),
+        WikiTree::Directory(path, children) => (path.join("README.md"), &children[..]),
+    };
+    let page = page.to_string_lossy();
+    urls.push_str("  <url>\n");
+    urls.push_str(&format!(
+        "    <loc>{}</loc>\n",
+        escape_xml(&format!("{}/{}", base_url, page_url(&page)))
+    ));
+    let modified = fs::metadata(src.join(&*page)).and_then(|metadata| metadata.modified());
+    if let Ok(modified) = modified {
+        let lastmod = OffsetDateTime::from(modified).format("%Y-%m-%d");
+        urls.push_str(&format!("    <lastmod>{}</lastmod>\n", lastmod));
+    }
+    urls.push_str("  </url>\n");
+    for child in children {
+        add_urls(urls, child, base_url, exclude, src);
+    }
+}
+
+/// The sitemap of the pages in `tree`, the source files in `src`, with their urls under
+/// `base_url`. Pages in `exclude`, and the pages in directories in it, are left out.
+pub fn sitemap(tree: &WikiTree, base_url: &str, exclude: &[String], src: &Path) -> String {
+    let mut urls = String::new();
+    add_urls(&mut urls, tree, base_url.trim_end_matches('/'), exclude, src);
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n{}</urlset>\n",
+        urls
+    )
+}
@@ -0,0 +1,296 @@
+//! A load test that drives concurrent create, edit and read traffic against an in-process
+//! instance, to catch regressions in the single worker that every change goes through. Run it
+//! with `mdwiki stress`, in a build with the `stress` feature. The wiki starts out with a book
+//! made by `fixtures::generate`, so builds take as long as they would for a real wiki.
+
+use crate::config::{Config, User};
+use crate::fixtures::{self, FixtureOptions};
+use crate::merge;
+use crate::plugin::Plugins;
+use crate::users::{hash_password, UserStore};
+use crate::utils::rand_safe_string;
+use crate::wiki::WikiState;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use rocket::futures::future::join_all;
+use rocket::http::{ContentType, Status};
+use rocket::local::asynchronous::Client;
+use rocket::tokio::task;
+
+const STRESS_USER: &str = "stress";
+
+/// How much traffic to simulate.
+#[derive(Debug, Clone)]
+pub struct StressOptions {
+    /// Users sending requests at the same time, each waiting for a response before the next.
+    pub users: usize,
+    /// Requests every user sends. A quarter of them create pages, a quarter edit them, and the
+    /// rest read them.
+    pub operations: usize,
+    /// The book the wiki starts out with.
+    pub book: FixtureOptions,
+}
+
+impl Default for StressOptions {
+    fn default() -> StressOptions {
+        StressOptions {
+            users: 8,
+            operations: 40,
+            book: FixtureOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Create,
+    Edit,
+    Read,
+}
+
+impl Operation {
+    const ALL: [Operation; 3] = [Operation::Create, Operation::Edit, Operation::Read];
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Edit => "edit",
+            Operation::Read => "read",
+        }
+    }
+}
+
+/// The latencies of one kind of request.
+#[derive(Debug, Clone)]
+pub struct OperationStats {
+    pub operation: Operation,
+    pub count: usize,
+    /// Requests that didn't get the response a working wiki gives.
+    pub errors: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// The outcome of a load test.
+#[derive(Debug, Clone)]
+pub struct StressReport {
+    pub options: StressOptions,
+    pub elapsed: Duration,
+    pub operations: Vec<OperationStats>,
+}
+
+impl StressReport {
+    pub fn total(&self) -> usize {
+        self.operations.iter().map(|stats| stats.count).sum()
+    }
+    pub fn errors(&self) -> usize {
+        self.operations.iter().map(|stats| stats.errors).sum()
+    }
+    /// Requests handled per second, of every kind.
+    pub fn throughput(&self) -> f64 {
+        self.total() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for StressReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests from {} users in {:.2}s, {:.1} requests/s, {} errors",
+            self.total(),
+            self.options.users,
+            self.elapsed.as_secs_f64(),
+            self.throughput(),
+            self.errors()
+        )?;
+        writeln!(
+            f,
+            "{:<8}{:>8}{:>8}{:>12}{:>12}{:>12}",
+            "", "count", "errors", "p50", "p99", "max"
+        )?;
+        for stats in &self.operations {
+            writeln!(
+                f,
+                "{:<8}{:>8}{:>8}{:>10.1}ms{:>10.1}ms{:>10.1}ms",
+                stats.operation.name(),
+                stats.count,
+                stats.errors,
+                stats.p50.as_secs_f64() * 1000.0,
+                stats.p99.as_secs_f64() * 1000.0,
+                stats.max.as_secs_f64() * 1000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The latency at `percentile` of the sorted `latencies`, by the nearest rank.
+fn percentile(latencies: &[Duration], percentile: f64) -> Duration {
+    if latencies.is_empty() {
+        return Duration::default();
+    }
+    let rank = (percentile / 100.0 * latencies.len() as f64).ceil() as usize;
+    latencies[rank.max(1).min(latencies.len()) - 1]
+}
+
+/// Percent-encodes everything but letters and digits, for form bodies.
+fn form_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// What one user did: how long each request took, and whether it worked.
+type Timings = Vec<(Operation, Duration, bool)>;
+
+/// Creates, edits and reads pages of its own as user number `user`.
+async fn simulate_user(client: &Client, user: usize, operations: usize) -> Timings {
+    let mut timings = Vec::with_capacity(operations);
+    let mut pages: Vec<(String, String)> = Vec::new();
+    for i in 0..operations {
+        let operation = match i % 4 {
+            _ if pages.is_empty() => Operation::Create,
+            0 => Operation::Create,
+            1 => Operation::Edit,
+            _ => Operation::Read,
+        };
+        // edits and reads go around the pages the user created
+        let page = i % pages.len().max(1);
+        let start = Instant::now();
+        let ok = match operation {
+            Operation::Create => {
+                let file = format!("stress/user-{}/page-{}.md", user, pages.len());
+                let content = format!("# Page {} of user {}\n\nCreated\n", pages.len(), user);
+                let response = client
+                    .post("/new")
+                    .header(ContentType::Form)
+                    .body(format!(
+                        "file={}&content={}",
+                        form_encode(&file),
+                        form_encode(&content)
+                    ))
+                    .dispatch()
+                    .await;
+                pages.push((file, content));
+                response.status() == Status::SeeOther
+            }
+            Operation::Edit => {
+                let (file, content) = &mut pages[page];
+                let base = merge::revision(content);
+                content.push_str(&format!("\nEdit {}\n", i));
+                let response = client
+                    .post(format!("/edit/{}", file))
+                    .header(ContentType::Form)
+                    .body(format!("base={}&content={}", base, form_encode(content)))
+                    .dispatch()
+                    .await;
+                response.status() == Status::SeeOther
+            }
+            Operation::Read => {
+                let html = pages[page].0.replace(".md", ".html");
+                let response = client.get(format!("/{}", html)).dispatch().await;
+                response.status() == Status::Ok
+            }
+        };
+        timings.push((operation, start.elapsed(), ok));
+    }
+    timings
+}
+
+/// Runs a load test against a new wiki with the default configuration, in a temporary
+/// directory that is removed afterwards. `mdwiki.toml` isn't read, so webhooks and the like
+/// aren't triggered, but the `MDWIKI_` environment variables of the process are changed to
+/// point at the new wiki.
+pub async fn run(options: StressOptions) -> Result<StressReport, String> {
+    let dir = env::temp_dir().join(format!("mdwiki-stress-{}", rand_safe_string(8)));
+    env::set_var("MDWIKI_CONFIG", dir.join("mdwiki.toml"));
+    env::set_var("MDWIKI_PATH", dir.join("wiki"));
+    let secret_key: [u8; 32] = rand::thread_rng().gen();
+    let secret_key: String = secret_key.iter().map(|b| format!("{:02x}", b)).collect();
+    env::set_var("MDWIKI_SECRET_KEY", secret_key);
+    let report = run_in(options).await;
+    let _ = fs::remove_dir_all(&dir);
+    report
+}
+
+async fn run_in(options: StressOptions) -> Result<StressReport, String> {
+    let config: Config = Config::figment()
+        .extract()
+        .map_err(|e| format!("configuration is invalid: {}", e))?;
+    let store = UserStore::load(config.data_path().join("users.json"));
+    let src = Path::new(&config.path).join("src");
+    fixtures::generate(&src, &options.book)?;
+    let (wiki_state, webapp_state) = WikiState::with_config(config, Plugins::new());
+    wiki_state.setup().await?;
+    store.upsert(User {
+        username: STRESS_USER.to_string(),
+        password: hash_password(STRESS_USER)?,
+        roles: Vec::new(),
+        disabled: false,
+        email: None,
+        email_verified: false,
+        display_name: None,
+        avatar: None,
+    })?;
+
+    let wiki = task::spawn(async { wiki_state.serve().await });
+    let client = Client::tracked(crate::rocket(webapp_state))
+        .await
+        .map_err(|e| format!("could not start the wiki: {}", e))?;
+    let response = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(format!("username={0}&password={0}", STRESS_USER))
+        .dispatch()
+        .await;
+    // a failed login redirects back to the login page
+    if response.headers().get_one("Location") == Some("/login") {
+        return Err("could not log in".to_string());
+    }
+
+    let start = Instant::now();
+    let users = (0..options.users).map(|user| simulate_user(&client, user, options.operations));
+    let timings = join_all(users).await;
+    let elapsed = start.elapsed();
+    // Dropping the client drops the webapp's state, which stops the wiki
+    drop(client);
+    let _ = wiki.await;
+
+    let operations = Operation::ALL
+        .iter()
+        .map(|&operation| {
+            let mut latencies = Vec::new();
+            let mut errors = 0;
+            for &(op, latency, ok) in timings.iter().flatten() {
+                if op == operation {
+                    latencies.push(latency);
+                    errors += (!ok) as usize;
+                }
+            }
+            latencies.sort();
+            OperationStats {
+                operation,
+                count: latencies.len(),
+                errors,
+                p50: percentile(&latencies, 50.0),
+                p99: percentile(&latencies, 99.0),
+                max: latencies.last().copied().unwrap_or_default(),
+            }
+        })
+        .collect();
+    Ok(StressReport {
+        options,
+        elapsed,
+        operations,
+    })
+}
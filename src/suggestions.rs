@@ -0,0 +1,102 @@
+use crate::session::now;
+use crate::utils::*;
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+/// A change to a page suggested by someone who may not change it, waiting for one of its
+/// editors to accept or reject it on `/reviews`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Suggestion {
+    pub id: String,
+    pub username: String,
+    /// The display name of the user, for the reviewers.
+    pub name: String,
+    pub page: String,
+    /// The revision of the page the suggestion started from, see `merge::revision`.
+    pub base: String,
+    pub content: String,
+    /// Unix time of the suggestion.
+    pub created: u64,
+}
+
+/// The suggestions waiting for review, persisted to a file in the data directory.
+#[derive(Debug)]
+pub struct SuggestionStore {
+    path: PathBuf,
+    suggestions: Mutex<Vec<Suggestion>>,
+}
+
+impl SuggestionStore {
+    pub fn load(path: PathBuf) -> SuggestionStore {
+        let suggestions = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).map_err(log_warn).ok())
+            .unwrap_or_default();
+        SuggestionStore {
+            path,
+            suggestions: Mutex::new(suggestions),
+        }
+    }
+    fn persist(&self, suggestions: &[Suggestion]) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            let _ = fs::create_dir_all(parent).map_err(log_warn);
+        }
+        let content = serde_json::to_string(suggestions)
+            .map_err(|e| format!("failed to serialize suggestions: {}", e))?;
+        fs::write(&self.path, content)
+            .map_err(|e| format!("could not write '{}': {}", self.path.display(), e))
+    }
+    /// Adds a suggestion by `username` to change `page` to `content`, returning its id.
+    pub fn suggest(
+        &self,
+        username: &str,
+        name: &str,
+        page: &str,
+        base: &str,
+        content: &str,
+    ) -> Result<String, String> {
+        let mut suggestions = self.suggestions.lock().unwrap();
+        let created = now();
+        let id = format!("{}-{}", created, rand_safe_string(8));
+        suggestions.push(Suggestion {
+            id: id.clone(),
+            username: username.to_string(),
+            name: name.to_string(),
+            page: page.to_string(),
+            base: base.to_string(),
+            content: content.to_string(),
+            created,
+        });
+        self.persist(&suggestions)?;
+        Ok(id)
+    }
+    /// Every suggestion, oldest first.
+    pub fn all(&self) -> Vec<Suggestion> {
+        self.suggestions.lock().unwrap().clone()
+    }
+    pub fn get(&self, id: &str) -> Option<Suggestion> {
+        let suggestions = self.suggestions.lock().unwrap();
+        suggestions
+            .iter()
+            .find(|suggestion| suggestion.id == id)
+            .cloned()
+    }
+    /// Removes the suggestion once it's accepted or rejected.
+    pub fn remove(&self, id: &str) -> Result<Option<Suggestion>, String> {
+        let mut suggestions = self.suggestions.lock().unwrap();
+        let i = match suggestions
+            .iter()
+            .position(|suggestion| suggestion.id == id)
+        {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let suggestion = suggestions.remove(i);
+        self.persist(&suggestions)?;
+        Ok(Some(suggestion))
+    }
+}
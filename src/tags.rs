@@ -0,0 +1,182 @@
+use crate::bulk::{diff, markdown_files, Plan, PlannedChange};
+use crate::lifecycle::front_matter;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// A change to the tags of every page, set with `tags: [a, b]` or `tags: a, b` in the front
+/// matter.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum TagChange {
+    /// Renames a tag that no page has `to` yet.
+    Rename { from: String, to: String },
+    /// Replaces `from` with `into`, another tag in use, on every page.
+    Merge { from: String, into: String },
+}
+
+impl TagChange {
+    fn tags(&self) -> (&str, &str) {
+        match self {
+            TagChange::Rename { from, to } => (from.trim(), to.trim()),
+            TagChange::Merge { from, into } => (from.trim(), into.trim()),
+        }
+    }
+    /// The commit message for the change, made to `pages` pages.
+    pub fn message(&self, pages: usize) -> String {
+        let (from, to) = self.tags();
+        match self {
+            TagChange::Rename { .. } => {
+                format!("Rename tag '{}' to '{}' in {} pages", from, to, pages)
+            }
+            TagChange::Merge { .. } => {
+                format!("Merge tag '{}' into '{}' in {} pages", from, to, pages)
+            }
+        }
+    }
+}
+
+/// The tags of every page, and the pages without any.
+#[derive(Debug, Default, Serialize)]
+pub struct TagIndex {
+    /// How many pages have each tag.
+    pub tags: BTreeMap<String, usize>,
+    pub untagged: Vec<String>,
+}
+
+/// The `tags` line of the front matter of `content`, without the `tags:`.
+fn tags_line(content: &str) -> Option<&str> {
+    front_matter(content)?
+        .0
+        .into_iter()
+        .find(|(key, _)| *key == "tags")
+        .map(|(_, value)| value)
+}
+
+/// The tags in the front matter of `content`.
+pub fn page_tags(content: &str) -> Vec<String> {
+    let line = match tags_line(content) {
+        Some(line) => line.trim_start_matches('[').trim_end_matches(']'),
+        None => return Vec::new(),
+    };
+    let mut tags: Vec<String> = Vec::new();
+    for tag in line.split(',') {
+        let tag = tag.trim().trim_matches(|c| c == '"' || c == '\'');
+        if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    tags
+}
+
+/// `content` with the tags in its front matter replaced with `tags`, written the same way.
+fn set_tags(content: &str, tags: &[String]) -> String {
+    let line = match tags_line(content) {
+        Some(line) => line,
+        None => return content.to_string(),
+    };
+    let tags = tags.join(", ");
+    let tags = if line.starts_with('[') {
+        format!("[{}]", tags)
+    } else {
+        tags
+    };
+    let mut offset = 0;
+    while offset < content.len() {
+        let end = content[offset..]
+            .find('\n')
+            .map_or(content.len(), |i| offset + i + 1);
+        let line = &content[offset..end];
+        if line.find(':').map_or(false, |i| line[..i].trim() == "tags") {
+            let ending = &line[line.trim_end().len()..];
+            return format!(
+                "{}tags: {}{}{}",
+                &content[..offset],
+                tags,
+                ending,
+                &content[end..]
+            );
+        }
+        offset = end;
+    }
+    content.to_string()
+}
+
+/// The tags of the pages in `src`.
+pub fn index(src: &Path) -> TagIndex {
+    let mut index = TagIndex::default();
+    for page in markdown_files(src) {
+        let content = fs::read_to_string(src.join(&page)).unwrap_or_default();
+        let tags = page_tags(&content);
+        if tags.is_empty() {
+            index.untagged.push(page.to_string_lossy().into_owned());
+        }
+        for tag in tags {
+            *index.tags.entry(tag).or_insert(0) += 1;
+        }
+    }
+    index
+}
+
+/// Plans renaming or merging a tag on every page that has it.
+pub fn plan_tag_change(src: &Path, change: &TagChange) -> Result<Plan, String> {
+    let (from, to) = change.tags();
+    if from.is_empty() || to.is_empty() {
+        return Err("Tags can't be empty".into());
+    }
+    if to.contains(|c| c == ',' || c == '[' || c == ']' || c == '\n') {
+        return Err(format!(
+            "'{}' can't be a tag, tags can't contain , [ or ]",
+            to
+        ));
+    }
+    if from == to {
+        return Err(format!("'{}' is already called that", from));
+    }
+    let existing = index(src).tags;
+    if !existing.contains_key(from) {
+        return Err(format!("No page has the tag '{}'", from));
+    }
+    match change {
+        TagChange::Rename { .. } if existing.contains_key(to) => {
+            return Err(format!(
+                "The tag '{}' already exists, merge the tags instead",
+                to
+            ));
+        }
+        TagChange::Merge { .. } if !existing.contains_key(to) => {
+            return Err(format!("No page has the tag '{}' to merge into", to));
+        }
+        _ => {}
+    }
+
+    let mut plan = Plan::default();
+    for page in markdown_files(src) {
+        let content = fs::read_to_string(src.join(&page))
+            .map_err(|e| format!("could not read '{}': {}", page.display(), e))?;
+        let tags = page_tags(&content);
+        if !tags.iter().any(|tag| tag == from) {
+            continue;
+        }
+        let mut new_tags: Vec<String> = Vec::new();
+        for tag in tags {
+            let tag = if tag == from { to.to_string() } else { tag };
+            if !new_tags.contains(&tag) {
+                new_tags.push(tag);
+            }
+        }
+        let new_content = set_tags(&content, &new_tags);
+        plan.changes.push(PlannedChange {
+            action: "edit",
+            path: page.to_string_lossy().into_owned(),
+            to: None,
+            diff: diff(&page, &content, &new_content),
+            content: Some(new_content),
+        });
+    }
+
+    Ok(plan)
+}
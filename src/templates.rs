@@ -0,0 +1,46 @@
+use crate::config::Config;
+
+use std::fs;
+use std::path::PathBuf;
+
+use rust_embed::RustEmbed;
+
+/// The built-in templates, compiled into the binary so it runs without any files next to it.
+#[derive(RustEmbed)]
+#[folder = "templates/"]
+struct DefaultTemplates;
+
+/// Writes the templates to a directory in the data directory for rocket to load, the built-in
+/// ones first and then any from `templates_dir` on top of them. Returns the directory.
+pub fn materialize(config: &Config) -> Result<PathBuf, String> {
+    let dir = std::env::current_dir()
+        .map_err(|e| format!("could not get the current directory: {}", e))?
+        .join(config.data_path())
+        .join("templates");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)
+        .map_err(|e| format!("could not create '{}': {}", dir.display(), e))?;
+
+    for name in DefaultTemplates::iter() {
+        let content = DefaultTemplates::get(&name).unwrap();
+        fs::write(dir.join(name.as_ref()), content)
+            .map_err(|e| format!("could not write template '{}': {}", name, e))?;
+    }
+
+    if let Some(templates_dir) = &config.templates_dir {
+        let entries = fs::read_dir(templates_dir)
+            .map_err(|e| format!("could not read templates_dir '{}': {}", templates_dir, e))?;
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if name.ends_with(".tera") && path.is_file() => name.to_string(),
+                _ => continue,
+            };
+            fs::copy(&path, dir.join(&name))
+                .map_err(|e| format!("could not copy template '{}': {}", path.display(), e))?;
+            info!("using template '{}' from {}", name, templates_dir);
+        }
+    }
+
+    Ok(dir)
+}
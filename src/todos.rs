@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
+use serde::Serialize;
+
+const TODO_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\bTODO\(@([\w.-]+)\)\s*:?\s*(.*)").unwrap());
+
+/// An action item in a page, `TODO(@name): text`, assigned to a user or a group.
+#[derive(Debug, Clone, Serialize)]
+pub struct Todo {
+    pub path: String,
+    pub line: usize,
+    pub assignee: String,
+    pub text: String,
+}
+
+fn scan_page(path: String, content: &str, todos: &mut Vec<Todo>) {
+    let mut in_code = false;
+    for (i, line) in content.lines().enumerate() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+        for captures in TODO_REGEX.captures_iter(line) {
+            todos.push(Todo {
+                path: path.clone(),
+                line: i + 1,
+                assignee: captures[1].to_string(),
+                text: captures[2].trim().to_string(),
+            });
+        }
+    }
+}
+
+fn scan_dir(src: &Path, dir: &Path, todos: &mut Vec<Todo>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("could not read directory '{}': {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let relative = path.strip_prefix(src).unwrap_or(&path).to_path_buf();
+        if path.is_dir() {
+            if !relative.starts_with("images") && !relative.starts_with("files") {
+                scan_dir(src, &path, todos);
+            }
+        } else if path.extension().map_or(false, |ext| ext == "md")
+            && !relative.ends_with("SUMMARY.md")
+        {
+            if let Ok(content) = fs::read_to_string(&path) {
+                scan_page(relative.to_string_lossy().to_string(), &content, todos);
+            }
+        }
+    }
+}
+
+/// The action items in the pages in `src`, ordered by page and line.
+pub fn scan(src: &Path) -> Vec<Todo> {
+    let mut todos = Vec::new();
+    scan_dir(src, src, &mut todos);
+    todos.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    todos
+}
+
+/// The action items found by the latest successful build.
+#[derive(Debug, Default)]
+pub struct TodoList {
+    todos: RwLock<Vec<Todo>>,
+}
+
+impl TodoList {
+    pub fn set(&self, todos: Vec<Todo>) {
+        *self.todos.write().unwrap() = todos;
+    }
+    pub fn all(&self) -> Vec<Todo> {
+        self.todos.read().unwrap().clone()
+    }
+}
@@ -0,0 +1,133 @@
+//! How uploaded images are named, set with `upload_names`. Teams have their own conventions for
+//! naming assets: some want names that sort by when they were uploaded, others the same name
+//! for the same image, or the name the file had on their computer.
+
+use crate::plugin::Plugins;
+use crate::utils::rand_safe_string;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_std::fs;
+use async_std::path::Path;
+
+use ring::digest;
+
+use serde::{Deserialize, Serialize};
+
+use time::OffsetDateTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadNames {
+    /// The name of the uploaded file as a slug and random letters, like `team_photo_abcdefgh`.
+    /// Images uploaded without a name get random names.
+    OriginalName,
+    /// 16 random lowercase letters, the default, which say nothing about the uploader's files.
+    Random,
+    /// A UUIDv7, which sorts by the time of the upload.
+    Uuid,
+    /// The SHA-256 of the image, so the same image uploaded twice gets the same name.
+    ContentHash,
+    /// The date of the upload and random letters, like `2021-03-14_abcdefgh`.
+    Date,
+    /// The first plugin that names the image, see `Plugin::upload_name`. Images no plugin names
+    /// get random names.
+    Plugins,
+}
+
+impl Default for UploadNames {
+    fn default() -> UploadNames {
+        UploadNames::Random
+    }
+}
+
+impl UploadNames {
+    /// The name, without the extension, for the image uploaded to `upload`. `original` is the
+    /// name the file was uploaded with, if it had one.
+    pub async fn name(
+        self,
+        original: Option<&str>,
+        upload: &Path,
+        plugins: &Plugins,
+    ) -> Result<String, String> {
+        let name = match self {
+            UploadNames::OriginalName => match original.map(slug).filter(|s| !s.is_empty()) {
+                Some(slug) => format!("{}_{}", slug, rand_safe_string(8)),
+                None => rand_safe_string(16),
+            },
+            UploadNames::Random => rand_safe_string(16),
+            UploadNames::Uuid => uuid_v7(),
+            UploadNames::ContentHash => {
+                let content = read(upload).await?;
+                hex(digest::digest(&digest::SHA256, &content).as_ref())
+            }
+            UploadNames::Date => {
+                let date = OffsetDateTime::now_utc().format("%Y-%m-%d");
+                format!("{}_{}", date, rand_safe_string(8))
+            }
+            UploadNames::Plugins => match plugins.upload_name(original, &read(upload).await?) {
+                Some(name) if is_upload_name(&name) => name,
+                Some(name) => {
+                    warn!(
+                        "'{}' can't be used to name an image, using a random name",
+                        name
+                    );
+                    rand_safe_string(16)
+                }
+                None => rand_safe_string(16),
+            },
+        };
+        Ok(name)
+    }
+}
+
+/// Whether pages can link to an image named `name`, with `/images/<name>.<extension>`.
+pub fn is_upload_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// `file_name` without its extension, lowercase and with anything but letters and digits
+/// replaced by `_`, so it can be used in image links.
+fn slug(file_name: &str) -> String {
+    let stem = Path::new(file_name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    stem.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+async fn read(upload: &Path) -> Result<Vec<u8>, String> {
+    fs::read(upload)
+        .await
+        .map_err(|e| format!("could not read '{}': {}", upload.display(), e))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The milliseconds since the unix epoch followed by random bits, as a UUID of version 7.
+fn uuid_v7() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+    bytes[6] = 0x70 | (bytes[6] & 0x0f);
+    bytes[8] = 0x80 | (bytes[8] & 0x3f);
+    let hex = hex(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
@@ -0,0 +1,125 @@
+use crate::config::User;
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use rand::Rng;
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+        .map_err(|e| format!("failed to hash password: {}", e))
+}
+
+#[derive(Debug, Default)]
+struct Users {
+    modified: Option<SystemTime>,
+    users: Vec<User>,
+}
+
+/// Users managed at runtime, persisted to a file in the data directory. The file is reloaded
+/// when it changes on disk, so it can also be edited by hand without a restart.
+#[derive(Debug)]
+pub struct UserStore {
+    path: PathBuf,
+    users: RwLock<Users>,
+}
+
+impl UserStore {
+    pub fn load(path: PathBuf) -> UserStore {
+        let store = UserStore {
+            path,
+            users: RwLock::new(Users::default()),
+        };
+        store.reload_if_changed();
+        store
+    }
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+    fn reload_if_changed(&self) {
+        let modified = self.modified();
+        if self.users.read().unwrap().modified == modified {
+            return;
+        }
+
+        let users = match fs::read_to_string(&self.path) {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(users) => users,
+                Err(e) => {
+                    warn!("could not parse users file, keeping previous users: {}", e);
+                    return;
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        debug!("loaded users from {}", self.path.to_string_lossy());
+
+        *self.users.write().unwrap() = Users { modified, users };
+    }
+    fn persist(&self, users: &mut Users) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("could not create data directory: {}", e))?;
+        }
+        let content = serde_json::to_string_pretty(&users.users)
+            .map_err(|e| format!("could not serialize users: {}", e))?;
+        fs::write(&self.path, content).map_err(|e| format!("could not write users: {}", e))?;
+        users.modified = self.modified();
+        Ok(())
+    }
+    pub fn all(&self) -> Vec<User> {
+        self.reload_if_changed();
+        self.users.read().unwrap().users.clone()
+    }
+    pub fn get(&self, username: &str) -> Option<User> {
+        self.reload_if_changed();
+        self.users
+            .read()
+            .unwrap()
+            .users
+            .iter()
+            .find(|user| user.username == username)
+            .cloned()
+    }
+    pub fn upsert(&self, user: User) -> Result<(), String> {
+        self.reload_if_changed();
+        let mut users = self.users.write().unwrap();
+        match users
+            .users
+            .iter_mut()
+            .find(|existing| existing.username == user.username)
+        {
+            Some(existing) => *existing = user,
+            None => users.users.push(user),
+        }
+        self.persist(&mut users)
+    }
+    pub fn update(&self, username: &str, f: impl FnOnce(&mut User)) -> Result<bool, String> {
+        self.reload_if_changed();
+        let mut users = self.users.write().unwrap();
+        match users
+            .users
+            .iter_mut()
+            .find(|user| user.username == username)
+        {
+            Some(user) => f(user),
+            None => return Ok(false),
+        }
+        self.persist(&mut users).map(|_| true)
+    }
+    pub fn remove(&self, username: &str) -> Result<bool, String> {
+        self.reload_if_changed();
+        let mut users = self.users.write().unwrap();
+        let before = users.users.len();
+        users.users.retain(|user| user.username != username);
+        if users.users.len() == before {
+            return Ok(false);
+        }
+        self.persist(&mut users).map(|_| true)
+    }
+}
@@ -1,4 +1,4 @@
-use async_std::path::{Component, Path};
+use async_std::path::{Component, Path, PathBuf};
 
 use rand::Rng;
 
@@ -13,7 +13,26 @@ macro_rules! try_response {
 }
 
 pub const RESERVED_NAMES: &[&str] = &["SUMMARY.md", "index.md"];
-pub const RESERVED_PREFIXES: &[&str] = &["new", "edit", "upload", "images"];
+/// A directory with this file has its entries in the summary written by hand in it, instead
+/// of being generated.
+pub const SUMMARY_FRAGMENT: &str = "_summary.md";
+pub const RESERVED_PREFIXES: &[&str] = &[
+    "new",
+    "edit",
+    "upload",
+    "images",
+    "files",
+    "embed",
+    "profile",
+    "avatars",
+    "watch",
+    "diff",
+    "blame",
+    "deleted",
+    "dashboard",
+    "todos",
+    "stats",
+];
 
 pub fn log_warn<T: std::fmt::Display>(err: T) -> T {
     warn!("{}", err);
@@ -31,13 +50,77 @@ pub fn is_reserved_name(path: &Path) -> bool {
             .is_some()
 }
 
+/// The directory, relative to the book source, with the files attached to `page`.
+pub fn attachments_dir(page: &Path) -> PathBuf {
+    Path::new("files").join(page.with_extension(""))
+}
+
+/// Whether `name` can be used as the name of an attached file.
+pub fn is_attachment_name(name: &str) -> bool {
+    !name.starts_with('.') && is_portable_name(name)
+}
+
+/// Whether `name` can be used for a file or directory on every platform, so the book can be
+/// checked out and served on Windows too. Windows doesn't allow some characters, names ending
+/// in a dot or a space, or the names of devices, like `con` or `com1.md`.
+pub fn is_portable_name(name: &str) -> bool {
+    const DEVICES: &[&str] = &["CON", "PRN", "AUX", "NUL"];
+    let stem = name.split('.').next().unwrap_or_default().to_uppercase();
+    let numbered = (stem.starts_with("COM") || stem.starts_with("LPT"))
+        && stem.len() == 4
+        && matches!(stem.as_bytes()[3], b'1'..=b'9');
+    !name.is_empty()
+        && !name.ends_with('.')
+        && !name.ends_with(' ')
+        && !DEVICES.contains(&stem.as_str())
+        && !numbered
+        && !name.contains(|c: char| c.is_control() || "<>:\"/\\|?*".contains(c))
+}
+
+/// Whether `path` is relative and only goes down into directories. Backslashes separate
+/// directories on Windows, so they aren't allowed in names anywhere.
 pub fn path_is_simple(path: &Path) -> bool {
-    path.components()
-        .find(|comp| match comp {
-            Component::Normal(_) => false,
-            _ => true,
+    path.components().all(|comp| match comp {
+        Component::Normal(name) => !name.to_string_lossy().contains('\\'),
+        _ => false,
+    })
+}
+
+/// `path` with `/` between its components, as in urls, the summary and the repository,
+/// whichever separator the platform uses.
+pub fn url_path(path: impl AsRef<std::path::Path>) -> String {
+    path.as_ref()
+        .components()
+        .filter_map(|comp| match comp {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy()),
+            std::path::Component::ParentDir => Some("..".into()),
+            _ => None,
         })
-        .is_none()
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// `path` under `root` with the casing of the files and directories on disk, if it exists there
+/// when case is ignored.
+pub fn find_ignoring_case(
+    root: &std::path::Path,
+    path: &std::path::Path,
+) -> Option<std::path::PathBuf> {
+    let mut found = std::path::PathBuf::new();
+    for component in path.components() {
+        let name = component.as_os_str().to_str()?.to_lowercase();
+        let dir = root.join(&found);
+        if dir.join(component).exists() {
+            found.push(component);
+            continue;
+        }
+        let entry = std::fs::read_dir(&dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name().to_str().map(str::to_lowercase) == Some(name.clone()))?;
+        found.push(entry.file_name());
+    }
+    Some(found)
 }
 
 pub fn rand_safe_string(length: usize) -> String {
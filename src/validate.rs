@@ -0,0 +1,308 @@
+//! Checks of the configuration, run when mdwiki starts and by `mdwiki check`. Mistakes like a
+//! misspelled key are reported with what to change, instead of a panic or a setting that is
+//! silently ignored.
+
+use crate::backup::BackupConfig;
+use crate::config::{Branding, Config, LoginLimits, OidcConfig, PreprocessorSandbox, SmtpConfig};
+use crate::doctor::is_writable;
+use crate::remote::RemoteConfig;
+use crate::robots::Crawlers;
+use crate::users::UserStore;
+
+use std::fmt;
+use std::path::Path;
+
+use figment::value::Value;
+use figment::Figment;
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+
+/// The profiles `mdwiki.toml` can have tables for. Keys in other tables are never used.
+const PROFILES: &[&str] = &["default", "global", "debug", "release"];
+
+/// Rocket's settings, which are read from the same file.
+const ROCKET_KEYS: &[&str] = &[
+    "address",
+    "port",
+    "workers",
+    "keep_alive",
+    "limits",
+    "tls",
+    "secret_key",
+    "log_level",
+    "cli_colors",
+    "ident",
+    "temp_dir",
+];
+
+#[derive(Debug)]
+pub struct Problem {
+    pub message: String,
+    /// What to change to fix it.
+    pub hint: String,
+    /// Whether mdwiki can't start with it.
+    pub fatal: bool,
+}
+
+impl Problem {
+    fn fatal(message: impl Into<String>, hint: impl Into<String>) -> Problem {
+        Problem {
+            message: message.into(),
+            hint: hint.into(),
+            fatal: true,
+        }
+    }
+    fn warning(message: impl Into<String>, hint: impl Into<String>) -> Problem {
+        Problem {
+            message: message.into(),
+            hint: hint.into(),
+            fatal: false,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.hint)
+    }
+}
+
+/// The configuration from `Config::figment`, and the problems with it mdwiki can start with.
+/// When there's a problem it can't start with, every problem found is returned instead.
+pub fn load() -> Result<(Config, Vec<Problem>), Vec<Problem>> {
+    let figment = Config::figment();
+    let mut problems = unknown_keys(&figment);
+    let config: Config = match figment.extract() {
+        Ok(config) => config,
+        Err(errors) => {
+            problems.extend(errors.into_iter().map(|e| {
+                Problem::fatal(
+                    e.to_string(),
+                    "check mdwiki.toml and the MDWIKI_ environment variables",
+                )
+            }));
+            return Err(problems);
+        }
+    };
+    problems.extend(check_users(&config));
+    problems.extend(check_paths(&config));
+    if problems.iter().any(|problem| problem.fatal) {
+        Err(problems)
+    } else {
+        Ok((config, problems))
+    }
+}
+
+/// Keys and profiles mdwiki doesn't know, which are most likely misspelled.
+fn unknown_keys(figment: &Figment) -> Vec<Problem> {
+    // Configuration that can't be read is reported when it's extracted
+    let data = match figment.data() {
+        Ok(data) => data,
+        Err(_) => return Vec::new(),
+    };
+    let known = fields::<Config>()
+        .iter()
+        .chain(ROCKET_KEYS)
+        .copied()
+        .collect::<Vec<_>>();
+    let sections = [
+        ("branding", fields::<Branding>()),
+        ("login_limits", fields::<LoginLimits>()),
+        ("crawlers", fields::<Crawlers>()),
+        ("oidc", fields::<OidcConfig>()),
+        ("smtp", fields::<SmtpConfig>()),
+        ("preprocessor_sandbox", fields::<PreprocessorSandbox>()),
+        ("backup", fields::<BackupConfig>()),
+        ("remote", fields::<RemoteConfig>()),
+    ];
+
+    let mut problems = Vec::new();
+    for (profile, dict) in data {
+        let profile = profile.to_string();
+        if !PROFILES
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(&profile))
+        {
+            problems.push(Problem::warning(
+                format!("unknown profile [{}], its keys are ignored", profile),
+                "move them to [default], [debug] or [release]",
+            ));
+            continue;
+        }
+        for (key, value) in &dict {
+            if !known.contains(&key.as_str()) {
+                problems.push(unknown_key(key, &profile, &known));
+                continue;
+            }
+            let section = sections.iter().find(|(section, _)| section == key);
+            if let (Some((_, fields)), Value::Dict(_, nested)) = (section, value) {
+                for nested_key in nested.keys() {
+                    if !fields.contains(&nested_key.as_str()) {
+                        let path = format!("{}.{}", key, nested_key);
+                        problems.push(unknown_key(&path, &profile, fields));
+                    }
+                }
+            }
+        }
+    }
+    problems
+}
+
+fn unknown_key(key: &str, profile: &str, known: &[&str]) -> Problem {
+    let name = key.rsplit('.').next().unwrap_or(key);
+    let hint = match closest(name, known) {
+        Some(closest) => format!("did you mean '{}'?", closest),
+        None => "remove it, see the README for the settings".to_string(),
+    };
+    Problem::warning(format!("unknown key '{}' in [{}]", key, profile), hint)
+}
+
+/// The key in `known` that `key` is most likely a misspelling of, if any is close enough.
+fn closest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|candidate| (distance(key, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 1 + key.len() / 4)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// The number of characters that have to be inserted, removed or replaced to turn `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let replace = previous[j] + if a == *b { 0 } else { 1 };
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Whether anyone can use the wiki. Without anonymous access there has to be a user to log in
+/// as, from the configuration, `mdwiki adduser` or single sign-on.
+fn check_users(config: &Config) -> Option<Problem> {
+    if config.allow_anonymous || config.oidc.is_some() || !config.users.is_empty() {
+        return None;
+    }
+    if !UserStore::load(config.data_path().join("users.json"))
+        .all()
+        .is_empty()
+    {
+        return None;
+    }
+    Some(Problem::fatal(
+        "anonymous access is disabled, but there are no users to log in as",
+        "add a [[default.users]] entry or run `mdwiki adduser`, configure oidc, or set \
+         allow_anonymous = true",
+    ))
+}
+
+/// Whether mdwiki can write where it needs to. A book that can't be written to is served
+/// without editing, see `WikiState::setup`.
+fn check_paths(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let book = Path::new(&config.path);
+    if book.is_dir() {
+        if !is_writable(book) {
+            problems.push(Problem::warning(
+                format!("the book at '{}' can't be written to", config.path),
+                "it's served as it was last built with editing disabled, fix the permissions \
+                 of the user running mdwiki to edit it",
+            ));
+        }
+    } else if !can_create(book) {
+        problems.push(Problem::fatal(
+            format!(
+                "the book at '{}' doesn't exist and can't be created",
+                config.path
+            ),
+            "create it, or set path to a directory mdwiki can write to",
+        ));
+    }
+    if let Some(upload_path) = &config.tmp_upload_path {
+        let path = Path::new(upload_path);
+        let usable = match path.is_dir() {
+            true => is_writable(path),
+            false => can_create(path),
+        };
+        if !usable {
+            problems.push(Problem::fatal(
+                format!(
+                    "uploads can't be written to tmp_upload_path '{}'",
+                    upload_path
+                ),
+                "fix the permissions of the user running mdwiki, or set tmp_upload_path to \
+                 another directory",
+            ));
+        }
+    }
+    problems
+}
+
+/// Whether the directory `path` can be created, which it can if the closest directory above it
+/// that exists can be written to.
+fn can_create(path: &Path) -> bool {
+    let existing = path
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.as_os_str().is_empty() || dir.is_dir());
+    match existing {
+        Some(dir) if dir.as_os_str().is_empty() => is_writable(Path::new(".")),
+        Some(dir) => is_writable(dir),
+        None => false,
+    }
+}
+
+/// The fields of the struct `T`, found by having it deserialize itself from a deserializer
+/// that stops as soon as it's told the fields.
+fn fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    match T::deserialize(Fields) {
+        Err(Stop(fields)) => fields,
+        Ok(_) => &[],
+    }
+}
+
+struct Fields;
+
+#[derive(Debug)]
+struct Stop(&'static [&'static str]);
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fields: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Stop {}
+
+impl de::Error for Stop {
+    fn custom<T: fmt::Display>(_: T) -> Stop {
+        Stop(&[])
+    }
+}
+
+impl<'de> Deserializer<'de> for Fields {
+    type Error = Stop;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Stop> {
+        Err(Stop(&[]))
+    }
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Stop> {
+        Err(Stop(fields))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
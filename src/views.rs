@@ -0,0 +1,84 @@
+use crate::utils::*;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long counted views may stay in memory only.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Counts {
+    pages: BTreeMap<String, u64>,
+    flushed: Instant,
+    dirty: bool,
+}
+
+/// How often each page has been viewed, counted in memory and written to a file in the data
+/// directory at most once a minute, and when mdwiki stops.
+#[derive(Debug)]
+pub struct PageViews {
+    path: PathBuf,
+    counts: Mutex<Counts>,
+}
+
+impl PageViews {
+    pub fn load(path: PathBuf) -> PageViews {
+        let pages = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).map_err(log_warn).ok())
+            .unwrap_or_default();
+        PageViews {
+            path,
+            counts: Mutex::new(Counts {
+                pages,
+                flushed: Instant::now(),
+                dirty: false,
+            }),
+        }
+    }
+    fn flush(&self, counts: &mut Counts) {
+        if let Some(parent) = self.path.parent() {
+            let _ = fs::create_dir_all(parent).map_err(log_warn);
+        }
+        match serde_json::to_string(&counts.pages) {
+            Ok(content) => {
+                let _ = fs::write(&self.path, content).map_err(log_warn);
+            }
+            Err(e) => warn!("failed to serialize page views: {}", e),
+        }
+        counts.flushed = Instant::now();
+        counts.dirty = false;
+    }
+    /// Counts a view of the page built from the markdown file at `page`.
+    pub fn record(&self, page: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.pages.entry(page.to_string()).or_default() += 1;
+        counts.dirty = true;
+        if counts.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.flush(&mut counts);
+        }
+    }
+    /// The viewed pages with their views, most viewed first.
+    pub fn most_viewed(&self) -> Vec<(String, u64)> {
+        let counts = self.counts.lock().unwrap();
+        let mut pages = counts
+            .pages
+            .iter()
+            .map(|(page, views)| (page.clone(), *views))
+            .collect::<Vec<_>>();
+        pages.sort_by(|a, b| b.1.cmp(&a.1));
+        pages
+    }
+}
+
+impl Drop for PageViews {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.dirty {
+            self.flush(&mut counts);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use crate::utils::*;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Watchers of a page, username to the email address notifications are sent to.
+type Watchers = BTreeMap<String, String>;
+
+/// Who watches which pages, persisted to a file in the data directory. The email address is
+/// stored with the watch, so the wiki task can notify users without looking them up.
+#[derive(Debug)]
+pub struct WatchStore {
+    path: PathBuf,
+    pages: Mutex<BTreeMap<String, Watchers>>,
+}
+
+impl WatchStore {
+    pub fn load(path: PathBuf) -> WatchStore {
+        let pages = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).map_err(log_warn).ok())
+            .unwrap_or_default();
+        WatchStore {
+            path,
+            pages: Mutex::new(pages),
+        }
+    }
+    fn persist(&self, pages: &BTreeMap<String, Watchers>) {
+        if let Some(parent) = self.path.parent() {
+            let _ = fs::create_dir_all(parent).map_err(log_warn);
+        }
+        match serde_json::to_string(pages) {
+            Ok(content) => {
+                let _ = fs::write(&self.path, content).map_err(log_warn);
+            }
+            Err(e) => warn!("failed to serialize watches: {}", e),
+        }
+    }
+    /// Starts or stops watching `page`, returning whether `username` watches it now.
+    pub fn toggle(&self, page: &str, username: &str, email: &str) -> bool {
+        let mut pages = self.pages.lock().unwrap();
+        let watchers = pages.entry(page.to_string()).or_default();
+        let watching = watchers.remove(username).is_none();
+        if watching {
+            watchers.insert(username.to_string(), email.to_string());
+        }
+        if watchers.is_empty() {
+            pages.remove(page);
+        }
+        self.persist(&pages);
+        watching
+    }
+    /// The pages `username` watches.
+    pub fn watched_by(&self, username: &str) -> Vec<String> {
+        self.pages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, watchers)| watchers.contains_key(username))
+            .map(|(page, _)| page.clone())
+            .collect()
+    }
+    pub fn watchers(&self, page: &str) -> Watchers {
+        self.pages
+            .lock()
+            .unwrap()
+            .get(page)
+            .cloned()
+            .unwrap_or_default()
+    }
+    /// Moves the watches of `from`, and of the pages under it if it's a directory, to `to`.
+    pub fn moved(&self, from: &str, to: &str) {
+        let mut pages = self.pages.lock().unwrap();
+        let moved = pages
+            .keys()
+            .filter(|page| *page == from || page.starts_with(&format!("{}/", from)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if moved.is_empty() {
+            return;
+        }
+        for page in moved {
+            let watchers = pages.remove(&page).unwrap_or_default();
+            let page = format!("{}{}", to, &page[from.len()..]);
+            pages.entry(page).or_default().extend(watchers);
+        }
+        self.persist(&pages);
+    }
+}
@@ -1,346 +1,2918 @@
-use crate::config::{Config, User};
+use crate::audit::RequestUser;
+use crate::auth::OidcProvider;
+use crate::builds::Build;
+use crate::bulk::{self, relative_link, MARKDOWN_LINK_REGEX};
+use crate::cache::{Cached, CachedBytes, CachedPage, Conditional, PageCache, Validators};
+use crate::config::{Branding, Config, User, WikiTree};
+use crate::contributors::{contributors, Contributor, PageContribution};
+use crate::drafts::{self, Draft};
+use crate::events::EventKind;
+use crate::headings;
+use crate::idempotency::IdempotencyStore;
+use crate::lifecycle::{page_status, PageStatus};
+use crate::lint::{self, Issue};
+use crate::locks::EditLocks;
+use crate::merge;
+use crate::mirrors::MirrorStatus;
+use crate::net::ClientIp;
+use crate::plugin::Plugins;
+use crate::range::{ByteRange, RangeHeader, RangedBody};
+use crate::ratelimit::LoginLimiter;
+use crate::reset::{ResetTokens, RESET_LIFETIME};
+use crate::robots;
+use crate::sanitize::sanitize_markdown;
+use crate::security::{with_frame_ancestors, CONTENT_SECURITY_POLICY};
+use crate::session::SessionStore;
+use crate::shared::SharedState;
+use crate::todos::Todo;
+use crate::users::{hash_password, UserStore};
 use crate::utils::*;
-use crate::wiki::WikiRequest;
+use crate::wiki::{BlameLine, DeletedPage, RecentChange, WikiRequest, WikiResponse};
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
 
 use async_std::fs;
 use async_std::path::{Path, PathBuf};
+use async_std::prelude::*;
 
 use rocket::data::{Data, ToByteUnit};
-use rocket::http::{ContentType, Cookie, CookieJar, Status};
+use rocket::http::uri::{Origin, Uri};
+use rocket::http::{ContentType, Cookie, CookieJar, Header, SameSite, Status};
 use rocket::request::{self, FlashMessage, Form, FromRequest, Request};
-use rocket::response::NamedFile;
-use rocket::response::{Flash, Redirect};
-use rocket::tokio::sync::{mpsc, oneshot};
+use rocket::response::{status, Flash, Redirect};
+use rocket::tokio::fs::File;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::sync::mpsc::error::TrySendError;
+use rocket::tokio::sync::{mpsc, oneshot, RwLock};
+use rocket::tokio::task;
 use rocket::State;
-use rocket_contrib::templates::Template;
+use rocket_contrib::templates::{Engines, Template};
+
+use serde::{Deserialize, Serialize};
 
-use serde::Serialize;
+use once_cell::sync::Lazy;
+
+use regex::{Captures, Regex};
 
 const MDWIKI_AUTH_COOKIE: &str = "mdwiki_auth";
+const MDWIKI_OIDC_COOKIE: &str = "mdwiki_oidc";
+
+const MD_LINK_EXTENSION_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\.md(#.*)?$").unwrap());
 
 #[rocket::async_trait]
 impl<'a, 'r> FromRequest<'a, 'r> for User {
     type Error = ();
 
     async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let username_cookie = if let Some(username) = req.cookies().get_private(MDWIKI_AUTH_COOKIE)
+        let session_cookie = if let Some(session) = req.cookies().get_private(MDWIKI_AUTH_COOKIE)
         {
-            username
+            session
         } else {
             return request::Outcome::Forward(());
         };
 
-        let user = if let Some(user) = try_outcome!(req.guard::<State<'r, Config>>().await)
-            .users
-            .iter()
-            .find(|user| user.username == username_cookie.value())
-        {
-            user.clone()
+        let config = try_outcome!(req.guard::<State<'r, Config>>().await);
+        let state = try_outcome!(req.guard::<State<'r, WebappState>>().await);
+
+        let session = if let Some(session) = state.sessions.get(session_cookie.value()) {
+            session
         } else {
-            return request::Outcome::Failure((Status::BadRequest, ()));
+            req.cookies()
+                .remove_private(Cookie::named(MDWIKI_AUTH_COOKIE));
+            return request::Outcome::Forward(());
+        };
+
+        // The user was removed or disabled since, or only existed in memory before a restart
+        let mut user = if let Some(user) = state.find_user(&config, &session.username).await {
+            user
+        } else {
+            state.sessions.revoke(session_cookie.value());
+            req.cookies()
+                .remove_private(Cookie::named(MDWIKI_AUTH_COOKIE));
+            return request::Outcome::Forward(());
         };
+        for role in session.roles {
+            if !user.roles.contains(&role) {
+                user.roles.push(role);
+            }
+        }
+
+        req.local_cache(|| RequestUser(Some(user.username.clone())));
 
         request::Outcome::Success(user)
     }
 }
 
+pub struct Admin(pub User);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let user = try_outcome!(req.guard::<User>().await);
+        if !user.is_admin() {
+            return request::Outcome::Failure((Status::Forbidden, ()));
+        }
+        request::Outcome::Success(Admin(user))
+    }
+}
+
 pub struct WebappState {
     tx: mpsc::Sender<WikiRequest>,
+    pub shared: Arc<SharedState>,
+    pub idempotency: IdempotencyStore,
+    oidc: Option<OidcProvider>,
+    oidc_users: RwLock<HashMap<String, User>>,
+    login_limiter: LoginLimiter,
+    sessions: SessionStore,
+    users: UserStore,
+    reset_tokens: ResetTokens,
+    edit_locks: EditLocks,
 }
 
 impl WebappState {
-    pub fn new(tx: mpsc::Sender<WikiRequest>) -> Self {
-        WebappState { tx }
+    pub fn new(tx: mpsc::Sender<WikiRequest>, config: &Config, shared: Arc<SharedState>) -> Self {
+        WebappState {
+            tx,
+            shared,
+            idempotency: IdempotencyStore::default(),
+            oidc: config.oidc.clone().map(OidcProvider::new),
+            oidc_users: RwLock::new(HashMap::new()),
+            login_limiter: LoginLimiter::default(),
+            sessions: SessionStore::load(config.data_path().join("sessions.json")),
+            users: UserStore::load(config.data_path().join("users.json")),
+            reset_tokens: ResetTokens::load(config.data_path().join("reset_key")),
+            edit_locks: EditLocks::default(),
+        }
+    }
+    /// Sends a request to the wiki task and waits for the response.
+    pub async fn send<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<T>) -> WikiRequest,
+    ) -> Result<T, WikiResponse> {
+        let (tx, rx) = oneshot::channel();
+        let queue = &self.shared.queue;
+        queue.sending();
+        match self.tx.try_send(request(tx)) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                queue.not_sent(true);
+                warn!("the wiki is busy, turning a request away");
+                let message = "The wiki is busy, try again in a moment".to_string();
+                return Err(WikiResponse::Busy(Some(message)));
+            }
+            Err(TrySendError::Closed(_)) => {
+                queue.not_sent(false);
+                warn!("the wiki task has stopped");
+                return Err(WikiResponse::Error(None));
+            }
+        }
+        rx.await
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))
+    }
+    /// Sends a request that only reads the book, see `WikiRequest::is_read`. Reading something
+    /// that isn't there gives `None`, while a busy wiki or a failed read is an error.
+    async fn read<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<Result<T, WikiResponse>>) -> WikiRequest,
+    ) -> Result<Option<T>, WikiResponse> {
+        match self.send(request).await.and_then(|res| res) {
+            Ok(read) => Ok(Some(read)),
+            Err(WikiResponse::NotFound(_)) => Ok(None),
+            Err(res) => Err(res),
+        }
+    }
+    fn start_session(
+        &self,
+        config: &Config,
+        cookies: &CookieJar<'_>,
+        username: &str,
+        remember: bool,
+        same_site: SameSite,
+        roles: Vec<String>,
+    ) {
+        let lifetime = if remember {
+            config.remember_me_lifetime
+        } else {
+            config.session_lifetime
+        };
+        let token = self.sessions.create(username, lifetime, remember, roles);
+        self.shared.events.publish(EventKind::UserLoggedIn {
+            user: username.to_string(),
+        });
+
+        let mut cookie = Cookie::new(MDWIKI_AUTH_COOKIE, token);
+        cookie.set_http_only(true);
+        cookie.set_same_site(same_site);
+        if remember {
+            cookie.set_max_age(time::Duration::seconds(lifetime as i64));
+        }
+        cookies.add_private(cookie);
+    }
+    /// Looks up a user in, in order, the users logged in with single sign-on, the user store, and
+    /// the users configured in mdwiki.toml. Disabled users are never returned.
+    async fn find_user(&self, config: &Config, username: &str) -> Option<User> {
+        if let Some(user) = self.oidc_users.read().await.get(username) {
+            return Some(user.clone()).filter(|user| !user.disabled);
+        }
+        self.users
+            .get(username)
+            .or_else(|| {
+                config
+                    .users
+                    .iter()
+                    .find(|user| user.username == username)
+                    .cloned()
+            })
+            .filter(|user| !user.disabled)
+    }
+    fn all_users(&self, config: &Config) -> Vec<User> {
+        let mut users = self.users.all();
+        users.extend(
+            config
+                .users
+                .iter()
+                .filter(|user| users.iter().all(|u| u.username != user.username))
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+        users
     }
 }
 
 #[derive(Serialize)]
 struct LoginContext {
     message: Option<String>,
+    next: Option<String>,
     user: Option<String>,
+    oidc: bool,
+    password_reset: bool,
+    sessions: usize,
 }
 
 #[derive(FromForm)]
 pub struct LoginForm {
     username: String,
     password: String,
+    remember: bool,
+    next: Option<String>,
 }
 
-#[get("/login")]
-pub fn login(message: Option<FlashMessage>, user: Option<User>) -> Template {
+/// Where to go after logging in. Only paths on the wiki itself are allowed, so a login link
+/// can't send users on to another site.
+fn local_redirect(next: Option<&str>) -> Redirect {
+    match next {
+        Some(next)
+            if next.starts_with('/')
+                && !next.starts_with("//")
+                && !next.contains('\\')
+                && Origin::parse(next).is_ok() =>
+        {
+            Redirect::to(next.to_string())
+        }
+        _ => Redirect::to("/"),
+    }
+}
+
+/// The login page, continuing to `next` after logging in.
+pub fn login_url(next: &str) -> String {
+    format!("/login?next={}", Uri::percent_encode(next))
+}
+
+#[get("/login?<next>")]
+pub fn login(
+    next: Option<String>,
+    message: Option<FlashMessage>,
+    user: Option<User>,
+    state: State<'_, WebappState>,
+) -> Template {
     let context = LoginContext {
         message: message.map(|f| f.msg().to_string()),
-        user: user.map(|user| user.username),
+        next,
+        sessions: user
+            .as_ref()
+            .map(|user| state.sessions.count_for_user(&user.username))
+            .unwrap_or(0),
+        user: user.map(|user| user.name().to_string()),
+        oidc: state.oidc.is_some(),
+        password_reset: state.shared.mailer.is_some(),
     };
     Template::render("login", &context)
 }
 
+#[derive(Responder)]
+pub enum LoginResponse {
+    LoggedIn(Redirect),
+    Failed(Flash<Redirect>),
+    #[response(status = 429)]
+    Throttled(Template, Header<'static>),
+}
+
 #[post("/login", data = "<form>")]
-pub fn login_post(
+pub async fn login_post(
     form: Form<LoginForm>,
+    ip: Option<ClientIp>,
     config: State<'_, Config>,
+    state: State<'_, WebappState>,
     cookies: &CookieJar<'_>,
-) -> Result<Redirect, Flash<Redirect>> {
-    let user = if let Some(user) = config
-        .users
-        .iter()
-        .find(|user| user.username == form.username)
+) -> LoginResponse {
+    let ip = ip.map(|ip| ip.to_string());
+    let ip_str = ip.as_deref().unwrap_or("unknown");
+
+    if let Some(wait) = state
+        .login_limiter
+        .check(ip.as_deref(), &form.username)
     {
-        user
-    } else {
-        return Err(Flash::error(
-            Redirect::to("/login"),
-            "Invalid username/password.",
-        ));
-    };
-    if user.password == form.password {
-        let mut cookie = Cookie::new(MDWIKI_AUTH_COOKIE, user.username.clone());
-        cookie.set_http_only(false);
-        cookies.add_private(cookie);
-        return Ok(Redirect::to("/"));
+        warn!(
+            "throttled login for '{}' from {}, {}s remaining",
+            form.username,
+            ip_str,
+            wait.as_secs()
+        );
+        let context = LoginContext {
+            message: Some(format!(
+                "Too many failed login attempts, try again in {} minutes.",
+                wait.as_secs() / 60 + 1
+            )),
+            next: form.next.clone(),
+            user: None,
+            oidc: state.oidc.is_some(),
+            password_reset: state.shared.mailer.is_some(),
+            sessions: 0,
+        };
+        return LoginResponse::Throttled(
+            Template::render("login", &context),
+            Header::new("Retry-After", wait.as_secs().to_string()),
+        );
     }
-    Err(Flash::error(
-        Redirect::to("/login"),
-        "Invalid username/password.",
-    ))
-}
-
-#[get("/logout")]
-pub fn logout(cookies: &CookieJar<'_>) -> Redirect {
-    cookies.remove_private(Cookie::named(MDWIKI_AUTH_COOKIE));
-    Redirect::to("/")
-}
 
-#[derive(Serialize)]
-struct ScriptContext {
-    logged_in: bool,
-}
+    let user = state.find_user(&config, &form.username).await;
 
-#[get("/mdwiki_script.js")]
-pub fn mdwiki_script(user: Option<User>) -> Template {
-    let context = ScriptContext {
-        logged_in: user.is_some(),
-    };
-    Template::render("mdwiki_script", &context)
+    match user {
+        Some(user) if user.verify_password(&form.password) => {
+            state.login_limiter.record_success(&user.username);
+            state.start_session(
+                &config,
+                cookies,
+                &user.username,
+                form.remember,
+                SameSite::Strict,
+                Vec::new(),
+            );
+            LoginResponse::LoggedIn(local_redirect(form.next.as_deref()))
+        }
+        _ => {
+            warn!("failed login for '{}' from {}", form.username, ip_str);
+            state
+                .login_limiter
+                .record_failure(ip.as_deref(), &form.username, &config.login_limits);
+            let login = match &form.next {
+                Some(next) => login_url(next),
+                None => "/login".to_string(),
+            };
+            LoginResponse::Failed(Flash::error(
+                Redirect::to(login),
+                "Invalid username/password.",
+            ))
+        }
+    }
 }
 
 #[derive(Serialize)]
-struct NewContext {
-    file: String,
-    content: String,
+struct ForgotPasswordContext {
     message: Option<String>,
+    enabled: bool,
 }
 
 #[derive(FromForm)]
-pub struct NewForm {
-    file: String,
-    content: String,
+pub struct ForgotPasswordForm {
+    username: String,
 }
 
-#[get("/new")]
-pub fn new_page(message: Option<FlashMessage>, _user: User) -> Template {
-    let context = NewContext {
-        file: "".to_string(),
-        content: "".to_string(),
+#[get("/login/forgot")]
+pub fn forgot_password(
+    message: Option<FlashMessage>,
+    state: State<'_, WebappState>,
+) -> Template {
+    let context = ForgotPasswordContext {
         message: message.map(|f| f.msg().to_string()),
+        enabled: state.shared.mailer.is_some(),
     };
-    Template::render("new_page", &context)
+    Template::render("forgot_password", &context)
 }
 
-#[post("/new", data = "<form>")]
-pub async fn new_page_post(
-    form: Form<NewForm>,
-    user: User,
+/// Emails a password reset link to a managed user with an email address. The response is the
+/// same whether or not such a user exists, so it can't be used to find usernames.
+#[post("/login/forgot", data = "<form>")]
+pub fn forgot_password_post(
+    form: Form<ForgotPasswordForm>,
+    config: State<'_, Config>,
     state: State<'_, WebappState>,
-) -> Result<Redirect, Template> {
-    // TODO check for legal characters in path
-    let form_file = form.file.replace(" ", "_");
-    let file = Path::new(&form_file);
-
-    let (tx, rx) = oneshot::channel();
-    state
-        .tx
-        .send(WikiRequest::CreateFile {
-            user,
-            file: file.to_path_buf().into_boxed_path(),
-            content: form.content.clone(),
-            respond: tx,
-        })
-        .await
-        .map_err(log_warn)
-        .map_err(|_| "")
-        .unwrap();
+) -> Flash<Redirect> {
+    let mailer = match (&state.shared.mailer, &config.public_url) {
+        (Some(mailer), Some(_)) => mailer,
+        _ => {
+            return Flash::error(
+                Redirect::to("/login/forgot"),
+                "Password reset by email is not configured.",
+            )
+        }
+    };
+    let sent = Flash::success(
+        Redirect::to("/login"),
+        "If the account has an email address, a reset link has been sent to it.",
+    );
 
-    let res = rx.await.map_err(log_warn).unwrap();
-    if !res.is_ok() {
-        let context = NewContext {
-            file: form.file.clone(),
-            content: form.content.clone(),
-            message: Some(
-                res.msg()
-                    .cloned()
-                    .unwrap_or("Something went wrong :(".to_string()),
-            ),
-        };
-        return Err(Template::render("new_page", &context));
-    }
+    let name = form.username.trim();
+    let user = state.users.all().into_iter().find(|user| {
+        !name.is_empty() && (user.username == name || user.email.as_deref() == Some(name))
+    });
+    let (user, email) = match user {
+        Some(user) if !user.disabled => match user.email.clone() {
+            Some(email) => (user, email),
+            None => return sent,
+        },
+        _ => return sent,
+    };
+    let token = match state.reset_tokens.create(&user) {
+        Some(token) => token,
+        None => return sent,
+    };
 
-    let html_file = Path::new(&form.file).with_extension("html");
-    return Ok(Redirect::to(format!(
-        "/{}",
-        html_file
-            .to_str()
-            .unwrap()
-            .replace("README.html", "")
-            .to_string()
-    )));
+    let link = format!(
+        "{}/login/reset?token={}",
+        config.public_url.as_deref().unwrap_or("").trim_end_matches('/'),
+        token
+    );
+    let body = format!(
+        "A password reset was requested for the mdwiki user '{}'.\n\n\
+        Open this link to choose a new password, it's valid for {} minutes:\n\n{}\n\n\
+        If you didn't request this, you can ignore this email.\n",
+        user.username,
+        RESET_LIFETIME / 60,
+        link
+    );
+    // Sent in the background, so the response time doesn't reveal whether the user exists
+    let mailer = mailer.clone();
+    task::spawn(async move {
+        match mailer.send(&email, "Reset your mdwiki password", body).await {
+            Ok(()) => info!("sent password reset link to '{}'", user.username),
+            Err(e) => warn!("{}", e),
+        }
+    });
+    sent
 }
 
 #[derive(Serialize)]
-struct EditContext {
-    file: std::path::PathBuf,
-    content: String,
+struct ResetPasswordContext {
     message: Option<String>,
+    token: String,
 }
 
 #[derive(FromForm)]
-pub struct EditForm {
-    content: String,
+pub struct ResetPasswordForm {
+    token: String,
+    password: String,
+    confirm: String,
 }
 
-#[get("/edit/<file..>")]
-pub async fn edit_page(
-    file: std::path::PathBuf,
-    message: Option<FlashMessage<'_, '_>>,
-    _user: User,
-    config: State<'_, Config>,
-) -> Result<Template, Option<Flash<Redirect>>> {
-    if !config.can_edit(&PathBuf::from(&file)).await.is_ok() {
-        return Err(None);
-    }
-    let path = Path::new(&config.path).join("src").join(&file);
-    let content = fs::read_to_string(&path)
-        .await
-        .map_err(log_warn)
-        .map_err(|_| None)?;
-    let context = EditContext {
-        file,
-        content,
+#[get("/login/reset?<token>")]
+pub fn reset_password(token: String, message: Option<FlashMessage>) -> Template {
+    let context = ResetPasswordContext {
         message: message.map(|f| f.msg().to_string()),
+        token,
     };
-    Ok(Template::render("edit_page", &context))
+    Template::render("reset_password", &context)
 }
 
-#[post("/edit/<file..>", data = "<form>")]
-pub async fn edit_page_post(
-    file: std::path::PathBuf,
-    form: Form<EditForm>,
-    user: User,
+#[post("/login/reset", data = "<form>")]
+pub fn reset_password_post(
+    form: Form<ResetPasswordForm>,
     state: State<'_, WebappState>,
-) -> Result<Redirect, Template> {
-    let (tx, rx) = oneshot::channel();
-    state
-        .tx
-        .send(WikiRequest::EditFile {
-            user,
-            file: PathBuf::from(file.to_path_buf()).into_boxed_path(),
-            content: form.content.clone(),
-            respond: tx,
-        })
+) -> Flash<Redirect> {
+    let retry = |message: &str| {
+        Flash::error(
+            Redirect::to(format!("/login/reset?token={}", form.token)),
+            message.to_string(),
+        )
+    };
+    if form.password.is_empty() {
+        return retry("The password can't be empty");
+    }
+    if form.password != form.confirm {
+        return retry("The passwords don't match");
+    }
+
+    let user = match state
+        .reset_tokens
+        .verify(&form.token, |username| state.users.get(username))
+    {
+        Some(user) => user,
+        None => {
+            return Flash::error(
+                Redirect::to("/login/forgot"),
+                "The reset link is invalid or has expired.",
+            )
+        }
+    };
+
+    let updated = hash_password(&form.password).and_then(|password| {
+        state
+            .users
+            .update(&user.username, |user| user.password = password)
+    });
+    if let Err(e) = updated {
+        warn!("{}", e);
+        return retry("Could not change the password");
+    }
+    state.sessions.revoke_user(&user.username);
+    state.login_limiter.record_success(&user.username);
+    info!("'{}' reset their password by email", user.username);
+
+    Flash::success(
+        Redirect::to("/login"),
+        "Your password has been changed, you can log in now.",
+    )
+}
+
+#[get("/login/oidc?<next>")]
+pub async fn login_oidc(
+    next: Option<String>,
+    state: State<'_, WebappState>,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let oidc = state.oidc.as_ref().ok_or_else(|| {
+        Flash::error(
+            Redirect::to("/login"),
+            "Single sign-on is not configured.",
+        )
+    })?;
+
+    let csrf_token = rand_safe_string(32);
+    let nonce = rand_safe_string(32);
+
+    let url = oidc
+        .authorize_url(&csrf_token, &nonce)
         .await
         .map_err(log_warn)
-        .map_err(|_| "")
-        .unwrap();
+        .map_err(|_| {
+            Flash::error(
+                Redirect::to("/login"),
+                "Could not reach the identity provider.",
+            )
+        })?;
 
-    let res = rx.await.map_err(log_warn).unwrap();
-    if !res.is_ok() {
-        let context = EditContext {
-            file,
-            content: form.content.clone(),
-            message: Some(
-                res.msg()
-                    .cloned()
-                    .unwrap_or("Something went wrong :(".to_string()),
-            ),
-        };
-        return Err(Template::render("edit_page", &context));
-    }
+    // Lax, as the cookie has to survive the redirect back from the identity provider
+    let mut cookie = Cookie::new(
+        MDWIKI_OIDC_COOKIE,
+        format!("{}:{}:{}", csrf_token, nonce, next.unwrap_or_default()),
+    );
+    cookie.set_same_site(SameSite::Lax);
+    cookies.add_private(cookie);
 
-    let html_file = file.with_extension("html");
-    return Ok(Redirect::to(format!(
-        "/{}",
-        html_file
-            .to_str()
-            .unwrap()
-            .replace("README.html", "")
-            .to_string()
-    )));
+    Ok(Redirect::to(url))
 }
 
-#[post("/upload/image", data = "<data>")]
-pub async fn upload_image(
-    data: Data,
-    _user: User,
-    content_type: &ContentType,
+#[get("/login/oidc/callback?<code>&<state>&<error>")]
+pub async fn login_oidc_callback(
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    webapp_state: State<'_, WebappState>,
     config: State<'_, Config>,
-) -> Result<String, ()> {
-    let filename = rand_safe_string(16);
-    let extension = if *content_type == ContentType::JPEG {
-        "jpg"
-    } else if *content_type == ContentType::GIF {
-        "gif"
-    } else if *content_type == ContentType::PNG {
-        "png"
-    } else if *content_type == ContentType::BMP {
-        "bmp"
-    } else {
-        return Err(());
-    };
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let failed = || Flash::error(Redirect::to("/login"), "Single sign-on failed.");
+
+    let oidc = webapp_state.oidc.as_ref().ok_or_else(failed)?;
+
+    let expected = cookies
+        .get_private(MDWIKI_OIDC_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .unwrap_or_default();
+    cookies.remove_private(Cookie::named(MDWIKI_OIDC_COOKIE));
+
+    if let Some(error) = error {
+        warn!("identity provider returned error: {}", error);
+        return Err(failed());
+    }
 
-    let file_path = Path::new(&config.tmp_upload_path)
-        .join(&filename)
-        .with_extension(&extension);
+    let mut expected = expected.splitn(3, ':');
+    let (csrf_token, nonce, next) = match (expected.next(), expected.next(), expected.next()) {
+        (Some(csrf_token), Some(nonce), next) => (csrf_token, nonce, next),
+        _ => return Err(failed()),
+    };
+    if state.as_deref() != Some(csrf_token) {
+        warn!("oidc callback with invalid state");
+        return Err(failed());
+    }
+    let code = code.ok_or_else(failed)?;
 
-    data.open(8_u8.mebibytes())
-        .stream_to_file(file_path)
+    let (user, roles) = oidc
+        .exchange_code(&code, nonce)
         .await
+        .and_then(|claims| oidc.user_from_claims(&claims, &webapp_state.all_users(&config)))
         .map_err(log_warn)
-        .map_err(|_| ())?;
+        .map_err(|_| failed())?;
+
+    let same_site = SameSite::Lax;
+    webapp_state.start_session(&config, cookies, &user.username, false, same_site, roles);
 
-    Ok(format!("/images/{}.{}", filename, extension))
+    // Users from mdwiki.toml can't be persisted, so what the claims say about them is only kept
+    // in memory
+    if config.users.iter().any(|u| u.username == user.username) {
+        webapp_state
+            .oidc_users
+            .write()
+            .await
+            .insert(user.username.clone(), user);
+    } else {
+        webapp_state
+            .users
+            .upsert(user)
+            .map_err(log_warn)
+            .map_err(|_| failed())?;
+    }
+
+    Ok(local_redirect(next.filter(|next| !next.is_empty())))
 }
 
-#[get("/", rank = 10)]
-pub async fn index() -> Redirect {
-    Redirect::permanent("/index.html")
+#[get("/logout")]
+pub fn logout(cookies: &CookieJar<'_>, state: State<'_, WebappState>) -> Redirect {
+    if let Some(session) = cookies.get_private(MDWIKI_AUTH_COOKIE) {
+        state.sessions.revoke(session.value());
+    }
+    cookies.remove_private(Cookie::named(MDWIKI_AUTH_COOKIE));
+    Redirect::to("/")
 }
 
-#[get("/<path..>", rank = 10)]
-pub async fn book_files(
-    path: std::path::PathBuf,
-    user: Option<User>,
-    config: State<'_, Config>,
-) -> Result<Option<NamedFile>, Redirect> {
-    const SAFE_PREFIXES: &[&'static str] = &["css", "FontAwesome", "favicon.svg"];
+#[post("/logout/all")]
+pub fn logout_all(
+    user: User,
+    cookies: &CookieJar<'_>,
+    state: State<'_, WebappState>,
+) -> Redirect {
+    let revoked = state.sessions.revoke_user(&user.username);
+    info!("revoked {} sessions for '{}'", revoked, user.username);
+    cookies.remove_private(Cookie::named(MDWIKI_AUTH_COOKIE));
+    Redirect::to("/")
+}
 
-    if !config.allow_anonymous
-        && user.is_none()
-        && SAFE_PREFIXES
-            .iter()
-            .find(|prefix| path.starts_with(prefix))
-            .is_none()
-    {
-        return Err(Redirect::to(uri!(login)));
+const AVATAR_EXTENSIONS: &[&str] = &["png", "jpg", "gif"];
+
+fn avatar_path(config: &Config, username: &str, extension: &str) -> Option<PathBuf> {
+    if username.contains(|c| c == '/' || c == '\\') || username.starts_with('.') {
+        return None;
     }
+    Some(
+        PathBuf::from(config.data_path())
+            .join("avatars")
+            .join(format!("{}.{}", username, extension)),
+    )
+}
+
+#[derive(Serialize)]
+struct ProfileContext {
+    username: String,
+    display_name: String,
+    managed: bool,
+    message: Option<String>,
+}
+
+#[derive(FromForm)]
+pub struct ProfileForm {
+    display_name: String,
+}
 
-    let full_path = Path::new(&config.path).join(&config.book_path).join(&path);
+#[get("/profile")]
+pub fn profile(
+    message: Option<FlashMessage>,
+    user: User,
+    state: State<'_, WebappState>,
+) -> Template {
+    let context = ProfileContext {
+        managed: state.users.get(&user.username).is_some(),
+        display_name: user.display_name.unwrap_or_default(),
+        username: user.username,
+        message: message.map(|f| f.msg().to_string()),
+    };
+    Template::render("profile", &context)
+}
 
-    if full_path.is_dir().await {
-        return Err(Redirect::permanent(format!(
-            "/{}",
-            path.join("index.html").to_str().unwrap()
-        )));
+#[post("/profile", data = "<form>")]
+pub fn profile_post(
+    form: Form<ProfileForm>,
+    user: User,
+    state: State<'_, WebappState>,
+) -> Flash<Redirect> {
+    let display_name = Some(form.display_name.trim())
+        .filter(|name| !name.is_empty())
+        .map(String::from);
+    match state
+        .users
+        .update(&user.username, |user| user.display_name = display_name)
+    {
+        Ok(true) => Flash::success(Redirect::to("/profile"), "Saved your profile"),
+        Ok(false) => Flash::error(
+            Redirect::to("/profile"),
+            "Your profile is managed outside of mdwiki",
+        ),
+        Err(e) => {
+            warn!("{}", e);
+            Flash::error(Redirect::to("/profile"), "Could not save your profile")
+        }
     }
+}
 
-    Ok(NamedFile::open(full_path).await.ok())
+/// Replaces the avatar of the logged in user with the image in the request body.
+#[post("/profile/avatar", data = "<data>")]
+pub async fn profile_avatar(
+    data: Data,
+    user: User,
+    content_type: &ContentType,
+    config: State<'_, Config>,
+) -> Status {
+    let extension = match AVATAR_EXTENSIONS
+        .iter()
+        .find(|extension| ContentType::from_extension(extension).as_ref() == Some(content_type))
+    {
+        Some(extension) => extension,
+        None => return Status::UnsupportedMediaType,
+    };
+    let path = match avatar_path(&config, &user.username, extension) {
+        Some(path) => path,
+        None => return Status::BadRequest,
+    };
+    if let Some(parent) = path.parent() {
+        if fs::create_dir_all(parent).await.map_err(log_warn).is_err() {
+            return Status::InternalServerError;
+        }
+    }
+    for other in AVATAR_EXTENSIONS {
+        if let Some(other) = avatar_path(&config, &user.username, other) {
+            let _ = fs::remove_file(other).await;
+        }
+    }
+
+    match data.open(1_u8.mebibytes()).stream_to_file(&path).await {
+        Ok(_) => {
+            info!("'{}' uploaded a new avatar", user.username);
+            Status::NoContent
+        }
+        Err(e) => {
+            warn!("could not save avatar: {}", e);
+            Status::InternalServerError
+        }
+    }
+}
+
+#[derive(Responder)]
+pub enum Avatar {
+    Uploaded(File, ContentType, Header<'static>),
+    Elsewhere(Redirect),
+}
+
+/// The uploaded avatar of a user, or their configured avatar url, falling back to gravatar.
+#[get("/avatars/<username>")]
+pub async fn avatar(
+    username: String,
+    user: Option<User>,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Option<Avatar> {
+    if !config.allow_anonymous && user.is_none() {
+        return None;
+    }
+    for extension in AVATAR_EXTENSIONS {
+        let path = avatar_path(&config, &username, extension)?;
+        if let Ok(file) = File::open(&path).await {
+            return Some(Avatar::Uploaded(
+                file,
+                ContentType::from_extension(extension)?,
+                Header::new("Cache-Control", "private, no-cache"),
+            ));
+        }
+    }
+
+    let user = state.find_user(&config, &username).await;
+    if let Some(url) = user.as_ref().and_then(|user| user.avatar.clone()) {
+        return Some(Avatar::Elsewhere(Redirect::to(url)));
+    }
+    let email = user
+        .and_then(|user| user.email)
+        .unwrap_or_else(|| username.clone());
+    let hash = md5::compute(email.trim().to_lowercase().as_bytes());
+    Some(Avatar::Elsewhere(Redirect::to(format!(
+        "https://www.gravatar.com/avatar/{:x}?d=identicon&s=80",
+        hash
+    ))))
+}
+
+#[post("/admin/sessions/<username>/revoke")]
+pub fn admin_revoke_sessions(
+    username: String,
+    admin: Admin,
+    state: State<'_, WebappState>,
+) -> Status {
+    let revoked = state.sessions.revoke_user(&username);
+    info!(
+        "'{}' revoked {} sessions for '{}'",
+        admin.0.username, revoked, username
+    );
+    Status::NoContent
+}
+
+#[derive(Serialize)]
+struct AdminUser {
+    username: String,
+    display_name: Option<String>,
+    email: Option<String>,
+    roles: Vec<String>,
+    disabled: bool,
+    managed: bool,
+    sessions: usize,
+}
+
+#[derive(Serialize)]
+struct AdminUsersContext {
+    users: Vec<AdminUser>,
+    message: Option<String>,
+}
+
+#[derive(FromForm)]
+pub struct NewUserForm {
+    username: String,
+    password: String,
+    email: String,
+    roles: String,
+}
+
+#[derive(FromForm)]
+pub struct PasswordForm {
+    password: String,
+}
+
+fn admin_users_redirect(message: String) -> Flash<Redirect> {
+    Flash::success(Redirect::to("/admin/users"), message)
+}
+
+fn admin_users_error(message: String) -> Flash<Redirect> {
+    Flash::error(Redirect::to("/admin/users"), message)
+}
+
+#[get("/admin/users")]
+pub fn admin_users(
+    message: Option<FlashMessage>,
+    _admin: Admin,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Template {
+    let managed = state.users.all();
+    let users = state
+        .all_users(&config)
+        .into_iter()
+        .map(|user| AdminUser {
+            managed: managed.iter().any(|u| u.username == user.username),
+            sessions: state.sessions.count_for_user(&user.username),
+            username: user.username,
+            display_name: user.display_name,
+            email: user.email,
+            roles: user.roles,
+            disabled: user.disabled,
+        })
+        .collect();
+    let context = AdminUsersContext {
+        users,
+        message: message.map(|f| f.msg().to_string()),
+    };
+    Template::render("admin_users", &context)
+}
+
+#[post("/admin/users", data = "<form>")]
+pub fn admin_users_create(
+    form: Form<NewUserForm>,
+    admin: Admin,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Flash<Redirect> {
+    let username = form.username.trim();
+    if username.is_empty()
+        || !username
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return admin_users_error(format!("Invalid username '{}'", username));
+    }
+    if form.password.is_empty() {
+        return admin_users_error("The password can't be empty".into());
+    }
+    if state
+        .all_users(&config)
+        .iter()
+        .any(|user| user.username == username)
+    {
+        return admin_users_error(format!("User '{}' already exists", username));
+    }
+
+    let email = Some(form.email.trim())
+        .filter(|email| !email.is_empty())
+        .map(String::from);
+    let user = hash_password(&form.password).and_then(|password| {
+        state.users.upsert(User {
+            username: username.to_string(),
+            password,
+            roles: form
+                .roles
+                .split(',')
+                .map(str::trim)
+                .filter(|role| !role.is_empty())
+                .map(String::from)
+                .collect(),
+            disabled: false,
+            // Set by an admin, so it can be trusted for commits
+            email_verified: email.is_some(),
+            email,
+            display_name: None,
+            avatar: None,
+        })
+    });
+    if let Err(e) = user {
+        warn!("{}", e);
+        return admin_users_error("Could not create user".into());
+    }
+
+    info!("'{}' created user '{}'", admin.0.username, username);
+    admin_users_redirect(format!("Created user '{}'", username))
+}
+
+#[post("/admin/users/<username>/password", data = "<form>")]
+pub fn admin_users_password(
+    username: String,
+    form: Form<PasswordForm>,
+    admin: Admin,
+    state: State<'_, WebappState>,
+) -> Flash<Redirect> {
+    if form.password.is_empty() {
+        return admin_users_error("The password can't be empty".into());
+    }
+    let updated = hash_password(&form.password)
+        .and_then(|password| state.users.update(&username, |user| user.password = password));
+    match updated {
+        Ok(true) => {
+            state.sessions.revoke_user(&username);
+            info!("'{}' reset the password of '{}'", admin.0.username, username);
+            admin_users_redirect(format!("Reset password for '{}'", username))
+        }
+        Ok(false) => admin_users_error(format!("'{}' is not a managed user", username)),
+        Err(e) => {
+            warn!("{}", e);
+            admin_users_error("Could not reset password".into())
+        }
+    }
+}
+
+#[post("/admin/users/<username>/disable")]
+pub fn admin_users_disable(
+    username: String,
+    admin: Admin,
+    state: State<'_, WebappState>,
+) -> Flash<Redirect> {
+    if username == admin.0.username {
+        return admin_users_error("You can't disable yourself".into());
+    }
+    let mut disabled = false;
+    match state.users.update(&username, |user| {
+        user.disabled = !user.disabled;
+        disabled = user.disabled;
+    }) {
+        Ok(true) => {
+            if disabled {
+                state.sessions.revoke_user(&username);
+            }
+            info!(
+                "'{}' {} user '{}'",
+                admin.0.username,
+                if disabled { "disabled" } else { "enabled" },
+                username
+            );
+            admin_users_redirect(format!(
+                "{} '{}'",
+                if disabled { "Disabled" } else { "Enabled" },
+                username
+            ))
+        }
+        Ok(false) => admin_users_error(format!("'{}' is not a managed user", username)),
+        Err(e) => {
+            warn!("{}", e);
+            admin_users_error("Could not update user".into())
+        }
+    }
+}
+
+#[post("/admin/users/<username>/delete")]
+pub fn admin_users_delete(
+    username: String,
+    admin: Admin,
+    state: State<'_, WebappState>,
+) -> Flash<Redirect> {
+    if username == admin.0.username {
+        return admin_users_error("You can't delete yourself".into());
+    }
+    match state.users.remove(&username) {
+        Ok(true) => {
+            state.sessions.revoke_user(&username);
+            info!("'{}' deleted user '{}'", admin.0.username, username);
+            admin_users_redirect(format!("Deleted '{}'", username))
+        }
+        Ok(false) => admin_users_error(format!("'{}' is not a managed user", username)),
+        Err(e) => {
+            warn!("{}", e);
+            admin_users_error("Could not delete user".into())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AdminBuildsContext {
+    builds: Vec<Build>,
+    message: Option<String>,
+}
+
+#[get("/admin/builds")]
+pub fn admin_builds(
+    message: Option<FlashMessage>,
+    _admin: Admin,
+    state: State<'_, WebappState>,
+) -> Template {
+    let context = AdminBuildsContext {
+        builds: state.shared.builds.list(),
+        message: message.map(|f| f.msg().to_string()),
+    };
+    Template::render("admin_builds", &context)
+}
+
+#[post("/admin/builds/<id>/pin")]
+pub fn admin_builds_pin(
+    id: String,
+    admin: Admin,
+    state: State<'_, WebappState>,
+) -> Flash<Redirect> {
+    let redirect = Redirect::to("/admin/builds");
+    if let Err(e) = state.shared.builds.pin(Some(&id)) {
+        return Flash::error(redirect, e);
+    }
+    state.shared.page_cache.clear();
+    info!("'{}' pinned build {}", admin.0.username, id);
+    Flash::success(redirect, format!("Serving build {}", id))
+}
+
+#[post("/admin/builds/unpin")]
+pub fn admin_builds_unpin(admin: Admin, state: State<'_, WebappState>) -> Flash<Redirect> {
+    let redirect = Redirect::to("/admin/builds");
+    if let Err(e) = state.shared.builds.pin(None) {
+        return Flash::error(redirect, e);
+    }
+    state.shared.page_cache.clear();
+    info!("'{}' unpinned the served build", admin.0.username);
+    Flash::success(redirect, "Serving the latest build".to_string())
+}
+
+#[derive(Serialize)]
+struct AdminMirrorsContext {
+    mirrors: Vec<MirrorStatus>,
+    message: Option<String>,
+}
+
+/// How pushing to every mirror is going.
+#[get("/admin/mirrors")]
+pub fn admin_mirrors(
+    message: Option<FlashMessage>,
+    _admin: Admin,
+    state: State<'_, WebappState>,
+) -> Template {
+    let context = AdminMirrorsContext {
+        mirrors: state.shared.mirrors.status(),
+        message: message.map(|f| f.msg().to_string()),
+    };
+    Template::render("admin_mirrors", &context)
+}
+
+/// Pushes to the mirrors right away, like once a mirror that refused the pushes is fixed.
+#[post("/admin/mirrors/push")]
+pub fn admin_mirrors_push(admin: Admin, state: State<'_, WebappState>) -> Flash<Redirect> {
+    state.shared.mirrors.changed();
+    info!("'{}' pushed to the mirrors", admin.0.username);
+    Flash::success(Redirect::to("/admin/mirrors"), "Pushing to the mirrors")
+}
+
+#[derive(Serialize)]
+struct AdminLintContext {
+    issues: Vec<Issue>,
+}
+
+/// Issues with the content of the pages, like missing titles, alt texts or headings.
+#[get("/admin/lint")]
+pub fn admin_lint(_admin: Admin, config: State<'_, Config>) -> Template {
+    let context = AdminLintContext {
+        issues: lint::lint(&config.path),
+    };
+    Template::render("admin_lint", &context)
+}
+
+/// Backs up the repository right away, returning the name of the backup.
+#[post("/admin/backup")]
+pub async fn admin_backup(
+    admin: Admin,
+    state: State<'_, WebappState>,
+) -> Result<String, status::Custom<String>> {
+    info!("'{}' started a backup", admin.0.username);
+    state
+        .send(|respond| WikiRequest::Backup { respond })
+        .await
+        .and_then(|res| res)
+        .map_err(|res| status::Custom(res.status(), res.msg().cloned().unwrap_or_default()))
+}
+
+/// The largest backup that can be uploaded to `/admin/restore`.
+const MAX_BACKUP_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Replaces the repository with the backup zip file in the request body, as uploaded by
+/// `/admin/backup`, returning the commit that records the restore.
+#[post("/admin/restore", data = "<data>")]
+pub async fn admin_restore(
+    admin: Admin,
+    data: Data,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<String, status::Custom<String>> {
+    let upload = config.upload_path().join(rand_safe_string(16));
+    let limit = MAX_BACKUP_SIZE + 1;
+    let written = match data.open(limit.bytes()).stream_to_file(&upload).await {
+        Ok(_) => fs::metadata(&upload).await.map(|metadata| metadata.len()),
+        Err(e) => Err(e),
+    };
+    match written {
+        Ok(written) if written < limit => {}
+        Ok(_) => {
+            let _ = fs::remove_file(&upload).await;
+            let message = format!("Backups can be at most {} bytes", MAX_BACKUP_SIZE);
+            return Err(status::Custom(Status::PayloadTooLarge, message));
+        }
+        Err(e) => {
+            warn!("could not save backup: {}", e);
+            let _ = fs::remove_file(&upload).await;
+            return Err(status::Custom(Status::InternalServerError, String::new()));
+        }
+    }
+
+    info!("'{}' is restoring a backup", admin.0.username);
+    state
+        .send(|respond| WikiRequest::Restore {
+            user: admin.0,
+            upload,
+            respond,
+        })
+        .await
+        .and_then(|res| res)
+        .map_err(|res| status::Custom(res.status(), res.msg().cloned().unwrap_or_default()))
+}
+
+/// Makes the configured branding available to every template, as `branding(key="title")`,
+/// `branding(key="logo")` and `branding(key="accent_color")`.
+pub fn template_functions(branding: Branding) -> impl Fn(&mut Engines) + Send + Sync + 'static {
+    let values = serde_json::to_value(branding).unwrap_or_default();
+    move |engines: &mut Engines| {
+        let values = values.clone();
+        engines.tera.register_function(
+            "branding",
+            move |args: &HashMap<String, serde_json::Value>| {
+                let key = args.get("key").and_then(|key| key.as_str()).unwrap_or("title");
+                Ok(values.get(key).cloned().unwrap_or_default())
+            },
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct ScriptContext {}
+
+/// The url path of the page built from the markdown file at `path`.
+pub fn page_url(path: &str) -> String {
+    // Names can't have backslashes, so they're from paths on Windows
+    let path = path.replace('\\', "/");
+    let path = path.trim_matches('/');
+    match path.strip_suffix("README.md") {
+        Some(dir) => format!("{}index.html", dir),
+        None => format!("{}.html", path.trim_end_matches(".md")),
+    }
+}
+
+/// The script injected into every book page. It's the same for everyone, and gets the user and
+/// page specific parts from `api::page_context`.
+#[get("/mdwiki_script.js")]
+pub fn mdwiki_script() -> Template {
+    Template::render("mdwiki_script", &ScriptContext {})
+}
+
+/// Starts or stops watching a page, which emails the user when someone else changes it.
+#[post("/watch/<file..>")]
+pub fn watch_page(
+    file: std::path::PathBuf,
+    user: User,
+    state: State<'_, WebappState>,
+) -> Result<Redirect, Status> {
+    let email = match (&state.shared.mailer, &user.email) {
+        (Some(_), Some(email)) => email,
+        _ => return Err(Status::BadRequest),
+    };
+    let page = url_path(&file);
+    let watching = state.shared.watches.toggle(&page, &user.username, email);
+    info!(
+        "'{}' {} watching {}",
+        user.username,
+        if watching { "started" } else { "stopped" },
+        page
+    );
+    Ok(Redirect::to(format!("/{}", page_url(&page))))
+}
+
+/// A page read through the wiki task, see `WebappState::read`, that couldn't be shown.
+#[derive(Responder)]
+pub enum ReadFailed {
+    Login(Redirect),
+    /// The wiki is busy, or the read failed.
+    Wiki(status::Custom<String>),
+}
+
+impl From<WikiResponse> for ReadFailed {
+    fn from(res: WikiResponse) -> Self {
+        let message = res.msg().cloned().unwrap_or_default();
+        ReadFailed::Wiki(status::Custom(res.status(), message))
+    }
+}
+
+/// The changes made by a commit, linked from watch notifications. Every hunk changing a page
+/// links to the section of the page it changes.
+#[get("/diff/<commit>")]
+pub async fn diff(
+    commit: String,
+    user: Option<User>,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<Option<Template>, ReadFailed> {
+    if !config.allow_anonymous && user.is_none() {
+        let login = Redirect::to(login_url(&format!("/diff/{}", commit)));
+        return Err(ReadFailed::Login(login));
+    }
+    Ok(state
+        .read(|respond| WikiRequest::Diff { commit, respond })
+        .await?
+        .map(|diff| Template::render("diff", &diff)))
+}
+
+#[derive(Serialize)]
+struct BlameContext {
+    page: String,
+    url: String,
+    lines: Vec<BlameLine>,
+}
+
+/// Who last changed every line of a page, linking to the changes of each commit.
+#[get("/blame/<file..>")]
+pub async fn blame_page(
+    file: std::path::PathBuf,
+    user: Option<User>,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<Option<Template>, ReadFailed> {
+    let page = url_path(&file);
+    if !config.allow_anonymous && user.is_none() {
+        let login = Redirect::to(login_url(&format!("/blame/{}", page)));
+        return Err(ReadFailed::Login(login));
+    }
+    if file.extension().map_or(true, |ext| ext != "md") {
+        return Ok(None);
+    }
+    let request = |respond| WikiRequest::Blame {
+        page: page.clone(),
+        respond,
+    };
+    Ok(state.read(request).await?.map(|lines| {
+        let context = BlameContext {
+            url: format!("/{}", page_url(&page)),
+            page,
+            lines,
+        };
+        Template::render("blame", &context)
+    }))
+}
+
+const DASHBOARD_PAGES: usize = 10;
+
+#[derive(Serialize)]
+struct RecentPage {
+    url: String,
+    status: Option<&'static str>,
+    #[serde(flatten)]
+    change: RecentChange,
+}
+
+#[derive(Serialize)]
+struct PopularPage {
+    path: String,
+    url: String,
+    status: Option<&'static str>,
+    views: u64,
+}
+
+#[derive(Serialize)]
+struct StatusPage {
+    path: String,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct StatusFilter {
+    name: &'static str,
+    label: &'static str,
+    pages: usize,
+}
+
+#[derive(Serialize)]
+struct DashboardContext {
+    recent: Vec<RecentPage>,
+    popular: Vec<PopularPage>,
+    todos: Vec<TodoItem>,
+    statuses: Vec<StatusFilter>,
+    status: Option<&'static str>,
+    status_pages: Vec<StatusPage>,
+}
+
+/// The status of every page, by path, with pages without one having the default status.
+async fn page_statuses(
+    config: &Config,
+    state: &WebappState,
+) -> Result<HashMap<String, PageStatus>, WikiResponse> {
+    fn visit(
+        tree: &WikiTree,
+        src: &std::path::Path,
+        default: PageStatus,
+        statuses: &mut HashMap<String, PageStatus>,
+    ) {
+        let (page, children) = match tree {
+            WikiTree::File(path) => (path.to_path_buf(), &[][..]),
+            WikiTree::Directory(path, children) => (path.join("README.md"), &children[..]),
+        };
+        let page = url_path(&page);
+        if let Ok(content) = std::fs::read_to_string(src.join(&page)) {
+            let status = page_status(&content).ok().flatten().unwrap_or(default);
+            statuses.insert(page, status);
+        }
+        for child in children {
+            visit(child, src, default, statuses);
+        }
+    }
+    let src = std::path::Path::new(&config.path).join("src");
+    let mut statuses = HashMap::new();
+    if let Some(tree) = state.read(|respond| WikiRequest::Tree { respond }).await? {
+        visit(&tree, &src, config.lifecycle.default, &mut statuses);
+    }
+    Ok(statuses)
+}
+
+#[derive(Serialize)]
+struct TodoItem {
+    #[serde(flatten)]
+    todo: Todo,
+    url: String,
+}
+
+impl From<Todo> for TodoItem {
+    fn from(todo: Todo) -> TodoItem {
+        TodoItem {
+            url: format!("/{}", page_url(&todo.path)),
+            todo,
+        }
+    }
+}
+
+/// Whether the action item is assigned to `user`, directly or through a group or role.
+fn assigned_to(config: &Config, todo: &Todo, user: &User) -> bool {
+    todo.assignee == user.username || config.includes(&format!("@{}", todo.assignee), user)
+}
+
+/// The recently changed pages, from the git history, and the most viewed ones. With `status`,
+/// only pages with that status are listed, along with all of them.
+#[get("/dashboard?<status>")]
+pub async fn dashboard(
+    status: Option<String>,
+    user: Option<User>,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<Template, ReadFailed> {
+    if !config.allow_anonymous && user.is_none() {
+        return Err(ReadFailed::Login(Redirect::to(login_url("/dashboard"))));
+    }
+    let status = status.as_deref().and_then(PageStatus::parse);
+    let statuses = page_statuses(&config, &state).await?;
+    // Only pages that don't have the default status get a badge
+    let badge = |path: &str| {
+        statuses
+            .get(path)
+            .filter(|status| **status != config.lifecycle.default)
+            .map(PageStatus::label)
+    };
+    let included = |path: &str| status.map_or(true, |status| statuses.get(path) == Some(&status));
+
+    let recent = state
+        .read(|respond| WikiRequest::RecentChanges {
+            limit: DASHBOARD_PAGES,
+            respond,
+        })
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|change| included(&change.path))
+        .map(|change| RecentPage {
+            url: format!("/{}", page_url(&change.path)),
+            status: badge(&change.path),
+            change,
+        })
+        .collect();
+    let src = std::path::Path::new(&config.path).join("src");
+    let popular = state
+        .shared
+        .views
+        .most_viewed()
+        .into_iter()
+        // Pages that were moved or deleted since
+        .filter(|(page, _)| src.join(page).is_file() && included(page))
+        .take(DASHBOARD_PAGES)
+        .map(|(path, views)| PopularPage {
+            url: format!("/{}", page_url(&path)),
+            status: badge(&path),
+            path,
+            views,
+        })
+        .collect();
+    let todos = match &user {
+        Some(user) => state
+            .shared
+            .todos
+            .all()
+            .into_iter()
+            .filter(|todo| assigned_to(&config, todo, user))
+            .map(TodoItem::from)
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut status_pages: Vec<_> = statuses
+        .iter()
+        .filter(|(_, page_status)| Some(**page_status) == status)
+        .map(|(path, _)| StatusPage {
+            url: format!("/{}", page_url(path)),
+            path: path.clone(),
+        })
+        .collect();
+    status_pages.sort_by(|a, b| a.path.cmp(&b.path));
+    let context = DashboardContext {
+        recent,
+        popular,
+        todos,
+        statuses: PageStatus::ALL
+            .iter()
+            .map(|filter| StatusFilter {
+                name: filter.name(),
+                label: filter.label(),
+                pages: statuses.values().filter(|status| *status == filter).count(),
+            })
+            .collect(),
+        status: status.map(|status| status.name()),
+        status_pages,
+    };
+    Ok(Template::render("dashboard", &context))
+}
+
+#[derive(Serialize)]
+struct TodosContext {
+    todos: Vec<TodoItem>,
+    assignee: Option<String>,
+}
+
+/// The `TODO(@name)` action items in all pages, or the ones assigned to `assignee`.
+#[get("/todos?<assignee>")]
+pub fn todo_list(
+    assignee: Option<String>,
+    user: Option<User>,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<Template, Redirect> {
+    if !config.allow_anonymous && user.is_none() {
+        return Err(Redirect::to(login_url("/todos")));
+    }
+    let assignee = assignee.map(|assignee| assignee.trim_start_matches('@').to_string());
+    let todos = state
+        .shared
+        .todos
+        .all()
+        .into_iter()
+        .filter(|todo| assignee.as_ref().map_or(true, |name| &todo.assignee == name))
+        .map(TodoItem::from)
+        .collect();
+    Ok(Template::render("todos", &TodosContext { todos, assignee }))
+}
+
+#[derive(Serialize)]
+struct ContributedPage {
+    #[serde(flatten)]
+    contribution: PageContribution,
+    /// Where the page is, if it wasn't moved or deleted since.
+    url: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ContributorsContext {
+    contributors: Vec<Contributor>,
+    /// The contributor whose pages are listed.
+    contributor: Option<Contributor>,
+    pages: Vec<ContributedPage>,
+}
+
+/// The contributors to the pages, with the pages the one named `name` changed.
+fn contributors_context(config: &Config, name: Option<&str>) -> Option<ContributorsContext> {
+    let contributors = contributors(&config.path)
+        .map_err(log_warn)
+        .unwrap_or_default();
+    let contributor = match name {
+        Some(name) => Some(contributors.iter().find(|c| c.name == name)?.clone()),
+        None => None,
+    };
+    let src = std::path::Path::new(&config.path).join("src");
+    let pages = contributor
+        .iter()
+        .flat_map(|contributor| contributor.pages.iter().cloned())
+        .map(|contribution| ContributedPage {
+            url: Some(format!("/{}", page_url(&contribution.page)))
+                .filter(|_| src.join(&contribution.page).is_file()),
+            contribution,
+        })
+        .collect();
+    Some(ContributorsContext {
+        contributors,
+        contributor,
+        pages,
+    })
+}
+
+/// Commits, created pages and changed lines of everyone who has changed the pages.
+#[get("/stats/contributors")]
+pub fn contributor_stats(
+    user: Option<User>,
+    config: State<'_, Config>,
+) -> Result<Template, Redirect> {
+    if !config.allow_anonymous && user.is_none() {
+        return Err(Redirect::to(login_url("/stats/contributors")));
+    }
+    let context = contributors_context(&config, None).unwrap();
+    Ok(Template::render("contributors", &context))
+}
+
+/// The pages the contributor named `name` changed, and how much.
+#[get("/stats/contributors/<name>")]
+pub fn contributor_details(
+    name: String,
+    user: Option<User>,
+    config: State<'_, Config>,
+) -> Result<Option<Template>, Redirect> {
+    if !config.allow_anonymous && user.is_none() {
+        let next = format!("/stats/contributors/{}", Uri::percent_encode(&name));
+        return Err(Redirect::to(login_url(&next)));
+    }
+    Ok(contributors_context(&config, Some(&name))
+        .map(|context| Template::render("contributors", &context)))
+}
+
+#[derive(Serialize)]
+struct NewContext {
+    file: String,
+    content: String,
+    message: Option<String>,
+}
+
+#[derive(FromForm)]
+pub struct NewForm {
+    file: String,
+    content: String,
+}
+
+/// The form for a new page, with the file name starting in `dir` if given. In read-only mode
+/// it leads back to the book, which says why pages can't be created.
+#[get("/new?<dir>")]
+pub fn new_page(
+    dir: Option<String>,
+    message: Option<FlashMessage>,
+    _user: User,
+    state: State<'_, WebappState>,
+) -> Result<Template, Redirect> {
+    if state.shared.read_only() {
+        return Err(Redirect::to("/"));
+    }
+    let context = NewContext {
+        file: dir
+            .map(|dir| dir.trim_matches('/').to_string())
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| format!("{}/", dir))
+            .unwrap_or_default(),
+        content: "".to_string(),
+        message: message.map(|f| f.msg().to_string()),
+    };
+    Ok(Template::render("new_page", &context))
+}
+
+#[post("/new", data = "<form>")]
+pub async fn new_page_post(
+    form: Form<NewForm>,
+    user: User,
+    state: State<'_, WebappState>,
+) -> Result<Redirect, Template> {
+    // TODO check for legal characters in path
+    let form_file = form.file.replace(" ", "_");
+    let file = Path::new(&form_file);
+
+    let res = match state
+        .send(|respond| WikiRequest::CreateFile {
+            user,
+            file: file.to_path_buf().into_boxed_path(),
+            content: form.content.clone(),
+            respond,
+        })
+        .await
+    {
+        Ok(res) | Err(res) => res,
+    };
+    if !res.is_ok() {
+        let context = NewContext {
+            file: form.file.clone(),
+            content: form.content.clone(),
+            message: Some(
+                res.msg()
+                    .cloned()
+                    .unwrap_or("Something went wrong :(".to_string()),
+            ),
+        };
+        return Err(Template::render("new_page", &context));
+    }
+
+    let html_file = Path::new(&form.file).with_extension("html");
+    return Ok(Redirect::to(format!(
+        "/{}",
+        html_file
+            .to_str()
+            .unwrap()
+            .replace("README.html", "")
+            .to_string()
+    )));
+}
+
+#[derive(Serialize)]
+struct EditContext {
+    file: std::path::PathBuf,
+    content: String,
+    base: String,
+    message: Option<String>,
+    attachments: Vec<Attachment>,
+    /// Who else is editing the page.
+    locked_by: Option<String>,
+    /// Seconds between renewing the lock on the page, 0 if there are no locks.
+    lock_renew: u64,
+    /// The id of the heading whose section is edited, if not the whole page.
+    section: Option<String>,
+    /// Whether saving suggests the change instead, see `Config::suggestions`.
+    suggest: bool,
+    /// When the draft of the user being edited was saved, if it's their draft that is edited.
+    draft: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Attachment {
+    name: String,
+    url: String,
+}
+
+/// The files attached to the page `file`, by name.
+async fn attachments(config: &Config, file: &std::path::Path) -> Vec<Attachment> {
+    let dir = attachments_dir(Path::new(file));
+    let mut entries = match fs::read_dir(Path::new(&config.path).join("src").join(&dir)).await {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut attachments = Vec::new();
+    while let Some(Ok(entry)) = entries.next().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        attachments.push(Attachment {
+            url: format!("/{}/{}", url_path(&dir), name),
+            name,
+        });
+    }
+    attachments.sort_by(|a, b| a.name.cmp(&b.name));
+    attachments
+}
+
+#[derive(FromForm)]
+pub struct EditForm {
+    content: String,
+    base: Option<String>,
+    /// Saves the edit as a draft instead, see `drafts`.
+    draft: bool,
+}
+
+/// Locks `file` for `user` while they edit it, see `EditLocks`. Returns who else is editing it
+/// if they hold the lock.
+fn lock_page(
+    file: &std::path::Path,
+    user: &User,
+    take_over: bool,
+    config: &Config,
+    state: &WebappState,
+) -> Result<(), String> {
+    if config.edit_lock_timeout == 0 {
+        return Ok(());
+    }
+    let timeout = std::time::Duration::from_secs(config.edit_lock_timeout);
+    state
+        .edit_locks
+        .acquire(&url_path(file), user, timeout, take_over)
+        .map_err(|lock| lock.name)
+}
+
+/// The editor for `file`, which locks the page for others. If someone else is editing it, the
+/// page says so, and `take_over` takes the lock from them. With `section`, the id of a heading,
+/// only the section under that heading is edited. In read-only mode it leads back to the page.
+#[get("/edit/<file..>?<take_over>&<section>")]
+pub async fn edit_page(
+    file: std::path::PathBuf,
+    take_over: Option<bool>,
+    section: Option<String>,
+    message: Option<FlashMessage<'_, '_>>,
+    user: User,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<Template, Option<Flash<Redirect>>> {
+    if !config.can_edit(&PathBuf::from(&file)).await.is_ok() {
+        return Err(None);
+    }
+    if let Some(message) = state.shared.read_only_message() {
+        let url = format!("/{}", page_url(&url_path(&file)));
+        return Err(Some(Flash::error(Redirect::to(url), message)));
+    }
+    let path = Path::new(&config.path).join("src").join(&file);
+    let content = fs::read_to_string(&path)
+        .await
+        .map_err(log_warn)
+        .map_err(|_| None)?;
+    // Suggestions don't change the page, so they don't keep editors out
+    let suggest = suggests_changes(&config, &user, &file);
+    // Users with a draft of the page go on editing it
+    let draft = match suggest {
+        true => None,
+        false => user_draft(&config, &user, &file),
+    };
+    let (content, base) = match &draft {
+        Some(draft) => (draft.content.clone(), draft.base.clone()),
+        None => {
+            let base = merge::revision(&content);
+            (content, base)
+        }
+    };
+    let content = match &section {
+        Some(id) => {
+            let range = headings::section_range(&content, id).ok_or(None)?;
+            content[range].to_string()
+        }
+        None => content,
+    };
+    let locked_by = match suggest {
+        true => None,
+        false => lock_page(&file, &user, take_over.unwrap_or(false), &config, &state).err(),
+    };
+    let context = EditContext {
+        attachments: attachments(&config, &file).await,
+        file,
+        base,
+        content,
+        message: message.map(|f| f.msg().to_string()),
+        locked_by,
+        lock_renew: if suggest {
+            0
+        } else {
+            config.edit_lock_timeout / 3
+        },
+        section,
+        suggest,
+        draft: draft.map(|draft| draft.saved),
+    };
+    Ok(Template::render("edit_page", &context))
+}
+
+/// The draft of `file` by `user`, if they have one.
+fn user_draft(config: &Config, user: &User, file: &std::path::Path) -> Option<Draft> {
+    let repo = git2::Repository::open(&config.path)
+        .map_err(log_warn)
+        .ok()?;
+    drafts::get(&repo, &user.username, &url_path(file))
+}
+
+/// Whether edits of `file` by `user` are suggested to its editors instead of saved.
+fn suggests_changes(config: &Config, user: &User, file: &std::path::Path) -> bool {
+    config.suggestions && !config.may_change(user, Path::new(file)).is_ok()
+}
+
+/// `edit` of a page that started from the revision `base`, with the parts that conflict with
+/// `current` marked, see `merge::mark_conflicts`.
+fn conflict_markers(config: &Config, base: &str, current: &str, edit: &str) -> Option<String> {
+    let repo = git2::Repository::open(&config.path)
+        .map_err(log_warn)
+        .ok()?;
+    let base = merge::content_at(&repo, base)?;
+    let work_dir = config.data_path().join("merge").join(rand_safe_string(8));
+    merge::mark_conflicts(&repo, &base, current, edit, &work_dir)
+        .map_err(log_warn)
+        .ok()
+}
+
+/// Saves an edit of `file`, or a draft of it. An edit of the `section` under a heading is put in
+/// place of that section in the current version of the page, or the draft of it the user is
+/// editing, and merged like any other edit.
+#[post("/edit/<file..>?<section>", data = "<form>")]
+pub async fn edit_page_post(
+    file: std::path::PathBuf,
+    section: Option<String>,
+    form: Form<EditForm>,
+    user: User,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<Redirect, Template> {
+    let page = match &section {
+        Some(id) => {
+            let current = match user_draft(&config, &user, &file) {
+                Some(draft) => draft.content,
+                None => {
+                    let path = Path::new(&config.path).join("src").join(&file);
+                    fs::read_to_string(&path).await.unwrap_or_default()
+                }
+            };
+            match headings::replace_section(&current, id, &form.content) {
+                Some(page) => page,
+                None => {
+                    let suggest = suggests_changes(&config, &user, &file);
+                    let context = EditContext {
+                        attachments: attachments(&config, &file).await,
+                        file,
+                        content: form.content.clone(),
+                        base: form.base.clone().unwrap_or_default(),
+                        message: Some(format!("The section '{}' isn't on the page anymore", id)),
+                        locked_by: None,
+                        lock_renew: config.edit_lock_timeout / 3,
+                        section,
+                        suggest,
+                        draft: None,
+                    };
+                    return Err(Template::render("edit_page", &context));
+                }
+            }
+        }
+        None => form.content.clone(),
+    };
+    if suggests_changes(&config, &user, &file) {
+        let suggested = state.shared.suggestions.suggest(
+            &user.username,
+            user.name(),
+            &url_path(&file),
+            form.base.as_deref().unwrap_or_default(),
+            &page,
+        );
+        return match suggested {
+            Ok(id) => {
+                info!(
+                    "'{}' suggested a change to {}: {}",
+                    user.username,
+                    file.display(),
+                    id
+                );
+                Ok(Redirect::to("/reviews"))
+            }
+            Err(e) => {
+                warn!("{}", e);
+                let context = EditContext {
+                    attachments: attachments(&config, &file).await,
+                    file,
+                    content: form.content.clone(),
+                    base: form.base.clone().unwrap_or_default(),
+                    message: Some("Could not save the suggestion".into()),
+                    locked_by: None,
+                    lock_renew: 0,
+                    section,
+                    suggest: true,
+                    draft: None,
+                };
+                Err(Template::render("edit_page", &context))
+            }
+        };
+    }
+    if form.draft {
+        let res = state
+            .send(|respond| WikiRequest::SaveDraft {
+                user,
+                file: PathBuf::from(file.to_path_buf()).into_boxed_path(),
+                content: page,
+                base: form.base.clone(),
+                respond,
+            })
+            .await
+            .and_then(WikiResponse::result);
+        return match res {
+            Ok(_) => Ok(Redirect::to("/drafts")),
+            Err(res) => {
+                let context = EditContext {
+                    attachments: attachments(&config, &file).await,
+                    file,
+                    content: form.content.clone(),
+                    base: form.base.clone().unwrap_or_default(),
+                    message: Some(
+                        res.msg()
+                            .cloned()
+                            .unwrap_or("Could not save the draft".into()),
+                    ),
+                    locked_by: None,
+                    lock_renew: config.edit_lock_timeout / 3,
+                    section,
+                    suggest: false,
+                    draft: None,
+                };
+                Err(Template::render("edit_page", &context))
+            }
+        };
+    }
+    let username = user.username.clone();
+    let res = match state
+        .send(|respond| WikiRequest::EditFile {
+            user,
+            file: PathBuf::from(file.to_path_buf()).into_boxed_path(),
+            content: page.clone(),
+            base: form.base.clone(),
+            respond,
+        })
+        .await
+    {
+        Ok(res) | Err(res) => res,
+    };
+    if !res.is_ok() {
+        let mut message = res
+            .msg()
+            .cloned()
+            .unwrap_or("Something went wrong :(".to_string());
+        // After a conflict, the edit comes back with the parts that conflict marked, and saving
+        // again replaces the version that conflicted. The markers can be anywhere on the page,
+        // so the whole page is edited from then on.
+        let (content, base, section) = match res {
+            WikiResponse::Conflict(_) => {
+                let current = fs::read_to_string(Path::new(&config.path).join("src").join(&file))
+                    .await
+                    .ok();
+                let marked = match (&current, &form.base) {
+                    (Some(current), Some(base)) => conflict_markers(&config, base, current, &page),
+                    _ => None,
+                };
+                if marked.is_some() {
+                    message.push_str(
+                        " Both versions of the parts that conflict are marked below, keep what \
+                         should stay and save again.",
+                    );
+                }
+                (
+                    marked.unwrap_or(page),
+                    current.map(|current| merge::revision(&current)),
+                    None,
+                )
+            }
+            _ => (form.content.clone(), form.base.clone(), section),
+        };
+        let context = EditContext {
+            attachments: attachments(&config, &file).await,
+            file,
+            content,
+            base: base.unwrap_or_default(),
+            message: Some(message),
+            locked_by: None,
+            lock_renew: config.edit_lock_timeout / 3,
+            section,
+            suggest: false,
+            draft: None,
+        };
+        return Err(Template::render("edit_page", &context));
+    }
+    state.edit_locks.release(&url_path(&file), &username);
+
+    let html_file = file.with_extension("html");
+    let anchor = section.map(|id| format!("#{}", id)).unwrap_or_default();
+    return Ok(Redirect::to(format!(
+        "/{}{}",
+        html_file
+            .to_str()
+            .unwrap()
+            .replace("README.html", "")
+            .to_string(),
+        anchor
+    )));
+}
+
+/// Renews the lock on `file` while its editor is open. Fails with `409 Conflict` once someone
+/// else took over editing it.
+#[post("/lock/<file..>")]
+pub fn renew_edit_lock(
+    file: std::path::PathBuf,
+    user: User,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<(), status::Custom<String>> {
+    lock_page(&file, &user, false, &config, &state).map_err(|name| {
+        let message = format!("{} took over editing the page", name);
+        status::Custom(Status::Conflict, message)
+    })
+}
+
+/// The lines of a unified diff of the page `page` from `from` to `to`, without the file names.
+fn diff_lines(page: &str, from: &str, to: &str) -> Vec<String> {
+    bulk::diff(std::path::Path::new(page), from, to)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.starts_with("---") && !line.starts_with("+++"))
+        .map(String::from)
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Review {
+    id: String,
+    name: String,
+    page: String,
+    url: String,
+    created: u64,
+    /// The lines of a unified diff from the version the suggestion started from.
+    lines: Vec<String>,
+    /// Whether the user may accept the suggestion, and not just withdraw their own.
+    can_accept: bool,
+}
+
+#[derive(Serialize)]
+struct ReviewsContext {
+    reviews: Vec<Review>,
+    message: Option<String>,
+}
+
+/// The suggested changes `user` may review, and the ones they suggested themselves.
+#[get("/reviews")]
+pub async fn reviews(
+    message: Option<FlashMessage<'_, '_>>,
+    user: User,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Template {
+    let repo = git2::Repository::open(&config.path).map_err(log_warn).ok();
+    let mut reviews = Vec::new();
+    for suggestion in state.shared.suggestions.all() {
+        let can_accept = config
+            .may_change(&user, Path::new(&suggestion.page))
+            .is_ok();
+        if !can_accept && suggestion.username != user.username {
+            continue;
+        }
+        let path = Path::new(&config.path).join("src").join(&suggestion.page);
+        let base = match repo
+            .as_ref()
+            .and_then(|repo| merge::content_at(repo, &suggestion.base))
+        {
+            Some(base) => base,
+            None => fs::read_to_string(&path).await.unwrap_or_default(),
+        };
+        reviews.push(Review {
+            lines: diff_lines(&suggestion.page, &base, &suggestion.content),
+            url: format!("/{}", page_url(&suggestion.page)),
+            id: suggestion.id,
+            name: suggestion.name,
+            page: suggestion.page,
+            created: suggestion.created,
+            can_accept,
+        });
+    }
+    let context = ReviewsContext {
+        reviews,
+        message: message.map(|f| f.msg().to_string()),
+    };
+    Template::render("reviews", &context)
+}
+
+/// Saves the suggested change `id`, merged with any changes made since it was suggested.
+#[post("/reviews/<id>/accept")]
+pub async fn accept_suggestion(
+    id: String,
+    user: User,
+    state: State<'_, WebappState>,
+) -> Flash<Redirect> {
+    let redirect = Redirect::to("/reviews");
+    let res = state
+        .send(|respond| WikiRequest::AcceptSuggestion { user, id, respond })
+        .await
+        .and_then(WikiResponse::result);
+    match res {
+        Ok(_) => Flash::success(redirect, "Accepted the suggestion"),
+        Err(res) => Flash::error(
+            redirect,
+            res.msg()
+                .cloned()
+                .unwrap_or("Could not accept the suggestion".into()),
+        ),
+    }
+}
+
+/// Drops the suggested change `id`, rejected by someone who may change the page or withdrawn
+/// by the user who suggested it.
+#[post("/reviews/<id>/reject")]
+pub fn reject_suggestion(
+    id: String,
+    user: User,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Flash<Redirect> {
+    let redirect = Redirect::to("/reviews");
+    let suggestion = match state.shared.suggestions.get(&id) {
+        Some(suggestion) => suggestion,
+        None => return Flash::error(redirect, "The suggestion was already reviewed"),
+    };
+    let own = suggestion.username == user.username;
+    let may_review = config
+        .may_change(&user, Path::new(&suggestion.page))
+        .is_ok();
+    if !own && !may_review {
+        return Flash::error(redirect, "You're not allowed to review this suggestion");
+    }
+    if let Err(e) = state.shared.suggestions.remove(&id) {
+        warn!("{}", e);
+        return Flash::error(redirect, "Could not remove the suggestion");
+    }
+    info!(
+        "'{}' rejected the suggestion {} to change {}",
+        user.username, id, suggestion.page
+    );
+    match own {
+        true => Flash::success(redirect, "Withdrew your suggestion"),
+        false => Flash::success(redirect, "Rejected the suggestion"),
+    }
+}
+
+#[derive(Serialize)]
+struct DraftView {
+    page: String,
+    url: String,
+    saved: u64,
+    /// The lines of a unified diff from the version the draft started from.
+    lines: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DraftsContext {
+    drafts: Vec<DraftView>,
+    message: Option<String>,
+}
+
+/// The drafts of `user`, with what publishing them would change.
+#[get("/drafts")]
+pub async fn list_drafts(
+    message: Option<FlashMessage<'_, '_>>,
+    user: User,
+    config: State<'_, Config>,
+) -> Template {
+    let repo = git2::Repository::open(&config.path).map_err(log_warn).ok();
+    let list = repo
+        .as_ref()
+        .map(|repo| drafts::list(repo, &user.username).map_err(log_warn))
+        .and_then(Result::ok)
+        .unwrap_or_default();
+    let mut views = Vec::new();
+    for draft in list {
+        let base = match repo
+            .as_ref()
+            .and_then(|repo| merge::content_at(repo, &draft.base))
+        {
+            Some(base) => base,
+            None => {
+                let path = Path::new(&config.path).join("src").join(&draft.page);
+                fs::read_to_string(&path).await.unwrap_or_default()
+            }
+        };
+        views.push(DraftView {
+            lines: diff_lines(&draft.page, &base, &draft.content),
+            url: format!("/{}", page_url(&draft.page)),
+            page: draft.page,
+            saved: draft.saved,
+        });
+    }
+    let context = DraftsContext {
+        drafts: views,
+        message: message.map(|f| f.msg().to_string()),
+    };
+    Template::render("drafts", &context)
+}
+
+/// Publishes every draft of `user` in one commit, see `WikiRequest::PublishDrafts`.
+#[post("/drafts/publish")]
+pub async fn publish_drafts(user: User, state: State<'_, WebappState>) -> Flash<Redirect> {
+    let redirect = Redirect::to("/drafts");
+    let res = state
+        .send(|respond| WikiRequest::PublishDrafts { user, respond })
+        .await
+        .and_then(|res| res);
+    match res {
+        Ok(pages) => Flash::success(redirect, format!("Published {}", pages.join(", "))),
+        Err(res) => Flash::error(
+            redirect,
+            res.msg()
+                .cloned()
+                .unwrap_or("Could not publish the drafts".into()),
+        ),
+    }
+}
+
+/// Discards the draft of `file` by `user`, it stays in the history of their drafts branch.
+#[post("/drafts/discard/<file..>")]
+pub async fn discard_draft(
+    file: std::path::PathBuf,
+    user: User,
+    state: State<'_, WebappState>,
+) -> Flash<Redirect> {
+    let redirect = Redirect::to("/drafts");
+    let page = url_path(&file);
+    let res = state
+        .send(|respond| WikiRequest::DiscardDraft {
+            user,
+            file: PathBuf::from(file).into_boxed_path(),
+            respond,
+        })
+        .await
+        .and_then(WikiResponse::result);
+    match res {
+        Ok(_) => Flash::success(redirect, format!("Discarded your draft of {}", page)),
+        Err(res) => Flash::error(
+            redirect,
+            res.msg()
+                .cloned()
+                .unwrap_or("Could not discard the draft".into()),
+        ),
+    }
+}
+
+#[derive(Serialize)]
+struct DeletedContext {
+    pages: Vec<DeletedPage>,
+    message: Option<String>,
+}
+
+/// The pages that were deleted, through mdwiki or a push to the repository, and weren't
+/// created again since.
+#[get("/deleted")]
+pub async fn list_deleted(
+    message: Option<FlashMessage<'_, '_>>,
+    _user: User,
+    state: State<'_, WebappState>,
+) -> Result<Template, ReadFailed> {
+    let context = DeletedContext {
+        pages: state
+            .read(|respond| WikiRequest::DeletedPages { respond })
+            .await?
+            .unwrap_or_default(),
+        message: message.map(|f| f.msg().to_string()),
+    };
+    Ok(Template::render("deleted", &context))
+}
+
+/// Brings back `file` as it was before `commit` deleted it, see `WikiRequest::RestorePage`.
+#[post("/deleted/restore/<commit>/<file..>")]
+pub async fn restore_deleted(
+    commit: String,
+    file: std::path::PathBuf,
+    user: User,
+    state: State<'_, WebappState>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let page = url_path(&file);
+    state
+        .send(|respond| WikiRequest::RestorePage {
+            user,
+            file: PathBuf::from(file).into_boxed_path(),
+            commit,
+            respond,
+        })
+        .await
+        .and_then(WikiResponse::result)
+        .map(|_| Redirect::to(format!("/{}", page_url(&page))))
+        .map_err(|res| {
+            let message = res
+                .msg()
+                .cloned()
+                .unwrap_or(format!("Could not restore {}", page));
+            Flash::error(Redirect::to("/deleted"), message)
+        })
+}
+
+/// Form fields an image can be uploaded in, the first one found is used.
+const IMAGE_FIELDS: &[&str] = &["image", "file"];
+
+/// The extension uploaded images of type `content_type` are stored with.
+fn image_extension(content_type: &ContentType) -> Option<&'static str> {
+    if *content_type == ContentType::JPEG {
+        Some("jpg")
+    } else if *content_type == ContentType::GIF {
+        Some("gif")
+    } else if *content_type == ContentType::PNG {
+        Some("png")
+    } else if *content_type == ContentType::BMP {
+        Some("bmp")
+    } else {
+        None
+    }
+}
+
+/// Names the image uploaded to `upload` as configured with `upload_names`, and moves it next to
+/// the other uploads under that name. Returns the url of the image.
+async fn name_image(
+    upload: &Path,
+    original: Option<&str>,
+    extension: &str,
+    config: &Config,
+    plugins: &Plugins,
+) -> Result<String, String> {
+    let name = match config.upload_names.name(original, upload, plugins).await {
+        Ok(name) => name,
+        Err(e) => {
+            let _ = fs::remove_file(upload).await;
+            return Err(e);
+        }
+    };
+    let path = Path::new(&config.upload_path()).join(format!("{}.{}", name, extension));
+    fs::rename(upload, path)
+        .await
+        .map_err(|e| format!("could not save image: {}", e))?;
+    Ok(format!("/images/{}.{}", name, extension))
+}
+
+/// Saves the image in the first of `IMAGE_FIELDS` in a multipart form, and returns the url of
+/// the image.
+async fn save_form_image(
+    data: Data,
+    content_type: &ContentType,
+    config: &Config,
+    plugins: &Plugins,
+) -> Result<String, String> {
+    let boundary = multer::parse_boundary(content_type.to_string())
+        .map_err(|e| format!("invalid multipart form: {}", e))?;
+    let mut form = multer::Multipart::with_reader(data.open(8_u8.mebibytes()), boundary);
+    while let Some(mut field) = form
+        .next_field()
+        .await
+        .map_err(|e| format!("invalid multipart form: {}", e))?
+    {
+        if !field.name().map_or(false, |name| IMAGE_FIELDS.contains(&name)) {
+            continue;
+        }
+        let file_name = field.file_name().unwrap_or_default().to_string();
+        let extension = field
+            .content_type()
+            .and_then(|mime| ContentType::parse_flexible(mime.as_ref()))
+            .or_else(|| {
+                let extension = Path::new(&file_name).extension()?.to_string_lossy();
+                ContentType::from_extension(&extension)
+            })
+            .as_ref()
+            .and_then(image_extension)
+            .ok_or_else(|| format!("'{}' is not a supported image", file_name))?;
+        let path = Path::new(&config.upload_path()).join(rand_safe_string(16));
+
+        let mut file = File::create(&path)
+            .await
+            .map_err(|e| format!("could not save image: {}", e))?;
+        loop {
+            let written = match field.chunk().await {
+                Ok(Some(chunk)) => file.write_all(&chunk).await.map_err(|e| e.to_string()),
+                Ok(None) => break,
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = written {
+                let _ = fs::remove_file(&path).await;
+                return Err(format!("could not save image: {}", e));
+            }
+        }
+        let original = Some(file_name.as_str()).filter(|name| !name.is_empty());
+        return name_image(&path, original, extension, config, plugins).await;
+    }
+    Err(format!("no {} field in the form", IMAGE_FIELDS.join(" or ")))
+}
+
+/// How an uploaded image is written in a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    /// Just the url.
+    Path,
+    /// `![alt](url)`
+    Markdown,
+    /// A `<figure>` with the image and a caption.
+    Figure,
+}
+
+/// What `/upload/image` returns, for the editor to insert.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ImageSnippet {
+    pub format: ImageFormat,
+    /// Link to the image relative to the page it's uploaded for, like `../images/<name>`, when
+    /// the page is known.
+    pub relative: bool,
+    /// The placeholder for the alt text.
+    pub alt: String,
+    /// The placeholder for the caption of figures.
+    pub caption: String,
+}
+
+impl Default for ImageSnippet {
+    fn default() -> ImageSnippet {
+        ImageSnippet {
+            format: ImageFormat::Path,
+            relative: false,
+            alt: "insert image description".to_string(),
+            caption: "insert caption".to_string(),
+        }
+    }
+}
+
+impl ImageSnippet {
+    /// The snippet for the image at `url`, `/images/<name>`, uploaded for `page`. `alt` replaces
+    /// the placeholder for the alt text.
+    pub fn render(&self, url: &str, page: Option<&str>, alt: Option<&str>) -> String {
+        let url = match page {
+            Some(page) if self.relative => {
+                let dir = std::path::Path::new(page)
+                    .parent()
+                    .unwrap_or_else(|| std::path::Path::new(""));
+                relative_link(dir, std::path::Path::new(url.trim_start_matches('/')))
+            }
+            _ => url.to_string(),
+        };
+        let alt = alt
+            .map(|alt| alt.replace(|c| c == '\r' || c == '\n', " "))
+            .unwrap_or_else(|| self.alt.clone());
+        match self.format {
+            ImageFormat::Path => url,
+            ImageFormat::Markdown => format!(
+                "![{}]({})",
+                alt.replace('[', "\\[").replace(']', "\\]"),
+                url
+            ),
+            ImageFormat::Figure => format!(
+                "<figure>\n<img src=\"{}\" alt=\"{}\">\n<figcaption>{}</figcaption>\n</figure>\n",
+                url,
+                alt.replace('&', "&amp;")
+                    .replace('"', "&quot;")
+                    .replace('<', "&lt;"),
+                self.caption
+            ),
+        }
+    }
+}
+
+/// Saves an uploaded image until a page uses it, returning the snippet for it configured with
+/// `image_snippet`, the image's url by default. The image is either the request body, with its
+/// type as the content type, or a file in a multipart form. `page` is the page it's uploaded
+/// for, relative to the book source, and `alt` its alt text, which `require_alt_text` requires.
+#[post("/upload/image?<page>&<alt>", data = "<data>")]
+pub async fn upload_image(
+    page: Option<String>,
+    alt: Option<String>,
+    data: Data,
+    _user: User,
+    content_type: &ContentType,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<String, status::Custom<String>> {
+    let plugins = &state.shared.plugins;
+    let alt = alt.filter(|alt| !alt.trim().is_empty());
+    if config.require_alt_text && alt.is_none() {
+        let message = "Describe the image for readers who can't see it".to_string();
+        return Err(status::Custom(Status::BadRequest, message));
+    }
+    let failed = || status::Custom(Status::InternalServerError, String::new());
+    let url = if content_type.is_form_data() {
+        save_form_image(data, content_type, &config, plugins)
+            .await
+            .map_err(log_warn)
+            .map_err(|_| failed())?
+    } else {
+        let extension = image_extension(content_type).ok_or_else(failed)?;
+        let upload = Path::new(&config.upload_path()).join(rand_safe_string(16));
+
+        data.open(8_u8.mebibytes())
+            .stream_to_file(&upload)
+            .await
+            .map_err(log_warn)
+            .map_err(|_| failed())?;
+
+        name_image(&upload, None, extension, &config, plugins)
+            .await
+            .map_err(log_warn)
+            .map_err(|_| failed())?
+    };
+    let page = page.filter(|page| !page.trim().is_empty());
+    Ok(config
+        .image_snippet
+        .render(&url, page.as_deref(), alt.as_deref()))
+}
+
+/// Attaches the file in the request body to the page `file`, returning the url of the file.
+#[post("/upload/attachment/<file..>?<name>", data = "<data>")]
+pub async fn upload_attachment(
+    file: std::path::PathBuf,
+    name: String,
+    data: Data,
+    user: User,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<String, status::Custom<String>> {
+    let error = |res: WikiResponse| {
+        let message = res.msg().cloned().unwrap_or_default();
+        status::Custom(res.status(), message)
+    };
+    let upload = config.upload_path().join(rand_safe_string(16));
+
+    // One byte more than allowed, to tell files that are too large from those that fit
+    let limit = config.max_attachment_size + 1;
+    let written = match data.open(limit.bytes()).stream_to_file(&upload).await {
+        Ok(_) => fs::metadata(&upload).await.map(|metadata| metadata.len()),
+        Err(e) => Err(e),
+    };
+    match written {
+        Ok(written) if written < limit => {}
+        Ok(_) => {
+            let _ = fs::remove_file(&upload).await;
+            let message = format!("Files can be at most {} bytes", config.max_attachment_size);
+            return Err(status::Custom(Status::PayloadTooLarge, message));
+        }
+        Err(e) => {
+            warn!("could not save attachment: {}", e);
+            let _ = fs::remove_file(&upload).await;
+            return Err(error(WikiResponse::Error(None)));
+        }
+    }
+
+    state
+        .send(|respond| WikiRequest::Attach {
+            user,
+            page: PathBuf::from(file).into_boxed_path(),
+            name,
+            upload,
+            respond,
+        })
+        .await
+        .and_then(|res| res)
+        .map_err(error)
+}
+
+#[derive(Serialize)]
+struct EmbedContext {
+    title: String,
+    base: String,
+    content: String,
+}
+
+#[derive(Responder)]
+pub struct Embed {
+    page: Template,
+    csp: Header<'static>,
+}
+
+/// A single page without the book's navigation, for embedding in an iframe. Only origins in
+/// `embed_frame_ancestors` (and mdwiki itself) may embed it. Both the source path
+/// (`/embed/dir/page.md`) and the book path (`/embed/dir/page.html`) are accepted.
+#[get("/embed/<file..>")]
+pub async fn embed_page(
+    file: std::path::PathBuf,
+    user: Option<User>,
+    config: State<'_, Config>,
+) -> Result<Embed, Status> {
+    if !config.allow_anonymous && user.is_none() {
+        return Err(Status::Unauthorized);
+    }
+
+    let file = match file.extension().and_then(|ext| ext.to_str()) {
+        Some("html") if file.file_stem().map(|stem| stem == "index").unwrap_or(false) => {
+            file.with_file_name("README.md")
+        }
+        Some("html") => file.with_extension("md"),
+        _ => file,
+    };
+    if !config.can_edit(&PathBuf::from(&file)).await.is_ok() {
+        return Err(Status::NotFound);
+    }
+
+    let markdown = fs::read_to_string(Path::new(&config.path).join("src").join(&file))
+        .await
+        .map_err(log_warn)
+        .map_err(|_| Status::InternalServerError)?;
+    // Links between pages point to the markdown files, the book has them as html
+    let markdown = MARKDOWN_LINK_REGEX.replace_all(&markdown, |cap: &Captures| {
+        let link = &cap[1];
+        if link.contains("://") || link.starts_with('/') {
+            return cap[0].to_string();
+        }
+        format!("]({})", MD_LINK_EXTENSION_REGEX.replace(link, ".html$1"))
+    });
+    let markdown = if config.sanitize_html {
+        sanitize_markdown(&markdown).into()
+    } else {
+        markdown
+    };
+
+    let title = markdown
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(String::from)
+        .or_else(|| file.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+        .unwrap_or_default();
+    let base = match file.parent().and_then(|parent| parent.to_str()) {
+        Some(parent) if !parent.is_empty() => format!("/{}/", parent),
+        _ => "/".into(),
+    };
+
+    let csp = with_frame_ancestors(
+        config
+            .security_headers
+            .get(CONTENT_SECURITY_POLICY)
+            .map(String::as_str),
+        &config.embed_frame_ancestors,
+    );
+
+    Ok(Embed {
+        page: Template::render(
+            "embed",
+            &EmbedContext {
+                title,
+                base,
+                content: mdbook::utils::render_markdown(&markdown, false),
+            },
+        ),
+        csp: Header::new(CONTENT_SECURITY_POLICY, csp),
+    })
+}
+
+/// Liveness probe, mdwiki is up as soon as it answers.
+#[get("/healthz")]
+pub fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness and startup probe, ready once the initial build is done.
+#[get("/readyz")]
+pub fn readyz(state: State<'_, WebappState>) -> Result<&'static str, Status> {
+    match state.shared.ready() {
+        true => Ok("ready"),
+        false => Err(Status::ServiceUnavailable),
+    }
+}
+
+/// How busy the wiki is, in Prometheus' text format.
+#[get("/metrics")]
+pub fn metrics(state: State<'_, WebappState>) -> String {
+    let queue = &state.shared.queue;
+    let metrics = [
+        (
+            "mdwiki_queue_depth",
+            "gauge",
+            "Requests waiting for the wiki task.",
+            queue.depth() as u64,
+        ),
+        (
+            "mdwiki_queue_capacity",
+            "gauge",
+            "Requests that can wait for the wiki task.",
+            queue.capacity() as u64,
+        ),
+        (
+            "mdwiki_queue_rejected_total",
+            "counter",
+            "Requests turned away because the queue was full.",
+            queue.rejected(),
+        ),
+    ];
+    metrics
+        .iter()
+        .map(|(name, kind, help, value)| {
+            format!(
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name, help, name, kind, name, value
+            )
+        })
+        .collect()
+}
+
+#[get("/robots.txt")]
+pub fn robots_txt(config: State<'_, Config>) -> String {
+    robots::robots_txt(&config)
+}
+
+#[get("/", rank = 10)]
+pub async fn index(user: Option<User>, config: State<'_, Config>) -> Redirect {
+    let home = user.as_ref().and_then(|user| {
+        config
+            .pages_for(user)
+            .into_iter()
+            .find_map(|pages| pages.home.as_ref())
+    });
+    match home {
+        Some(home) => Redirect::to(format!("/{}", page_url(home))),
+        // Where `/` leads depends on who asks, or on the configuration, so browsers mustn't
+        // remember it
+        None if !config.group_pages.is_empty() || config.home_page.is_some() => {
+            Redirect::to(format!("/{}", page_url(config.home_page())))
+        }
+        None => Redirect::permanent("/index.html"),
+    }
+}
+
+#[derive(Responder)]
+pub enum BookFile {
+    Disk(Cached<RangedBody<File>>),
+    Memory(Cached<RangedBody<Cursor<CachedBytes>>>),
+}
+
+async fn cached_page(
+    page: CachedPage,
+    conditional: &Conditional,
+    range: Option<ByteRange>,
+) -> Option<BookFile> {
+    if page.validators.not_modified(conditional) {
+        return Some(BookFile::Memory(Cached::NotModified(page.validators)));
+    }
+    let range = range.filter(|_| page.validators.range_allowed(conditional));
+    let total = page.body.as_ref().len() as u64;
+    RangedBody::new(Cursor::new(page.body), total, Some(ContentType::HTML), range)
+        .await
+        .map_err(log_warn)
+        .ok()
+        .map(|body| BookFile::Memory(Cached::Fresh(body, page.validators)))
+}
+
+/// Where a request for `path` should be redirected to, if anywhere. Pages are served from the
+/// `.html` files mdbook builds and directories from their `index.html`, so `/page`, `/page.md`,
+/// `/page.html/`, `/dir` and `/dir/` lead there instead of failing. With `ignore_case`, so do
+/// paths in the wrong case.
+fn canonical_path(
+    book_dir: &std::path::Path,
+    path: &std::path::Path,
+    trailing_slash: bool,
+    ignore_case: bool,
+) -> Option<std::path::PathBuf> {
+    let full_path = book_dir.join(path);
+    if full_path.is_dir() {
+        return Some(path.join("index.html"));
+    }
+    if full_path.is_file() {
+        return Some(path.to_path_buf()).filter(|_| trailing_slash);
+    }
+    if ignore_case {
+        if let Some(found) = find_ignoring_case(book_dir, path) {
+            return canonical_path(book_dir, &found, false, false).or(Some(found));
+        }
+    }
+    let guess = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("md") if path.ends_with("README.md") => path.with_file_name("index.html"),
+        Some("md") | None => path.with_extension("html"),
+        Some(_) => return None,
+    };
+    if book_dir.join(&guess).is_file() {
+        Some(guess)
+    } else if ignore_case {
+        find_ignoring_case(book_dir, &guess)
+    } else {
+        None
+    }
+}
+
+/// Counts a view of the page built to `path`.
+fn record_view(state: &WebappState, path: &std::path::Path) {
+    let page = match path.file_name() {
+        Some(name) if name == "index.html" => path.with_file_name("README.md"),
+        _ => path.with_extension("md"),
+    };
+    state.shared.views.record(&url_path(&page));
+}
+
+#[get("/<path..>", rank = 10)]
+pub async fn book_files(
+    path: std::path::PathBuf,
+    uri: &Origin<'_>,
+    range: RangeHeader,
+    conditional: Conditional,
+    user: Option<User>,
+    config: State<'_, Config>,
+    state: State<'_, WebappState>,
+) -> Result<Option<BookFile>, Redirect> {
+    const SAFE_PREFIXES: &[&'static str] = &["css", "FontAwesome", "favicon.svg"];
+
+    if !config.allow_anonymous
+        && user.is_none()
+        && SAFE_PREFIXES
+            .iter()
+            .find(|prefix| path.starts_with(prefix))
+            .is_none()
+    {
+        return Err(Redirect::to(login_url(&format!("/{}", url_path(&path)))));
+    }
+
+    let page_cache = &state.shared.page_cache;
+    let is_page = path.extension().map(|ext| ext == "html").unwrap_or(false);
+    if is_page {
+        if let Some(page) = page_cache.get(&path) {
+            record_view(&state, &path);
+            return Ok(cached_page(page, &conditional, range.0).await);
+        }
+    }
+    let generation = page_cache.generation();
+
+    let book_dir = match state.shared.builds.pinned_dir() {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&config.path).join(&config.book_path),
+    };
+    let full_path = book_dir.join(&path);
+
+    // Opened while holding `book_swap`, so a new build being swapped in is never seen half-way
+    let opened = {
+        let _swap = state.shared.book_swap.read().unwrap();
+        let trailing_slash = uri.path().ends_with('/');
+        match canonical_path(
+            book_dir.as_ref(),
+            &path,
+            trailing_slash,
+            config.case_insensitive_paths,
+        ) {
+            Some(canonical) => Err(canonical),
+            None => Ok(std::fs::File::open(&full_path)),
+        }
+    };
+    let mut file = match opened {
+        Err(canonical) => {
+            let query = uri.query().map(|query| format!("?{}", query));
+            return Err(Redirect::permanent(format!(
+                "/{}{}",
+                url_path(&canonical),
+                query.unwrap_or_default()
+            )));
+        }
+        Ok(Ok(file)) => File::from_std(file),
+        Ok(Err(_)) => return Ok(None),
+    };
+    if is_page {
+        record_view(&state, &path);
+    }
+    let metadata = match file.metadata().await {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(None),
+    };
+    let validators = Validators::new(&path, &metadata, !config.allow_anonymous);
+
+    if is_page && page_cache.enabled() && metadata.len() <= PageCache::MAX_PAGE_SIZE {
+        let mut body = Vec::with_capacity(metadata.len() as usize);
+        if file.read_to_end(&mut body).await.map_err(log_warn).is_err() {
+            return Ok(None);
+        }
+        let page = CachedPage::new(body, validators);
+        page_cache.insert(generation, path, page.clone());
+        return Ok(cached_page(page, &conditional, range.0).await);
+    }
+
+    if validators.not_modified(&conditional) {
+        return Ok(Some(BookFile::Disk(Cached::NotModified(validators))));
+    }
+    let range = range.0.filter(|_| validators.range_allowed(&conditional));
+
+    let content_type = full_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(ContentType::from_extension);
+
+    Ok(RangedBody::new(file, metadata.len(), content_type, range)
+        .await
+        .map_err(log_warn)
+        .ok()
+        .map(|body| BookFile::Disk(Cached::Fresh(body, validators))))
 }
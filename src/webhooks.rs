@@ -0,0 +1,237 @@
+use crate::chat::ChatChannel;
+use crate::config::Config;
+use crate::session::now;
+
+use std::future::Future;
+use std::time::Duration;
+
+use rocket::tokio::task;
+use rocket::tokio::time::sleep;
+
+use ring::hmac;
+
+use serde::{Deserialize, Serialize};
+
+/// An url that gets a `PageChange` POSTed to it after every change to the wiki's pages.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Signs the payload with HMAC-SHA256, sent as `X-Mdwiki-Signature: t=<time>,sha256=<hex>`,
+    /// see `verify_signature`.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// How many times a failed delivery is retried, waiting twice as long before each retry.
+    #[serde(default = "Webhook::default_retries")]
+    pub retries: u32,
+}
+
+impl Webhook {
+    fn default_retries() -> u32 {
+        3
+    }
+}
+
+const CHAT_RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DiffSummary {
+    pub files_changed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PageChange {
+    /// `create`, `edit`, `move`, `replace` or `tag`.
+    pub action: &'static str,
+    pub path: String,
+    /// Where the page was moved from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    pub user: String,
+    /// The display name of `user`, for chat messages.
+    #[serde(skip)]
+    pub user_name: String,
+    pub commit: String,
+    pub diff: DiffSummary,
+}
+
+/// The header webhook deliveries are signed in.
+pub const SIGNATURE_HEADER: &str = "X-Mdwiki-Signature";
+
+/// How old a signed delivery may be, in seconds, before `verify_signature` rejects it as a replay.
+pub const SIGNATURE_TOLERANCE: u64 = 5 * 60;
+
+fn signing_key(secret: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+}
+
+/// What's signed for a delivery at `timestamp`, the timestamp and the payload joined by a `.`.
+fn signed_message(timestamp: u64, payload: &[u8]) -> Vec<u8> {
+    let mut message = format!("{}.", timestamp).into_bytes();
+    message.extend_from_slice(payload);
+    message
+}
+
+/// The hex encoded HMAC-SHA256 of a delivery of `payload` at `timestamp`, in seconds since the
+/// unix epoch.
+pub fn signature(secret: &str, timestamp: u64, payload: &[u8]) -> String {
+    hmac::sign(&signing_key(secret), &signed_message(timestamp, payload))
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The value of the signature header for a delivery of `payload` made now.
+fn signature_header(secret: &str, payload: &[u8]) -> String {
+    let timestamp = now();
+    let signature = signature(secret, timestamp, payload);
+    format!("t={},sha256={}", timestamp, signature)
+}
+
+/// Checks a webhook delivery: that `header`, the value of `X-Mdwiki-Signature`, is a signature
+/// of the raw request body `payload` with `secret`, made at most `tolerance` seconds ago, like
+/// `SIGNATURE_TOLERANCE`. Old deliveries are rejected so a captured request can't be replayed
+/// later, receivers that must never handle one twice should also remember the signatures they
+/// saw within the tolerance.
+///
+/// ```
+/// # fn handle(body: &[u8], header: Option<&str>) -> Result<(), String> {
+/// let header = header.ok_or("unsigned delivery")?;
+/// mdwiki::verify_signature("s3cret", header, body, mdwiki::SIGNATURE_TOLERANCE)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn verify_signature(
+    secret: &str,
+    header: &str,
+    payload: &[u8],
+    tolerance: u64,
+) -> Result<(), String> {
+    let mut timestamp = None;
+    let mut tag = None;
+    for part in header.split(',') {
+        let mut pair = part.trim().splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some("t"), Some(value)) => timestamp = value.parse::<u64>().ok(),
+            (Some("sha256"), Some(value)) => tag = hex_decode(value),
+            _ => {}
+        }
+    }
+    let (timestamp, tag) = match (timestamp, tag) {
+        (Some(timestamp), Some(tag)) => (timestamp, tag),
+        _ => return Err(format!("invalid signature header '{}'", header)),
+    };
+    let message = signed_message(timestamp, payload);
+    hmac::verify(&signing_key(secret), &message, &tag)
+        .map_err(|_| "the signature doesn't match the payload".to_string())?;
+    let now = now();
+    if now.saturating_sub(timestamp) > tolerance || timestamp.saturating_sub(now) > tolerance {
+        return Err(format!(
+            "the delivery was signed at {}, more than {}s from now",
+            timestamp, tolerance
+        ));
+    }
+    Ok(())
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Retries `send` up to `retries` times while it fails, waiting twice as long before each retry.
+async fn with_retries<F, Fut>(retries: u32, send: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let mut delay = Duration::from_secs(1);
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            sleep(delay).await;
+            delay *= 2;
+        }
+        match send().await {
+            Ok(()) => return,
+            Err(e) => warn!("{} (attempt {} of {})", e, attempt + 1, retries + 1),
+        }
+    }
+}
+
+/// Where page changes are sent, the webhooks and the chat channels.
+#[derive(Debug)]
+pub struct Webhooks {
+    hooks: Vec<Webhook>,
+    chats: Vec<ChatChannel>,
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(config: &Config) -> Webhooks {
+        Webhooks {
+            hooks: config.webhooks.clone(),
+            chats: config.chat_notifications.clone(),
+            base_url: config
+                .public_url
+                .as_deref()
+                .unwrap_or("")
+                .trim_end_matches('/')
+                .to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty() && self.chats.is_empty()
+    }
+    /// Sends `change` to every webhook and chat channel in the background, so slow receivers
+    /// don't hold up the wiki.
+    pub fn deliver(&self, change: &PageChange) {
+        for chat in &self.chats {
+            let (chat, client) = (chat.clone(), self.client.clone());
+            let (change, base_url) = (change.clone(), self.base_url.clone());
+            task::spawn(async move {
+                with_retries(CHAT_RETRIES, || chat.send(&client, &change, &base_url)).await
+            });
+        }
+
+        let payload = match serde_json::to_string(change) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("failed to serialize page change: {}", e);
+                return;
+            }
+        };
+        for hook in &self.hooks {
+            let (hook, client, payload) = (hook.clone(), self.client.clone(), payload.clone());
+            task::spawn(async move {
+                with_retries(hook.retries, || send(&client, &hook, &payload)).await
+            });
+        }
+    }
+}
+
+async fn send(client: &reqwest::Client, hook: &Webhook, payload: &str) -> Result<(), String> {
+    let mut request = client
+        .post(&hook.url)
+        .header("Content-Type", "application/json")
+        .body(payload.to_string());
+    // Every attempt is signed anew, so retries aren't rejected as replays
+    if let Some(secret) = &hook.secret {
+        let signature = signature_header(secret, payload.as_bytes());
+        request = request.header(SIGNATURE_HEADER, signature);
+    }
+    request
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map(|_| ())
+        .map_err(|e| format!("webhook '{}' failed: {}", hook.url, e))
+}
@@ -1,32 +1,83 @@
+use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::attribution;
+use crate::audit::Mutation;
+use crate::backup;
+use crate::bulk::{self, Plan};
 use crate::config::{Config, User, WikiTree, MDWIKI_USER};
+use crate::doctor::{self, BUILTIN_PREPROCESSORS};
+use crate::drafts;
+use crate::events::EventKind;
+use crate::faults::Step;
+use crate::footer::FooterPreprocessor;
+use crate::headings;
+use crate::lfs;
+use crate::lint;
+use crate::lifecycle::{page_status, superseded_by, LifecyclePreprocessor};
+use crate::maintenance;
+use crate::merge::{self, Conflict};
+use crate::migrations;
+use crate::mirrors;
+use crate::plugin::{PluginPreprocessor, Plugins};
+use crate::remote;
+use crate::render::{RenderPreprocessor, Renderer};
+use crate::robots;
+use crate::sandbox;
+use crate::sanitize::SanitizePreprocessor;
+use crate::shared::SharedState;
+use crate::sinks;
+use crate::sitemap;
+use crate::tags::{self, TagChange};
+use crate::todos;
 use crate::utils::*;
-use crate::webapp::WebappState;
+use crate::webapp::{page_url, WebappState};
+use crate::webhooks::{DiffSummary, PageChange, Webhooks};
 
 use async_std::fs;
-use async_std::path::Path;
+use async_std::path::{Path, PathBuf};
 
 use once_cell::sync::Lazy;
 
-use rocket::tokio::sync::{mpsc, oneshot};
+use rocket::http::Status;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::{mpsc, oneshot, RwLock};
+use rocket::tokio::task;
+use rocket::tokio::time::timeout;
 
 use mdbook::MDBook;
 
-use git2::{IndexAddOption, Repository, Signature};
+use git2::build::CheckoutBuilder;
+use git2::{
+    Delta, DiffFormat, DiffOptions, ErrorClass, ErrorCode, Index, IndexAddOption, IndexMatchedPath,
+    Oid, Repository, Signature, StatusOptions, Tree,
+};
 
 use regex::Regex;
 
+use serde::Serialize;
+
 const SUMMARY_HEAD: &str = include_str!("../files/summary_head.md");
 
 const THEME_OVERRIDE_SCRIPT: &str = include_str!("../files/theme_override_head.html.hbs");
+// mdwiki's part of the theme's head.hbs, which is replaced on startup
+pub const THEME_SCRIPT_START: &str = "{{!-- mdwiki start, replaced on startup --}}";
+pub const THEME_SCRIPT_END: &str = "{{!-- mdwiki end --}}";
 
 const MDWIKI_README: &str = include_str!("../files/default_README.md");
 const MDWIKI_BOOK_TOML: &str = include_str!("../files/default_book.toml");
-const MDWIKI_GITIGNORE: &str = include_str!("../files/default_gitignore");
+pub const MDWIKI_GITIGNORE: &str = include_str!("../files/default_gitignore");
+
+const SUMMARY_LINK_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\]\(([^()]*)\)$").unwrap());
 
-pub const IMAGE_LINK_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#"!\[[^\[\]]*\]\(/images/(\w+\.\w+)\)"#).unwrap());
+/// Images in markdown or `<img>` tags, linked with an absolute or a relative url.
+pub const IMAGE_LINK_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?:!\[[^\[\]]*\]\(|<img\s[^>]*src=")(?:\.\./)*/?images/([\w-]+\.\w+)[)"]"#)
+        .unwrap()
+});
 
 #[derive(Debug)]
 pub enum WikiResponse {
@@ -34,7 +85,10 @@ pub enum WikiResponse {
     BadRequest(Option<String>),
     NotAllowed(Option<String>),
     NotFound(Option<String>),
+    Conflict(Option<String>),
     Error(Option<String>),
+    /// Too many requests are waiting for the wiki task.
+    Busy(Option<String>),
 }
 
 impl WikiResponse {
@@ -57,7 +111,20 @@ impl WikiResponse {
             | WikiResponse::BadRequest(msg)
             | WikiResponse::NotAllowed(msg)
             | WikiResponse::NotFound(msg)
-            | WikiResponse::Error(msg) => msg.as_ref(),
+            | WikiResponse::Conflict(msg)
+            | WikiResponse::Error(msg)
+            | WikiResponse::Busy(msg) => msg.as_ref(),
+        }
+    }
+    pub fn status(&self) -> Status {
+        match self {
+            WikiResponse::OK(_) => Status::Ok,
+            WikiResponse::BadRequest(_) => Status::BadRequest,
+            WikiResponse::NotAllowed(_) => Status::Forbidden,
+            WikiResponse::NotFound(_) => Status::NotFound,
+            WikiResponse::Conflict(_) => Status::Conflict,
+            WikiResponse::Error(_) => Status::InternalServerError,
+            WikiResponse::Busy(_) => Status::ServiceUnavailable,
         }
     }
 }
@@ -73,25 +140,179 @@ pub enum WikiRequest {
         user: User,
         file: Box<Path>,
         content: String,
+        /// The revision of the page the edit started from, see `merge::revision`.
+        base: Option<String>,
         respond: oneshot::Sender<WikiResponse>,
     },
+    /// Creates or replaces `file`, responding with whether it was created.
+    PutFile {
+        user: User,
+        file: Box<Path>,
+        /// The uploaded markdown, which is removed afterwards.
+        upload: std::path::PathBuf,
+        respond: oneshot::Sender<(bool, WikiResponse)>,
+    },
+    Move {
+        user: User,
+        from: Box<Path>,
+        to: Box<Path>,
+        dry_run: bool,
+        respond: oneshot::Sender<Result<Plan, WikiResponse>>,
+    },
+    Attach {
+        user: User,
+        page: Box<Path>,
+        name: String,
+        /// The uploaded file, which is moved into the book.
+        upload: std::path::PathBuf,
+        respond: oneshot::Sender<Result<String, WikiResponse>>,
+    },
+    Replace {
+        user: User,
+        search: String,
+        replace: String,
+        regex: bool,
+        dry_run: bool,
+        respond: oneshot::Sender<Result<Plan, WikiResponse>>,
+    },
+    ChangeTags {
+        user: User,
+        change: TagChange,
+        dry_run: bool,
+        respond: oneshot::Sender<Result<Plan, WikiResponse>>,
+    },
+    Backup {
+        respond: oneshot::Sender<Result<String, WikiResponse>>,
+    },
+    Restore {
+        user: User,
+        /// The uploaded backup, which is removed afterwards.
+        upload: std::path::PathBuf,
+        respond: oneshot::Sender<Result<String, WikiResponse>>,
+    },
+    /// Saves a suggested change as `user`, who may change the page, see `Suggestion`.
+    AcceptSuggestion {
+        user: User,
+        id: String,
+        respond: oneshot::Sender<WikiResponse>,
+    },
+    /// Commits `content` to the drafts branch of `user`, see `drafts`.
+    SaveDraft {
+        user: User,
+        file: Box<Path>,
+        content: String,
+        /// The revision of the page the draft started from, see `merge::revision`.
+        base: Option<String>,
+        respond: oneshot::Sender<WikiResponse>,
+    },
+    /// Publishes every draft of `user`, responding with the pages.
+    PublishDrafts {
+        user: User,
+        respond: oneshot::Sender<Result<Vec<String>, WikiResponse>>,
+    },
+    DiscardDraft {
+        user: User,
+        file: Box<Path>,
+        respond: oneshot::Sender<WikiResponse>,
+    },
+    /// Brings back a page as it was before `commit` deleted it, see `deleted_pages`.
+    RestorePage {
+        user: User,
+        file: Box<Path>,
+        commit: String,
+        respond: oneshot::Sender<WikiResponse>,
+    },
+    /// The pages and directories of the book.
+    Tree {
+        respond: oneshot::Sender<Result<WikiTree, WikiResponse>>,
+    },
+    /// See `recent_changes`.
+    RecentChanges {
+        limit: usize,
+        respond: oneshot::Sender<Result<Vec<RecentChange>, WikiResponse>>,
+    },
+    /// See `deleted_pages`.
+    DeletedPages {
+        respond: oneshot::Sender<Result<Vec<DeletedPage>, WikiResponse>>,
+    },
+    /// Who last changed every line of `page`, see `blame`. Pages that aren't in the book are
+    /// `WikiResponse::NotFound`.
+    Blame {
+        page: String,
+        respond: oneshot::Sender<Result<Vec<BlameLine>, WikiResponse>>,
+    },
+    /// See `commit_diff`, with commits that aren't in the repository `WikiResponse::NotFound`.
+    Diff {
+        commit: String,
+        respond: oneshot::Sender<Result<CommitDiff, WikiResponse>>,
+    },
+}
+
+impl WikiRequest {
+    /// Whether the request only reads the book, like listing the pages, looking at the history
+    /// or previewing a move. Reads are handled side by side instead of waiting for each other.
+    pub fn is_read(&self) -> bool {
+        match self {
+            WikiRequest::Move { dry_run, .. }
+            | WikiRequest::Replace { dry_run, .. }
+            | WikiRequest::ChangeTags { dry_run, .. } => *dry_run,
+            WikiRequest::Backup { .. }
+            | WikiRequest::Tree { .. }
+            | WikiRequest::RecentChanges { .. }
+            | WikiRequest::DeletedPages { .. }
+            | WikiRequest::Blame { .. }
+            | WikiRequest::Diff { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 pub struct WikiState {
     config: Config,
-    rx: mpsc::Receiver<WikiRequest>,
+    shared: Arc<SharedState>,
+    /// Taken by `serve`.
+    rx: Option<mpsc::Receiver<WikiRequest>>,
+    /// Held for reading while a read is handled and for writing while anything else is, so
+    /// reads run side by side but never while the book is being changed. Taken in the order the
+    /// requests come in, so a read never overtakes a change sent before it.
+    book_lock: Arc<RwLock<()>>,
+    /// Set when `setup` skipped the initial build, see `warm_start`.
+    build_pending: AtomicBool,
+    /// Whether the last build failed, in which case changes are committed even if the book
+    /// still can't be built with them.
+    build_failing: AtomicBool,
+    /// The files changed by the request being handled, relative to the book. Its commit adds
+    /// these and nothing else.
+    staged: Mutex<Vec<String>>,
+    webhooks: Webhooks,
 }
 
 impl WikiState {
     pub fn new() -> (WikiState, WebappState) {
-        let (tx, rx) = mpsc::channel(100);
+        WikiState::with_plugins(Plugins::default())
+    }
+    pub fn with_plugins(plugins: Plugins) -> (WikiState, WebappState) {
+        WikiState::with_config(Config::figment().extract().unwrap(), plugins)
+    }
+    pub fn with_config(config: Config, plugins: Plugins) -> (WikiState, WebappState) {
+        let (tx, rx) = mpsc::channel(config.queue_capacity.max(1));
+
+        let plugins = plugins.with_scripts(&config.scripts);
+        let shared = Arc::new(SharedState::new(&config, plugins));
+        let webapp_state = WebappState::new(tx, &config, shared.clone());
 
         (
             WikiState {
-                config: Config::figment().extract().unwrap(),
-                rx,
+                webhooks: Webhooks::new(&config),
+                config,
+                shared,
+                rx: Some(rx),
+                book_lock: Arc::new(RwLock::new(())),
+                build_pending: AtomicBool::new(false),
+                build_failing: AtomicBool::new(false),
+                staged: Mutex::new(Vec::new()),
             },
-            WebappState::new(tx),
+            webapp_state,
         )
     }
     pub async fn setup(&self) -> Result<(), String> {
@@ -100,69 +321,1366 @@ impl WikiState {
             self.config.path
         );
 
+        if !self.book_writable().await {
+            return self.serve_read_only().await;
+        }
         self.init_book().await?;
-        let (book, _repo) = self.get_book()?;
+        self.repair().await?;
+        self.migrate()?;
+        self.add_theme_script().await?;
+        self.sync_preprocessors().await?;
+        if let Some(remote) = &self.config.remote {
+            info!("checking the credentials for the remote {}", remote.url);
+            remote::check(&self.config.path, remote)?;
+        }
+        for mirror in &self.config.mirrors {
+            remote::validate(mirror).map_err(|e| format!("invalid mirror: {}", e))?;
+        }
+
+        let book_dir = Path::new(&self.config.path).join(&self.config.book_path);
+        if self.config.warm_start && book_dir.is_dir().await {
+            info!("serving the previous build, rebuilding once the wiki has started");
+            self.build_pending.store(true, Ordering::SeqCst);
+            self.shared.set_ready();
+            return Ok(());
+        }
 
         info!("running initial build",);
-        book.build()
-            .map_err(|e| format!("failed to build book: {}", e))?;
+        self.build().await?;
+        self.shared.set_ready();
 
         Ok(())
     }
+    /// Whether files can be written to the book, checked by writing one. A book that doesn't
+    /// exist yet is created, so it counts as writable.
+    async fn book_writable(&self) -> bool {
+        let path = Path::new(&self.config.path);
+        let metadata = match fs::metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(_) => return true,
+        };
+        if metadata.permissions().readonly() {
+            warn!("the book at {} is read-only", self.config.path);
+            return false;
+        }
+        let probe = path.join(".mdwiki-write-check");
+        match fs::write(&probe, "").await {
+            Ok(_) => {
+                let _ = fs::remove_file(&probe).await.map_err(log_warn);
+                true
+            }
+            Err(e) => {
+                warn!("the book can't be written to: {}", e);
+                false
+            }
+        }
+    }
+    /// Serves the last build of a book that can't be written to, without changing anything in
+    /// it. Pages can't be changed, and the book isn't built again.
+    async fn serve_read_only(&self) -> Result<(), String> {
+        let book_dir = Path::new(&self.config.path).join(&self.config.book_path);
+        if !book_dir.is_dir().await {
+            return Err(format!(
+                "the book at {} is read-only and hasn't been built, build it with mdbook or \
+                 make it writable",
+                self.config.path
+            ));
+        }
+        info!("serving the book without changes, editing is disabled");
+        self.shared.set_book_read_only();
+        self.shared.set_ready();
+        Ok(())
+    }
+    /// Handles requests until every sender is dropped, so requests already queued when the
+    /// webapp shuts down are still committed and built. Reads, see `WikiRequest::is_read`, are
+    /// handled side by side, while changes are made one at a time.
     pub async fn serve(mut self) {
-        while let Some(req) = self.rx.recv().await {
-            match req {
-                WikiRequest::CreateFile {
-                    user,
-                    file,
-                    content,
-                    respond,
-                } => {
-                    if let Err(err) = self.create_file(&*file, &content).await {
-                        let _ = respond.send(err);
-                        continue;
+        let rx = self.rx.take().expect("the wiki is served once");
+        Arc::new(self).run(rx).await
+    }
+    async fn run(self: Arc<Self>, mut rx: mpsc::Receiver<WikiRequest>) {
+        task::spawn(sinks::forward(
+            self.config.event_sinks.clone(),
+            self.shared.events.subscribe(),
+        ));
+        if !self.shared.mirrors.is_empty() {
+            task::spawn(mirrors::run(self.config.path.clone(), self.shared.clone()));
+        }
+        if !self.shared.plugins.is_empty() {
+            let (shared, mut events) = (self.shared.clone(), self.shared.events.subscribe());
+            task::spawn(async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => shared.plugins.on_event(&event),
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
                     }
-                    let _ = self.move_new_images(&content).await;
-                    if let Err(err) = self
-                        .on_created(&user, &*file)
-                        .await
-                        .map_err(log_warn)
-                        .map_err(|_| WikiResponse::Error(None))
-                    {
-                        let _ = respond.send(err);
-                        continue;
+                }
+            });
+        }
+
+        if self.build_pending.swap(false, Ordering::SeqCst) {
+            info!("running initial build");
+            let _ = self.build().await;
+        }
+
+        // Maintenance rewrites the repository
+        let maintenance_interval = match self.shared.book_read_only() {
+            true => Duration::from_secs(0),
+            false => Duration::from_secs(self.config.maintenance_interval),
+        };
+        let backup_interval =
+            Duration::from_secs(self.config.backup.as_ref().map_or(0, |backup| backup.interval));
+        let schedule =
+            |interval: Duration| Some(Instant::now() + interval).filter(|_| interval.as_secs() > 0);
+        let mut next_maintenance = schedule(maintenance_interval);
+        let mut next_backup = schedule(backup_interval);
+        loop {
+            // Due before the next request, so they also run while requests keep coming in
+            let now = Instant::now();
+            if next_maintenance.map_or(false, |next| next <= now) {
+                let _writing = self.book_lock.write().await;
+                self.maintain().await;
+                next_maintenance = schedule(maintenance_interval);
+            }
+            if next_backup.map_or(false, |next| next <= now) {
+                let _reading = self.book_lock.read().await;
+                self.backup(None).await;
+                next_backup = schedule(backup_interval);
+            }
+            let req = match next_maintenance.into_iter().chain(next_backup).min() {
+                None => rx.recv().await,
+                Some(next) => {
+                    let wait = next.saturating_duration_since(Instant::now());
+                    match timeout(wait, rx.recv()).await {
+                        Ok(req) => req,
+                        Err(_) => continue,
                     }
-                    let _ = respond.send(WikiResponse::OK(None));
                 }
-                WikiRequest::EditFile {
-                    user,
-                    file,
-                    content,
-                    respond,
-                } => {
-                    if let Err(err) = self.edit_file(&*file, &content).await {
-                        let _ = respond.send(err);
-                        continue;
+            };
+            let req = match req {
+                Some(req) => req,
+                None => break,
+            };
+            self.shared.queue.received();
+            if req.is_read() {
+                let (wiki, reading) = (self.clone(), self.book_lock.clone().read_owned().await);
+                task::spawn(async move {
+                    wiki.handle(req).await;
+                    drop(reading);
+                });
+            } else {
+                let _writing = self.book_lock.write().await;
+                self.handle(req).await;
+            }
+        }
+        // Reads still running finish before the wiki stops
+        let _ = self.book_lock.write().await;
+        info!("wiki queue drained, stopping");
+    }
+    /// Handles `req`, with `book_lock` held by `run`.
+    async fn handle(&self, req: WikiRequest) {
+        let writing = !req.is_read();
+        if writing {
+            self.staged.lock().unwrap().clear();
+        }
+        self.handle_request(req).await;
+        // What's still staged wasn't committed, as the request failed, and mustn't go in with
+        // the next one
+        if writing {
+            self.staged.lock().unwrap().clear();
+        }
+    }
+    async fn handle_request(&self, req: WikiRequest) {
+        match req {
+            WikiRequest::CreateFile {
+                user,
+                file,
+                content,
+                respond,
+            } => {
+                let _ = respond.send(self.create(user, file, content).await);
+            }
+            WikiRequest::EditFile {
+                user,
+                file,
+                content,
+                base,
+                respond,
+            } => {
+                let _ = respond.send(self.edit(user, file, content, base).await);
+            }
+            WikiRequest::PutFile {
+                user,
+                file,
+                upload,
+                respond,
+            } => {
+                let content = fs::read_to_string(&upload).await;
+                let _ = fs::remove_file(&upload).await;
+                let exists = Path::new(&self.config.path)
+                    .join("src")
+                    .join(&file)
+                    .is_file()
+                    .await;
+                let res = match content {
+                    Ok(content) if exists => self.edit(user, file, content, None).await,
+                    Ok(content) => self.create(user, file, content).await,
+                    Err(e) => WikiResponse::BadRequest(Some(format!("Could not read page: {}", e))),
+                };
+                let _ = respond.send((!exists, res));
+            }
+            WikiRequest::Move {
+                user,
+                from,
+                to,
+                dry_run,
+                respond,
+            } => {
+                let res = self.move_path(&user, &from, &to, dry_run).await;
+                if !dry_run {
+                    let outcome = res.as_ref().map(|plan| plan.commit.clone());
+                    self.audit(&user, "move", Some(&*from), outcome);
+                }
+                let _ = respond.send(res);
+            }
+            WikiRequest::Attach {
+                user,
+                page,
+                name,
+                upload,
+                respond,
+            } => {
+                let res = self.attach_file(&user, &page, &name, &upload).await;
+                let _ = std::fs::remove_file(&upload);
+                let outcome = res.as_ref().map(|_| None);
+                self.audit(&user, "attach", Some(&*page), outcome);
+                let _ = respond.send(res);
+            }
+            WikiRequest::Replace {
+                user,
+                search,
+                replace,
+                regex,
+                dry_run,
+                respond,
+            } => {
+                let res = self.replace(&user, &search, &replace, regex, dry_run).await;
+                if !dry_run {
+                    let outcome = res.as_ref().map(|plan| plan.commit.clone());
+                    self.audit(&user, "replace", None, outcome);
+                }
+                let _ = respond.send(res);
+            }
+            WikiRequest::ChangeTags {
+                user,
+                change,
+                dry_run,
+                respond,
+            } => {
+                let res = self.change_tags(&user, &change, dry_run).await;
+                if !dry_run {
+                    let outcome = res.as_ref().map(|plan| plan.commit.clone());
+                    self.audit(&user, "tags", None, outcome);
+                }
+                let _ = respond.send(res);
+            }
+            WikiRequest::Backup { respond } => self.backup(Some(respond)).await,
+            WikiRequest::Restore {
+                user,
+                upload,
+                respond,
+            } => {
+                let res = self.restore(&user, &upload, "an upload").await;
+                let _ = std::fs::remove_file(&upload);
+                let outcome = res.as_ref().map(|commit| Some(commit.clone()));
+                self.audit(&user, "restore", None, outcome);
+                let _ = respond.send(res);
+            }
+            WikiRequest::RestorePage {
+                user,
+                file,
+                commit,
+                respond,
+            } => {
+                let res = self.restore_page(&user, &file, &commit).await;
+                let outcome = res.as_ref().map(Clone::clone);
+                self.audit(&user, "restore", Some(&*file), outcome);
+                if let Ok(Some(commit)) = &res {
+                    let path = url_path(&*file);
+                    self.page_changed("create", &user, path, None, commit);
+                    self.shared.events.publish(EventKind::PageCreated {
+                        path: url_path(&*file),
+                        user: user.username,
+                    });
+                }
+                let _ = respond.send(match res {
+                    Ok(_) => WikiResponse::OK(None),
+                    Err(err) => err,
+                });
+            }
+            WikiRequest::SaveDraft {
+                user,
+                file,
+                content,
+                base,
+                respond,
+            } => {
+                let res = self.save_draft(&user, &file, &content, base).await;
+                self.audit(&user, "draft", Some(&*file), res.as_ref().map(Clone::clone));
+                let _ = respond.send(match res {
+                    Ok(_) => WikiResponse::OK(None),
+                    Err(err) => err,
+                });
+            }
+            WikiRequest::PublishDrafts { user, respond } => {
+                let res = self.publish_drafts(&user).await;
+                let outcome = res.as_ref().map(|(_, commit)| Some(commit.clone()));
+                self.audit(&user, "publish drafts", None, outcome);
+                if let Ok((pages, commit)) = &res {
+                    self.notify_watchers(&user, pages, commit);
+                    for page in pages {
+                        self.page_changed("edit", &user, page.clone(), None, commit);
+                        self.shared.events.publish(EventKind::PageEdited {
+                            path: page.clone(),
+                            user: user.username.clone(),
+                        });
                     }
-                    let _ = self.move_new_images(&content).await;
-                    if let Err(err) = self
-                        .on_edited(&user, &*file)
-                        .await
-                        .map_err(log_warn)
-                        .map_err(|_| WikiResponse::Error(None))
-                    {
-                        let _ = respond.send(err);
-                        continue;
+                }
+                let _ = respond.send(res.map(|(pages, _)| pages));
+            }
+            WikiRequest::DiscardDraft {
+                user,
+                file,
+                respond,
+            } => {
+                let res = match self.discard_draft(&user, &file) {
+                    Ok(None) => Err(WikiResponse::NotFound(Some(
+                        "You have no draft of the page".into(),
+                    ))),
+                    res => res,
+                };
+                let outcome = res.as_ref().map(Clone::clone);
+                self.audit(&user, "discard draft", Some(&*file), outcome);
+                let _ = respond.send(match res {
+                    Ok(_) => WikiResponse::OK(None),
+                    Err(err) => err,
+                });
+            }
+            WikiRequest::AcceptSuggestion { user, id, respond } => {
+                let res = self.accept_suggestion(&user, &id).await;
+                let outcome = res.as_ref().map(|(_, commit)| commit.clone());
+                let file = res.as_ref().ok().map(|(file, _)| file.as_path());
+                self.audit(&user, "accept suggestion", file, outcome);
+                if let Ok((file, Some(commit))) = &res {
+                    self.notify_watchers(&user, &[url_path(&*file)], commit);
+                    let path = url_path(&*file);
+                    self.page_changed("edit", &user, path, None, commit);
+                }
+                if let Ok((file, _)) = &res {
+                    self.shared.events.publish(EventKind::PageEdited {
+                        path: url_path(&*file),
+                        user: user.username,
+                    });
+                }
+                let _ = respond.send(match res {
+                    Ok(_) => WikiResponse::OK(None),
+                    Err(err) => err,
+                });
+            }
+            WikiRequest::Tree { respond } => {
+                let _ = respond.send(Ok(self.config.get_wiki_tree().await));
+            }
+            WikiRequest::RecentChanges { limit, respond } => {
+                let res = self.read_history(move |path| recent_changes(path, limit));
+                let _ = respond.send(res.await);
+            }
+            WikiRequest::DeletedPages { respond } => {
+                let _ = respond.send(self.read_history(deleted_pages).await);
+            }
+            WikiRequest::Blame { page, respond } => {
+                let res = self.read_history(move |path| blame(path, &page)).await;
+                let res = res.and_then(|lines| lines.ok_or(WikiResponse::NotFound(None)));
+                let _ = respond.send(res);
+            }
+            WikiRequest::Diff { commit, respond } => {
+                let res = self
+                    .read_history(move |path| commit_diff(path, &commit))
+                    .await;
+                let res = res.and_then(|diff| diff.ok_or(WikiResponse::NotFound(None)));
+                let _ = respond.send(res);
+            }
+        }
+    }
+    /// Creates `file` as `user`, see `WikiRequest::CreateFile`.
+    async fn create(&self, user: User, file: Box<Path>, content: String) -> WikiResponse {
+        let res = self.create_page(&user, &file, &content).await;
+        let outcome = res.as_ref().map(Clone::clone);
+        self.audit(&user, "create", Some(&*file), outcome);
+        if let Ok(Some(commit)) = &res {
+            let path = url_path(&*file);
+            self.page_changed("create", &user, path, None, commit);
+        }
+        if res.is_ok() {
+            self.shared.events.publish(EventKind::PageCreated {
+                path: url_path(&*file),
+                user: user.username,
+            });
+        }
+        match res {
+            Ok(_) => WikiResponse::OK(None),
+            Err(err) => err,
+        }
+    }
+    /// Saves `content` to `file` as `user`, see `WikiRequest::EditFile`.
+    async fn edit(
+        &self,
+        user: User,
+        file: Box<Path>,
+        content: String,
+        base: Option<String>,
+    ) -> WikiResponse {
+        let message = format!("Edit {}", url_path(&*file));
+        let res = self.edit_page(&user, &file, content, base, message).await;
+        self.audit(&user, "edit", Some(&*file), res.as_ref().map(Clone::clone));
+        if let Ok(Some(commit)) = &res {
+            self.notify_watchers(&user, &[url_path(&*file)], commit);
+            let path = url_path(&*file);
+            self.page_changed("edit", &user, path, None, commit);
+            // the draft the edit started from, if any, is what was saved
+            let _ = self.discard_draft(&user, &file);
+        }
+        if res.is_ok() {
+            self.shared.events.publish(EventKind::PageEdited {
+                path: url_path(&*file),
+                user: user.username,
+            });
+        }
+        match res {
+            Ok(_) => WikiResponse::OK(None),
+            Err(err) => err,
+        }
+    }
+    /// Runs `read` on the repository in a blocking task, as walking the history can take a
+    /// while. Reads that fail are logged, and answered with `WikiResponse::Error`.
+    async fn read_history<T: Send + 'static>(
+        &self,
+        read: impl FnOnce(&str) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, WikiResponse> {
+        let path = self.config.path.clone();
+        let shared = self.shared.clone();
+        task::spawn_blocking(move || {
+            shared.faults.check(Step::Read)?;
+            read(&path)
+        })
+        .await
+        .unwrap_or_else(|e| Err(format!("history task failed: {}", e)))
+        .map_err(|e| {
+            warn!("could not read the history: {}", e);
+            WikiResponse::Error(None)
+        })
+    }
+    /// Repacks the repository and prunes old unreachable objects, between requests so nothing
+    /// is committed meanwhile.
+    async fn maintain(&self) {
+        let path = self.config.path.clone();
+        match task::spawn_blocking(move || maintenance::gc(&path)).await {
+            Ok(Ok((before, after))) => {
+                info!(
+                    "repository maintenance done, {} before, {} after",
+                    before, after
+                )
+            }
+            Ok(Err(e)) => warn!("repository maintenance failed: {}", e),
+            Err(e) => warn!("repository maintenance task failed: {}", e),
+        }
+    }
+    /// Archives the repository between requests, so nothing is half committed, and uploads it
+    /// in the background. `respond` gets the name of the backup.
+    async fn backup(&self, respond: Option<oneshot::Sender<Result<String, WikiResponse>>>) {
+        let backup = match &self.config.backup {
+            Some(backup) => backup.clone(),
+            None => {
+                if let Some(respond) = respond {
+                    let message = "Backups aren't configured".to_string();
+                    let _ = respond.send(Err(WikiResponse::BadRequest(Some(message))));
+                }
+                return;
+            }
+        };
+        let config = self.config.clone();
+        let archive = task::spawn_blocking(move || backup::archive(&config))
+            .await
+            .unwrap_or_else(|e| Err(format!("backup task failed: {}", e)));
+        task::spawn(async move {
+            let res = match archive {
+                Ok(archive) => backup.upload(&reqwest::Client::new(), archive).await,
+                Err(e) => Err(e),
+            };
+            let res = match res {
+                Ok(name) => {
+                    info!("uploaded backup '{}'", name);
+                    Ok(name)
+                }
+                Err(e) => {
+                    warn!("backup failed: {}", e);
+                    Err(WikiResponse::Error(Some(e)))
+                }
+            };
+            if let Some(respond) = respond {
+                let _ = respond.send(res);
+            }
+        });
+    }
+    /// Replaces the repository with the backup `archive`, see `backup::restore`, and commits
+    /// that it was restored from `source` before rebuilding the book. Returns the commit.
+    pub async fn restore(
+        &self,
+        user: &User,
+        archive: &std::path::Path,
+        source: &str,
+    ) -> Result<String, WikiResponse> {
+        self.check_writable()?;
+        let config = self.config.clone();
+        let archive = archive.to_path_buf();
+        let replaced = task::spawn_blocking(move || backup::restore(&config, &archive))
+            .await
+            .unwrap_or_else(|e| Err(format!("restore task failed: {}", e)))
+            .map_err(|e| WikiResponse::BadRequest(Some(format!("Could not restore: {}", e))))?;
+        info!(
+            "restored backup, the replaced files are in '{}'",
+            replaced.display()
+        );
+
+        let repo = self
+            .get_repo()
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        let message = format!("Restore from backup ({})", source);
+        let commit = self
+            .commit_all(&repo, user, message)
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        self.build().await.map_err(|_| {
+            WikiResponse::Error(Some(
+                "The backup was restored, but the book couldn't be built".into(),
+            ))
+        })?;
+        Ok(commit.to_string())
+    }
+    /// Adds the uploaded file to the files attached to `page`, replacing the one with the same
+    /// name if there is one, and returns its url.
+    async fn attach_file(
+        &self,
+        user: &User,
+        page: &Path,
+        name: &str,
+        upload: &std::path::Path,
+    ) -> Result<String, WikiResponse> {
+        self.check_writable()?;
+        self.config.can_edit(page).await.result()?;
+        self.config.may_change(user, page).result()?;
+        if !is_attachment_name(name) {
+            return Err(WikiResponse::BadRequest(Some(format!(
+                "'{}' can't be used as a file name",
+                name
+            ))));
+        }
+
+        let attachment = attachments_dir(page).join(name);
+        let path = Path::new(&self.config.path).join("src").join(&attachment);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(log_warn)
+                .map_err(|_| WikiResponse::Error(None))?;
+        }
+        // The upload directory can be on another filesystem
+        fs::copy(Path::new(upload), &path)
+            .await
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        self.stage(Path::new("src").join(&attachment));
+        let message = format!("Attach {} to {}", name, url_path(page));
+        self.commit_if_builds(user, message).await?;
+
+        Ok(format!("/{}", url_path(&attachment)))
+    }
+    async fn move_path(
+        &self,
+        user: &User,
+        from: &Path,
+        to: &Path,
+        dry_run: bool,
+    ) -> Result<Plan, WikiResponse> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+        self.config.can_move(from, to).await.result()?;
+        self.config.may_change(user, from).result()?;
+        self.config.may_change(user, to).result()?;
+
+        let src = Path::new(&self.config.path).join("src");
+        let mut plan = bulk::plan_move(src.as_ref(), from.as_ref(), to.as_ref())
+            .map_err(log_warn)
+            .map_err(|e| WikiResponse::Error(Some(e)))?;
+        plan.dry_run = dry_run;
+        if dry_run {
+            return Ok(plan);
+        }
+
+        self.create_parents(to).await?;
+        fs::rename(src.join(from), src.join(to))
+            .await
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        self.stage(Path::new("src").join(from));
+        self.stage(Path::new("src").join(to));
+        self.apply_plan(user, &plan).await?;
+
+        let commit = self
+            .on_bulk_change(user, format!("Move {} to {}", url_path(from), url_path(to)))
+            .await?;
+        plan.commit = Some(commit.to_string());
+        self.page_changed(
+            "move",
+            user,
+            url_path(to),
+            Some(url_path(from)),
+            &commit.to_string(),
+        );
+        self.shared.watches.moved(&url_path(from), &url_path(to));
+        self.shared.events.publish(EventKind::PageMoved {
+            from: url_path(from),
+            to: url_path(to),
+            user: user.username.clone(),
+        });
+
+        Ok(plan)
+    }
+    async fn replace(
+        &self,
+        user: &User,
+        search: &str,
+        replace: &str,
+        regex: bool,
+        dry_run: bool,
+    ) -> Result<Plan, WikiResponse> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+
+        let src = Path::new(&self.config.path).join("src");
+        let mut plan = bulk::plan_replace(src.as_ref(), search, replace, regex)
+            .map_err(|e| WikiResponse::BadRequest(Some(e)))?;
+        for change in &plan.changes {
+            self.config
+                .may_change(user, Path::new(&change.path))
+                .result()?;
+        }
+        plan.dry_run = dry_run;
+        if dry_run || plan.changes.is_empty() {
+            return Ok(plan);
+        }
+
+        self.apply_plan(user, &plan).await?;
+
+        let commit = self
+            .on_bulk_change(
+                user,
+                format!(
+                    "Replace '{}' with '{}' in {} pages",
+                    search,
+                    replace,
+                    plan.changes.len()
+                ),
+            )
+            .await?;
+        plan.commit = Some(commit.to_string());
+        let paths = plan
+            .changes
+            .iter()
+            .map(|change| change.path.clone())
+            .collect::<Vec<_>>();
+        self.notify_watchers(user, &paths, &commit.to_string());
+        for path in &paths {
+            self.page_changed("replace", user, path.clone(), None, &commit.to_string());
+        }
+        self.shared.events.publish(EventKind::PagesReplaced {
+            paths,
+            user: user.username.clone(),
+        });
+
+        Ok(plan)
+    }
+    async fn change_tags(
+        &self,
+        user: &User,
+        change: &TagChange,
+        dry_run: bool,
+    ) -> Result<Plan, WikiResponse> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+
+        let src = Path::new(&self.config.path).join("src");
+        let mut plan = tags::plan_tag_change(src.as_ref(), change)
+            .map_err(|e| WikiResponse::BadRequest(Some(e)))?;
+        for change in &plan.changes {
+            self.config
+                .may_change(user, Path::new(&change.path))
+                .result()?;
+        }
+        plan.dry_run = dry_run;
+        if dry_run || plan.changes.is_empty() {
+            return Ok(plan);
+        }
+
+        self.apply_plan(user, &plan).await?;
+
+        let commit = self
+            .on_bulk_change(user, change.message(plan.changes.len()))
+            .await?;
+        plan.commit = Some(commit.to_string());
+        let paths = plan
+            .changes
+            .iter()
+            .map(|change| change.path.clone())
+            .collect::<Vec<_>>();
+        self.notify_watchers(user, &paths, &commit.to_string());
+        for path in &paths {
+            self.page_changed("tag", user, path.clone(), None, &commit.to_string());
+        }
+        self.shared.events.publish(EventKind::PagesTagged {
+            paths,
+            user: user.username.clone(),
+        });
+
+        Ok(plan)
+    }
+    async fn apply_plan(&self, user: &User, plan: &Plan) -> Result<(), WikiResponse> {
+        for change in &plan.changes {
+            if let Some(content) = &change.content {
+                self.shared
+                    .plugins
+                    .on_save(user, change.path.as_ref(), content)
+                    .map_err(|e| WikiResponse::BadRequest(Some(e)))?;
+            }
+        }
+
+        let src = Path::new(&self.config.path).join("src");
+        for change in &plan.changes {
+            if let Some(content) = &change.content {
+                fs::write(src.join(&change.path), content)
+                    .await
+                    .map_err(log_warn)
+                    .map_err(|_| WikiResponse::Error(None))?;
+                self.stage(Path::new("src").join(&change.path));
+            }
+        }
+        Ok(())
+    }
+    /// Updates the summary after pages were moved or changed in bulk, and commits the changes if
+    /// the book still builds with them, see `commit_if_builds`.
+    async fn on_bulk_change(&self, user: &User, message: String) -> Result<Oid, WikiResponse> {
+        info!("updating summary");
+        let sections = match self.update_summary().await {
+            Ok(sections) => sections,
+            Err(e) => {
+                warn!("{}", e);
+                let _ = self.discard_changes().map_err(log_warn);
+                return Err(WikiResponse::Error(None));
+            }
+        };
+        let commit = self.commit_if_builds(user, message).await?;
+        self.sections_reorganized(user, sections);
+        Ok(commit)
+    }
+    /// Emails the watchers of the changed pages, except the user who changed them.
+    fn notify_watchers(&self, user: &User, pages: &[String], commit: &str) {
+        let mailer = match &self.shared.mailer {
+            Some(mailer) => mailer,
+            None => return,
+        };
+        let base_url = self
+            .config
+            .public_url
+            .as_deref()
+            .unwrap_or("")
+            .trim_end_matches('/');
+        for page in pages {
+            for (username, email) in self.shared.watches.watchers(page) {
+                if username == user.username {
+                    continue;
+                }
+                let subject = format!(
+                    "[{}] {} was changed by {}",
+                    self.config.branding.title,
+                    page,
+                    user.name()
+                );
+                let body = format!(
+                    "{} changed {}, which you are watching.\n\n\
+                    See the changes: {}/diff/{}\n\
+                    Open the page: {}/{}\n\n\
+                    You can stop watching the page from the page itself.\n",
+                    user.name(),
+                    page,
+                    base_url,
+                    commit,
+                    base_url,
+                    page_url(page)
+                );
+                let mailer = mailer.clone();
+                task::spawn(async move {
+                    match mailer.send(&email, &subject, body).await {
+                        Ok(()) => info!("notified '{}' of a change to a watched page", username),
+                        Err(e) => warn!("{}", e),
                     }
+                });
+            }
+        }
+    }
+    /// Sends the change of `path` to the webhooks. Moves change links in other pages too, so
+    /// their diff covers the whole commit.
+    fn page_changed(
+        &self,
+        action: &'static str,
+        user: &User,
+        path: String,
+        from: Option<String>,
+        commit: &str,
+    ) {
+        if self.webhooks.is_empty() {
+            return;
+        }
+        let diff = match from {
+            Some(_) => self.diff_summary(commit, None),
+            None => self.diff_summary(commit, Some(&path)),
+        };
+        self.webhooks.deliver(&PageChange {
+            action,
+            path,
+            from,
+            user: user.username.clone(),
+            user_name: user.name().to_string(),
+            commit: commit.to_string(),
+            diff,
+        });
+    }
+    /// How much `commit` changed, limited to the page `path` if given.
+    fn diff_summary(&self, commit: &str, path: Option<&str>) -> DiffSummary {
+        let summary = || -> Result<DiffSummary, git2::Error> {
+            let repo = Repository::open(&self.config.path)?;
+            let commit = repo.find_commit(Oid::from_str(commit)?)?;
+            let parent_tree = match commit.parents().next() {
+                Some(parent) => Some(parent.tree()?),
+                None => None,
+            };
+            let mut options = DiffOptions::new();
+            if let Some(path) = path {
+                options.pathspec(format!("src/{}", path));
+            }
+            let tree = commit.tree()?;
+            let stats = repo
+                .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(&mut options))?
+                .stats()?;
+            Ok(DiffSummary {
+                files_changed: stats.files_changed(),
+                insertions: stats.insertions(),
+                deletions: stats.deletions(),
+            })
+        };
+        summary().map_err(log_warn).unwrap_or_default()
+    }
+    fn sections_reorganized(&self, user: &User, sections: Vec<String>) {
+        if !sections.is_empty() {
+            self.shared.events.publish(EventKind::SectionsReorganized {
+                sections,
+                user: user.username.clone(),
+            });
+        }
+    }
+    fn audit(
+        &self,
+        user: &User,
+        action: &str,
+        path: Option<&Path>,
+        outcome: Result<Option<String>, &WikiResponse>,
+    ) {
+        Mutation::new(
+            &user.username,
+            action,
+            path.map(|path| url_path(path)),
+            outcome,
+        )
+        .log();
+    }
+    async fn create_page(
+        &self,
+        user: &User,
+        file: &Path,
+        content: &String,
+    ) -> Result<Option<String>, WikiResponse> {
+        self.config.may_change(user, file).result()?;
+        self.check_status(user, file, content).await?;
+        self.check_alt_text(content)?;
+        self.shared
+            .plugins
+            .on_save(user, file.as_ref(), content)
+            .map_err(|e| WikiResponse::BadRequest(Some(e)))?;
+        self.create_file(file, content).await?;
+        let _ = self.move_new_images(content).await;
+        let message = format!("Create {}", url_path(file));
+        let commit = self.on_created(user, file, message).await?;
+        Ok(Some(commit.to_string()))
+    }
+    /// Writes `file` back as it was before the commit `commit` deleted it, as `user`.
+    async fn restore_page(
+        &self,
+        user: &User,
+        file: &Path,
+        commit: &str,
+    ) -> Result<Option<String>, WikiResponse> {
+        self.config.may_change(user, file).result()?;
+        let page = url_path(file);
+        let content = deleted_content(&self.config.path, &page, commit)
+            .map_err(log_warn)
+            .map_err(|_| {
+                WikiResponse::NotFound(Some(format!("'{}' wasn't deleted by {}", page, commit)))
+            })?;
+        self.create_file(file, &content).await?;
+        let short = commit.chars().take(7).collect::<String>();
+        let message = format!("Restore {}, deleted in {}", page, short);
+        let commit = self.on_created(user, file, message).await?;
+        Ok(Some(commit.to_string()))
+    }
+    async fn edit_page(
+        &self,
+        user: &User,
+        file: &Path,
+        content: String,
+        base: Option<String>,
+        message: String,
+    ) -> Result<Option<String>, WikiResponse> {
+        self.config.may_change(user, file).result()?;
+        let content = match base {
+            Some(base) => self.merge_concurrent_edit(user, file, content, &base).await?,
+            None => content,
+        };
+        self.check_status(user, file, &content).await?;
+        self.check_alt_text(&content)?;
+        self.shared
+            .plugins
+            .on_save(user, file.as_ref(), &content)
+            .map_err(|e| WikiResponse::BadRequest(Some(e)))?;
+        self.edit_file(file, &content).await?;
+        let _ = self.move_new_images(&content).await;
+        let commit = self.on_edited(user, file, message).await?;
+        Ok(Some(commit.to_string()))
+    }
+    /// Saves the suggestion `id` as `user`, merged with the changes made to the page since it
+    /// was suggested, and removes it from the suggestions. Returns the page and the commit.
+    async fn accept_suggestion(
+        &self,
+        user: &User,
+        id: &str,
+    ) -> Result<(PathBuf, Option<String>), WikiResponse> {
+        let suggestion = self.shared.suggestions.get(id).ok_or_else(|| {
+            WikiResponse::NotFound(Some("The suggestion was already reviewed".into()))
+        })?;
+        let file = PathBuf::from(&suggestion.page);
+        let message = format!("Edit {}, suggested by {}", suggestion.page, suggestion.name);
+        let commit = self
+            .edit_page(
+                user,
+                &file,
+                suggestion.content,
+                Some(suggestion.base),
+                message,
+            )
+            .await?;
+        self.shared
+            .suggestions
+            .remove(id)
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        Ok((file, commit))
+    }
+    /// Commits `content` as the draft of `file` by `user`, who has to be allowed to change the
+    /// page. Returns the commit, on the drafts branch of the user.
+    async fn save_draft(
+        &self,
+        user: &User,
+        file: &Path,
+        content: &str,
+        base: Option<String>,
+    ) -> Result<Option<String>, WikiResponse> {
+        self.check_writable()?;
+        self.config.can_edit(file).await.result()?;
+        self.config.may_change(user, file).result()?;
+        let repo = self
+            .get_repo()
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        let page = url_path(file);
+        let email = self.config.commit_email(user);
+        let base = base.unwrap_or_default();
+        let commit = drafts::save(&repo, user, &email, &page, &base, content)
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(Some("Could not save the draft".into())))?;
+        Ok(Some(commit))
+    }
+    /// Discards the draft of `file` by `user`, returning the commit, or `None` if they have no
+    /// draft of the page.
+    fn discard_draft(&self, user: &User, file: &Path) -> Result<Option<String>, WikiResponse> {
+        self.check_writable()?;
+        let repo = self
+            .get_repo()
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        let page = url_path(file);
+        if drafts::get(&repo, &user.username, &page).is_none() {
+            return Ok(None);
+        }
+        drafts::discard(&repo, user, &self.config.commit_email(user), &page)
+            .map(Some)
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))
+    }
+    /// Publishes the drafts of `user` in one commit, each merged with the changes made to its
+    /// page since the draft started, and deletes their drafts branch. Nothing is published if
+    /// one of them can't be. Returns the pages and the commit.
+    async fn publish_drafts(&self, user: &User) -> Result<(Vec<String>, String), WikiResponse> {
+        self.check_writable()?;
+        let repo = self
+            .get_repo()
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        let drafts = drafts::list(&repo, &user.username)
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        if drafts.is_empty() {
+            return Err(WikiResponse::BadRequest(Some(
+                "You have no drafts to publish".into(),
+            )));
+        }
 
-                    let _ = respond.send(WikiResponse::OK(None));
+        let mut pages = Vec::new();
+        for draft in drafts {
+            if let Err(e) = self.write_draft(user, &draft).await {
+                let _ = self.discard_changes().map_err(log_warn);
+                return Err(e);
+            }
+            pages.push(draft.page);
+        }
+        let sections = self.update_summary().await.map_err(|e| {
+            warn!("{}", e);
+            let _ = self.discard_changes().map_err(log_warn);
+            WikiResponse::Error(None)
+        })?;
+        let message = format!("Publish drafts of {}", pages.join(", "));
+        let commit = self.commit_if_builds(user, message).await?;
+        self.sections_reorganized(user, sections);
+        let _ = drafts::remove(&repo, &user.username).map_err(log_warn);
+        Ok((pages, commit.to_string()))
+    }
+    /// Writes `draft` to its page, merged with the changes made to the page since it started,
+    /// checked like any other edit.
+    async fn write_draft(&self, user: &User, draft: &drafts::Draft) -> Result<(), WikiResponse> {
+        let file = PathBuf::from(&draft.page);
+        self.config.may_change(user, &file).result()?;
+        let content = self
+            .merge_concurrent_edit(user, &file, draft.content.clone(), &draft.base)
+            .await
+            .map_err(|e| match e {
+                WikiResponse::Conflict(_) => WikiResponse::Conflict(Some(format!(
+                    "Your draft of '{}' conflicts with changes made to the page since, open it \
+                     in the editor to merge them",
+                    draft.page
+                ))),
+                e => e,
+            })?;
+        self.check_status(user, &file, &content).await?;
+        self.check_alt_text(&content)?;
+        self.shared
+            .plugins
+            .on_save(user, file.as_ref(), &content)
+            .map_err(|e| WikiResponse::BadRequest(Some(e)))?;
+        self.create_parents(&file).await?;
+        let path = Path::new(&self.config.path).join("src").join(&file);
+        fs::write(path, &content)
+            .await
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        self.stage(Path::new("src").join(&file));
+        let _ = self.move_new_images(&content).await;
+        Ok(())
+    }
+    /// Checks that the status in the front matter of `content` is known, that `user` may change
+    /// the page at `file` to it, and that the page it is superseded by exists.
+    async fn check_status(
+        &self,
+        user: &User,
+        file: &Path,
+        content: &str,
+    ) -> Result<(), WikiResponse> {
+        let default = self.config.lifecycle.default;
+        let to = page_status(content)
+            .map_err(|e| WikiResponse::BadRequest(Some(e)))?
+            .unwrap_or(default);
+        if let Some(successor) = superseded_by(content) {
+            let src = Path::new(&self.config.path).join("src");
+            if !src.join(successor).is_file().await {
+                return Err(WikiResponse::BadRequest(Some(format!(
+                    "The page '{}' it is superseded by doesn't exist",
+                    successor
+                ))));
+            }
+        }
+        let path = Path::new(&self.config.path).join("src").join(file);
+        let from = match fs::read_to_string(&path).await {
+            Ok(current) => page_status(&current).ok().flatten().unwrap_or(default),
+            Err(_) => default,
+        };
+        self.config.may_transition(user, from, to).result()?;
+        Ok(())
+    }
+    /// Checks that every image in `content` has alt text, and not just the placeholder of
+    /// `image_snippet`, if `require_alt_text` is set.
+    fn check_alt_text(&self, content: &str) -> Result<(), WikiResponse> {
+        if !self.config.require_alt_text {
+            return Ok(());
+        }
+        let placeholder = self.config.image_snippet.alt.trim();
+        let missing = lint::images(content).into_iter().find(|image| {
+            let alt = image.alt.trim();
+            alt.is_empty() || alt == placeholder
+        });
+        match missing {
+            Some(image) => Err(WikiResponse::BadRequest(Some(format!(
+                "The image '{}' on line {} needs alt text describing it",
+                image.src, image.line
+            )))),
+            None => Ok(()),
+        }
+    }
+    /// If the page changed since the edit started, the changes are merged. When they conflict,
+    /// the configured merge driver gets a try, and if there's none or it fails the edit is kept
+    /// in the review area and the page is left as is.
+    async fn merge_concurrent_edit(
+        &self,
+        user: &User,
+        file: &Path,
+        content: String,
+        base: &str,
+    ) -> Result<String, WikiResponse> {
+        let path = Path::new(&self.config.path).join("src").join(&file);
+        let current = match fs::read_to_string(&path).await {
+            Ok(current) => current,
+            // Let edit_file report missing files
+            Err(_) => return Ok(content),
+        };
+        if merge::revision(&current) == base {
+            return Ok(content);
+        }
+        info!("{} changed while it was edited, merging", file.to_string_lossy());
+
+        let repo = Repository::open(&self.config.path)
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        let base = merge::content_at(&repo, base);
+        if let Some(base) = &base {
+            if let Some(merged) = merge::merge(&repo, base, &current, &content)
+                .map_err(log_warn)
+                .map_err(|_| WikiResponse::Error(None))?
+            {
+                return Ok(merged);
+            }
+        }
+
+        let conflict = Conflict {
+            file: file.as_ref(),
+            username: &user.username,
+            base: base.as_deref().unwrap_or_default(),
+            current: &current,
+            edit: &content,
+        };
+        if let Some(driver) = &self.config.merge_driver {
+            let work_dir = self.config.data_path().join("merge");
+            if let Some(merged) = conflict
+                .run_driver(driver, &work_dir)
+                .map_err(log_warn)
+                .unwrap_or(None)
+            {
+                return Ok(merged);
+            }
+        }
+
+        let review_dir = conflict
+            .save_for_review(&self.config.data_path().join("conflicts"))
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        warn!(
+            "conflicting edit of {} by '{}' saved to {}",
+            file.to_string_lossy(),
+            user.username,
+            review_dir.display()
+        );
+        Err(WikiResponse::Conflict(Some(
+            "The page was changed by someone else while you were editing it, and the changes \
+             could not be merged. Your version has been saved for review."
+                .into(),
+        )))
+    }
+    /// Builds the book and swaps it into place.
+    async fn build(&self) -> Result<(), String> {
+        if self.shared.book_read_only() {
+            return Err("the book is read-only, it can't be built".into());
+        }
+        let res = self
+            .build_staged()
+            .await
+            .and_then(|staging| {
+                self.add_attribution(&staging);
+                self.swap_build(&staging)
+            })
+            .map_err(log_warn);
+        self.finish_build(res)
+    }
+    /// Builds the book into a new staging directory on a blocking thread, returning the
+    /// directory. A build that takes longer than `build_timeout` is reported as failed, and its
+    /// output is never swapped in. The thread can't be stopped, so it finishes in the
+    /// background, but external preprocessors can be killed with `preprocessor_sandbox`.
+    async fn build_staged(&self) -> Result<std::path::PathBuf, String> {
+        let staging = self
+            .config
+            .data_path()
+            .join("staging")
+            .join(rand_safe_string(8));
+        let job = {
+            let (config, plugins) = (self.config.clone(), self.shared.plugins.clone());
+            let (shared, staging) = (self.shared.clone(), staging.clone());
+            task::spawn_blocking(move || {
+                shared.faults.check(Step::Build)?;
+                load_book(&config, plugins, staging)?
+                    .build()
+                    .map_err(|e| format!("failed to build book: {:#}", e))?;
+                let issues = lint::lint(&config.path)
+                    .into_iter()
+                    .filter(|issue| issue.accessibility)
+                    .count();
+                if issues > 0 {
+                    warn!("{} accessibility issues in the pages, see /admin/lint", issues);
                 }
+                Ok(())
+            })
+        };
+        let res = match self.config.build_timeout {
+            0 => job.await,
+            secs => match timeout(Duration::from_secs(secs), job).await {
+                Ok(res) => res,
+                Err(_) => Ok(Err(format!("build cancelled after {} seconds", secs))),
+            },
+        };
+        res.unwrap_or_else(|e| Err(format!("build task failed: {}", e)))?;
+        headings::add_heading_ids(&staging)?;
+        let _ = self.write_sitemap(&staging).await.map_err(log_warn);
+        if !self.config.crawlers_allowed() {
+            robots::add_noindex(&staging)?;
+        }
+        Ok(staging)
+    }
+    /// Adds who last edited every page to the build in `dir`, once the changes it was built
+    /// with are committed. Pages are still served without it if the history can't be read.
+    fn add_attribution(&self, dir: &std::path::Path) {
+        if self.config.attribution {
+            let _ = attribution::add_attribution(&self.config.path, dir).map_err(log_warn);
+        }
+    }
+    /// Adds `sitemap.xml` to the build in `dir`, if search engines may index the wiki.
+    async fn write_sitemap(&self, dir: &std::path::Path) -> Result<(), String> {
+        let base_url = match &self.config.public_url {
+            Some(base_url) if self.config.crawlers_allowed() => base_url,
+            _ => return Ok(()),
+        };
+        let tree = self.config.get_wiki_tree().await;
+        let src = std::path::Path::new(&self.config.path).join("src");
+        let sitemap = sitemap::sitemap(&tree, base_url, &self.config.sitemap_exclude, &src);
+        std::fs::write(dir.join("sitemap.xml"), sitemap)
+            .map_err(|e| format!("could not write sitemap: {}", e))
+    }
+    /// Keeps a copy of a successful build and collects the action items in its pages, and lets
+    /// the cache, plugins and event log know about the build.
+    fn finish_build(&self, res: Result<(), String>) -> Result<(), String> {
+        self.build_failing.store(res.is_err(), Ordering::SeqCst);
+        if res.is_ok() {
+            let book_dir = std::path::Path::new(&self.config.path).join(&self.config.book_path);
+            let _ = self.shared.builds.retain(&book_dir).map_err(log_warn);
+            let src = std::path::Path::new(&self.config.path).join("src");
+            self.shared.todos.set(todos::scan(&src));
+        }
+        self.shared.page_cache.clear();
+        self.shared.plugins.on_build(res.is_ok());
+        self.shared.events.publish(EventKind::BuildFinished {
+            success: res.is_ok(),
+        });
+        res
+    }
+    /// Moves a finished build from the staging directory into place. The webapp opens files while
+    /// holding `book_swap`, so readers see either the old or the new build, and a failed build
+    /// leaves the old one in place.
+    fn swap_build(&self, staging: &std::path::Path) -> Result<(), String> {
+        use std::fs;
+
+        let book_dir = std::path::Path::new(&self.config.path).join(&self.config.book_path);
+        let previous = self.config.data_path().join("previous");
+
+        let _ = fs::remove_dir_all(&previous);
+        {
+            let _swap = self.shared.book_swap.write().unwrap();
+            if book_dir.exists() {
+                fs::rename(&book_dir, &previous)
+                    .map_err(|e| format!("could not move the old build away: {}", e))?;
+            }
+            if let Err(e) = fs::rename(&staging, &book_dir) {
+                let _ = fs::rename(&previous, &book_dir);
+                return Err(format!("could not move the new build into place: {}", e));
             }
         }
+        let _ = fs::remove_dir_all(&previous);
+        // Also removes what failed or cancelled builds left behind
+        let _ = fs::remove_dir_all(self.config.data_path().join("staging"));
+        Ok(())
+    }
+    fn check_writable(&self) -> Result<(), WikiResponse> {
+        if let Some(message) = self.shared.read_only_message() {
+            return Err(WikiResponse::NotAllowed(Some(message.into())));
+        }
+        Ok(())
     }
     async fn create_file(&self, file: &Path, content: &String) -> Result<(), WikiResponse> {
+        self.check_writable()?;
         self.config.can_create(file).await.result()?;
 
+        self.create_parents(file).await?;
+
+        let path = Path::new(&self.config.path).join("src").join(&file);
+        self.shared
+            .faults
+            .check(Step::Write)
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        fs::write(path, content)
+            .await
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        self.stage(Path::new("src").join(file));
+
+        Ok(())
+    }
+    /// Creates the parent directories of a file, with an index page for each of them.
+    async fn create_parents(&self, file: &Path) -> Result<(), WikiResponse> {
         let path = Path::new(&self.config.path).join("src").join(&file);
 
         if let Some(parent) = path.parent() {
@@ -196,59 +1714,193 @@ impl WikiState {
                 .await
                 .map_err(log_warn)
                 .map_err(|_| WikiResponse::Error(None))?;
+                self.stage(Path::new("src").join(dir).join("README.md"));
             }
         }
 
-        fs::write(path, content)
-            .await
-            .map_err(log_warn)
-            .map_err(|_| WikiResponse::Error(None))?;
-
         Ok(())
     }
-    async fn on_created(&self, user: &User, file: &Path) -> Result<(), String> {
+    async fn on_created(
+        &self,
+        user: &User,
+        file: &Path,
+        message: String,
+    ) -> Result<Oid, WikiResponse> {
         info!("running post-create hooks for {}", file.to_string_lossy());
 
         info!("updating summary");
-        self.update_summary().await.map_err(log_warn)?;
-
-        let (book, repo) = self.get_book().map_err(log_warn)?;
-
-        info!("committing {}", file.to_string_lossy());
-        self.commit(&repo, user, format!("Create {}", file.to_string_lossy()))
-            .map_err(log_warn)?;
-
-        info!("rebuilding book");
-        book.build()
+        let sections = self
+            .update_summary()
+            .await
             .map_err(log_warn)
-            .map_err(|e| format!("failed to build book: {}", e))?;
+            .map_err(|_| WikiResponse::Error(None))?;
 
-        Ok(())
+        let commit = self.commit_if_builds(user, message).await?;
+        self.sections_reorganized(user, sections);
+
+        Ok(commit)
     }
     async fn edit_file(&self, file: &Path, content: &String) -> Result<(), WikiResponse> {
+        self.check_writable()?;
         self.config.can_edit(&file).await.result()?;
 
         let path = Path::new(&self.config.path).join("src").join(&file);
+        self.shared
+            .faults
+            .check(Step::Write)
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
         fs::write(path, content)
             .await
             .map_err(log_warn)
             .map_err(|_| WikiResponse::Error(None))?;
+        self.stage(Path::new("src").join(file));
 
         Ok(())
     }
-    async fn on_edited(&self, user: &User, file: &Path) -> Result<(), String> {
+    async fn on_edited(
+        &self,
+        user: &User,
+        file: &Path,
+        message: String,
+    ) -> Result<Oid, WikiResponse> {
         info!("running post-edit hooks for {}", file.to_string_lossy());
-        let (book, repo) = self.get_book().map_err(log_warn)?;
-
-        info!("committing changes to {}", file.to_string_lossy());
-        self.commit(&repo, user, format!("Edit {}", file.to_string_lossy()))
-            .map_err(log_warn)?;
+        if !file.ends_with(SUMMARY_FRAGMENT) {
+            return self.commit_if_builds(user, message).await;
+        }
+        info!("updating summary");
+        let sections = self
+            .update_summary()
+            .await
+            .map_err(log_warn)
+            .map_err(|_| WikiResponse::Error(None))?;
+        let commit = self.commit_if_builds(user, message).await?;
+        self.sections_reorganized(user, sections);
+        Ok(commit)
+    }
+    /// Builds the book with the uncommitted changes before committing them, and swaps the build
+    /// in once they are committed. Changes that break the build are discarded, and rejected with
+    /// mdbook's error. If the book already failed to build, the changes are committed anyway, as
+    /// they might be what fixes it. Changes that can't be committed are discarded too.
+    async fn commit_if_builds(&self, user: &User, message: String) -> Result<Oid, WikiResponse> {
+        info!("building book with the changes");
+        let staged = self.build_staged().await;
+        if let Err(e) = &staged {
+            if !self.build_failing.load(Ordering::SeqCst) {
+                warn!("rejecting changes that break the build: {}", e);
+                let _ = std::fs::remove_dir_all(self.config.data_path().join("staging"));
+                self.discard_changes()
+                    .map_err(log_warn)
+                    .map_err(|_| WikiResponse::Error(None))?;
+                return Err(WikiResponse::BadRequest(Some(format!(
+                    "The change was not saved, the book could not be built with it: {}",
+                    e
+                ))));
+            }
+        }
 
-        info!("rebuilding book");
-        book.build()
+        let repo = self
+            .get_repo()
             .map_err(log_warn)
-            .map_err(|e| format!("failed to build book: {}", e))?;
+            .map_err(|_| WikiResponse::Error(None))?;
+        info!("committing: {}", message);
+        let commit = match self.commit(&repo, user, message) {
+            Ok(commit) => commit,
+            Err(e) => {
+                // The change was rejected, so it mustn't go in with the next one
+                warn!("{}", e);
+                let _ = std::fs::remove_dir_all(self.config.data_path().join("staging"));
+                let _ = self.discard_changes().map_err(log_warn);
+                return Err(WikiResponse::Error(None));
+            }
+        };
 
+        let res = staged
+            .and_then(|staging| {
+                self.add_attribution(&staging);
+                self.swap_build(&staging)
+            })
+            .map_err(log_warn);
+        self.finish_build(res)
+            .map_err(|_| WikiResponse::Error(None))?;
+
+        Ok(commit)
+    }
+    /// Reverts the changes made by the request being handled, see `stage`. New images go back
+    /// to the upload directory, so they can be used when the page is saved again. Other changes
+    /// to the book, like pages edited by hand, are left as they are.
+    fn discard_changes(&self) -> Result<(), String> {
+        let paths = std::mem::take(&mut *self.staged.lock().unwrap());
+        // An empty pathspec would match everything
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let repo = self.get_repo()?;
+        let root = std::path::Path::new(&self.config.path);
+        let src = root.join("src");
+
+        let mut options = StatusOptions::new();
+        options
+            .include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .disable_pathspec_match(true);
+        for path in &paths {
+            options.pathspec(path);
+        }
+        let statuses = repo
+            .statuses(Some(&mut options))
+            .map_err(|e| format!("failed to get repository status: {}", e))?;
+        for entry in statuses.iter().filter(|entry| entry.status().is_wt_new()) {
+            let path = match entry.path() {
+                Some(path) => root.join(path),
+                None => continue,
+            };
+            let res = match entry.path().and_then(|path| path.strip_prefix("src/images/")) {
+                Some(image) => std::fs::rename(&path, self.config.upload_path().join(image)),
+                None => std::fs::remove_file(&path),
+            };
+            let _ = res.map_err(log_warn);
+            // Directories created for the page, if they're empty now
+            for dir in path.ancestors().skip(1) {
+                if dir == src || dir == root || std::fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force().disable_pathspec_match(true);
+        for path in &paths {
+            checkout.path(path);
+        }
+        repo.checkout_head(Some(&mut checkout))
+            .map_err(|e| format!("failed to discard changes: {}", e))
+    }
+    /// Adds the `.gitattributes` lines that store images and attachments with Git LFS, for
+    /// the git-lfs command line tool and remotes.
+    async fn track_with_lfs(&self, repo: &Repository) -> Result<(), String> {
+        let path = Path::new(&self.config.path).join(".gitattributes");
+        let mut attributes = fs::read_to_string(&path).await.unwrap_or_default();
+        let missing: Vec<_> = lfs::gitattributes()
+            .into_iter()
+            .filter(|line| !attributes.lines().any(|existing| existing.trim() == line))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        info!("tracking images and attachments with git lfs");
+        if !attributes.is_empty() && !attributes.ends_with('\n') {
+            attributes.push('\n');
+        }
+        for line in missing {
+            attributes.push_str(&line);
+            attributes.push('\n');
+        }
+        fs::write(&path, attributes)
+            .await
+            .map_err(|e| format!("could not write .gitattributes: {}", e))?;
+        self.stage(".gitattributes");
+        self.commit(repo, &MDWIKI_USER, "Track images and attachments with Git LFS".into())?;
         Ok(())
     }
     async fn init_book(&self) -> Result<(), String> {
@@ -266,6 +1918,12 @@ impl WikiState {
                     .map_err(|e| format!("failed to init repo at '{}': {}", self.config.path, e))?
             }
         };
+        if self.config.external_book && MDBook::load(&self.config.path).is_err() {
+            return Err(format!(
+                "external_book is set, but there is no book at {}",
+                self.config.path
+            ));
+        }
         if MDBook::load(&self.config.path).is_err() {
             info!(
                 "could not find existing mdbook, creating new at {}",
@@ -294,7 +1952,12 @@ impl WikiState {
                     )
                 })?;
             }
-            fs::write(book_path.join("book.toml"), MDWIKI_BOOK_TOML)
+            // A json string is also a valid toml string
+            let title = serde_json::to_string(&self.config.branding.title)
+                .map_err(|e| format!("invalid title: {}", e))?;
+            let book_toml =
+                MDWIKI_BOOK_TOML.replace(r#"title = "mdwiki""#, &format!("title = {}", title));
+            fs::write(book_path.join("book.toml"), book_toml)
                 .await
                 .map_err(|e| format!("could not write book.toml: {}", e))?;
             fs::write(book_path.join(".gitignore"), MDWIKI_GITIGNORE)
@@ -303,129 +1966,462 @@ impl WikiState {
             fs::write(book_src_path.join("README.md"), MDWIKI_README)
                 .await
                 .map_err(|e| format!("could not write index file: {}", e))?;
+            let theme_path = book_path.join("theme");
+            fs::create_dir_all(&theme_path)
+                .await
+                .map_err(|e| format!("could not create theme directory: {}", e))?;
+            let head_path = theme_path.join("head.hbs");
+            let head = fs::read_to_string(&head_path).await.unwrap_or_default();
+            if let Some(head) = with_theme_script(&head) {
+                fs::write(&head_path, head)
+                    .await
+                    .map_err(|e| format!("could not write theme script: {}", e))?;
+            }
+            let root = std::path::Path::new(&self.config.path);
+            migrations::set_layout(root, migrations::latest())?;
 
             self.update_summary().await?;
 
-            self.commit(&repo, &MDWIKI_USER, "Initial mdwiki commit".into())?;
+            self.commit_all(&repo, &MDWIKI_USER, "Initial mdwiki commit".into())?;
         };
-        let theme_dir = book_path.join("theme");
-        let theme_path = theme_dir.join("head.hbs");
-        if !theme_path.is_file().await {
-            debug!("adding mdwiki theme script");
-            if !theme_dir.is_dir().await {
-                fs::create_dir(&theme_dir)
+        if self.config.lfs {
+            self.track_with_lfs(&repo).await?;
+        }
+        let home = Path::new(self.config.home_page());
+        if !path_is_simple(home)
+            || home.extension().map_or(true, |ext| ext != "md")
+            || is_reserved_name(home)
+        {
+            return Err(format!(
+                "home_page '{}' must be a markdown file in the book",
+                home.display()
+            ));
+        }
+        let home_path = book_src_path.join(home);
+        // it wouldn't be in the summary
+        if !home_path.is_file().await && self.config.external_book {
+            return Err(format!("home_page '{}' is not in the book", home.display()));
+        }
+        if !home_path.is_file().await {
+            info!("creating home page {}", home.display());
+            if let Some(parent) = home_path.parent() {
+                fs::create_dir_all(parent)
                     .await
-                    .map_err(|_| "failed to create theme dir")?;
+                    .map_err(|e| format!("could not create directory for home page: {}", e))?;
             }
-
-            fs::write(&theme_path, THEME_OVERRIDE_SCRIPT)
+            let title = home.file_stem().unwrap().to_string_lossy().replace("_", " ");
+            fs::write(&home_path, format!("# {}\n", title))
                 .await
-                .map_err(|e| format!("failed to write theme script: {}", e))?;
+                .map_err(|e| format!("could not write home page: {}", e))?;
+            self.stage(Path::new("src").join(home));
+            self.update_summary().await?;
+            self.commit(&repo, &MDWIKI_USER, "Add home page".into())?;
         }
-        let tmp_upload_path = Path::new(&self.config.tmp_upload_path);
-        if !tmp_upload_path.is_dir().await {
-            fs::create_dir(&tmp_upload_path).await.map_err(|e| {
+        let upload_path = self.config.upload_path();
+        if !upload_path.is_dir() {
+            fs::create_dir_all(&upload_path).await.map_err(|e| {
                 format!(
                     "could not create directory '{}': {}",
-                    self.config.tmp_upload_path, e
+                    upload_path.display(),
+                    e
                 )
             })?;
         }
 
         Ok(())
     }
-    fn get_book(&self) -> Result<(MDBook, Repository), String> {
-        let repo = match Repository::open(&self.config.path) {
-            Ok(repo) => {
-                info!("using existing git repository");
-                repo
+    /// Repairs state left behind by crashes or manual edits: uncommitted changes are committed,
+    /// and the summary is regenerated if it's out of sync with the files.
+    /// Merges `preprocessors` from the configuration into book.toml, committing it if that
+    /// changed it. Fails if an external preprocessor isn't installed, as every build would.
+    async fn sync_preprocessors(&self) -> Result<(), String> {
+        if self.config.preprocessors.is_empty() {
+            return Ok(());
+        }
+        if self.config.external_book {
+            return Err(
+                "preprocessors can't be set with external_book, add them to book.toml".into(),
+            );
+        }
+        let in_container = self
+            .config
+            .preprocessor_sandbox
+            .as_ref()
+            .map_or(false, |sandbox| sandbox.container.is_some());
+        for (name, settings) in &self.config.preprocessors {
+            let executable = doctor::preprocessor_executable(name, settings);
+            if !BUILTIN_PREPROCESSORS.contains(&name.as_str())
+                && !in_container
+                && doctor::find_in_path(&executable).is_none()
+            {
+                return Err(format!(
+                    "preprocessor '{}' needs '{}', which is not installed",
+                    name, executable
+                ));
             }
-            Err(_) => {
-                return Err(format!("could not find git repo at {}", self.config.path));
+        }
+
+        let book_toml_path = Path::new(&self.config.path).join("book.toml");
+        let content = fs::read_to_string(&book_toml_path)
+            .await
+            .map_err(|e| format!("could not read book.toml: {}", e))?;
+        let mut book_toml = content
+            .parse::<toml::Value>()
+            .map_err(|e| format!("could not parse book.toml: {}", e))?;
+        let preprocessors = book_toml
+            .as_table_mut()
+            .ok_or("book.toml is not a table")?
+            .entry("preprocessor")
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .ok_or("preprocessor in book.toml is not a table")?;
+        let mut changed = false;
+        for (name, settings) in &self.config.preprocessors {
+            changed |= !preprocessors.contains_key(name);
+            let table = preprocessors
+                .entry(name.clone())
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or(format!("preprocessor.{} in book.toml is not a table", name))?;
+            for (key, value) in settings {
+                if table.get(key) != Some(value) {
+                    table.insert(key.clone(), value.clone());
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+
+        info!("updating the preprocessors in book.toml");
+        let content = toml::to_string(&book_toml)
+            .map_err(|e| format!("could not serialize book.toml: {}", e))?;
+        fs::write(&book_toml_path, content)
+            .await
+            .map_err(|e| format!("could not write book.toml: {}", e))?;
+        let repo = self.get_repo()?;
+        self.stage("book.toml");
+        self.commit(&repo, &MDWIKI_USER, "Update preprocessors".into())?;
+
+        Ok(())
+    }
+    async fn repair(&self) -> Result<(), String> {
+        let repo = self.get_repo()?;
+
+        if has_changes(&repo)? {
+            warn!("found uncommitted changes, committing them as recovered changes");
+            self.commit_all(&repo, &MDWIKI_USER, "Recovered changes".into())?;
+        }
+
+        let summary_path = Path::new(&self.config.path).join("src/SUMMARY.md");
+        let current_summary = fs::read_to_string(&summary_path).await.ok();
+        if !self.config.external_book && current_summary.as_ref() != Some(&self.summary().await) {
+            warn!("summary is out of sync with the files, regenerating");
+            self.update_summary().await?;
+            self.commit(&repo, &MDWIKI_USER, "Update summary".into())?;
+        }
+
+        if !Path::new(&self.config.path)
+            .join(&self.config.book_path)
+            .is_dir()
+            .await
+        {
+            warn!("build output is missing, it will be rebuilt");
+        }
+
+        Ok(())
+    }
+    /// Adds the script with mdwiki's buttons to the theme, or updates it, keeping the rest of
+    /// `theme/head.hbs` as it is. Changes are committed, unless the file is ignored.
+    async fn add_theme_script(&self) -> Result<(), String> {
+        if self.config.external_book {
+            return Ok(());
+        }
+        let theme_dir = Path::new(&self.config.path).join("theme");
+        let theme_path = theme_dir.join("head.hbs");
+        let head = fs::read_to_string(&theme_path).await.unwrap_or_default();
+        let updated = match with_theme_script(&head) {
+            Some(updated) if updated != head => updated,
+            Some(_) => return Ok(()),
+            None => {
+                warn!(
+                    "theme/head.hbs loads mdwiki's script outside of the mdwiki block, \
+                     it won't be updated"
+                );
+                return Ok(());
             }
         };
-        let book = match MDBook::load(&self.config.path) {
-            Ok(book) => {
-                info!("using existing mdbook at {}", self.config.path);
-                book
+        debug!("updating mdwiki theme script");
+        if !theme_dir.is_dir().await {
+            fs::create_dir(&theme_dir)
+                .await
+                .map_err(|_| "failed to create theme dir")?;
+        }
+        fs::write(&theme_path, updated)
+            .await
+            .map_err(|e| format!("failed to write theme script: {}", e))?;
+
+        let repo = self.get_repo()?;
+        let ignored = repo
+            .is_path_ignored("theme/head.hbs")
+            .map_err(|e| format!("could not check if the theme is ignored: {}", e))?;
+        if !ignored {
+            self.stage("theme/head.hbs");
+            let message = "Update mdwiki's script in the theme".to_string();
+            self.commit(&repo, &MDWIKI_USER, message)?;
+        }
+        Ok(())
+    }
+    /// Upgrades a book made by an older version of mdwiki, committing every migration on its
+    /// own. A migration that fails is rolled back, and mdwiki doesn't start.
+    fn migrate(&self) -> Result<(), String> {
+        let path = std::path::Path::new(&self.config.path);
+        let repo = self.get_repo()?;
+        if self.config.external_book {
+            return exclude_from_repo(&repo, &[".mdwiki", &self.config.book_path]);
+        }
+        for migration in migrations::pending(path)? {
+            info!(
+                "migrating the book to layout {}: {}",
+                migration.version, migration.description
+            );
+            let res = (migration.run)(path)
+                .and_then(|_| migrations::set_layout(path, migration.version))
+                .and_then(|_| {
+                    let message = format!(
+                        "Migrate to layout {}: {}",
+                        migration.version, migration.description
+                    );
+                    self.commit_all(&repo, &MDWIKI_USER, message)
+                });
+            if let Err(e) = res {
+                let mut checkout = CheckoutBuilder::new();
+                checkout.force().remove_untracked(true);
+                let _ = repo.checkout_head(Some(&mut checkout)).map_err(log_warn);
+                return Err(format!(
+                    "could not migrate the book to layout {}: {}",
+                    migration.version, e
+                ));
             }
-            Err(_) => {
-                return Err(format!("could not find book at {}", self.config.path));
+        }
+        Ok(())
+    }
+    fn get_repo(&self) -> Result<Repository, String> {
+        match Repository::open(&self.config.path) {
+            Ok(repo) => {
+                info!("using existing git repository");
+                Ok(repo)
             }
-        };
-        Ok((book, repo))
+            Err(_) => Err(format!("could not find git repo at {}", self.config.path)),
+        }
+    }
+    /// Regenerates the summary, returning the sections that were reorganized.
+    /// Books managed outside of mdwiki keep their summary as it is.
+    async fn update_summary(&self) -> Result<Vec<String>, String> {
+        if self.config.external_book {
+            return Ok(Vec::new());
+        }
+        let summary = self.summary().await;
+
+        let summary_path = Path::new(&self.config.path).join("src/SUMMARY.md");
+        let old_summary = fs::read_to_string(&summary_path).await.unwrap_or_default();
+        fs::write(summary_path, &summary)
+            .await
+            .map_err(|e| format!("could not write summary file: {}", e))?;
+        self.stage("src/SUMMARY.md");
+
+        Ok(reorganized_sections(&old_summary, &summary))
     }
-    async fn update_summary(&self) -> Result<(), String> {
+    async fn summary(&self) -> String {
+        use std::fmt::Write;
         let tree = self.config.get_wiki_tree().await;
+        let src = std::path::Path::new(&self.config.path).join("src");
 
-        fn build_summary(summary: &mut String, tree: WikiTree) {
-            use std::fmt::Write;
+        fn write_entry(summary: &mut String, level: usize, path: &Path, is_dir: bool) {
+            let title = match is_dir {
+                true => path.file_stem().map(|p| p.to_str()).flatten(),
+                false => path.file_stem().unwrap().to_str(),
+            };
+            let title = title.unwrap_or("README").replace("_", " ");
+            let link = match is_dir {
+                true => path.join("README.md"),
+                false => path.to_path_buf(),
+            };
+            write!(
+                summary,
+                "{1:0$}- [{2}]({3})\n",
+                level * 2,
+                "",
+                title,
+                url_path(&link)
+            )
+            .unwrap();
+        }
+        /// Writes the entries of the `fragment` of the directory `dir`, with the links made
+        /// relative to the book source, and returns the links.
+        fn write_fragment(
+            summary: &mut String,
+            fragment: &str,
+            dir: &Path,
+            level: usize,
+        ) -> Vec<PathBuf> {
+            let mut listed = Vec::new();
+            for line in fragment.lines() {
+                let item = line.trim();
+                if !item.starts_with("- ") && !item.starts_with("* ") {
+                    continue;
+                }
+                let link = match SUMMARY_LINK_REGEX.captures(item) {
+                    Some(captures) => captures.get(1).unwrap(),
+                    None => continue,
+                };
+                // links starting with `/` are relative to the book source already
+                let target = match link.as_str().strip_prefix('/') {
+                    Some(link) => PathBuf::from(link),
+                    None => dir.join(link.as_str()),
+                };
+                let indent = level * 2 + line.len() - line.trim_start().len();
+                writeln!(
+                    summary,
+                    "{1:0$}{2}{3})",
+                    indent,
+                    "",
+                    &item[..link.start()],
+                    url_path(&target)
+                )
+                .unwrap();
+                listed.push(target);
+            }
+            listed
+        }
+        /// Writes the pages under `tree` that aren't `listed` by the fragment of a directory
+        /// they're in, so they're still part of the book.
+        fn write_unlisted(
+            summary: &mut String,
+            tree: WikiTree,
+            listed: &[PathBuf],
+            level: usize,
+            home: &str,
+        ) {
             match tree {
+                WikiTree::File(path) => {
+                    if &*path != Path::new(home) && !listed.iter().any(|l| **l == *path) {
+                        write_entry(summary, level, &path, false);
+                    }
+                }
+                WikiTree::Directory(path, children) => {
+                    let readme = path.join("README.md");
+                    let level = match listed.contains(&readme) {
+                        true => level,
+                        false => {
+                            write_entry(summary, level, &path, true);
+                            level + 1
+                        }
+                    };
+                    for child in children {
+                        write_unlisted(summary, child, listed, level, home);
+                    }
+                }
+            }
+        }
+        fn build_summary(summary: &mut String, tree: WikiTree, home: &str, src: &std::path::Path) {
+            match tree {
+                // Already linked from the head of the summary
+                WikiTree::File(path) if &*path == Path::new(home) => {}
                 WikiTree::File(path) => {
                     let level = path.ancestors().count() - 2;
-                    let link_to = path.to_str().unwrap();
-                    let page_title = path
-                        .file_stem()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .replace("_", " ");
-                    write!(
-                        summary,
-                        "{1:0$}- [{2}]({3})\n",
-                        level * 2,
-                        "",
-                        page_title,
-                        link_to
-                    )
-                    .unwrap();
+                    write_entry(summary, level, &path, false);
                 }
                 WikiTree::Directory(path, children) => {
                     if &*path == Path::new("") {
-                        summary.write_str(SUMMARY_HEAD).unwrap();
+                        let head = SUMMARY_HEAD.replace("(README.md)", &format!("({})", home));
+                        summary.write_str(&head).unwrap();
                     } else {
                         let level = path.ancestors().count() - 2;
-                        let readme_path = path.join("README.md");
-                        let link_to = readme_path.to_str().unwrap();
-                        let page_title = path
-                            .file_stem()
-                            .map(|p| p.to_str())
-                            .flatten()
-                            .unwrap_or("README")
-                            .replace("_", " ");
-                        write!(
-                            summary,
-                            "{1:0$}- [{2}]({3})\n",
-                            level * 2,
-                            "",
-                            page_title,
-                            link_to
-                        )
-                        .unwrap();
+                        write_entry(summary, level, &path, true);
+                    }
+                    // Hand-curated directories list their pages themselves
+                    let fragment = src.join(&*path).join(SUMMARY_FRAGMENT);
+                    if let Ok(fragment) = std::fs::read_to_string(fragment) {
+                        let level = path.ancestors().count() - 1;
+                        let listed = write_fragment(summary, &fragment, &path, level);
+                        for child in children {
+                            write_unlisted(summary, child, &listed, level, home);
+                        }
+                        return;
                     }
                     for child in children {
-                        build_summary(summary, child);
+                        build_summary(summary, child, home, src);
                     }
                 }
             }
         }
         let mut summary = String::new();
-        build_summary(&mut summary, tree);
-
-        let summary_path = Path::new(&self.config.path).join("src/SUMMARY.md");
-        fs::write(summary_path, summary)
-            .await
-            .map_err(|e| format!("could not write summary file: {}", e))?;
-
-        Ok(())
+        build_summary(&mut summary, tree, self.config.home_page(), &src);
+        summary
+    }
+    /// Records that `path`, relative to the book, was changed by the request being handled, so
+    /// its commit adds it.
+    fn stage(&self, path: impl AsRef<std::path::Path>) {
+        self.staged.lock().unwrap().push(url_path(path));
     }
-    fn commit(&self, repo: &Repository, user: &User, commit_message: String) -> Result<(), String> {
+    /// Commits the files changed by the request being handled, see `stage`.
+    /// They stay staged when it fails, so `discard_changes` can revert them.
+    fn commit(&self, repo: &Repository, user: &User, message: String) -> Result<Oid, String> {
+        let paths = self.staged.lock().unwrap().clone();
+        let commit = self.commit_paths(repo, user, message, Some(&paths))?;
+        self.staged.lock().unwrap().clear();
+        Ok(commit)
+    }
+    /// Commits every change in the book that isn't ignored, for changes mdwiki can't list, like
+    /// restoring a backup.
+    fn commit_all(&self, repo: &Repository, user: &User, message: String) -> Result<Oid, String> {
+        self.staged.lock().unwrap().clear();
+        self.commit_paths(repo, user, message, None)
+    }
+    /// Commits the changes to `paths`, files or directories relative to the book, or to every
+    /// file without them.
+    fn commit_paths(
+        &self,
+        repo: &Repository,
+        user: &User,
+        commit_message: String,
+        paths: Option<&[String]>,
+    ) -> Result<Oid, String> {
+        self.shared.faults.check(Step::Commit)?;
         let mut index = repo
             .index()
             .map_err(|e| format!("failed to get the index file: {}", e))?;
-        index
-            .add_all(["*"].iter(), IndexAddOption::DEFAULT, None)
-            .map_err(|e| format!("failed to add files: {}", e))?;
+        // Files stored with Git LFS are added as pointers instead
+        let use_lfs = self.config.lfs;
+        let mut skip = |path: &std::path::Path, _: &[u8]| {
+            if self.config.never_committed(path) || (use_lfs && lfs::is_tracked(path)) {
+                1
+            } else {
+                0
+            }
+        };
+        let everything = ["*".to_string()];
+        // Names like `notes[1].md` are matched as they are, not as patterns
+        let (pathspecs, options) = match paths {
+            Some(paths) => (paths, IndexAddOption::DISABLE_PATHSPEC_MATCH),
+            None => (&everything[..], IndexAddOption::DEFAULT),
+        };
+        // An empty pathspec would match everything
+        if !pathspecs.is_empty() {
+            index
+                .add_all(
+                    pathspecs.iter(),
+                    options,
+                    Some(&mut skip as &mut IndexMatchedPath),
+                )
+                .map_err(|e| format!("failed to add files: {}", e))?;
+            if use_lfs {
+                lfs::add_pointers(repo, &mut index, paths)?;
+            }
+        }
+        self.untrack_never_committed(&mut index)?;
         index
             .write()
             .map_err(|e| format!("failed to write to index: {}", e))?;
@@ -434,7 +2430,7 @@ impl WikiState {
             .map_err(|e| format!("failed to write tree: {}", e))?;
 
         {
-            let sig = Signature::now(&user.username, "mdwiki@example.com")
+            let sig = Signature::now(user.name(), &self.config.commit_email(user))
                 .map_err(|e| format!("failed to get signature: {}", e))?;
             let tree = repo
                 .find_tree(tree_id)
@@ -444,15 +2440,35 @@ impl WikiState {
                 .ok()
                 .map(|head| head.peel_to_commit().ok())
                 .flatten();
-            repo.commit(
-                Some("HEAD"),
-                &sig,
-                &sig,
-                &commit_message,
-                &tree,
-                &parent.iter().collect::<Vec<_>>(),
-            )
-            .map_err(|e| format!("failed to create initial commit: {}", e))?;
+            let parent_tree = parent.as_ref().and_then(|parent| parent.tree().ok());
+            let commit_message = with_file_list(repo, parent_tree.as_ref(), &tree, commit_message);
+            let oid = repo
+                .commit(
+                    Some("HEAD"),
+                    &sig,
+                    &sig,
+                    &commit_message,
+                    &tree,
+                    &parent.iter().collect::<Vec<_>>(),
+                )
+                .map_err(|e| format!("failed to create initial commit: {}", e))?;
+            self.shared.mirrors.changed();
+            Ok(oid)
+        }
+    }
+    /// Removes the files that are never committed, see `Config::never_committed`, from `index`,
+    /// like build output added while `.gitignore` didn't ignore it. They stay on disk.
+    fn untrack_never_committed(&self, index: &mut Index) -> Result<(), String> {
+        let paths = index
+            .iter()
+            .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+            .filter(|path| self.config.never_committed(std::path::Path::new(path)))
+            .collect::<Vec<_>>();
+        for path in paths {
+            warn!("'{}' is never committed, removing it from the index", path);
+            index
+                .remove_path(std::path::Path::new(&path))
+                .map_err(|e| format!("could not remove '{}' from the index: {}", path, e))?;
         }
         Ok(())
     }
@@ -463,7 +2479,7 @@ impl WikiState {
             .collect();
         let mut failed = Vec::new();
         for filename in captures {
-            let uploaded_file = Path::new(&self.config.tmp_upload_path).join(&filename);
+            let uploaded_file = Path::new(&self.config.upload_path()).join(&filename);
             if uploaded_file.is_file().await {
                 debug!("adding image: {}", &filename);
                 if fs::rename(
@@ -477,6 +2493,8 @@ impl WikiState {
                 {
                     warn!("failed to add image: {}", &filename);
                     failed.push(filename)
+                } else {
+                    self.stage(Path::new("src/images").join(&filename));
                 }
             }
         }
@@ -487,3 +2505,555 @@ impl WikiState {
         }
     }
 }
+
+/// The outermost directories with entries that differ between two summaries, `""` being the
+/// top level.
+fn reorganized_sections(old: &str, new: &str) -> Vec<String> {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    fn entries(summary: &str) -> HashSet<&str> {
+        summary
+            .lines()
+            .filter_map(|line| SUMMARY_LINK_REGEX.captures(line))
+            .filter_map(|captures| captures.get(1))
+            .map(|link| link.as_str())
+            .collect()
+    }
+    let (old, new) = (entries(old), entries(new));
+
+    // A README.md entry stands for its directory, which is listed in its parent
+    let mut sections = old
+        .symmetric_difference(&new)
+        .map(|link| {
+            let path = Path::new(link);
+            match path.file_name() {
+                Some(name) if name == "README.md" => path.parent(),
+                _ => Some(path),
+            }
+            .and_then(Path::parent)
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+    sections.sort();
+    sections.dedup();
+    let contains = |outer: &String, section: &String| {
+        outer != section && (outer.is_empty() || section.starts_with(&format!("{}/", outer)))
+    };
+    sections
+        .iter()
+        .filter(|section| !sections.iter().any(|outer| contains(outer, section)))
+        .cloned()
+        .collect()
+}
+
+/// Loads the book to build it into `build_dir`, with mdwiki's own preprocessors added.
+fn load_book(
+    config: &Config,
+    plugins: Arc<Plugins>,
+    build_dir: std::path::PathBuf,
+) -> Result<MDBook, String> {
+    let mut book = match MDBook::load(&config.path) {
+        Ok(book) => {
+            info!("using existing mdbook at {}", config.path);
+            book
+        }
+        Err(_) => {
+            return Err(format!("could not find book at {}", config.path));
+        }
+    };
+    if !plugins.is_empty() {
+        book.with_preprocessor(PluginPreprocessor(plugins.clone()));
+    }
+    if config.sanitize_html {
+        book.with_preprocessor(SanitizePreprocessor);
+    }
+    book.with_preprocessor(LifecyclePreprocessor);
+    if config.renderer != Renderer::Mdbook {
+        book.with_preprocessor(RenderPreprocessor {
+            renderer: config.renderer.clone(),
+            plugins,
+            sanitize: config.sanitize_html,
+        });
+    }
+    if config.footer {
+        book.with_preprocessor(FooterPreprocessor::new(&config.branding.title, &config.path));
+    }
+    if let Some(sandbox) = &config.preprocessor_sandbox {
+        sandbox::sandbox_preprocessors(&mut book, sandbox);
+    }
+    book.config.build.build_dir = build_dir;
+    Ok(book)
+}
+
+/// A hunk of a diff, with the section of the page it changes.
+#[derive(Debug, Serialize)]
+pub struct DiffHunk {
+    pub header: String,
+    /// The heading of the section the first change is in, for pages.
+    pub section: Option<String>,
+    /// Where the section is in the current version of the page, if it still exists.
+    pub url: Option<String>,
+    /// The lines of the hunk, starting with `+`, `-` or a space.
+    pub lines: Vec<String>,
+    #[serde(skip)]
+    first_change: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileDiff {
+    pub path: String,
+    pub hunks: Vec<DiffHunk>,
+    #[serde(skip)]
+    new_id: Oid,
+}
+
+/// The changes made by a commit, file by file.
+#[derive(Debug, Serialize)]
+pub struct CommitDiff {
+    pub commit: String,
+    pub author: String,
+    pub summary: String,
+    pub files: Vec<FileDiff>,
+}
+
+/// Links every hunk changing the page `page`, relative to the book source, to the section it
+/// changes, using the headings of the page as of the diff, `content`.
+fn link_sections(path: &str, page: &str, content: &str, hunks: &mut [DiffHunk]) {
+    let current = std::fs::read_to_string(std::path::Path::new(path).join("src").join(page));
+    let current = match current {
+        Ok(current) => headings::sections(&current),
+        Err(_) => return,
+    };
+    let sections = headings::sections(content);
+    let url = format!("/{}", page_url(page));
+    for hunk in hunks {
+        let section = sections
+            .iter()
+            .take_while(|section| section.line <= hunk.first_change)
+            .last();
+        hunk.section = section.map(|section| section.title.clone());
+        hunk.url = match section {
+            Some(section) if current.iter().any(|current| current.id == section.id) => {
+                Some(format!("{}#{}", url, section.id))
+            }
+            _ => Some(url.clone()),
+        };
+    }
+}
+
+/// How many changed files are listed in a commit message at most.
+const COMMIT_FILE_LIST: usize = 50;
+
+/// `message` with the files the commit changes from `parent` to `tree` listed after it, like
+/// `git show --name-status` does, when it changes more than one. Actions that change more files
+/// than the one they're named after, like creating the index pages of new directories, say so
+/// in the history.
+fn with_file_list(
+    repo: &Repository,
+    parent: Option<&Tree>,
+    tree: &Tree,
+    message: String,
+) -> String {
+    let diff = match repo.diff_tree_to_tree(parent, Some(tree), None) {
+        Ok(diff) => diff,
+        Err(e) => {
+            warn!("could not list the changed files: {}", e);
+            return message;
+        }
+    };
+    let files = diff
+        .deltas()
+        .filter_map(|delta| {
+            let (status, file) = match delta.status() {
+                Delta::Added => ("A", delta.new_file()),
+                Delta::Deleted => ("D", delta.old_file()),
+                _ => ("M", delta.new_file()),
+            };
+            Some(format!("{} {}", status, url_path(file.path()?)))
+        })
+        .collect::<Vec<_>>();
+    if files.len() < 2 {
+        return message;
+    }
+    let mut message = format!("{}\n", message.trim_end());
+    for file in files.iter().take(COMMIT_FILE_LIST) {
+        message.push_str(&format!("\n{}", file));
+    }
+    if files.len() > COMMIT_FILE_LIST {
+        message.push_str(&format!("\nand {} more", files.len() - COMMIT_FILE_LIST));
+    }
+    message
+}
+
+/// The changes made by `commit`, with every hunk of a page linked to the section it changes.
+/// `None` if there's no such commit.
+pub fn commit_diff(path: &str, commit: &str) -> Result<Option<CommitDiff>, String> {
+    let repo = Repository::open(path).map_err(|e| format!("could not open repo: {}", e))?;
+    let commit = match Oid::from_str(commit).and_then(|oid| repo.find_commit(oid)) {
+        Ok(commit) => commit,
+        Err(e) if e.code() == ErrorCode::NotFound || e.class() == ErrorClass::Invalid => {
+            return Ok(None)
+        }
+        Err(e) => return Err(format!("could not find commit '{}': {}", commit, e)),
+    };
+    let tree = commit.tree().map_err(|e| e.to_string())?;
+    let parent_tree = match commit.parents().next() {
+        Some(parent) => Some(parent.tree().map_err(|e| e.to_string())?),
+        None => None,
+    };
+    let diff = repo
+        .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+        .map_err(|e| format!("could not diff commit: {}", e))?;
+
+    let mut files: Vec<FileDiff> = Vec::new();
+    // the line in the new file the hunk has got to
+    let mut new_line = 0;
+    diff.print(DiffFormat::Patch, |delta, hunk, line| {
+        let file = delta
+            .new_file()
+            .path()
+            .or_else(|| delta.old_file().path())
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if files.last().map_or(true, |last| last.path != file) {
+            files.push(FileDiff {
+                path: file,
+                hunks: Vec::new(),
+                new_id: delta.new_file().id(),
+            });
+        }
+        let hunks = &mut files.last_mut().unwrap().hunks;
+        match (line.origin(), hunk) {
+            ('H', Some(hunk)) => {
+                new_line = hunk.new_start() as usize;
+                hunks.push(DiffHunk {
+                    header: String::from_utf8_lossy(hunk.header())
+                        .trim_end()
+                        .to_string(),
+                    section: None,
+                    url: None,
+                    lines: Vec::new(),
+                    first_change: 0,
+                });
+            }
+            (origin @ '+', _) | (origin @ '-', _) | (origin @ ' ', _) => {
+                if let Some(current) = hunks.last_mut() {
+                    if let Some(lineno) = line.new_lineno() {
+                        new_line = lineno as usize;
+                    }
+                    if origin != ' ' && current.first_change == 0 {
+                        current.first_change = new_line;
+                    }
+                    let content = String::from_utf8_lossy(line.content());
+                    let content = content.trim_end_matches(|c| c == '\n' || c == '\r');
+                    current.lines.push(format!("{}{}", origin, content));
+                }
+            }
+            _ => {}
+        }
+        true
+    })
+    .map_err(|e| format!("could not print diff: {}", e))?;
+
+    for file in &mut files {
+        let page = match std::path::Path::new(&file.path).strip_prefix("src") {
+            Ok(page)
+                if page.extension().map_or(false, |ext| ext == "md")
+                    && !page.ends_with("SUMMARY.md") =>
+            {
+                page.to_string_lossy().to_string()
+            }
+            _ => continue,
+        };
+        let content = match repo.find_blob(file.new_id) {
+            Ok(blob) => String::from_utf8_lossy(blob.content()).to_string(),
+            Err(_) => continue,
+        };
+        link_sections(path, &page, &content, &mut file.hunks);
+    }
+
+    Ok(Some(CommitDiff {
+        commit: commit.id().to_string(),
+        author: commit.author().to_string(),
+        summary: commit.summary().unwrap_or("").to_string(),
+        files,
+    }))
+}
+
+/// A page changed by a recent commit.
+#[derive(Debug, Serialize)]
+pub struct RecentChange {
+    pub path: String,
+    pub author: String,
+    pub time: i64,
+    pub commit: String,
+}
+
+/// The `limit` most recently changed pages, newest first, each with the last commit changing
+/// it. Looks at no more than the last 500 commits.
+pub fn recent_changes(path: &str, limit: usize) -> Result<Vec<RecentChange>, String> {
+    let repo = Repository::open(path).map_err(|e| format!("could not open repo: {}", e))?;
+    let mut revwalk = repo.revwalk().map_err(|e| e.to_string())?;
+    revwalk.push_head().map_err(|e| e.to_string())?;
+
+    let mut changes: Vec<RecentChange> = Vec::new();
+    for oid in revwalk.take(500) {
+        let commit = oid
+            .and_then(|oid| repo.find_commit(oid))
+            .map_err(|e| e.to_string())?;
+        let tree = commit.tree().map_err(|e| e.to_string())?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree().map_err(|e| e.to_string())?),
+            None => None,
+        };
+        let diff = repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+            .map_err(|e| format!("could not diff commit: {}", e))?;
+        for delta in diff.deltas() {
+            let page = delta
+                .new_file()
+                .path()
+                .and_then(|path| path.strip_prefix("src").ok())
+                .filter(|path| path.extension().map_or(false, |ext| ext == "md"))
+                .filter(|path| !path.ends_with("SUMMARY.md"))
+                .map(|path| path.to_string_lossy().to_string());
+            let page = match page {
+                Some(page) if !changes.iter().any(|change| change.path == page) => page,
+                _ => continue,
+            };
+            if !std::path::Path::new(path).join("src").join(&page).is_file() {
+                continue;
+            }
+            changes.push(RecentChange {
+                path: page,
+                author: commit.author().name().unwrap_or("").to_string(),
+                time: commit.time().seconds(),
+                commit: commit.id().to_string(),
+            });
+            if changes.len() >= limit {
+                return Ok(changes);
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// A line of a page, with the commit that last changed it.
+#[derive(Debug, Serialize)]
+pub struct BlameLine {
+    pub number: usize,
+    pub text: String,
+    pub commit: String,
+    pub author: String,
+    pub time: i64,
+    pub summary: String,
+    /// Whether the line before it was changed by another commit, so the commit is shown.
+    pub first: bool,
+}
+
+/// Who last changed every line of `page`, relative to the book source, as of the last commit.
+/// `None` if the page isn't in the last commit.
+pub fn blame(path: &str, page: &str) -> Result<Option<Vec<BlameLine>>, String> {
+    let err = |e: git2::Error| format!("could not blame '{}': {}", page, e);
+    let repo = Repository::open(path).map_err(|e| format!("could not open repo: {}", e))?;
+    let file = std::path::Path::new("src").join(page);
+    let tree = repo
+        .head()
+        .and_then(|head| head.peel_to_tree())
+        .map_err(err)?;
+    let entry = match tree.get_path(&file) {
+        Ok(entry) => entry,
+        Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(err(e)),
+    };
+    let blob = entry
+        .to_object(&repo)
+        .and_then(|object| object.peel_to_blob())
+        .map_err(err)?;
+    let content = String::from_utf8_lossy(blob.content()).into_owned();
+    let blame = repo.blame_file(&file, None).map_err(err)?;
+
+    let mut summaries = HashMap::new();
+    let mut lines: Vec<BlameLine> = Vec::new();
+    for (i, text) in content.lines().enumerate() {
+        let hunk = match blame.get_line(i + 1) {
+            Some(hunk) => hunk,
+            None => continue,
+        };
+        let id = hunk.final_commit_id();
+        let summary = summaries
+            .entry(id)
+            .or_insert_with(|| {
+                repo.find_commit(id)
+                    .ok()
+                    .and_then(|commit| commit.summary().map(String::from))
+                    .unwrap_or_default()
+            })
+            .clone();
+        let signature = hunk.final_signature();
+        let commit = id.to_string();
+        lines.push(BlameLine {
+            number: i + 1,
+            text: text.to_string(),
+            first: lines.last().map_or(true, |line| line.commit != commit),
+            commit,
+            author: signature.name().unwrap_or("").to_string(),
+            time: signature.when().seconds(),
+            summary,
+        });
+    }
+    Ok(Some(lines))
+}
+
+/// A page that was deleted, and not created again since.
+#[derive(Debug, Serialize)]
+pub struct DeletedPage {
+    pub path: String,
+    /// The commit that deleted the page.
+    pub commit: String,
+    pub author: String,
+    pub time: i64,
+    pub summary: String,
+}
+
+/// The pages deleted from the book at `path`, most recently deleted first, whether through
+/// mdwiki or by a push to the repository.
+pub fn deleted_pages(path: &str) -> Result<Vec<DeletedPage>, String> {
+    let err = |e: git2::Error| format!("could not read the history: {}", e);
+    let repo = Repository::open(path).map_err(|e| format!("could not open repo: {}", e))?;
+    let mut revwalk = repo.revwalk().map_err(err)?;
+    revwalk.push_head().map_err(err)?;
+
+    let src = std::path::Path::new(path).join("src");
+    let mut seen = Vec::new();
+    let mut deleted = Vec::new();
+    for oid in revwalk {
+        let commit = oid.and_then(|oid| repo.find_commit(oid)).map_err(err)?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => parent.tree().map_err(err)?,
+            None => continue,
+        };
+        let tree = commit.tree().map_err(err)?;
+        let diff = repo
+            .diff_tree_to_tree(Some(&parent_tree), Some(&tree), None)
+            .map_err(err)?;
+        for delta in diff.deltas() {
+            if delta.status() != git2::Delta::Deleted {
+                continue;
+            }
+            let page = delta
+                .old_file()
+                .path()
+                .and_then(|path| path.strip_prefix("src").ok())
+                .filter(|path| path.extension().map_or(false, |ext| ext == "md"))
+                .filter(|path| !path.ends_with("SUMMARY.md"))
+                .map(|path| path.to_string_lossy().to_string());
+            // only the last time a page was deleted counts
+            let page = match page {
+                Some(page) if !seen.contains(&page) => page,
+                _ => continue,
+            };
+            seen.push(page.clone());
+            if src.join(&page).exists() {
+                continue;
+            }
+            deleted.push(DeletedPage {
+                path: page,
+                commit: commit.id().to_string(),
+                author: commit.author().name().unwrap_or("").to_string(),
+                time: commit.time().seconds(),
+                summary: commit.summary().unwrap_or("").to_string(),
+            });
+        }
+    }
+    Ok(deleted)
+}
+
+/// The content of `page` before the commit `commit` deleted it.
+fn deleted_content(path: &str, page: &str, commit: &str) -> Result<String, String> {
+    let err = |e: git2::Error| format!("could not find '{}' before {}: {}", page, commit, e);
+    let repo = Repository::open(path).map_err(|e| format!("could not open repo: {}", e))?;
+    let commit = repo
+        .revparse_single(commit)
+        .and_then(|object| object.peel_to_commit())
+        .map_err(err)?;
+    let file = std::path::Path::new("src").join(page);
+    if commit.tree().map_err(err)?.get_path(&file).is_ok() {
+        return Err(format!("'{}' wasn't deleted by {}", page, commit.id()));
+    }
+    let blob = commit
+        .parent(0)
+        .and_then(|parent| parent.tree())
+        .and_then(|tree| tree.get_path(&file))
+        .and_then(|entry| entry.to_object(&repo))
+        .and_then(|object| object.peel_to_blob())
+        .map_err(err)?;
+    String::from_utf8(blob.content().to_vec()).map_err(|e| e.to_string())
+}
+
+/// `head`, the theme's `head.hbs`, with mdwiki's script between the markers. Without markers
+/// the script is added at the end, replacing the file mdwiki used to write. Returns `None` if
+/// `head` loads the script on its own, which is left to the user.
+fn with_theme_script(head: &str) -> Option<String> {
+    let block = format!(
+        "{}\n{}\n{}\n",
+        THEME_SCRIPT_START,
+        THEME_OVERRIDE_SCRIPT.trim_end(),
+        THEME_SCRIPT_END
+    );
+    if let Some(start) = head.find(THEME_SCRIPT_START) {
+        if let Some(len) = head[start..].find(THEME_SCRIPT_END) {
+            let end = start + len + THEME_SCRIPT_END.len();
+            let rest = head[end..].strip_prefix('\n').unwrap_or(&head[end..]);
+            return Some(format!("{}{}{}", &head[..start], block, rest));
+        }
+    }
+    if head.trim().is_empty() || head.trim() == THEME_OVERRIDE_SCRIPT.trim() {
+        return Some(block);
+    }
+    if head.contains("/mdwiki_script.js") {
+        return None;
+    }
+    match head.ends_with('\n') {
+        true => Some(format!("{}{}", head, block)),
+        false => Some(format!("{}\n{}", head, block)),
+    }
+}
+
+/// Keeps the `patterns` out of commits through the repository's `info/exclude`, which unlike
+/// `.gitignore` isn't part of the book.
+fn exclude_from_repo(repo: &Repository, patterns: &[&str]) -> Result<(), String> {
+    let path = repo.path().join("info").join("exclude");
+    let mut exclude = std::fs::read_to_string(&path).unwrap_or_default();
+    let missing = patterns
+        .iter()
+        .filter(|pattern| !exclude.lines().any(|line| line.trim() == **pattern))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if !exclude.is_empty() && !exclude.ends_with('\n') {
+        exclude.push('\n');
+    }
+    for pattern in missing {
+        exclude.push_str(pattern);
+        exclude.push('\n');
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("could not create '{}': {}", parent.display(), e))?;
+    }
+    std::fs::write(&path, exclude)
+        .map_err(|e| format!("could not write '{}': {}", path.display(), e))
+}
+
+fn has_changes(repo: &Repository) -> Result<bool, String> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true);
+    let statuses = repo
+        .statuses(Some(&mut options))
+        .map_err(|e| format!("failed to get repository status: {}", e))?;
+    Ok(statuses.iter().any(|entry| !entry.status().is_ignored()))
+}